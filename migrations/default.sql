@@ -12,7 +12,9 @@ create table libraries
             on update cascade on delete cascade,
     id         uuid                    not null
         constraint libraries_pk
-            primary key
+            primary key,
+    max_versions           integer,
+    version_retention_days integer
 );
 create table repos
 (
@@ -31,6 +33,264 @@ create table users
     name       varchar(255)            not null,
     password   varchar(128),
     email      varchar(128)            not null,
-    username   varchar(64)             not null
+    username   varchar(64)             not null,
+    credentials_version integer default 0 not null
 );
 
+create table uploads
+(
+    id            uuid                    not null
+        constraint uploads_pk
+            primary key,
+    library_id    uuid                    not null
+        constraint uploads_library_id
+            references libraries
+            on update cascade on delete cascade,
+    path          text                    not null,
+    upload_length bigint                  not null,
+    upload_offset bigint    default 0     not null,
+    metadata      text,
+    created_at    timestamp default now() not null,
+    expires_at    timestamp               not null,
+    created_by    varchar(64)             not null
+        constraint uploads_created_by
+            references users
+            on update cascade on delete cascade
+);
+create table library_permissions
+(
+    library_id uuid                    not null
+        constraint library_permissions_library_id
+            references libraries
+            on update cascade on delete cascade,
+    user_id    varchar(64)             not null
+        constraint library_permissions_user_id
+            references users
+            on update cascade on delete cascade,
+    level      smallint                not null,
+    created_at timestamp default now() not null,
+    constraint library_permissions_pk
+        primary key (library_id, user_id)
+);
+create table api_tokens
+(
+    id           uuid                    not null
+        constraint api_tokens_pk
+            primary key,
+    user_id      varchar(64)             not null
+        constraint api_tokens_user_id
+            references users
+            on update cascade on delete cascade,
+    name         varchar(64)             not null,
+    token_hash   varchar(64)             not null
+        constraint api_tokens_token_hash
+            unique,
+    library_id   uuid
+        constraint api_tokens_library_id
+            references libraries
+            on update cascade on delete cascade,
+    scope        smallint                not null,
+    created_at   timestamp default now() not null,
+    expires_at   timestamp,
+    last_used_at timestamp
+);
+create table sessions
+(
+    id         varchar(64)             not null
+        constraint sessions_pk
+            primary key,
+    data       jsonb                   not null,
+    user_id    varchar(64) generated always as (data #>> '{login,user,id}') stored,
+    created_at timestamp default now() not null,
+    expires_at timestamp               not null
+);
+create index sessions_expires_at
+    on sessions (expires_at);
+create index sessions_user_id
+    on sessions (user_id);
+create table trash
+(
+    id          uuid                    not null
+        constraint trash_pk
+            primary key,
+    library_id  uuid                    not null
+        constraint trash_library_id
+            references libraries
+            on update cascade on delete cascade,
+    path        text                    not null,
+    is_folder   boolean                 not null,
+    size        bigint,
+    deleted_by  varchar(64)
+        constraint trash_deleted_by
+            references users
+            on update cascade on delete set null,
+    deleted_at  timestamp default now() not null
+);
+create index trash_library_id
+    on trash (library_id);
+
+create table file_versions
+(
+    id          uuid                    not null
+        constraint file_versions_pk
+            primary key,
+    library_id  uuid                    not null
+        constraint file_versions_library_id
+            references libraries
+            on update cascade on delete cascade,
+    path        text                    not null,
+    size        bigint                  not null,
+    created_by  varchar(64)
+        constraint file_versions_created_by
+            references users
+            on update cascade on delete set null,
+    created_at  timestamp default now() not null
+);
+create index file_versions_library_id_path
+    on file_versions (library_id, path);
+
+create table file_index
+(
+    library_id   uuid                    not null
+        constraint file_index_library_id
+            references libraries
+            on update cascade on delete cascade,
+    path         text                    not null,
+    name         text                    not null,
+    is_folder    boolean                 not null,
+    size         bigint,
+    content_type varchar(255),
+    modified_at  timestamp,
+    contents     tsvector,
+    indexed_at   timestamp default now() not null,
+    constraint file_index_pk
+        primary key (library_id, path)
+);
+create index file_index_library_id_path
+    on file_index (library_id, path text_pattern_ops);
+create index file_index_contents
+    on file_index using gin (contents);
+
+create table share_links
+(
+    id             uuid                    not null
+        constraint share_links_pk
+            primary key,
+    library_id     uuid                    not null
+        constraint share_links_library_id
+            references libraries
+            on update cascade on delete cascade,
+    path           text                    not null,
+    token          varchar(64)             not null
+        constraint share_links_token
+            unique,
+    mode           smallint                not null,
+    password       varchar(255),
+    expires_at     timestamp,
+    max_downloads  integer,
+    download_count integer   default 0     not null,
+    ask_uploader   boolean   default false not null,
+    created_by     varchar(64)             not null
+        constraint share_links_created_by
+            references users
+            on update cascade on delete cascade,
+    created_at     timestamp default now() not null
+);
+create index share_links_library_id
+    on share_links (library_id);
+create table share_link_uploads
+(
+    id             uuid                    not null
+        constraint share_link_uploads_pk
+            primary key,
+    library_id     uuid                    not null
+        constraint share_link_uploads_library_id
+            references libraries
+            on update cascade on delete cascade,
+    link_id        uuid
+        constraint share_link_uploads_link_id
+            references share_links
+            on update cascade on delete set null,
+    path           text                    not null,
+    size           bigint                  not null,
+    uploader_name  varchar(255),
+    uploader_email varchar(255),
+    uploaded_at    timestamp default now() not null
+);
+create index share_link_uploads_library_id
+    on share_link_uploads (library_id);
+create table favorites
+(
+    id         uuid                    not null
+        constraint favorites_pk
+            primary key,
+    user_id    varchar(64)             not null
+        constraint favorites_user_id
+            references users
+            on update cascade on delete cascade,
+    library_id uuid                    not null
+        constraint favorites_library_id
+            references libraries
+            on update cascade on delete cascade,
+    path       text                    not null,
+    created_at timestamp default now() not null,
+    constraint favorites_user_path
+        unique (user_id, library_id, path)
+);
+create index favorites_library_id
+    on favorites (library_id);
+create table activity
+(
+    id         uuid                    not null
+        constraint activity_pk
+            primary key,
+    library_id uuid                    not null
+        constraint activity_library_id
+            references libraries
+            on update cascade on delete cascade,
+    user_id    varchar(64)
+        constraint activity_user_id
+            references users
+            on update cascade on delete set null,
+    action     smallint                not null,
+    path       text                    not null,
+    detail     text,
+    ip         varchar(45),
+    created_at timestamp default now() not null
+);
+create index activity_library_id_created_at
+    on activity (library_id, created_at);
+create index activity_created_at
+    on activity (created_at);
+create table notifications
+(
+    id         uuid                    not null
+        constraint notifications_pk
+            primary key,
+    user_id    varchar(64)             not null
+        constraint notifications_user_id
+            references users
+            on update cascade on delete cascade,
+    kind       smallint                not null,
+    library_id uuid                    not null
+        constraint notifications_library_id
+            references libraries
+            on update cascade on delete cascade,
+    path       text                    not null,
+    detail     text,
+    read_at    timestamp,
+    created_at timestamp default now() not null
+);
+create index notifications_user_id_created_at
+    on notifications (user_id, created_at);
+create table notification_preferences
+(
+    user_id varchar(64) not null
+        constraint notification_preferences_user_id
+            references users
+            on update cascade on delete cascade,
+    kind    smallint    not null,
+    enabled boolean     not null,
+    constraint notification_preferences_pk
+        primary key (user_id, kind)
+);
@@ -0,0 +1,484 @@
+use std::collections::HashSet;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use async_compression::tokio::bufread::GzipDecoder;
+use async_compression::tokio::write::GzipEncoder;
+use async_zip::{Compression, ZipDateTime, ZipEntryBuilder};
+use async_zip::base::write::ZipFileWriter;
+use chrono::{DateTime, Utc};
+use log::{debug, error};
+use rocket::http::{ContentType, Header};
+use rocket::response::Responder;
+use rocket::futures::StreamExt;
+use rocket::serde::Serialize;
+use rocket::{FromFormField, Request, Response};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use crate::consts::{ARCHIVE_BUFFER_SIZE, MAX_EXTRACT_ENTRIES, MAX_EXTRACT_SIZE};
+use crate::managers::versions::VersionManager;
+use crate::objs::library::{check_path, ListOptions, Library};
+use crate::storage::{numbered_path, FileType, StorageError};
+use crate::util::{storage_error, JsonErrorResponse, LimitedReader, ResponseError};
+
+/// The formats that folders and selections of files can be downloaded as
+#[derive(Debug, Clone, Copy, Default, PartialEq, FromFormField)]
+pub enum ArchiveFormat {
+    #[default]
+    #[field(value = "zip")]
+    Zip,
+    #[field(value = "tar.gz")]
+    #[field(value = "tgz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+
+    fn content_type(&self) -> ContentType {
+        match self {
+            ArchiveFormat::Zip => ContentType::ZIP,
+            ArchiveFormat::TarGz => ContentType::GZIP,
+        }
+    }
+}
+
+/// A file or folder to add to the archive
+struct ArchiveEntry {
+    /// Path inside the library
+    path: PathBuf,
+    /// Path inside the archive
+    name: String,
+}
+
+/// An archive of files and folders that is built while it is being sent.
+/// Files are streamed from the library one at a time, so neither the archive nor the files are held in memory or written to disk
+pub struct ArchiveDownload {
+    file_name: String,
+    format: ArchiveFormat,
+    reader: DuplexStream,
+}
+
+impl ArchiveDownload {
+    /// Starts building the archive of the paths, an empty path being the whole library. Folders are added with everything in them.
+    /// Every path is checked first, as the response can't report errors once the archive is being sent
+    pub async fn start(library: &Library, paths: &[PathBuf], format: ArchiveFormat) -> Result<Self, ResponseError> {
+        let mut entries = Vec::new();
+        let mut names = HashSet::new();
+        for path in paths {
+            if !library.exists(path).await.map_err(storage_error)? {
+                return Err(StorageError::NotFound.into())
+            }
+            let mut name = path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| library.model().name.clone());
+            // Two selected files can have the same name when they are in different folders
+            let mut n = 1;
+            while names.contains(&name) {
+                name = numbered_path(&PathBuf::from(&name), n).to_string_lossy().into_owned();
+                n += 1;
+            }
+            names.insert(name.clone());
+            entries.push(ArchiveEntry { path: path.clone(), name });
+        }
+        let file_name = match entries.as_slice() {
+            [entry] => format!("{}.{}", entry.name, format.extension()),
+            _ => format!("{}.{}", library.model().name, format.extension()),
+        };
+        let (writer, reader) = tokio::io::duplex(ARCHIVE_BUFFER_SIZE);
+        let library = library.clone();
+        tokio::spawn(async move {
+            let result = match format {
+                ArchiveFormat::Zip => write_zip(&library, entries, writer).await,
+                ArchiveFormat::TarGz => write_tar_gz(&library, entries, writer).await,
+            };
+            // The client only sees a truncated download, as the response already started
+            if let Err(e) = result {
+                error!("Failed to build archive of library {}: {}", library.model().id, e);
+            }
+        });
+        Ok(ArchiveDownload { file_name, format, reader })
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ArchiveDownload {
+    fn respond_to(self, _request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        Response::build()
+            .header(self.format.content_type())
+            .header(Header::new("Content-Disposition", format!("attachment; filename=\"{}\"", self.file_name.replace('"', ""))))
+            .streamed_body(self.reader)
+            .ok()
+    }
+}
+
+/// A file or folder found while walking the selected entries
+enum WalkItem {
+    Folder { name: String, last_modified: Option<DateTime<Utc>> },
+    File { name: String, size: u64, last_modified: Option<DateTime<Utc>>, contents: Box<dyn AsyncRead + Send + Unpin> },
+}
+
+/// Walks the entries depth first, opening each file just before it is added
+struct Walker<'a> {
+    library: &'a Library,
+    pending: Vec<ArchiveEntry>,
+}
+
+impl<'a> Walker<'a> {
+    fn new(library: &'a Library, mut entries: Vec<ArchiveEntry>) -> Self {
+        entries.reverse();
+        Walker { library, pending: entries }
+    }
+
+    async fn next(&mut self) -> Result<Option<WalkItem>, anyhow::Error> {
+        while let Some(entry) = self.pending.pop() {
+            if let Some(metadata) = self.library.stat_file(&entry.path).await? {
+                // The file was removed after being listed
+                let Some(contents) = self.library.read_file(&entry.path).await? else {
+                    continue
+                };
+                return Ok(Some(WalkItem::File {
+                    name: entry.name,
+                    size: metadata.size,
+                    last_modified: metadata.last_modified,
+                    contents: Box::new(contents.take(metadata.size)),
+                }))
+            }
+            let files = self.library.list_files(&entry.path, ListOptions::default()).await?;
+            // Symlinks are skipped, they could point outside the library
+            for file in files.into_iter().rev() {
+                if matches!(file._type, FileType::File | FileType::Folder) {
+                    self.pending.push(ArchiveEntry {
+                        path: entry.path.join(&file.path),
+                        name: format!("{}/{}", entry.name, file.path),
+                    });
+                }
+            }
+            return Ok(Some(WalkItem::Folder { name: entry.name, last_modified: None }))
+        }
+        Ok(None)
+    }
+}
+
+async fn write_zip(library: &Library, entries: Vec<ArchiveEntry>, writer: impl AsyncWrite + Unpin) -> Result<(), anyhow::Error> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let mut walker = Walker::new(library, entries);
+    while let Some(item) = walker.next().await? {
+        match item {
+            WalkItem::Folder { name, last_modified } => {
+                debug!("archive: adding folder {}", name);
+                let entry = ZipEntryBuilder::new(format!("{}/", name).into(), Compression::Stored)
+                    .last_modification_date(ZipDateTime::from_chrono(&last_modified.unwrap_or_else(Utc::now)));
+                zip.write_entry_whole(entry, &[]).await?;
+            },
+            WalkItem::File { name, last_modified, contents, .. } => {
+                debug!("archive: adding file {}", name);
+                let entry = ZipEntryBuilder::new(name.into(), Compression::Deflate)
+                    .last_modification_date(ZipDateTime::from_chrono(&last_modified.unwrap_or_else(Utc::now)));
+                let mut entry_writer = zip.write_entry_stream(entry).await?;
+                rocket::futures::io::copy(contents.compat(), &mut entry_writer).await?;
+                entry_writer.close().await?;
+            }
+        }
+    }
+    let mut writer = zip.close().await?.into_inner();
+    writer.shutdown().await?;
+    Ok(())
+}
+
+async fn write_tar_gz(library: &Library, entries: Vec<ArchiveEntry>, writer: impl AsyncWrite + Unpin + Send + 'static) -> Result<(), anyhow::Error> {
+    let mut tar = tokio_tar::Builder::new(GzipEncoder::new(writer));
+    let mut walker = Walker::new(library, entries);
+    while let Some(item) = walker.next().await? {
+        let mut header = tokio_tar::Header::new_gnu();
+        match item {
+            WalkItem::Folder { name, last_modified } => {
+                header.set_entry_type(tokio_tar::EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                header.set_mtime(last_modified.unwrap_or_else(Utc::now).timestamp().max(0) as u64);
+                tar.append_data(&mut header, format!("{}/", name), tokio::io::empty()).await?;
+            },
+            WalkItem::File { name, size, last_modified, contents } => {
+                header.set_entry_type(tokio_tar::EntryType::Regular);
+                header.set_mode(0o644);
+                header.set_size(size);
+                header.set_mtime(last_modified.unwrap_or_else(Utc::now).timestamp().max(0) as u64);
+                tar.append_data(&mut header, name, contents).await?;
+            }
+        }
+    }
+    let mut encoder = tar.into_inner().await?;
+    encoder.shutdown().await?;
+    Ok(())
+}
+
+/// An entry of an uploaded archive that was not extracted
+#[derive(Debug, Serialize)]
+pub struct ExtractFailure {
+    /// The entry's path as stored in the archive
+    pub path: String,
+    pub code: String,
+    pub message: String,
+}
+
+/// What was extracted from an uploaded archive
+#[derive(Debug, Default, Serialize)]
+pub struct ExtractResult {
+    pub files: u64,
+    pub folders: u64,
+    /// Total size of the extracted files
+    pub bytes: u64,
+    pub failures: Vec<ExtractFailure>,
+}
+
+impl ExtractResult {
+    fn fail(&mut self, path: &str, code: &str, message: impl Into<String>) {
+        self.failures.push(ExtractFailure {
+            path: path.to_string(),
+            code: code.to_string(),
+            message: message.into(),
+        });
+    }
+}
+
+/// Returns the path of an archive entry relative to the folder it's extracted into, which is empty for the folder itself.
+/// Returns None if it's absolute or leaves the folder (zip slip)
+fn entry_path(name: &str) -> Option<PathBuf> {
+    let name = name.replace('\\', "/");
+    if name.starts_with('/') {
+        return None
+    }
+    let mut path = PathBuf::new();
+    for segment in name.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => return None,
+            // Windows drive letters, ex. `C:`
+            segment if segment.contains(':') => return None,
+            segment => path.push(segment),
+        }
+    }
+    Some(path)
+}
+
+/// Extracts the entries of archives into a folder of a library
+struct Extractor<'a> {
+    library: &'a Library,
+    versions: &'a VersionManager,
+    target: PathBuf,
+    user_id: &'a str,
+    /// Folders that exist, so parents are only created once
+    folders: HashSet<PathBuf>,
+    entries: usize,
+    result: ExtractResult,
+}
+
+impl<'a> Extractor<'a> {
+    /// Returns true after recording the failure if the entry is past [MAX_EXTRACT_ENTRIES], and extracting should stop
+    fn too_many_entries(&mut self, name: &str) -> bool {
+        if self.entries < MAX_EXTRACT_ENTRIES {
+            return false
+        }
+        self.result.fail(name, "TOO_MANY_ENTRIES", format!("Only the first {} entries of an archive are extracted, the remaining entries were skipped", MAX_EXTRACT_ENTRIES));
+        true
+    }
+
+    /// Checks the entry can be extracted, returning the path it's extracted to. Returns None after recording why not
+    fn check_entry(&mut self, name: &str) -> Option<PathBuf> {
+        self.entries += 1;
+        let Some(path) = entry_path(name) else {
+            self.result.fail(name, "INVALID_PATH", "Entry would be extracted outside of the folder");
+            return None
+        };
+        // Entries for the folder itself, ex. `./` in tar files
+        if path.as_os_str().is_empty() {
+            return None
+        }
+        let path = self.target.join(path);
+        if check_path(&path).is_err() {
+            self.result.fail(name, "INVALID_PATH", "Entry would be extracted into an internal folder");
+            return None
+        }
+        Some(path)
+    }
+
+    async fn create_folder(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+        if path.as_os_str().is_empty() || self.folders.contains(path) {
+            return Ok(())
+        }
+        self.library.touch_file(&path.to_path_buf(), FileType::Folder).await?;
+        self.folders.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    async fn extract_folder(&mut self, name: &str, path: PathBuf) {
+        match self.create_folder(&path).await {
+            Ok(()) => self.result.folders += 1,
+            Err(e) => self.result.fail(name, "EXTRACT_FAILED", e.to_string()),
+        }
+    }
+
+    /// Writes the file, returns false if the size limit was reached and extracting should stop
+    async fn extract_file(&mut self, name: &str, path: PathBuf, contents: &mut (dyn AsyncRead + Send + Unpin)) -> bool {
+        if let Some(parent) = path.parent() {
+            if let Err(e) = self.create_folder(parent).await {
+                self.result.fail(name, "EXTRACT_FAILED", e.to_string());
+                return true
+            }
+        }
+        let remaining = MAX_EXTRACT_SIZE.as_u64().saturating_sub(self.result.bytes);
+        let mut reader = LimitedReader::new(contents, remaining);
+        match self.versions.write_file(self.library, &path.to_string_lossy(), &mut reader, Some(self.user_id)).await {
+            Ok(size) => {
+                self.result.files += 1;
+                self.result.bytes += size;
+                true
+            },
+            // The version manager already removed the partially written file, and restored the one it replaced
+            Err(e) => {
+                if reader.exceeded() {
+                    self.result.fail(name, "ARCHIVE_TOO_LARGE", format!("Archives can extract to at most {}, the remaining entries were skipped", MAX_EXTRACT_SIZE));
+                    return false
+                }
+                self.result.fail(name, "EXTRACT_FAILED", e.to_string());
+                true
+            }
+        }
+    }
+}
+
+/// Extracts an uploaded zip, tar or tar.gz archive into the folder, which is created if needed. Existing files are replaced,
+/// keeping their previous contents as a version. Entries that can't be extracted are reported in the result instead of failing the upload
+pub async fn extract_archive(library: &Library, versions: &VersionManager, target: &Path, archive_path: &Path, user_id: &str) -> Result<ExtractResult, ResponseError> {
+    check_path(target).map_err(storage_error)?;
+    // A file can't be extracted into, an existing folder can
+    if library.stat_file(&target.to_path_buf()).await.map_err(storage_error)?.is_some() {
+        return Err(StorageError::AlreadyExists.into())
+    }
+    let mut file = File::open(archive_path).await
+        .map_err(|_| ResponseError::GenericError)?;
+    // Enough to find the format, the tar magic is at offset 257
+    let mut magic = Vec::with_capacity(512);
+    (&mut file).take(512).read_to_end(&mut magic).await
+        .map_err(|_| ResponseError::GenericError)?;
+    file.seek(SeekFrom::Start(0)).await
+        .map_err(|_| ResponseError::GenericError)?;
+
+    let mut extractor = Extractor {
+        library,
+        versions,
+        target: target.to_path_buf(),
+        user_id,
+        folders: HashSet::new(),
+        entries: 0,
+        result: ExtractResult::default(),
+    };
+    let is_zip = magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06");
+    let is_gzip = magic.starts_with(&[0x1f, 0x8b]);
+    let is_tar = magic.get(257..262) == Some(b"ustar");
+    if !is_zip && !is_gzip && !is_tar {
+        return Err(ResponseError::UnsupportedMediaType(JsonErrorResponse {
+            code: "UNSUPPORTED_ARCHIVE".to_string(),
+            message: "Only zip, tar and tar.gz archives can be extracted".to_string(),
+        }))
+    }
+    let result = if is_zip {
+        extract_zip(&mut extractor, file).await
+    } else if is_gzip {
+        extract_tar(&mut extractor, GzipDecoder::new(BufReader::new(file))).await
+    } else {
+        extract_tar(&mut extractor, file).await
+    };
+    match result {
+        // Also creates the folder for archives without any entries
+        Ok(()) => extractor.create_folder(target).await.map_err(storage_error)?,
+        // Whatever was extracted before the archive turned out to be corrupt is kept
+        Err(e) if extractor.entries > 0 => {
+            extractor.result.fail("", "INVALID_ARCHIVE", format!("Archive could not be read past this point: {}", e));
+        },
+        Err(e) => return Err(ResponseError::BadRequest(JsonErrorResponse {
+            code: "INVALID_ARCHIVE".to_string(),
+            message: format!("Archive could not be read: {}", e),
+        })),
+    }
+    Ok(extractor.result)
+}
+
+async fn extract_zip(extractor: &mut Extractor<'_>, file: File) -> Result<(), anyhow::Error> {
+    let mut zip = async_zip::tokio::read::seek::ZipFileReader::with_tokio(BufReader::new(file)).await?;
+    for index in 0..zip.file().entries().len() {
+        let entry = &zip.file().entries()[index];
+        let name = String::from_utf8_lossy(entry.filename().as_bytes()).into_owned();
+        let is_symlink = entry.unix_permissions().is_some_and(|mode| mode & 0o170000 == 0o120000);
+        if extractor.too_many_entries(&name) {
+            break
+        }
+        let Some(path) = extractor.check_entry(&name) else {
+            continue
+        };
+        if is_symlink {
+            extractor.result.fail(&name, "UNSUPPORTED_ENTRY", "Symbolic links are not extracted");
+        } else if name.ends_with('/') {
+            extractor.extract_folder(&name, path).await;
+        } else {
+            let mut contents = zip.reader_without_entry(index).await?.compat();
+            if !extractor.extract_file(&name, path, &mut contents).await {
+                break
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn extract_tar(extractor: &mut Extractor<'_>, reader: impl AsyncRead + Unpin + Send) -> Result<(), anyhow::Error> {
+    let mut archive = tokio_tar::Archive::new(reader);
+    let mut entries = archive.entries()?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let entry_type = entry.header().entry_type();
+        if extractor.too_many_entries(&name) {
+            break
+        }
+        let Some(path) = extractor.check_entry(&name) else {
+            continue
+        };
+        if entry_type.is_dir() {
+            extractor.extract_folder(&name, path).await;
+        } else if entry_type.is_file() {
+            if !extractor.extract_file(&name, path, &mut entry).await {
+                break
+            }
+        } else {
+            extractor.result.fail(&name, "UNSUPPORTED_ENTRY", "Links and special files are not extracted");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_entry_paths() {
+        assert_eq!(entry_path("a/b.txt"), Some(PathBuf::from("a/b.txt")));
+        assert_eq!(entry_path("./a/./b.txt"), Some(PathBuf::from("a/b.txt")));
+        assert_eq!(entry_path("a//b/"), Some(PathBuf::from("a/b")));
+        assert_eq!(entry_path("a\\b.txt"), Some(PathBuf::from("a/b.txt")));
+        assert_eq!(entry_path("..a/b.."), Some(PathBuf::from("..a/b..")));
+        assert_eq!(entry_path(""), Some(PathBuf::new()));
+    }
+
+    #[test]
+    fn rejects_entries_outside_the_target() {
+        for name in ["..", "../x", "a/../../x", "a/b/../../../x", "a\\..\\..\\x", "..\\x", "/etc/passwd", "\\x", "C:\\x", "C:x", "a/c:/x"] {
+            assert_eq!(entry_path(name), None, "{:?} was accepted", name);
+        }
+    }
+}
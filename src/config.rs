@@ -13,7 +13,15 @@ use rocket::serde::{Serialize, Deserialize};
 pub struct AppConfig {
     pub general: GeneralConfig,
     pub auth: AuthConfig,
-    pub smtp: Option<EmailConfig>
+    pub smtp: Option<EmailConfig>,
+    #[serde(default)]
+    pub uploads: UploadsConfig,
+    #[serde(default)]
+    pub trash: TrashConfig,
+    #[serde(default)]
+    pub webdav: WebDavConfig,
+    #[serde(default)]
+    pub search: SearchConfig,
 }
 
 pub fn get_settings() -> AppConfig {
@@ -96,3 +104,36 @@ pub struct EmailConfig {
     pub from_name: Option<String>,
     pub from_email: Option<String>,
 }
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct UploadsConfig {
+    /// Where partial resumable uploads are kept until complete, defaults to the system's temp directory
+    pub staging_dir: Option<String>,
+    /// How long an upload can go without any new data before it's removed, defaults to 24 hours
+    pub expire_hours: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct TrashConfig {
+    /// How long deleted files are kept in the trash before being permanently deleted, defaults to 30 days.
+    /// 0 keeps them until the trash is emptied manually
+    pub retention_days: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct WebDavConfig {
+    /// Serves WebDAV under /dav/ on the main listen address
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct SearchConfig {
+    /// Also index the contents of text files, so they can be found by the words in them
+    #[serde(default)]
+    pub index_contents: bool,
+}
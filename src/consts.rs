@@ -9,6 +9,57 @@ use crate::GlobalMetadata;
 /// The maximum amount of bytes that can be uploaded at once
 pub const MAX_UPLOAD_SIZE: ByteUnit = ByteUnit::Mebibyte(100_000);
 
+/// How often expired resumable uploads are cleaned up
+pub const UPLOAD_EXPIRY_INTERVAL_SECONDS: u64 = 3600;
+
+/// How often items past the trash retention period are purged
+pub const TRASH_PURGE_INTERVAL_SECONDS: u64 = 3600;
+
+/// How often file versions past their library's retention period are removed
+pub const VERSION_PRUNE_INTERVAL_SECONDS: u64 = 3600;
+
+/// How long WebDAV passwords are remembered after being verified, as clients send them on every request
+pub const WEBDAV_AUTH_CACHE_SECONDS: u64 = 300;
+/// How many failed WebDAV logins an IP address gets before it is locked out
+pub const WEBDAV_MAX_FAILED_LOGINS: u32 = 10;
+/// How long an IP address is locked out of WebDAV after too many failed logins, counted from the last one
+pub const WEBDAV_LOGIN_LOCKOUT_SECONDS: u64 = 900;
+/// The longest a WebDAV lock can be held before it has to be refreshed
+pub const WEBDAV_MAX_LOCK_SECONDS: u64 = 3600;
+
+/// How many numbered names, ex. `file (1).txt`, are tried when renaming a file to avoid a conflict
+pub const MAX_RENAME_ATTEMPTS: u32 = 1000;
+
+/// Size of the buffer between the task building an archive download and the response sending it
+pub const ARCHIVE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// The most an uploaded archive can extract to, which stops archives that decompress to far more than their size
+pub const MAX_EXTRACT_SIZE: ByteUnit = ByteUnit::Gibibyte(20);
+/// The most entries that are extracted from an uploaded archive
+pub const MAX_EXTRACT_ENTRIES: usize = 50_000;
+
+/// How much of a text file's contents are added to the search index
+pub const MAX_INDEXED_CONTENT_SIZE: ByteUnit = ByteUnit::Mebibyte(1);
+/// The most search results returned at once
+pub const MAX_SEARCH_RESULTS: u32 = 200;
+/// The most activity returned at once
+pub const MAX_ACTIVITY_RESULTS: u32 = 200;
+/// The most notifications returned at once
+pub const MAX_NOTIFICATION_RESULTS: u32 = 200;
+/// How many files and folders a page of a folder shows in the UI
+pub const LIST_PAGE_SIZE: usize = 200;
+/// The most files and folders a recursive listing can hold
+pub const MAX_RECURSIVE_LIST_ENTRIES: usize = 100_000;
+/// How long computed folder sizes are cached, in case files change outside of the app
+pub const FOLDER_SIZE_CACHE_SECONDS: u64 = 3600;
+/// The most folder sizes kept in the cache, across all libraries
+pub const FOLDER_SIZE_CACHE_CAPACITY: u64 = 100_000;
+/// How many of the latest uploads through share links a library's share page shows
+pub const SHARE_UPLOADS_SHOWN: i64 = 50;
+
+/// The maximum amount of ranges a single download request can ask for, before the whole file is sent instead
+pub const MAX_DOWNLOAD_RANGES: usize = 16;
+
 /// The number of encryption rounds
 pub const ENCRYPTION_ROUNDS: u32 = 12;
 
@@ -16,6 +67,15 @@ pub const SESSION_LIFETIME_SECONDS: u64 = 3600 * 24 * 14; // 14 days
 
 pub const SESSION_COOKIE_NAME: &'static str = "storage-session";
 
+/// How often expired sessions are removed from the database
+pub const SESSION_CLEANUP_INTERVAL_SECONDS: u64 = 3600;
+
+/// How often the last activity of a session is updated
+pub const SESSION_ACTIVITY_UPDATE_SECONDS: i64 = 60;
+
+/// Prefix of personal API tokens, to tell them apart from session ids in `Authorization: Bearer` headers
+pub const API_TOKEN_PREFIX: &str = "stk_";
+
 
 #[derive(Serialize)]
 pub struct FileConstants<'a> {
@@ -24,15 +84,9 @@ pub struct FileConstants<'a> {
 }
 pub const FILE_CONSTANTS: FileConstants = FileConstants {
     display_options: &["list", "grid"],
-    sort_keys: &["name", "last_modified", "size"],
+    sort_keys: &["name", "last_modified", "created", "size"],
 };
 
-
-/// Disables CSRF & password verification for login
-/// Used for development due to no session persistence
-pub static DISABLE_LOGIN_CHECK: LazyLock<bool> = LazyLock::new(|| {
-    env::var("DANGER_DISABLE_LOGIN_CHECKS").is_ok()
-});
 pub static APP_METADATA: LazyLock<GlobalMetadata> = LazyLock::new(|| {
     GlobalMetadata {
         app_name: env!("CARGO_PKG_NAME").to_string(),
@@ -0,0 +1,359 @@
+use std::io::Cursor;
+use std::path::PathBuf;
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Responder;
+use rocket::{Request, Response};
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+use crate::consts::MAX_DOWNLOAD_RANGES;
+use crate::objs::library::{check_path, Library};
+use crate::storage::{FileMetadata, ReadStream};
+use crate::util::{format_http_date, parse_http_date};
+
+/// The headers of a download request that affect what is sent back, see [FileDownload]
+pub struct DownloadHeaders {
+    range: Option<String>,
+    if_range: Option<String>,
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DownloadHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(DownloadHeaders::from_headers(|name| headers.get_one(name)))
+    }
+}
+
+impl DownloadHeaders {
+    /// Reads the headers through the lookup function, for requests that don't come through Rocket
+    pub(crate) fn from_headers<'a>(get: impl Fn(&str) -> Option<&'a str>) -> Self {
+        DownloadHeaders {
+            range: get("Range").map(str::to_string),
+            if_range: get("If-Range").map(str::to_string),
+            if_none_match: get("If-None-Match").map(str::to_string),
+            if_modified_since: get("If-Modified-Since").map(str::to_string),
+        }
+    }
+
+    /// Does the request continue a download, asking only for parts of the file after its first byte.
+    /// Requests without a Range header, with a range including the first byte, a suffix range or an invalid header
+    /// could all get the whole file, so they don't
+    pub fn is_continuation(&self) -> bool {
+        let Some((unit, specs)) = self.range.as_deref().and_then(|range| range.split_once('=')) else {
+            return false
+        };
+        let specs: Vec<_> = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+        unit.trim().eq_ignore_ascii_case("bytes") && !specs.is_empty() && specs.iter()
+            .all(|spec| spec.split_once('-')
+                .and_then(|(start, _)| start.trim().parse::<u64>().ok())
+                .is_some_and(|start| start > 0))
+    }
+
+    /// Returns true if the client's cached copy is still valid, and a 304 should be sent instead
+    fn is_not_modified(&self, metadata: &FileMetadata) -> bool {
+        // If-None-Match takes precedence, If-Modified-Since is ignored when it is present
+        if let Some(if_none_match) = &self.if_none_match {
+            return match &metadata.etag {
+                Some(etag) => if_none_match.split(',')
+                    .map(|tag| tag.trim())
+                    .any(|tag| tag == "*" || strip_etag(tag) == etag),
+                None => false
+            }
+        }
+        match (&self.if_modified_since, metadata.last_modified) {
+            (Some(since), Some(last_modified)) => match parse_http_date(since) {
+                Some(since) => last_modified.timestamp() <= since.timestamp(),
+                None => false
+            },
+            _ => false
+        }
+    }
+
+    /// Returns true if the Range header should be used, which is only when If-Range still matches the file
+    fn is_range_valid(&self, metadata: &FileMetadata) -> bool {
+        let Some(if_range) = &self.if_range else { return true };
+        if if_range.starts_with('"') {
+            // Requires a strong comparison, weak tags never match
+            metadata.etag.as_deref() == Some(if_range.trim_matches('"'))
+        } else {
+            match (parse_http_date(if_range), metadata.last_modified) {
+                (Some(date), Some(last_modified)) => date.timestamp() == last_modified.timestamp(),
+                _ => false
+            }
+        }
+    }
+}
+
+/// Removes the weak prefix and quotes of an entity tag
+fn strip_etag(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag).trim_matches('"')
+}
+
+/// A range of bytes of a file, with both start and end being inclusive
+#[derive(Debug, Clone, Copy)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Parses a Range header for a file of the given size.
+/// Returns None if the header is invalid and should be ignored, or an empty list if no range can be satisfied
+fn parse_ranges(header: &str, size: u64) -> Option<Vec<ByteRange>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None
+    }
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()) {
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            // Suffix range, the last n bytes of the file
+            let suffix: u64 = end.parse().ok()?;
+            if suffix == 0 || size == 0 { continue; }
+            ranges.push(ByteRange { start: size.saturating_sub(suffix), end: size - 1 });
+        } else {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = if end.is_empty() { u64::MAX } else { end.parse().ok()? };
+            if end < start { return None }
+            if start >= size { continue; }
+            ranges.push(ByteRange { start, end: end.min(size - 1) });
+        }
+    }
+    // Merge overlapping and adjacent ranges, so the same bytes are never sent twice
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => last.end = last.end.max(range.end),
+            _ => merged.push(range)
+        }
+    }
+    // Too many ranges is more likely abuse than a real client, send the whole file instead
+    if merged.len() > MAX_DOWNLOAD_RANGES {
+        return None
+    }
+    Some(merged)
+}
+
+/// A download of a file, which supports range requests (206 Partial Content, including multiple ranges)
+/// and conditional requests (304 Not Modified) based on the file's ETag and last modified date
+pub struct FileDownload {
+    status: Status,
+    body: Option<ReadStream>,
+    content_type: ContentType,
+    content_length: Option<u64>,
+    content_range: Option<String>,
+    disposition: Option<String>,
+    metadata: FileMetadata,
+}
+
+impl FileDownload {
+    /// Prepares the download of the file, returns None if the file does not exist
+    pub async fn open(library: &Library, path: &PathBuf, content_type: ContentType, headers: &DownloadHeaders) -> Result<Option<FileDownload>, anyhow::Error> {
+        check_path(path)?;
+        Self::open_internal(library, path, content_type, headers).await
+    }
+
+    /// Prepares the download of the file without restricting access to the library's internal folder
+    pub(crate) async fn open_internal(library: &Library, path: &PathBuf, content_type: ContentType, headers: &DownloadHeaders) -> Result<Option<FileDownload>, anyhow::Error> {
+        let Some(metadata) = library.stat_internal(path).await? else {
+            return Ok(None)
+        };
+        let mut download = FileDownload {
+            status: Status::Ok,
+            body: None,
+            content_type,
+            content_length: None,
+            content_range: None,
+            disposition: None,
+            metadata,
+        };
+        let size = download.metadata.size;
+        if headers.is_not_modified(&download.metadata) {
+            download.status = Status::NotModified;
+            return Ok(Some(download))
+        }
+        let ranges = headers.range.as_deref()
+            .filter(|_| headers.is_range_valid(&download.metadata))
+            .and_then(|header| parse_ranges(header, size));
+        match ranges.as_deref() {
+            None => {
+                let Some(stream) = library.read_internal(path).await? else { return Ok(None) };
+                download.body = Some(stream);
+                download.content_length = Some(size);
+            },
+            Some([]) => {
+                download.status = Status::RangeNotSatisfiable;
+                download.content_range = Some(format!("bytes */{}", size));
+            },
+            Some([range]) => {
+                let Some(stream) = library.read_range_internal(path, range.start, range.len()).await? else { return Ok(None) };
+                download.status = Status::PartialContent;
+                download.body = Some(stream);
+                download.content_length = Some(range.len());
+                download.content_range = Some(format!("bytes {}-{}/{}", range.start, range.end, size));
+            },
+            Some(ranges) => {
+                // Each range is sent as its own part, with its own Content-Type and Content-Range
+                let boundary = Uuid::new_v4().simple().to_string();
+                let mut body: ReadStream = Box::new(tokio::io::empty());
+                let mut length = 0;
+                for (i, range) in ranges.iter().enumerate() {
+                    let part_header = format!("{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        if i == 0 { "" } else { "\r\n" }, boundary, download.content_type, range.start, range.end, size);
+                    let Some(stream) = library.read_range_internal(path, range.start, range.len()).await? else { return Ok(None) };
+                    length += part_header.len() as u64 + range.len();
+                    body = Box::new(body.chain(Cursor::new(part_header)).chain(stream));
+                }
+                let closing = format!("\r\n--{}--\r\n", boundary);
+                length += closing.len() as u64;
+                download.status = Status::PartialContent;
+                download.body = Some(Box::new(body.chain(Cursor::new(closing))));
+                download.content_length = Some(length);
+                download.content_type = ContentType::new("multipart", "byteranges")
+                    .with_params(("boundary", boundary));
+            }
+        }
+        Ok(Some(download))
+    }
+
+    /// Sets the Content-Disposition header, ex. `attachment; filename="file.txt"`
+    pub fn with_disposition(mut self, disposition: String) -> Self {
+        self.disposition = Some(disposition);
+        self
+    }
+
+    /// Splits the download into the status, headers and body to send
+    pub(crate) fn into_parts(self) -> (Status, Vec<Header<'static>>, Option<ReadStream>) {
+        let mut headers = vec![Header::new("Accept-Ranges", "bytes")];
+        if let Some(etag) = self.metadata.etag {
+            headers.push(Header::new("ETag", format!("\"{}\"", etag)));
+        }
+        if let Some(last_modified) = self.metadata.last_modified {
+            headers.push(Header::new("Last-Modified", format_http_date(&last_modified)));
+        }
+        if self.status == Status::NotModified {
+            return (self.status, headers, None)
+        }
+        headers.push(self.content_type.into());
+        if let Some(content_range) = self.content_range {
+            headers.push(Header::new("Content-Range", content_range));
+        }
+        if let Some(disposition) = self.disposition {
+            headers.push(Header::new("Content-Disposition", disposition));
+        }
+        if let Some(length) = self.content_length {
+            // Body is streamed, so the length can't be known otherwise
+            headers.push(Header::new("Content-Length", length.to_string()));
+        }
+        (self.status, headers, self.body)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for FileDownload {
+    fn respond_to(self, _: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let (status, headers, body) = self.into_parts();
+        let mut builder = Response::build();
+        builder.status(status);
+        for header in headers {
+            builder.header(header);
+        }
+        if let Some(body) = body {
+            builder.streamed_body(body);
+        }
+        builder.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, size: u64) -> Option<Vec<(u64, u64)>> {
+        parse_ranges(header, size).map(|ranges| ranges.iter().map(|range| (range.start, range.end)).collect())
+    }
+
+    fn range_header(range: &str) -> DownloadHeaders {
+        DownloadHeaders::from_headers(|name| (name == "Range").then_some(range))
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(ranges("bytes=0-99", 1000), Some(vec![(0, 99)]));
+        assert_eq!(ranges("bytes=900-", 1000), Some(vec![(900, 999)]));
+        assert_eq!(ranges("bytes=-100", 1000), Some(vec![(900, 999)]));
+        assert_eq!(ranges("BYTES = 10 - 20", 1000), Some(vec![(10, 20)]));
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_file() {
+        assert_eq!(ranges("bytes=500-5000", 1000), Some(vec![(500, 999)]));
+        assert_eq!(ranges("bytes=-5000", 1000), Some(vec![(0, 999)]));
+        assert_eq!(ranges("bytes=0-18446744073709551615", 10), Some(vec![(0, 9)]));
+    }
+
+    #[test]
+    fn skips_unsatisfiable_ranges() {
+        assert_eq!(ranges("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(ranges("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(ranges("bytes=0-", 0), Some(vec![]));
+        assert_eq!(ranges("bytes=2000-3000,0-9", 1000), Some(vec![(0, 9)]));
+    }
+
+    #[test]
+    fn merges_overlapping_and_adjacent_ranges() {
+        assert_eq!(ranges("bytes=0-99,50-149", 1000), Some(vec![(0, 149)]));
+        assert_eq!(ranges("bytes=0-99,100-199", 1000), Some(vec![(0, 199)]));
+        assert_eq!(ranges("bytes=500-599,0-9,5-20", 1000), Some(vec![(0, 20), (500, 599)]));
+        assert_eq!(ranges("bytes=0-,0-,0-", 1000), Some(vec![(0, 999)]));
+        assert_eq!(ranges("bytes=-100,900-999", 1000), Some(vec![(900, 999)]));
+    }
+
+    #[test]
+    fn ignores_too_many_ranges() {
+        let within: Vec<String> = (0..MAX_DOWNLOAD_RANGES).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        assert_eq!(ranges(&format!("bytes={}", within.join(",")), 1000).map(|ranges| ranges.len()), Some(MAX_DOWNLOAD_RANGES));
+        let over: Vec<String> = (0..=MAX_DOWNLOAD_RANGES).map(|i| format!("{}-{}", i * 10, i * 10)).collect();
+        assert_eq!(ranges(&format!("bytes={}", over.join(",")), 1000), None);
+        // Ranges that merge into few don't count as many
+        let overlapping: Vec<String> = (0..100).map(|i| format!("{}-{}", i, i + 10)).collect();
+        assert_eq!(ranges(&format!("bytes={}", overlapping.join(",")), 1000), Some(vec![(0, 109)]));
+    }
+
+    #[test]
+    fn ignores_invalid_headers() {
+        assert_eq!(ranges("items=0-9", 1000), None);
+        assert_eq!(ranges("bytes", 1000), None);
+        assert_eq!(ranges("bytes=9-0", 1000), None);
+        assert_eq!(ranges("bytes=a-9", 1000), None);
+        assert_eq!(ranges("bytes=0-9,x", 1000), None);
+        assert_eq!(ranges("bytes=-1-2", 1000), None);
+        assert_eq!(ranges("bytes=--5", 1000), None);
+    }
+
+    #[test]
+    fn only_ranges_after_the_first_byte_continue_a_download() {
+        assert!(range_header("bytes=1-").is_continuation());
+        assert!(range_header("bytes=100-199, 500-").is_continuation());
+        assert!(!DownloadHeaders::from_headers(|_| None).is_continuation());
+        assert!(!range_header("bytes=0-").is_continuation());
+        assert!(!range_header("bytes=0-0").is_continuation());
+        assert!(!range_header("bytes=100-199,0-").is_continuation());
+        assert!(!range_header("bytes=-500").is_continuation());
+        assert!(!range_header("bytes=").is_continuation());
+        assert!(!range_header("items=1-").is_continuation());
+        assert!(!range_header("bytes=x-").is_continuation());
+    }
+}
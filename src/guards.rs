@@ -1,12 +1,21 @@
+use std::net::IpAddr;
 use rocket::http::Status;
-use rocket::Request;
+use rocket::{Request, State};
 use rocket::request::{FromRequest, Outcome};
-use rocket_session_store::{Session, SessionResult};
+use rocket_session_store::{Session, SessionResult, SessionStore};
+use chrono::Utc;
+use log::{error, warn};
+use crate::consts::{API_TOKEN_PREFIX, SESSION_ACTIVITY_UPDATE_SECONDS};
+use crate::models;
+use crate::models::api_token::ApiTokenModel;
 use crate::models::user::UserModel;
-use crate::{LoginSessionData, SessionData};
+use crate::util::hash_api_token;
+use crate::{LoginSessionData, SessionData, DB};
 
 pub struct AuthUser {
-    pub session: LoginSessionData
+    pub session: LoginSessionData,
+    /// The IP address the request came from
+    pub ip: Option<IpAddr>,
 }
 
 #[derive(Debug)]
@@ -18,20 +27,270 @@ impl<'r> FromRequest<'r> for AuthUser {
     type Error = UserError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let sess = match request.guard::<Session<SessionData>>().await {
+        let session = match request.guard::<Session<SessionData>>().await {
             Outcome::Success(sess ) => sess,
             _ => return Outcome::Forward(Status::Unauthorized)
         };
-        let sess = match sess.get().await {
+        let mut sess = match session.get().await {
             Ok(Some(sess)) => {
                 sess
             }
             _ => return Outcome::Forward(Status::Unauthorized),
         };
-        if let Some(login) = &sess.login {
-            Outcome::Success(Self { session: login.clone() })
+        if record_activity(&mut sess) && let Err(e) = session.set(sess.clone()).await {
+            warn!("Failed to update session activity: {:?}", e);
+        }
+        if let Some(login) = sess.login {
+            Outcome::Success(Self { session: login, ip: request.client_ip() })
         } else {
             Outcome::Forward(Status::Unauthorized)
         }
     }
-}
\ No newline at end of file
+}
+
+/// Updates when the login was last active, at most every [SESSION_ACTIVITY_UPDATE_SECONDS] to not write the session
+/// on every request. Returns true if the session needs to be saved
+fn record_activity(sess: &mut SessionData) -> bool {
+    let Some(login) = &mut sess.login else {
+        return false
+    };
+    let now = Utc::now();
+    if (now - login.last_active).num_seconds() < SESSION_ACTIVITY_UPDATE_SECONDS {
+        return false
+    }
+    login.last_active = now;
+    true
+}
+
+/// The User-Agent header of the request, if any
+pub struct UserAgent(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(UserAgent(request.headers().get_one("User-Agent").map(str::to_string)))
+    }
+}
+
+/// A valid personal API token sent as `Authorization: Bearer <token>`, along with the user that owns it
+pub struct ApiToken {
+    pub token: ApiTokenModel,
+    pub user: UserModel,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiToken {
+    type Error = ApiUserError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(bearer) = get_bearer_token(request).filter(|token| token.starts_with(API_TOKEN_PREFIX)) else {
+            return Outcome::Forward(Status::Unauthorized)
+        };
+        let pool = request.guard::<&State<DB>>().await
+            .expect("Database pool must be managed");
+        let token = match models::api_token::use_api_token(pool, &hash_api_token(bearer)).await {
+            Ok(Some(token)) => token,
+            Ok(None) => return Outcome::Error((Status::Unauthorized, ApiUserError::Invalid)),
+            Err(e) => {
+                error!("Failed to look up api token: {}", e);
+                return Outcome::Error((Status::InternalServerError, ApiUserError::Invalid))
+            }
+        };
+        match models::user::find_user(pool, &token.user_id).await {
+            Ok(Some(user)) => Outcome::Success(Self { token, user }),
+            Ok(None) => Outcome::Error((Status::Unauthorized, ApiUserError::Invalid)),
+            Err(e) => {
+                error!("Failed to look up user of api token: {}", e);
+                Outcome::Error((Status::InternalServerError, ApiUserError::Invalid))
+            }
+        }
+    }
+}
+
+/// A user authenticated for the JSON API, either with the session cookie, or with an `Authorization: Bearer` header
+/// containing a personal API token or a session id.
+/// Unlike [AuthUser], this fails with 401 instead of forwarding, so the /api catchers can respond with json
+#[derive(Clone)]
+pub struct ApiUser {
+    pub user: UserModel,
+    /// The API token used, which limits what the user can access
+    pub token: Option<ApiTokenModel>,
+    /// The IP address the request came from
+    pub ip: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+pub enum ApiUserError {
+    /// No credentials were provided
+    Missing,
+    /// The provided credentials are invalid or expired
+    Invalid,
+}
+
+/// Returns the token of an `Authorization: Bearer <token>` header
+fn get_bearer_token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    let header = request.headers().get_one("Authorization")?;
+    let (scheme, token) = header.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiUser {
+    type Error = ApiUserError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.guard::<ApiToken>().await {
+            Outcome::Success(api_token) => return Outcome::Success(Self { user: api_token.user, token: Some(api_token.token), ip: request.client_ip() }),
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(_) => {}
+        }
+        let login: SessionResult<Option<SessionData>> = if let Some(token) = get_bearer_token(request) {
+            // The bearer token is the session id, the same value as the session cookie
+            let store = request.guard::<&State<SessionStore<SessionData>>>().await
+                .expect("Session store must be set in fairing");
+            match store.store.get(token).await {
+                Ok(Some(mut sess)) => {
+                    if record_activity(&mut sess) && let Err(e) = store.store.set(token, sess.clone(), store.duration).await {
+                        warn!("Failed to update session activity: {:?}", e);
+                    }
+                    Ok(Some(sess))
+                },
+                result => result
+            }
+        } else {
+            match request.guard::<Session<SessionData>>().await {
+                Outcome::Success(session) => match session.get().await {
+                    Ok(Some(mut sess)) => {
+                        if record_activity(&mut sess) && let Err(e) = session.set(sess.clone()).await {
+                            warn!("Failed to update session activity: {:?}", e);
+                        }
+                        Ok(Some(sess))
+                    },
+                    result => result
+                },
+                _ => return Outcome::Error((Status::Unauthorized, ApiUserError::Missing))
+            }
+        };
+        match login {
+            Ok(Some(SessionData { login: Some(login), .. })) => Outcome::Success(Self { user: login.user, token: None, ip: request.client_ip() }),
+            Ok(_) if get_bearer_token(request).is_none() => Outcome::Error((Status::Unauthorized, ApiUserError::Missing)),
+            _ => Outcome::Error((Status::Unauthorized, ApiUserError::Invalid))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::{Cookie, Header};
+    use rocket::local::asynchronous::Client;
+    use chrono::Duration;
+    use sqlx::types::Uuid;
+    use super::*;
+    use crate::consts::SESSION_COOKIE_NAME;
+    use crate::models::api_token::ApiTokenScope;
+    use crate::testing::TestData;
+    use crate::util::setup_session_store;
+
+    #[rocket::get("/")]
+    fn api_user(user: ApiUser) -> String {
+        format!("{} token={}", user.user.id, user.token.is_some())
+    }
+
+    #[rocket::get("/token")]
+    fn api_token(token: ApiToken) -> String {
+        token.token.id.to_string()
+    }
+
+    async fn client(data: &TestData) -> Client {
+        let rocket = rocket::build()
+            .manage(data.pool.clone())
+            .attach(setup_session_store(data.pool.clone()).fairing())
+            .mount("/", rocket::routes![api_user, api_token]);
+        Client::untracked(rocket).await.unwrap()
+    }
+
+    fn bearer(token: &str) -> Header<'static> {
+        Header::new("Authorization", format!("Bearer {}", token))
+    }
+
+    #[tokio::test]
+    async fn authenticates_api_users_by_session() {
+        let mut data = TestData::new().await;
+        let user = data.user("user").await;
+        let session_id = data.login(&user).await;
+        let client = client(&data).await;
+
+        let response = client.get("/").cookie(Cookie::new(SESSION_COOKIE_NAME, session_id.clone())).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().await.unwrap(), format!("{} token=false", user.id));
+        // The session id also works as a bearer token, for clients that can't keep cookies
+        let response = client.get("/").header(bearer(&session_id)).dispatch().await;
+        assert_eq!(response.into_string().await.unwrap(), format!("{} token=false", user.id));
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_and_invalid_credentials() {
+        let mut data = TestData::new().await;
+        let user = data.user("user").await;
+        let session_id = data.login(&user).await;
+        let client = client(&data).await;
+
+        assert_eq!(client.get("/").dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(client.get("/").cookie(Cookie::new(SESSION_COOKIE_NAME, "unknown")).dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(client.get("/").header(bearer("unknown")).dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(client.get("/").header(Header::new("Authorization", format!("Basic {}", session_id))).dispatch().await.status(), Status::Unauthorized);
+        // A session without a login, ex. from visiting the login page
+        let anonymous = Uuid::new_v4().to_string();
+        models::session::set_session(&data.pool, &anonymous, &serde_json::to_value(SessionData::default()).unwrap(), 60).await.unwrap();
+        assert_eq!(client.get("/").cookie(Cookie::new(SESSION_COOKIE_NAME, anonymous.clone())).dispatch().await.status(), Status::Unauthorized);
+        assert_eq!(client.get("/").header(bearer(&anonymous)).dispatch().await.status(), Status::Unauthorized);
+        // Logging out everywhere ends the session
+        models::session::delete_user_sessions(&data.pool, &user.id).await.unwrap();
+        assert_eq!(client.get("/").header(bearer(&session_id)).dispatch().await.status(), Status::Unauthorized);
+
+        models::session::delete_session(&data.pool, &anonymous).await.unwrap();
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn authenticates_api_users_by_token() {
+        let mut data = TestData::new().await;
+        let user = data.user("user").await;
+        let (token, model) = data.api_token(&user, None, ApiTokenScope::Read, Some((Utc::now() + Duration::days(1)).naive_utc())).await;
+        let client = client(&data).await;
+
+        let response = client.get("/").header(bearer(&token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().await.unwrap(), format!("{} token=true", user.id));
+        let response = client.get("/token").header(bearer(&token)).dispatch().await;
+        assert_eq!(response.into_string().await.unwrap(), model.id.to_string());
+        let tokens = models::api_token::get_user_api_tokens(&data.pool, &user.id).await.unwrap();
+        assert!(tokens[0].token.last_used_at.is_some());
+        // Session ids are forwarded to the session guards, rather than looked up as tokens
+        let session_id = data.login(&user).await;
+        assert_eq!(client.get("/token").header(bearer(&session_id)).dispatch().await.status(), Status::Unauthorized);
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_and_revoked_tokens() {
+        let mut data = TestData::new().await;
+        let user = data.user("user").await;
+        let (expired, _) = data.api_token(&user, None, ApiTokenScope::ReadWrite, Some((Utc::now() - Duration::minutes(1)).naive_utc())).await;
+        let (revoked, model) = data.api_token(&user, None, ApiTokenScope::ReadWrite, None).await;
+        models::api_token::delete_api_token(&data.pool, &user.id, &model.id).await.unwrap();
+        let session_id = data.login(&user).await;
+        let client = client(&data).await;
+
+        for token in [expired, revoked, format!("{}unknown", API_TOKEN_PREFIX)] {
+            assert_eq!(client.get("/").header(bearer(&token)).dispatch().await.status(), Status::Unauthorized, "{} was accepted", token);
+            // An invalid token fails the request, even with a valid session cookie
+            let response = client.get("/").header(bearer(&token)).cookie(Cookie::new(SESSION_COOKIE_NAME, session_id.clone())).dispatch().await;
+            assert_eq!(response.status(), Status::Unauthorized);
+        }
+        data.cleanup().await;
+    }
+}
@@ -1,5 +1,6 @@
 use std::fmt::Write;
 use anyhow::anyhow;
+use chrono::{DateTime, NaiveDateTime};
 use log::debug;
 use rocket_dyn_templates::handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError, RenderErrorReason};
 pub(crate) fn bytes(h: &Helper< '_>, _: &Handlebars<'_>, _: &Context, rc:
@@ -48,4 +49,17 @@ pub(crate) fn is_active(h: &Helper< '_>, hbs: &Handlebars<'_>, ctx: &Context, rc
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+/// Formats an RFC 3339 timestamp, or one without a timezone which is taken as UTC, as `YYYY-MM-DD HH:MM UTC`
+pub(crate) fn datetime(h: &Helper< '_>, _: &Handlebars<'_>, _: &Context, _:
+&mut RenderContext<'_, '_>, out: &mut dyn Output) -> HelperResult {
+    let param = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+    let parsed = DateTime::parse_from_rfc3339(param)
+        .map(|date| date.naive_utc())
+        .or_else(|_| NaiveDateTime::parse_from_str(param, "%Y-%m-%dT%H:%M:%S%.f"));
+    match parsed {
+        Ok(date) => out.write(&date.format("%Y-%m-%d %H:%M UTC").to_string())?,
+        Err(_) => out.write(param)?,
+    }
+    Ok(())
+}
@@ -1,28 +1,38 @@
 use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
+use chrono::{DateTime, Utc};
 use log::{debug, error, info, trace, warn};
 use rocket::{catch, catchers, launch, routes, uri, Request, Route, State};
 use rocket::data::ByteUnit;
+use rocket::fairing::{AdHoc, Fairing};
 use rocket::fs::{relative, FileServer};
 use rocket::futures::AsyncWriteExt;
 use rocket::http::private::cookie::CookieBuilder;
 use rocket::http::uri::Uri;
 use rocket::response::Redirect;
-use rocket::serde::Serialize;
+use rocket::serde::{Deserialize, Serialize};
 use rocket_dyn_templates::handlebars::{handlebars_helper, Context, Handlebars, Helper, HelperResult, Output, RenderContext};
 use rocket_dyn_templates::{context, Template};
-use rocket_session_store::memory::MemoryStore;
 use rocket_session_store::SessionStore;
 use sqlx::{migrate, Pool, Postgres};
 use sqlx::postgres::PgPoolOptions;
-use sqlx::types::Json;
+use sqlx::types::{Json, Uuid};
 use tokio::sync::Mutex;
 use tracing_subscriber::fmt::writer::MakeWriterExt;
+use crate::managers::activity::ActivityManager;
+use crate::managers::favorites::FavoriteManager;
 use crate::managers::libraries::LibraryManager;
+use crate::managers::notifications::NotificationManager;
 use crate::managers::repos::RepoManager;
+use crate::managers::search::SearchManager;
+use crate::managers::share_links::ShareLinkManager;
+use crate::managers::uploads::UploadManager;
+use crate::managers::trash::TrashManager;
+use crate::managers::versions::VersionManager;
 use crate::objs::library::Library;
 use crate::util::{setup_db, setup_logger, setup_session_store, JsonErrorResponse, ResponseError};
+use crate::webdav::WebDav;
 use routes::api;
 use crate::config::{get_settings, AppConfig};
 use crate::consts::{init_statics, SESSION_COOKIE_NAME, SESSION_LIFETIME_SECONDS};
@@ -43,19 +53,35 @@ mod helpers;
 mod consts;
 mod guards;
 mod config;
+mod download;
+mod archive;
+mod webdav;
+#[cfg(test)]
+mod testing;
 
 pub type DB = Pool<Postgres>;
 
 
-#[derive(Clone, Debug, Serialize, Default)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
 struct SessionData {
     csrf_token: Option<String>,
     login: Option<LoginSessionData>,
+    /// Password protected share links the visitor has entered the password of
+    #[serde(default)]
+    unlocked_share_links: Vec<Uuid>,
+    /// Share links the visitor opened, whose creators were already notified of it
+    #[serde(default)]
+    accessed_share_links: Vec<Uuid>,
 }
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct LoginSessionData {
+    /// Identifies the login, without revealing the session token
+    id: Uuid,
     user: UserModel,
     ip_address: IpAddr,
+    user_agent: Option<String>,
+    created_at: DateTime<Utc>,
+    last_active: DateTime<Utc>,
 }
 #[derive(Clone, Debug, Serialize)]
 struct SessionUser {
@@ -106,13 +132,34 @@ async fn rocket() -> _ {
         manager.fetch_repos().await.unwrap();
         manager
     };
+    let search_manager = Arc::new(SearchManager::new(pool.clone(), &settings.search));
+    let notification_manager = Arc::new(NotificationManager::new(pool.clone()));
+    let share_link_manager = Arc::new(ShareLinkManager::new(pool.clone(), &settings.general, notification_manager.clone()));
+    let favorite_manager = Arc::new(FavoriteManager::new(pool.clone()));
+    let activity_manager = Arc::new(ActivityManager::new(pool.clone()));
+    let trash_manager = Arc::new(TrashManager::new(pool.clone(), &settings.trash));
     let libraries_manager = {
-        let mut manager = LibraryManager::new(pool.clone(), repo_manager.clone());
+        let mut manager = LibraryManager::new(pool.clone(), repo_manager.clone(), search_manager.clone(), favorite_manager.clone(), activity_manager.clone(),
+            notification_manager.clone(), trash_manager.clone());
         Arc::new(Mutex::new(manager))
     };
+    search_manager.start_reindex_task(libraries_manager.clone());
+
+    let version_manager = Arc::new(VersionManager::new(pool.clone(), libraries_manager.clone()));
+    version_manager.start_prune_task();
+    let upload_manager = Arc::new(UploadManager::new(pool.clone(), version_manager.clone(), &settings.uploads));
+    upload_manager.start_expiry_task();
+    trash_manager.start_purge_task(libraries_manager.clone());
+    // WebDAV takes the listen address when enabled, with Rocket behind it on loopback
+    let (webdav, rocket_addr) = if settings.webdav.enabled {
+        let webdav = Arc::new(WebDav::new(pool.clone(), libraries_manager.clone(), trash_manager.clone(), version_manager.clone()));
+        (Some(webdav), webdav::app_address().expect("no free loopback port for the app server"))
+    } else {
+        (None, listen_addr)
+    };
 
     // TODO: move to own func
-    let store = setup_session_store();
+    let store = setup_session_store(pool.clone());
     let sso: SSOState = {
         if settings.auth.oidc.is_some() { Some(Arc::new(Mutex::new(SSO::create(&settings).await)) ) } else { None }
     };
@@ -122,30 +169,42 @@ async fn rocket() -> _ {
     };
 
     let figment = rocket::Config::figment()
-        .merge(("port", listen_addr.port()))
-        .merge(("address", listen_addr.ip()));
+        .merge(("port", rocket_addr.port()))
+        .merge(("address", rocket_addr.ip()));
 
-    rocket::custom(figment)
+    let rocket = rocket::custom(figment)
         .manage(pool)
         .manage(repo_manager)
         .manage(libraries_manager)
+        .manage(upload_manager)
+        .manage(trash_manager)
+        .manage(version_manager)
+        .manage(search_manager)
+        .manage(share_link_manager)
+        .manage(favorite_manager)
+        .manage(activity_manager)
+        .manage(notification_manager)
         .manage(settings)
         .manage(sso)
         .manage(users)
 
         .attach(store.fairing())
-        .attach(Template::custom(|engines| {
-            let hb = &mut engines.handlebars;
-
-            hb.register_helper("bytes", Box::new(helpers::bytes));
-            hb.register_helper("debug", Box::new(helpers::debug));
-            hb.register_helper("is-active", Box::new(helpers::is_active));
-            hb.register_helper("is-active-exact", Box::new(helpers::is_active));
-        }))
+        .attach(templates())
 
         .mount("/static", FileServer::from(relative!("static")))
         .mount("/api/library", routes![
-            api::library::move_file, api::library::upload_file, api::library::download_file, api::library::list_files, api::library::get_file, api::library::delete_file,
+            api::library::move_file, api::library::copy_file, api::library::download_archive, api::library::upload_file, api::library::download_file, api::library::list_files, api::library::get_file, api::library::delete_file,
+            api::library::list_permissions, api::library::set_permission, api::library::remove_permission,
+            api::library::list_trash, api::library::restore_trash_item, api::library::purge_trash_item, api::library::empty_trash,
+            api::library::list_versions, api::library::download_version, api::library::restore_version, api::library::set_version_limits,
+            api::library::list_links, api::library::create_link, api::library::revoke_link, api::library::list_link_uploads,
+            api::uploads::options, api::uploads::create, api::uploads::status, api::uploads::append, api::uploads::terminate,
+        ])
+        .mount("/api", routes![
+            api::search::search,
+            api::favorites::list, api::favorites::add, api::favorites::remove,
+            api::activity::list,
+            api::notifications::list, api::notifications::unread_count, api::notifications::mark_read, api::notifications::mark_all_read,
         ])
         .mount("/", routes![
             ui::auth::logout,
@@ -154,7 +213,19 @@ async fn rocket() -> _ {
             ui::auth::forgot_password::page, ui::auth::forgot_password::handler,
         ])
         .mount("/", routes![
-            ui::user::user_settings, ui::user::index, ui::user::redirect_list_library_files, ui::user::list_library_files, ui::user::get_library_file,
+            ui::user::index, ui::user::redirect_list_library_files, ui::user::list_library_files, ui::user::get_library_file,
+            ui::library::share_page, ui::library::share_handler, ui::library::unshare_handler,
+            ui::library::create_link_handler, ui::library::revoke_link_handler,
+            ui::library::trash_page, ui::library::restore_trash_handler, ui::library::purge_trash_handler, ui::library::empty_trash_handler,
+            ui::library::versions_page, ui::library::download_version, ui::library::download_archive, ui::library::restore_version_handler,
+            ui::library::settings_page, ui::library::version_limits_handler,
+            ui::settings::user_settings, ui::settings::create_token_handler, ui::settings::revoke_token_handler,
+            ui::settings::revoke_session_handler, ui::settings::logout_everywhere_handler, ui::settings::notification_preferences_handler,
+            ui::search::search_page, ui::favorites::favorites_page, ui::activity::activity_page,
+            ui::notifications::notifications_page, ui::notifications::mark_read_handler,
+        ])
+        .mount("/", routes![
+            ui::share::share_page, ui::share::unlock_handler, ui::share::download_file, ui::share::download_archive, ui::share::upload_file,
         ])
         .mount("/", routes![
             ui::help::about,
@@ -164,11 +235,17 @@ async fn rocket() -> _ {
             ui::admin::index
         ])
         .register("/api", catchers![
-            not_found_api,
+            not_found_api, not_authorized_api, forbidden_api,
         ])
         .register("/", catchers![
             not_found, not_authorized, forbidden
-        ])
+        ]);
+    match webdav {
+        Some(webdav) => rocket.attach(AdHoc::on_liftoff("WebDAV", move |rocket| Box::pin(async move {
+            webdav.serve(listen_addr, rocket_addr, rocket.shutdown());
+        }))),
+        None => rocket
+    }
 }
 
 #[catch(401)]
@@ -177,6 +254,19 @@ pub fn not_authorized(req: &Request) -> Redirect {
     Redirect::to(format!("/auth/login?return_to={}", req.uri().path().percent_encode()))
 }
 
+/// Renders the templates, with the helpers they use
+pub fn templates() -> impl Fairing {
+    Template::custom(|engines| {
+        let hb = &mut engines.handlebars;
+
+        hb.register_helper("bytes", Box::new(helpers::bytes));
+        hb.register_helper("datetime", Box::new(helpers::datetime));
+        hb.register_helper("debug", Box::new(helpers::debug));
+        hb.register_helper("is-active", Box::new(helpers::is_active));
+        hb.register_helper("is-active-exact", Box::new(helpers::is_active));
+    })
+}
+
 #[catch(403)]
 pub fn forbidden(req: &Request) -> Template {
    Template::render("errors/403", context! {
@@ -191,6 +281,26 @@ fn not_found(req: &Request) -> Template {
     })
 }
 
+#[catch(401)]
+fn not_authorized_api(req: &Request) -> ResponseError {
+    ResponseError::Unauthorized(
+        JsonErrorResponse {
+            code: "UNAUTHORIZED".to_string(),
+            message: "A valid session cookie or Authorization: Bearer token is required".to_string(),
+        }
+    )
+}
+
+#[catch(403)]
+fn forbidden_api(req: &Request) -> ResponseError {
+    ResponseError::Forbidden(
+        JsonErrorResponse {
+            code: "FORBIDDEN".to_string(),
+            message: "You do not have permission to access this resource".to_string(),
+        }
+    )
+}
+
 #[catch(404)]
 fn not_found_api(req: &Request) -> ResponseError {
     ResponseError::NotFound(
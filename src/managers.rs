@@ -1,4 +1,14 @@
 pub mod repos;
 pub mod libraries;
 pub mod sso;
-pub mod user;
\ No newline at end of file
+pub mod user;
+pub mod uploads;
+
+pub mod sessions;
+pub mod trash;pub mod versions;
+pub mod search;
+pub mod folder_sizes;
+pub mod share_links;
+pub mod favorites;
+pub mod activity;
+pub mod notifications;
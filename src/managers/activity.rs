@@ -0,0 +1,121 @@
+use std::net::IpAddr;
+use std::path::{Component, Path};
+use chrono::NaiveDate;
+use log::warn;
+use sqlx::types::Uuid;
+use crate::{models, DB};
+use crate::consts::MAX_ACTIVITY_RESULTS;
+use crate::guards::{ApiUser, AuthUser};
+use crate::models::activity::{ActivityAction, ActivityFilter, ActivityModel, NewActivity};
+use crate::objs::library::{Library, INTERNAL_FOLDER};
+
+/// Who is making changes to a library, recorded with them in the activity log.
+/// Changes made through public share links have no user, and those made by background tasks have no IP either
+#[derive(Debug, Clone, Default)]
+pub struct Actor {
+    pub user_id: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+impl From<&AuthUser> for Actor {
+    fn from(user: &AuthUser) -> Self {
+        Actor { user_id: Some(user.session.user.id.clone()), ip: user.ip }
+    }
+}
+
+impl From<&ApiUser> for Actor {
+    fn from(user: &ApiUser) -> Self {
+        Actor { user_id: Some(user.user.id.clone()), ip: user.ip }
+    }
+}
+
+/// Which activity to show, and the filters to apply
+#[derive(Debug, Default)]
+pub struct ActivityOptions<'a> {
+    pub action: Option<ActivityAction>,
+    /// The id, username or email of the user who made the changes
+    pub user: Option<&'a str>,
+    /// Part of the path that changed
+    pub path: Option<&'a str>,
+    /// Only activity on or after the day
+    pub since: Option<NaiveDate>,
+    /// Only activity on or before the day
+    pub until: Option<NaiveDate>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// Records every change made to the files of a library and to who it is shared with, along with who made it and from where.
+/// Libraries record their own changes, with the [Actor] they were opened for
+pub struct ActivityManager {
+    pool: DB,
+}
+
+/// The path activity is recorded under, empty for the library itself. None for anything in the [INTERNAL_FOLDER]
+fn activity_path(rel_path: &Path) -> Option<String> {
+    let segments: Vec<_> = rel_path.components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => Some(segment.to_string_lossy()),
+            _ => None
+        })
+        .collect();
+    if segments.first().is_some_and(|segment| segment == INTERNAL_FOLDER) {
+        return None
+    }
+    Some(segments.join("/"))
+}
+
+impl ActivityManager {
+    pub fn new(pool: DB) -> Self {
+        Self {
+            pool,
+        }
+    }
+
+    /// Records the change by the actor of the library. Changes to the library's internal files are not recorded
+    pub async fn record(&self, library: &Library, action: ActivityAction, rel_path: &Path, detail: Option<&str>) {
+        let Some(path) = activity_path(rel_path) else {
+            return
+        };
+        let actor = library.actor();
+        let ip = actor.ip.map(|ip| ip.to_string());
+        let activity = NewActivity {
+            library_id: &library.model().id,
+            user_id: actor.user_id.as_deref(),
+            action,
+            path: &path,
+            detail,
+            ip: ip.as_deref(),
+        };
+        if let Err(e) = models::activity::create_activity(&self.pool, &activity).await {
+            warn!("failed to record {:?} of {:?} in library {}: {}", action, rel_path, library.model().id, e);
+        }
+    }
+
+    /// Returns the activity in the libraries, newest first. IP addresses are only kept for `admin_library_ids`,
+    /// the libraries the user viewing the activity manages
+    pub async fn list(&self, library_ids: &[Uuid], admin_library_ids: &[Uuid], options: &ActivityOptions<'_>) -> Result<Vec<ActivityModel>, anyhow::Error> {
+        let user_id = match options.user.filter(|user| !user.is_empty()) {
+            Some(user) => match models::user::find_user(&self.pool, user).await? {
+                Some(user) => Some(user.id),
+                None => return Ok(Vec::new())
+            },
+            None => None
+        };
+        let filter = ActivityFilter {
+            library_ids,
+            action: options.action,
+            user_id: user_id.as_deref(),
+            path: options.path.filter(|path| !path.is_empty()),
+            after: options.since.map(|day| day.and_time(Default::default())),
+            before: options.until.and_then(|day| day.succ_opt()).map(|day| day.and_time(Default::default())),
+            limit: options.limit.unwrap_or(50).min(MAX_ACTIVITY_RESULTS) as i64,
+            offset: options.offset.unwrap_or(0) as i64,
+        };
+        let mut activity = models::activity::get_activity(&self.pool, &filter).await?;
+        for entry in activity.iter_mut().filter(|entry| !admin_library_ids.contains(&entry.library_id)) {
+            entry.ip = None;
+        }
+        Ok(activity)
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::path::{Component, Path};
+use log::warn;
+use sqlx::types::Uuid;
+use crate::{models, DB};
+use crate::models::favorite::FavoriteModel;
+use crate::objs::library::{Library, INTERNAL_FOLDER};
+use crate::storage::FileEntry;
+use crate::util::{storage_error, JsonErrorResponse, ResponseError};
+
+/// Keeps the files, folders and libraries users starred. Libraries call it whenever files are moved or deleted,
+/// so favorites follow files that are moved or renamed
+pub struct FavoriteManager {
+    pool: DB,
+}
+
+/// The path a favorite is stored under, empty for the library itself. None for anything in the [INTERNAL_FOLDER]
+fn favorite_key(rel_path: &Path) -> Option<String> {
+    stored_key(rel_path).filter(|key| !is_internal(key))
+}
+
+/// The path favorites are stored under, including in the [INTERNAL_FOLDER], where the favorites of trashed files are kept
+fn stored_key(rel_path: &Path) -> Option<String> {
+    let mut segments = Vec::new();
+    for component in rel_path.components() {
+        match component {
+            Component::Normal(segment) => segments.push(segment.to_string_lossy()),
+            Component::CurDir | Component::RootDir => {},
+            _ => return None
+        }
+    }
+    Some(segments.join("/"))
+}
+
+fn is_internal(key: &str) -> bool {
+    key.split('/').next() == Some(INTERNAL_FOLDER)
+}
+
+fn invalid_path() -> ResponseError {
+    ResponseError::BadRequest(JsonErrorResponse {
+        code: "INVALID_PATH".to_string(),
+        message: "The path is not valid".to_string(),
+    })
+}
+
+impl FavoriteManager {
+    pub fn new(pool: DB) -> Self {
+        Self {
+            pool,
+        }
+    }
+
+    /// Stars the file or folder for the user, or the library when the path is empty
+    pub async fn add(&self, library: &Library, user_id: &str, rel_path: &Path) -> Result<(), ResponseError> {
+        let key = favorite_key(rel_path).ok_or_else(invalid_path)?;
+        if !key.is_empty() && !library.exists(&rel_path.to_path_buf()).await.map_err(storage_error)? {
+            return Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "FILE_NOT_FOUND".to_string(),
+                message: "No file or folder exists at the path".to_string(),
+            }))
+        }
+        models::favorite::add_favorite(&self.pool, user_id, &library.model().id, &key).await
+            .map_err(|_| ResponseError::GenericError)
+    }
+
+    pub async fn remove(&self, library: &Library, user_id: &str, rel_path: &Path) -> Result<(), ResponseError> {
+        let key = favorite_key(rel_path).ok_or_else(invalid_path)?;
+        if !models::favorite::delete_favorite(&self.pool, user_id, &library.model().id, &key).await
+            .map_err(|_| ResponseError::GenericError)? {
+            return Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "FAVORITE_NOT_FOUND".to_string(),
+                message: "The path is not a favorite".to_string(),
+            }))
+        }
+        Ok(())
+    }
+
+    /// The user's favorites across every library they can access
+    pub async fn list(&self, user_id: &str) -> Result<Vec<FavoriteModel>, anyhow::Error> {
+        let mut favorites = models::favorite::get_user_favorites(&self.pool, user_id).await?;
+        favorites.retain(|favorite| !is_internal(&favorite.path));
+        Ok(favorites)
+    }
+
+    /// Sets `favorited` on the entries of a listing of the folder that the user starred
+    pub async fn mark(&self, library: &Library, user_id: &str, folder: &Path, files: &mut [FileEntry]) -> Result<(), anyhow::Error> {
+        let Some(folder) = favorite_key(folder) else {
+            return Ok(())
+        };
+        let paths: HashSet<String> = models::favorite::get_favorited_paths(&self.pool, user_id, &library.model().id, &folder).await?
+            .into_iter()
+            .collect();
+        if paths.is_empty() {
+            return Ok(())
+        }
+        for entry in files {
+            let key = if folder.is_empty() { entry.path.clone() } else { format!("{}/{}", folder, entry.path) };
+            entry.favorited = paths.contains(&key);
+        }
+        Ok(())
+    }
+
+    /// Moves the favorites of the file or folder after it was moved, and removes those of anything that was overwritten.
+    /// Favorites follow files into the [INTERNAL_FOLDER] too, so they are back when a file is restored from the trash
+    pub async fn on_move(&self, library: &Library, from: &Path, to: &Path) {
+        let library_id = &library.model().id;
+        let result = match (stored_key(from).filter(|key| !key.is_empty()), stored_key(to).filter(|key| !key.is_empty())) {
+            (Some(from), Some(to)) => self.move_path(library_id, &from, &to).await,
+            _ => Ok(())
+        };
+        if let Err(e) = result {
+            warn!("failed to update favorites after moving {:?} to {:?} in library {}: {}", from, to, library_id, e);
+        }
+    }
+
+    async fn move_path(&self, library_id: &Uuid, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        if from == to {
+            return Ok(())
+        }
+        models::favorite::delete_favorites(&self.pool, library_id, to).await?;
+        models::favorite::move_favorites(&self.pool, library_id, from, to).await
+    }
+
+    /// Removes the favorites of the file or folder, and of everything in it, after it was deleted or purged from the trash
+    pub async fn on_delete(&self, library: &Library, rel_path: &Path) {
+        let Some(key) = stored_key(rel_path).filter(|key| !key.is_empty()) else {
+            return
+        };
+        if let Err(e) = models::favorite::delete_favorites(&self.pool, &library.model().id, &key).await {
+            warn!("failed to remove favorites of {:?} in library {}: {}", rel_path, library.model().id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::storage::ConflictPolicy;
+    use crate::testing::{error_code, write_file, TestData};
+    use super::*;
+
+    #[test]
+    fn keys_favorites_by_their_normal_path() {
+        assert_eq!(favorite_key(Path::new("")).as_deref(), Some(""));
+        assert_eq!(favorite_key(Path::new("/")).as_deref(), Some(""));
+        assert_eq!(favorite_key(Path::new("docs/a.txt")).as_deref(), Some("docs/a.txt"));
+        assert_eq!(favorite_key(Path::new("/docs/./a.txt")).as_deref(), Some("docs/a.txt"));
+        assert_eq!(favorite_key(Path::new("docs/")).as_deref(), Some("docs"));
+        assert_eq!(favorite_key(Path::new("docs/../a.txt")), None);
+        assert_eq!(favorite_key(Path::new("..")), None);
+        // Trashed files keep their favorites in the internal folder, where they can't be starred
+        let trashed = format!("{}/trash/a.txt", INTERNAL_FOLDER);
+        assert_eq!(favorite_key(Path::new(&trashed)), None);
+        assert_eq!(stored_key(Path::new(&trashed)), Some(trashed.clone()));
+        assert_eq!(favorite_key(Path::new(&format!("{}x/a.txt", INTERNAL_FOLDER))), Some(format!("{}x/a.txt", INTERNAL_FOLDER)));
+    }
+
+    async fn favorite_paths(favorites: &FavoriteManager, user_id: &str) -> Vec<String> {
+        favorites.list(user_id).await.unwrap().into_iter().map(|favorite| favorite.path).collect()
+    }
+
+    #[tokio::test]
+    async fn follows_files_that_are_moved_trashed_and_restored() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let trash = data.trash();
+        let libraries = data.libraries(trash.clone()).await;
+        let library = data.open(&libraries, &model).await;
+        let favorites = FavoriteManager::new(data.pool.clone());
+        write_file(&library, "docs/a.txt", "a").await;
+        write_file(&library, "docs/b.txt", "b").await;
+
+        favorites.add(&library, &owner.id, Path::new("")).await.unwrap();
+        favorites.add(&library, &owner.id, Path::new("/docs/a.txt")).await.unwrap();
+        favorites.add(&library, &owner.id, Path::new("docs/b.txt")).await.unwrap();
+        assert_eq!(error_code(favorites.add(&library, &owner.id, Path::new("missing.txt")).await).as_deref(), Some("FILE_NOT_FOUND"));
+        assert_eq!(favorite_paths(&favorites, &owner.id).await, ["", "docs/a.txt", "docs/b.txt"]);
+
+        library.move_file(&PathBuf::from("docs"), &PathBuf::from("papers"), ConflictPolicy::Fail).await.unwrap();
+        assert_eq!(favorite_paths(&favorites, &owner.id).await, ["", "papers/a.txt", "papers/b.txt"]);
+        // The overwritten file goes to the trash with its favorite, the moved file keeps its own
+        library.move_file(&PathBuf::from("papers/b.txt"), &PathBuf::from("papers/a.txt"), ConflictPolicy::Overwrite).await.unwrap();
+        assert_eq!(favorite_paths(&favorites, &owner.id).await, ["", "papers/a.txt"]);
+
+        let item = trash.trash(&library, "papers/a.txt", &owner.id).await.unwrap();
+        assert_eq!(favorite_paths(&favorites, &owner.id).await, [""]);
+        trash.restore(&library, &item).await.unwrap();
+        assert_eq!(favorite_paths(&favorites, &owner.id).await, ["", "papers/a.txt"]);
+
+        favorites.remove(&library, &owner.id, Path::new("papers/a.txt")).await.unwrap();
+        assert_eq!(error_code(favorites.remove(&library, &owner.id, Path::new("papers/a.txt")).await).as_deref(), Some("FAVORITE_NOT_FOUND"));
+        assert_eq!(favorite_paths(&favorites, &owner.id).await, [""]);
+
+        data.cleanup().await;
+    }
+}
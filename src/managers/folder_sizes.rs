@@ -0,0 +1,200 @@
+use std::path::{Component, Path};
+use std::time::Duration;
+use log::error;
+use moka::future::Cache;
+use sqlx::types::Uuid;
+use crate::consts::{FOLDER_SIZE_CACHE_CAPACITY, FOLDER_SIZE_CACHE_SECONDS};
+use crate::objs::library::{Library, ListOptions};
+use crate::storage::{FileType, StorageError};
+
+/// The total size of the files in a folder and its subfolders, and how many files and folders that is
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FolderSize {
+    pub size: u64,
+    pub items: u64,
+}
+
+/// Computes folder sizes by listing everything in them, caching the size of every folder it lists on the way.
+/// Libraries invalidate the cached sizes of the folders containing a path whenever it is written, moved or deleted
+pub struct FolderSizeManager {
+    cache: Cache<(Uuid, String), FolderSize>,
+}
+
+/// The cache key of a folder, its normal path components joined by slashes. The root is empty
+fn folder_key(rel_path: &Path) -> String {
+    rel_path.components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => Some(segment.to_string_lossy()),
+            _ => None
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl FolderSizeManager {
+    pub fn new() -> Self {
+        Self {
+            cache: Cache::builder()
+                .max_capacity(FOLDER_SIZE_CACHE_CAPACITY)
+                .time_to_live(Duration::from_secs(FOLDER_SIZE_CACHE_SECONDS))
+                .support_invalidation_closures()
+                .build(),
+        }
+    }
+
+    /// Returns the size of the folder, from the cache or by listing it and all of its subfolders.
+    /// Symlinks are not followed, and the library's internal folder is not counted.
+    /// Fails with [StorageError::ListingTooLarge] once more than `budget` entries had to be listed, which is shared
+    /// between calls so a listing computing the size of each of its folders is limited as a whole
+    pub async fn get(&self, library: &Library, folder: &Path, budget: &mut usize) -> Result<FolderSize, anyhow::Error> {
+        let key = (library.model().id, folder_key(folder));
+        if let Some(size) = self.cache.get(&key).await {
+            return Ok(size)
+        }
+        let entries = library.list_files(&folder.to_path_buf(), ListOptions::default()).await?;
+        *budget = budget.checked_sub(entries.len()).ok_or(StorageError::ListingTooLarge)?;
+        let mut total = FolderSize::default();
+        for entry in entries {
+            total.items += 1;
+            match entry._type {
+                FileType::File => total.size += entry.size,
+                FileType::Folder => {
+                    let size = Box::pin(self.get(library, &folder.join(&entry.path), budget)).await?;
+                    total.size += size.size;
+                    total.items += size.items;
+                },
+                _ => {}
+            }
+        }
+        self.cache.insert(key, total).await;
+        Ok(total)
+    }
+
+    /// Forgets the sizes of every folder containing the path, and of the path itself.
+    /// With recursive, also those of every folder inside it, for when a folder is moved, copied or deleted
+    pub async fn invalidate(&self, library_id: &Uuid, rel_path: &Path, recursive: bool) {
+        let key = folder_key(rel_path);
+        let mut ancestor = key.as_str();
+        loop {
+            self.cache.invalidate(&(*library_id, ancestor.to_string())).await;
+            match ancestor.rsplit_once('/') {
+                Some((parent, _)) => ancestor = parent,
+                None if !ancestor.is_empty() => ancestor = "",
+                None => break
+            }
+        }
+        if recursive && !key.is_empty() {
+            let library_id = *library_id;
+            let prefix = format!("{}/", key);
+            let result = self.cache.invalidate_entries_if(move |(id, folder), _| *id == library_id && folder.starts_with(&prefix));
+            if let Err(e) = result {
+                error!("Could not invalidate folder sizes in {}: {}", library_id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+    use crate::testing::{write_file, TestData};
+
+    #[test]
+    fn keys_folders_by_their_normal_path() {
+        assert_eq!(folder_key(Path::new("")), "");
+        assert_eq!(folder_key(Path::new("/")), "");
+        assert_eq!(folder_key(Path::new("a/b")), "a/b");
+        assert_eq!(folder_key(Path::new("/a/./b/")), "a/b");
+        assert_eq!(folder_key(Path::new("a//b")), "a/b");
+    }
+
+    async fn cached(sizes: &FolderSizeManager, library_id: &Uuid, folders: &[&str]) -> Vec<String> {
+        let mut cached = Vec::new();
+        for folder in folders {
+            if sizes.cache.get(&(*library_id, folder.to_string())).await.is_some() {
+                cached.push(folder.to_string());
+            }
+        }
+        cached
+    }
+
+    #[tokio::test]
+    async fn invalidates_the_folders_containing_the_path() {
+        let sizes = FolderSizeManager::new();
+        let (library_id, other_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let folders = ["", "a", "a/b", "a/b/c", "a/b/c/d", "a/bc", "x"];
+        let fill = async || {
+            for folder in folders {
+                sizes.cache.insert((library_id, folder.to_string()), FolderSize::default()).await;
+            }
+            sizes.cache.insert((other_id, "a".to_string()), FolderSize::default()).await;
+        };
+
+        fill().await;
+        sizes.invalidate(&library_id, Path::new("a/b/file.txt"), false).await;
+        assert_eq!(cached(&sizes, &library_id, &folders).await, ["a/b/c", "a/b/c/d", "a/bc", "x"]);
+        fill().await;
+        sizes.invalidate(&library_id, Path::new("/a/b/"), false).await;
+        assert_eq!(cached(&sizes, &library_id, &folders).await, ["a/b/c", "a/b/c/d", "a/bc", "x"]);
+        // Folders with the path as a prefix of their name aren't inside it
+        fill().await;
+        sizes.invalidate(&library_id, Path::new("a/b"), true).await;
+        assert_eq!(cached(&sizes, &library_id, &folders).await, ["a/bc", "x"]);
+        fill().await;
+        sizes.invalidate(&library_id, Path::new(""), true).await;
+        assert_eq!(cached(&sizes, &library_id, &folders).await, ["a", "a/b", "a/b/c", "a/b/c/d", "a/bc", "x"]);
+        assert_eq!(cached(&sizes, &other_id, &["a"]).await, ["a"]);
+    }
+
+    #[tokio::test]
+    async fn computes_sizes_within_the_budget() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let libraries = data.libraries(data.trash()).await;
+        let library = data.open(&libraries, &model).await;
+        write_file(&library, "docs/a.txt", "12345").await;
+        write_file(&library, "docs/sub/b.txt", "123").await;
+        write_file(&library, "c.txt", "1").await;
+        let sizes = FolderSizeManager::new();
+
+        let mut budget = 10;
+        let size = sizes.get(&library, Path::new("docs"), &mut budget).await.unwrap();
+        assert_eq!((size.size, size.items), (8, 3));
+        assert_eq!(budget, 7);
+        // The library's internal folder isn't counted
+        let size = sizes.get(&library, Path::new(""), &mut 10).await.unwrap();
+        assert_eq!((size.size, size.items), (9, 5));
+        // Cached sizes don't use up the budget
+        let mut budget = 0;
+        assert_eq!(sizes.get(&library, Path::new("docs/sub"), &mut budget).await.unwrap().size, 3);
+
+        sizes.invalidate(&model.id, Path::new("docs/sub/b.txt"), false).await;
+        let error = sizes.get(&library, Path::new("docs"), &mut 2).await.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(StorageError::ListingTooLarge)));
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn lists_sizes_that_follow_changes() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let libraries = data.libraries(data.trash()).await;
+        let library = data.open(&libraries, &model).await;
+        write_file(&library, "docs/sub/a.txt", "12345").await;
+        let size = async || {
+            let files = library.list_files(&PathBuf::new(), ListOptions { folder_sizes: true, ..Default::default() }).await.unwrap();
+            let docs = files.iter().find(|file| file.path == "docs").unwrap();
+            (docs.size, docs.item_count)
+        };
+
+        assert_eq!(size().await, (5, Some(2)));
+        write_file(&library, "docs/sub/b.txt", "123").await;
+        assert_eq!(size().await, (8, Some(3)));
+        library.move_file(&PathBuf::from("docs/sub"), &PathBuf::from("sub"), Default::default()).await.unwrap();
+        assert_eq!(size().await, (0, Some(0)));
+        data.cleanup().await;
+    }
+}
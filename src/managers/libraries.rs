@@ -1,31 +1,53 @@
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 use sqlx::{query_as, Pool, Postgres};
 use tokio::sync::RwLock;
+use sqlx::types::Uuid;
+use crate::guards::{ApiUser, AuthUser};
 use crate::objs::library::Library;
 use crate::managers::repos::{RepoContainer, RepoManager};
+use crate::managers::activity::{ActivityManager, Actor};
+use crate::managers::favorites::FavoriteManager;
+use crate::managers::folder_sizes::FolderSizeManager;
+use crate::managers::notifications::NotificationManager;
+use crate::managers::search::SearchManager;
+use crate::managers::trash::TrashManager;
 use crate::models;
-use crate::models::library::LibraryModel;
-use crate::util::{JsonErrorResponse, ResponseError};
+use crate::models::activity::ActivityAction;
+use crate::models::library::{LibraryModel, LibraryShareModel, PermissionLevel};
+use crate::models::notification::NotificationKind;
+use crate::util::{database_error, JsonErrorResponse, ResponseError};
 
 pub struct LibraryManager {
     pool: Pool<Postgres>,
-    repos: RepoManager // TODO: make this rwlock so repo manager itself can be clone?
+    repos: RepoManager, // TODO: make this rwlock so repo manager itself can be clone?
+    search: Arc<SearchManager>,
+    sizes: Arc<FolderSizeManager>,
+    favorites: Arc<FavoriteManager>,
+    activity: Arc<ActivityManager>,
+    notifications: Arc<NotificationManager>,
+    trash: Arc<TrashManager>,
 }
 
 impl LibraryManager {
-    pub fn new(pool: Pool<Postgres>, repos: RepoManager) -> Self {
+    pub fn new(pool: Pool<Postgres>, repos: RepoManager, search: Arc<SearchManager>, favorites: Arc<FavoriteManager>, activity: Arc<ActivityManager>,
+        notifications: Arc<NotificationManager>, trash: Arc<TrashManager>) -> Self {
         Self {
             pool,
-            repos
+            repos,
+            search,
+            sizes: Arc::new(FolderSizeManager::new()),
+            favorites,
+            activity,
+            notifications,
+            trash,
         }
     }
 
+    /// Lists all libraries the user owns or has been shared
     pub async fn list(&self, user_id: &str) -> Result<Vec<LibraryModel>, anyhow::Error> {
-        // TODO: check for access from library_permissions
-        let libraries = query_as!(LibraryModel, "SELECT * FROM storage.libraries WHERE owner_id = $1", user_id)
-            .fetch_all(&self.pool)
-            .await.map_err(anyhow::Error::from)?;
-        Ok(libraries)
+        models::library::get_user_libraries(&self.pool, user_id).await
     }
     pub async fn get(&self, library_id: &str) -> Result<Library, ResponseError> {
         let Some(library) = models::library::get_library(&self.pool, library_id).await
@@ -41,6 +63,240 @@ impl LibraryManager {
                 message: "Library is incorrectly configured, repository does not exist".to_string()
             }))
         };
-        Ok(Library::new(library, repo))
+        Ok(Library::new(library, repo, self.search.clone(), self.sizes.clone(), self.favorites.clone(), self.activity.clone(), self.trash.clone()))
     }
-}
\ No newline at end of file
+
+    /// Returns the user's access to the library, or None if they have no access
+    pub async fn get_permission(&self, library: &LibraryModel, user_id: &str) -> Result<Option<PermissionLevel>, anyhow::Error> {
+        if library.owner_id == user_id {
+            return Ok(Some(PermissionLevel::Admin))
+        }
+        models::library::get_library_permission(&self.pool, &library.id, user_id).await
+    }
+
+    /// The ids of the libraries the user manages, out of the libraries
+    pub async fn admin_library_ids(&self, libraries: &[LibraryModel], user_id: &str) -> Result<Vec<Uuid>, anyhow::Error> {
+        let mut ids = Vec::new();
+        for library in libraries {
+            if self.get_permission(library, user_id).await? == Some(PermissionLevel::Admin) {
+                ids.push(library.id);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Gets the library, if the user has at least the required permission level. Changes to it are recorded as made by the user
+    pub async fn get_for_user(&self, library_id: &str, user_id: &str, required: PermissionLevel) -> Result<Library, ResponseError> {
+        let library = self.get(library_id).await?
+            .with_actor(Actor { user_id: Some(user_id.to_string()), ip: None });
+        let permission = self.get_permission(library.model(), user_id).await
+            .map_err(database_error)?;
+        match permission {
+            Some(level) if level >= required => Ok(library),
+            Some(_) => Err(ResponseError::Forbidden(JsonErrorResponse {
+                code: "LIBRARY_PERMISSION_DENIED".to_string(),
+                message: "You do not have permission to do this in this library".to_string()
+            })),
+            None => Err(ResponseError::Forbidden(JsonErrorResponse {
+                code: "LIBRARY_ACCESS_DENIED".to_string(),
+                message: "You do not have access to this library".to_string()
+            }))
+        }
+    }
+
+    /// Gets the library for an API request, also enforcing the library and scope of the API token used, if any
+    pub async fn get_for_api_user(&self, library_id: &str, user: &ApiUser, required: PermissionLevel) -> Result<Library, ResponseError> {
+        if let Some(token) = &user.token {
+            if token.library_id.is_some_and(|id| Uuid::parse_str(library_id).ok() != Some(id)) {
+                return Err(ResponseError::Forbidden(JsonErrorResponse {
+                    code: "TOKEN_LIBRARY_DENIED".to_string(),
+                    message: "API token does not have access to this library".to_string()
+                }))
+            }
+            if required > token.scope.max_permission() {
+                return Err(ResponseError::Forbidden(JsonErrorResponse {
+                    code: "TOKEN_SCOPE_DENIED".to_string(),
+                    message: "API token scope does not allow this".to_string()
+                }))
+            }
+        }
+        self.get_for_user(library_id, &user.user.id, required).await
+            .map(|library| library.with_actor(user.into()))
+    }
+
+    /// Gets the library for a request from the UI, if the user has at least the required permission level
+    pub async fn get_for_auth_user(&self, library_id: &str, user: &AuthUser, required: PermissionLevel) -> Result<Library, ResponseError> {
+        self.get_for_user(library_id, &user.session.user.id, required).await
+            .map(|library| library.with_actor(user.into()))
+    }
+
+    pub async fn list_shares(&self, library: &Library) -> Result<Vec<LibraryShareModel>, anyhow::Error> {
+        models::library::get_library_shares(&self.pool, &library.model().id).await
+    }
+
+    /// Shares the library with the user, or changes their permission if already shared
+    pub async fn share(&self, library: &Library, user_id: &str, level: PermissionLevel) -> Result<(), ResponseError> {
+        if library.model().owner_id == user_id {
+            return Err(ResponseError::BadRequest(JsonErrorResponse {
+                code: "LIBRARY_SHARE_OWNER".to_string(),
+                message: "Library cannot be shared with its owner".to_string()
+            }))
+        }
+        models::library::set_library_permission(&self.pool, &library.model().id, user_id, level).await
+            .map_err(database_error)?;
+        let detail = format!("{} ({})", self.username(user_id).await, level.as_str());
+        library.record_activity(ActivityAction::Share, Path::new(""), Some(&detail)).await;
+        if let Some(actor_id) = &library.actor().user_id && actor_id != user_id {
+            let detail = format!("{} ({})", self.username(actor_id).await, level.as_str());
+            self.notifications.notify(user_id, NotificationKind::LibraryShared, &library.model().id, "", Some(&detail)).await;
+        }
+        Ok(())
+    }
+
+    /// Removes the user's access to the library
+    pub async fn unshare(&self, library: &Library, user_id: &str) -> Result<(), ResponseError> {
+        let removed = models::library::remove_library_permission(&self.pool, &library.model().id, user_id).await
+            .map_err(database_error)?;
+        if !removed {
+            return Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "LIBRARY_SHARE_NOT_FOUND".to_string(),
+                message: "Library is not shared with this user".to_string()
+            }))
+        }
+        library.record_activity(ActivityAction::Unshare, Path::new(""), Some(&self.username(user_id).await)).await;
+        Ok(())
+    }
+
+    /// The username of the user, or their id if they can't be found, to record in the activity log and notifications
+    async fn username(&self, user_id: &str) -> String {
+        match models::user::get_user(&self.pool, user_id).await {
+            Ok(Some(user)) => user.username,
+            _ => user_id.to_string()
+        }
+    }
+
+    /// Sets how many previous versions of each file are kept and for how many days, None for no limit
+    pub async fn set_version_limits(&self, library: &Library, max_versions: Option<u32>, retention_days: Option<u32>) -> Result<(), ResponseError> {
+        let max_versions = max_versions.map(i32::try_from).transpose();
+        let retention_days = retention_days.map(i32::try_from).transpose();
+        let (Ok(max_versions), Ok(retention_days)) = (max_versions, retention_days) else {
+            return Err(ResponseError::BadRequest(JsonErrorResponse {
+                code: "INVALID_VERSION_LIMIT".to_string(),
+                message: "Version limit is too large".to_string()
+            }))
+        };
+        models::library::set_library_version_limits(&self.pool, &library.model().id, max_versions, retention_days).await
+            .map_err(database_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::api_token::ApiTokenScope;
+    use crate::testing::{error_code, TestData};
+
+    #[test]
+    fn orders_permission_levels() {
+        assert!(PermissionLevel::ReadOnly < PermissionLevel::ReadWrite);
+        assert!(PermissionLevel::ReadWrite < PermissionLevel::Admin);
+        assert_eq!(PermissionLevel::Admin.max(PermissionLevel::ReadOnly), PermissionLevel::Admin);
+    }
+
+    #[tokio::test]
+    async fn checks_the_users_permission() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let reader = data.user("reader").await;
+        let writer = data.user("writer").await;
+        let stranger = data.user("stranger").await;
+        let library = data.library(&owner).await;
+        let libraries = data.libraries(data.trash()).await;
+        models::library::set_library_permission(&data.pool, &library.id, &reader.id, PermissionLevel::ReadOnly).await.unwrap();
+        models::library::set_library_permission(&data.pool, &library.id, &writer.id, PermissionLevel::ReadWrite).await.unwrap();
+        let id = library.id.to_string();
+
+        for level in [PermissionLevel::ReadOnly, PermissionLevel::ReadWrite, PermissionLevel::Admin] {
+            assert_eq!(error_code(libraries.get_for_user(&id, &owner.id, level).await), None);
+        }
+        assert_eq!(error_code(libraries.get_for_user(&id, &reader.id, PermissionLevel::ReadOnly).await), None);
+        assert_eq!(error_code(libraries.get_for_user(&id, &reader.id, PermissionLevel::ReadWrite).await).as_deref(), Some("LIBRARY_PERMISSION_DENIED"));
+        assert_eq!(error_code(libraries.get_for_user(&id, &writer.id, PermissionLevel::ReadWrite).await), None);
+        assert_eq!(error_code(libraries.get_for_user(&id, &writer.id, PermissionLevel::Admin).await).as_deref(), Some("LIBRARY_PERMISSION_DENIED"));
+        assert_eq!(error_code(libraries.get_for_user(&id, &stranger.id, PermissionLevel::ReadOnly).await).as_deref(), Some("LIBRARY_ACCESS_DENIED"));
+        assert_eq!(error_code(libraries.get_for_user(&Uuid::new_v4().to_string(), &owner.id, PermissionLevel::ReadOnly).await).as_deref(), Some("LIBRARY_NOT_FOUND"));
+
+        // Changes are recorded as made by the user
+        let library = libraries.get_for_user(&id, &writer.id, PermissionLevel::ReadWrite).await.unwrap();
+        assert_eq!(library.actor().user_id.as_deref(), Some(writer.id.as_str()));
+        data.cleanup().await;
+    }
+
+    #[test]
+    fn limits_token_scopes() {
+        assert_eq!(ApiTokenScope::Read.max_permission(), PermissionLevel::ReadOnly);
+        assert_eq!(ApiTokenScope::ReadWrite.max_permission(), PermissionLevel::ReadWrite);
+    }
+
+    #[tokio::test]
+    async fn limits_api_tokens_to_their_library_and_scope() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let library = data.library(&owner).await;
+        let other = data.library(&owner).await;
+        let libraries = data.libraries(data.trash()).await;
+        let (id, other_id) = (library.id.to_string(), other.id.to_string());
+        let api_user = async |library_id: Option<&Uuid>, scope: ApiTokenScope| ApiUser {
+            user: owner.clone(),
+            token: Some(data.api_token(&owner, library_id, scope, None).await.1),
+            ip: None,
+        };
+
+        let read = api_user(None, ApiTokenScope::Read).await;
+        assert_eq!(error_code(libraries.get_for_api_user(&id, &read, PermissionLevel::ReadOnly).await), None);
+        assert_eq!(error_code(libraries.get_for_api_user(&id, &read, PermissionLevel::ReadWrite).await).as_deref(), Some("TOKEN_SCOPE_DENIED"));
+        // Even for the owner, tokens can't manage libraries
+        let read_write = api_user(None, ApiTokenScope::ReadWrite).await;
+        assert_eq!(error_code(libraries.get_for_api_user(&id, &read_write, PermissionLevel::ReadWrite).await), None);
+        assert_eq!(error_code(libraries.get_for_api_user(&id, &read_write, PermissionLevel::Admin).await).as_deref(), Some("TOKEN_SCOPE_DENIED"));
+
+        let single = api_user(Some(&library.id), ApiTokenScope::ReadWrite).await;
+        assert_eq!(error_code(libraries.get_for_api_user(&id, &single, PermissionLevel::ReadWrite).await), None);
+        assert_eq!(error_code(libraries.get_for_api_user(&other_id, &single, PermissionLevel::ReadOnly).await).as_deref(), Some("TOKEN_LIBRARY_DENIED"));
+        assert_eq!(error_code(libraries.get_for_api_user("not a uuid", &single, PermissionLevel::ReadOnly).await).as_deref(), Some("TOKEN_LIBRARY_DENIED"));
+
+        // Sessions act with the user's own permission
+        let session = ApiUser { user: owner.clone(), token: None, ip: None };
+        assert_eq!(error_code(libraries.get_for_api_user(&other_id, &session, PermissionLevel::Admin).await), None);
+        // Tokens can't go beyond the user's own permission
+        let stranger = data.user("stranger").await;
+        let stranger = ApiUser { token: Some(data.api_token(&stranger, None, ApiTokenScope::Read, None).await.1), user: stranger, ip: None };
+        assert_eq!(error_code(libraries.get_for_api_user(&id, &stranger, PermissionLevel::ReadOnly).await).as_deref(), Some("LIBRARY_ACCESS_DENIED"));
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn revokes_access_when_unshared() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let user = data.user("user").await;
+        let library = data.library(&owner).await;
+        let libraries = data.libraries(data.trash()).await;
+        let id = library.id.to_string();
+        let owned = libraries.get_for_user(&id, &owner.id, PermissionLevel::Admin).await.unwrap();
+
+        assert_eq!(error_code(libraries.share(&owned, &owner.id, PermissionLevel::ReadOnly).await).as_deref(), Some("LIBRARY_SHARE_OWNER"));
+        libraries.share(&owned, &user.id, PermissionLevel::ReadOnly).await.unwrap();
+        assert_eq!(error_code(libraries.get_for_user(&id, &user.id, PermissionLevel::ReadWrite).await).as_deref(), Some("LIBRARY_PERMISSION_DENIED"));
+        // Sharing again changes the level
+        libraries.share(&owned, &user.id, PermissionLevel::ReadWrite).await.unwrap();
+        assert_eq!(error_code(libraries.get_for_user(&id, &user.id, PermissionLevel::ReadWrite).await), None);
+        assert_eq!(libraries.list(&user.id).await.unwrap().iter().map(|library| library.id).collect::<Vec<_>>(), [library.id]);
+
+        libraries.unshare(&owned, &user.id).await.unwrap();
+        assert_eq!(error_code(libraries.get_for_user(&id, &user.id, PermissionLevel::ReadOnly).await).as_deref(), Some("LIBRARY_ACCESS_DENIED"));
+        assert_eq!(error_code(libraries.unshare(&owned, &user.id).await).as_deref(), Some("LIBRARY_SHARE_NOT_FOUND"));
+        assert!(libraries.list(&user.id).await.unwrap().is_empty());
+        data.cleanup().await;
+    }
+}
@@ -0,0 +1,84 @@
+use log::warn;
+use rocket::serde::Serialize;
+use sqlx::types::Uuid;
+use crate::{models, DB};
+use crate::consts::MAX_NOTIFICATION_RESULTS;
+use crate::models::notification::{NotificationKind, NotificationModel};
+use crate::util::{JsonErrorResponse, ResponseError};
+
+/// Whether the user gets notifications of the kind
+#[derive(Debug, Serialize)]
+pub struct NotificationPreference {
+    pub kind: NotificationKind,
+    pub enabled: bool,
+}
+
+/// Notifies users of what others did with their libraries and links, unless they turned the kind of notification off
+pub struct NotificationManager {
+    pool: DB,
+}
+
+impl NotificationManager {
+    pub fn new(pool: DB) -> Self {
+        Self {
+            pool,
+        }
+    }
+
+    /// Notifies the user of something that happened at the path in the library. Failures are only logged, as whatever
+    /// happened already did. Accesses to a link are collapsed into the unread notification of the last one
+    pub async fn notify(&self, user_id: &str, kind: NotificationKind, library_id: &Uuid, path: &str, detail: Option<&str>) {
+        let collapse = kind == NotificationKind::LinkAccessed;
+        if let Err(e) = models::notification::create_notification(&self.pool, user_id, kind, library_id, path, detail, collapse).await {
+            warn!("failed to notify {} of {:?} of {:?} in library {}: {}", user_id, kind, path, library_id, e);
+        }
+    }
+
+    /// Returns the user's notifications, newest first. `library_id` limits them to one library, for API tokens
+    /// scoped to it, and so does it for the other methods
+    pub async fn list(&self, user_id: &str, library_id: Option<&Uuid>, unread_only: bool, limit: Option<u32>, offset: Option<u32>) -> Result<Vec<NotificationModel>, anyhow::Error> {
+        let limit = limit.unwrap_or(50).min(MAX_NOTIFICATION_RESULTS) as i64;
+        models::notification::get_user_notifications(&self.pool, user_id, library_id, unread_only, limit, offset.unwrap_or(0) as i64).await
+    }
+
+    pub async fn unread_count(&self, user_id: &str, library_id: Option<&Uuid>) -> Result<i64, anyhow::Error> {
+        models::notification::count_unread_notifications(&self.pool, user_id, library_id).await
+    }
+
+    pub async fn mark_read(&self, user_id: &str, library_id: Option<&Uuid>, id: &Uuid) -> Result<(), ResponseError> {
+        if !models::notification::mark_notification_read(&self.pool, user_id, library_id, id).await
+            .map_err(|e| {
+                warn!("failed to mark notification {} of {} read: {}", id, user_id, e);
+                ResponseError::GenericError
+            })? {
+            return Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "NOTIFICATION_NOT_FOUND".to_string(),
+                message: "You have no notification with that id".to_string(),
+            }))
+        }
+        Ok(())
+    }
+
+    pub async fn mark_all_read(&self, user_id: &str, library_id: Option<&Uuid>) -> Result<(), anyhow::Error> {
+        models::notification::mark_all_notifications_read(&self.pool, user_id, library_id).await
+    }
+
+    /// Returns whether the user gets each kind of notification, all kinds are on until turned off
+    pub async fn preferences(&self, user_id: &str) -> Result<Vec<NotificationPreference>, anyhow::Error> {
+        let saved = models::notification::get_notification_preferences(&self.pool, user_id).await?;
+        Ok(NotificationKind::ALL.into_iter()
+            .map(|kind| NotificationPreference {
+                kind,
+                enabled: saved.iter().find(|(saved_kind, _)| *saved_kind == kind).is_none_or(|(_, enabled)| *enabled),
+            })
+            .collect())
+    }
+
+    /// Turns on the kinds of notifications in `enabled`, and turns off every other kind
+    pub async fn set_preferences(&self, user_id: &str, enabled: &[NotificationKind]) -> Result<(), anyhow::Error> {
+        for kind in NotificationKind::ALL {
+            models::notification::set_notification_preference(&self.pool, user_id, kind, enabled.contains(&kind)).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use chrono::{NaiveDate, NaiveDateTime};
+use log::{debug, error, info, warn};
+use rocket::serde::Serialize;
+use rocket::FromFormField;
+use sqlx::types::Uuid;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use crate::config::SearchConfig;
+use crate::consts::{MAX_INDEXED_CONTENT_SIZE, MAX_SEARCH_RESULTS};
+use crate::DB;
+use crate::managers::libraries::LibraryManager;
+use crate::models;
+use crate::models::file_index::{FileIndexModel, SearchFilter, SearchResultModel};
+use crate::objs::library::{Library, ListOptions, INTERNAL_FOLDER};
+use crate::storage::{guess_mime_type, FileMetadata, FileType};
+use crate::util::{JsonErrorResponse, ResponseError};
+
+/// The kinds of files a search can be limited to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchFileType {
+    File,
+    Folder,
+    Image,
+    Video,
+    Audio,
+    Text,
+}
+
+impl SearchFileType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SearchFileType::File => "file",
+            SearchFileType::Folder => "folder",
+            SearchFileType::Image => "image",
+            SearchFileType::Video => "video",
+            SearchFileType::Audio => "audio",
+            SearchFileType::Text => "text",
+        }
+    }
+}
+
+/// What to search for, and the filters to apply
+#[derive(Debug, Default)]
+pub struct SearchOptions<'a> {
+    pub query: &'a str,
+    pub file_type: Option<SearchFileType>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Only files modified on or after the day
+    pub modified_since: Option<NaiveDate>,
+    /// Only files modified on or before the day
+    pub modified_until: Option<NaiveDate>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// Parses a `YYYY-MM-DD` date filter, an empty value is no filter
+pub fn parse_date_filter(value: Option<&str>) -> Result<Option<NaiveDate>, ResponseError> {
+    value.filter(|value| !value.is_empty())
+        .map(|value| NaiveDate::parse_from_str(value, "%Y-%m-%d"))
+        .transpose()
+        .map_err(|_| ResponseError::BadRequest(JsonErrorResponse {
+            code: "INVALID_DATE".to_string(),
+            message: "Dates must be formatted as YYYY-MM-DD".to_string()
+        }))
+}
+
+/// Keeps an index of the names, paths and optionally the text of every file in every library, stored in the database.
+/// Libraries call it whenever files are written, moved or deleted, and everything is reindexed on startup
+/// to pick up any changes made directly to the storage
+pub struct SearchManager {
+    pool: DB,
+    index_contents: bool,
+}
+
+/// The path a file is indexed under, None for the root of the library or anything in the [INTERNAL_FOLDER]
+fn index_key(rel_path: &Path) -> Option<String> {
+    let mut segments = Vec::new();
+    for component in rel_path.components() {
+        match component {
+            Component::Normal(segment) => segments.push(segment.to_string_lossy()),
+            Component::CurDir | Component::RootDir => {},
+            _ => return None
+        }
+    }
+    if segments.is_empty() || segments[0] == INTERNAL_FOLDER {
+        return None
+    }
+    Some(segments.join("/"))
+}
+
+fn file_name(key: &str) -> &str {
+    key.rsplit('/').next().unwrap_or(key)
+}
+
+fn is_text(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || matches!(content_type, "application/json" | "application/xml" | "application/javascript")
+}
+
+/// Compares at the precision the database stores timestamps at
+fn same_time(a: Option<NaiveDateTime>, b: Option<NaiveDateTime>) -> bool {
+    a.map(|a| a.and_utc().timestamp_micros()) == b.map(|b| b.and_utc().timestamp_micros())
+}
+
+impl SearchManager {
+    pub fn new(pool: DB, config: &SearchConfig) -> Self {
+        Self {
+            pool,
+            index_contents: config.index_contents,
+        }
+    }
+
+    /// Indexes the file or folder after it was written or created. When recursive, everything in the folder is also indexed
+    pub async fn on_write(&self, library: &Library, rel_path: &Path, recursive: bool) {
+        if let Err(e) = self.index_path(library, rel_path, recursive).await {
+            warn!("failed to index {:?} in library {}: {}", rel_path, library.model().id, e);
+        }
+    }
+
+    /// Removes the file or folder, and everything in it, after it was deleted
+    pub async fn on_delete(&self, library: &Library, rel_path: &Path) {
+        let Some(key) = index_key(rel_path) else {
+            return
+        };
+        if let Err(e) = models::file_index::delete_file_index(&self.pool, &library.model().id, &key).await {
+            warn!("failed to remove {:?} in library {} from index: {}", rel_path, library.model().id, e);
+        }
+    }
+
+    /// Updates the index after a file or folder was moved, including in or out of the [INTERNAL_FOLDER]
+    pub async fn on_move(&self, library: &Library, from: &Path, to: &Path) {
+        let result = match (index_key(from), index_key(to)) {
+            (None, None) => Ok(()),
+            (Some(from), None) => models::file_index::delete_file_index(&self.pool, &library.model().id, &from).await,
+            (None, Some(_)) => self.index_path(library, to, true).await,
+            (Some(from), Some(to)) => self.move_path(library, &from, &to).await,
+        };
+        if let Err(e) = result {
+            warn!("failed to update index after moving {:?} to {:?} in library {}: {}", from, to, library.model().id, e);
+        }
+    }
+
+    async fn move_path(&self, library: &Library, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let library_id = &library.model().id;
+        // Anything that was overwritten
+        models::file_index::delete_file_index(&self.pool, library_id, to).await?;
+        models::file_index::move_file_index(&self.pool, library_id, from, to, file_name(to)).await?;
+        self.add_parents(library_id, to).await
+    }
+
+    /// Adds the folders the path is in to the index, as writing a file creates them
+    async fn add_parents(&self, library_id: &Uuid, key: &str) -> Result<(), anyhow::Error> {
+        let (paths, names): (Vec<String>, Vec<String>) = key.match_indices('/')
+            .map(|(index, _)| &key[..index])
+            .map(|path| (path.to_string(), file_name(path).to_string()))
+            .unzip();
+        if paths.is_empty() {
+            return Ok(())
+        }
+        models::file_index::add_folders_to_index(&self.pool, library_id, &paths, &names).await
+    }
+
+    async fn add_folder(&self, library_id: &Uuid, key: &str) -> Result<(), anyhow::Error> {
+        models::file_index::add_folders_to_index(&self.pool, library_id, &[key.to_string()], &[file_name(key).to_string()]).await
+    }
+
+    async fn index_path(&self, library: &Library, rel_path: &Path, recursive: bool) -> Result<(), anyhow::Error> {
+        let Some(key) = index_key(rel_path) else {
+            return Ok(())
+        };
+        let library_id = &library.model().id;
+        self.add_parents(library_id, &key).await?;
+        match library.stat_file(&PathBuf::from(&key)).await? {
+            Some(metadata) => self.index_file(library, &key, metadata).await,
+            None if !library.exists(&PathBuf::from(&key)).await? => Ok(()),
+            None => {
+                self.add_folder(library_id, &key).await?;
+                if recursive {
+                    self.index_tree(library, PathBuf::from(&key), &mut HashMap::new()).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn index_file(&self, library: &Library, key: &str, metadata: FileMetadata) -> Result<(), anyhow::Error> {
+        let content_type = guess_mime_type(file_name(key));
+        let entry = FileIndexModel {
+            library_id: library.model().id,
+            path: key.to_string(),
+            name: file_name(key).to_string(),
+            is_folder: false,
+            size: Some(metadata.size as i64),
+            content_type,
+            modified_at: metadata.last_modified.map(|modified| modified.naive_utc()),
+        };
+        let contents = match &entry.content_type {
+            Some(content_type) if self.index_contents && is_text(content_type) => self.read_contents(library, key).await?,
+            _ => None
+        };
+        if let Err(e) = models::file_index::upsert_file_index(&self.pool, &entry, contents.as_deref()).await {
+            // Postgres limits how large the indexed text can be, the file can still be found by name
+            if contents.is_none() {
+                return Err(e)
+            }
+            debug!("failed to index contents of {} in library {}: {}", key, entry.library_id, e);
+            models::file_index::upsert_file_index(&self.pool, &entry, None).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the start of the file as text, None if it turns out to be binary
+    async fn read_contents(&self, library: &Library, key: &str) -> Result<Option<String>, anyhow::Error> {
+        let Some(stream) = library.read_file(&PathBuf::from(key)).await? else {
+            return Ok(None)
+        };
+        let mut data = Vec::new();
+        stream.take(MAX_INDEXED_CONTENT_SIZE.as_u64()).read_to_end(&mut data).await?;
+        if data.contains(&0) {
+            return Ok(None)
+        }
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    /// Indexes everything in the folder. Files in `known` whose size and modification time haven't changed are skipped,
+    /// everything found is removed from it. Returns how many files and folders were found
+    async fn index_tree(&self, library: &Library, folder: PathBuf, known: &mut HashMap<String, FileIndexModel>) -> Result<usize, anyhow::Error> {
+        let library_id = &library.model().id;
+        let mut count = 0;
+        let mut pending = vec![folder];
+        while let Some(folder) = pending.pop() {
+            for entry in library.list_files(&folder, ListOptions::default()).await? {
+                let rel_path = folder.join(&entry.path);
+                let Some(key) = index_key(&rel_path) else {
+                    continue
+                };
+                let existing = known.remove(&key);
+                match entry._type {
+                    FileType::Folder => {
+                        if existing.is_none_or(|existing| !existing.is_folder) {
+                            self.add_folder(library_id, &key).await?;
+                        }
+                        pending.push(rel_path);
+                    },
+                    FileType::File => {
+                        // The listing already has what's indexed, so files aren't stat-ed one by one
+                        let metadata = FileMetadata { size: entry.size, last_modified: entry.last_modified, etag: entry.etag };
+                        let unchanged = existing.is_some_and(|existing| !existing.is_folder
+                            && existing.size == Some(metadata.size as i64)
+                            && same_time(existing.modified_at, metadata.last_modified.map(|modified| modified.naive_utc())));
+                        if !unchanged {
+                            self.index_file(library, &key, metadata).await?;
+                        }
+                    },
+                    // Symlinks are not followed, they could point outside the library
+                    _ => continue
+                }
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Brings the library's index up to date with its storage, returning how many files and folders it has
+    pub async fn reindex(&self, library: &Library) -> Result<usize, anyhow::Error> {
+        let library_id = &library.model().id;
+        let mut known: HashMap<String, FileIndexModel> = models::file_index::get_library_file_index(&self.pool, library_id).await?
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+        let count = self.index_tree(library, PathBuf::new(), &mut known).await?;
+        // Whatever wasn't found was removed outside the app
+        let removed: Vec<String> = known.into_keys().collect();
+        if !removed.is_empty() {
+            models::file_index::delete_file_index_paths(&self.pool, library_id, &removed).await?;
+        }
+        Ok(count)
+    }
+
+    /// Reindexes every library in the background
+    pub fn start_reindex_task(self: &Arc<Self>, libraries: Arc<Mutex<LibraryManager>>) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let models = match models::library::get_all_libraries(&manager.pool).await {
+                Ok(models) => models,
+                Err(e) => return error!("Failed to list libraries to index: {}", e),
+            };
+            for model in models {
+                let library = match libraries.lock().await.get(&model.id.to_string()).await {
+                    Ok(library) => library,
+                    Err(_) => {
+                        warn!("Skipping index of library {}, it could not be loaded", model.id);
+                        continue
+                    }
+                };
+                match manager.reindex(&library).await {
+                    Ok(count) => info!("Indexed library {} with {} files and folders", model.id, count),
+                    Err(e) => error!("Failed to index library {}: {}", model.id, e),
+                }
+            }
+        });
+    }
+
+    /// Searches the libraries for files and folders whose path contains the query, or whose contents match it
+    pub async fn search(&self, library_ids: &[Uuid], options: &SearchOptions<'_>) -> Result<Vec<SearchResultModel>, anyhow::Error> {
+        let (is_folder, content_type) = match options.file_type {
+            None => (None, None),
+            Some(SearchFileType::Folder) => (Some(true), None),
+            Some(SearchFileType::File) => (Some(false), None),
+            Some(SearchFileType::Image) => (Some(false), Some("image/")),
+            Some(SearchFileType::Video) => (Some(false), Some("video/")),
+            Some(SearchFileType::Audio) => (Some(false), Some("audio/")),
+            Some(SearchFileType::Text) => (Some(false), Some("text/")),
+        };
+        let filter = SearchFilter {
+            library_ids,
+            query: options.query.trim(),
+            is_folder,
+            content_type,
+            min_size: options.min_size.map(|size| size as i64),
+            max_size: options.max_size.map(|size| size as i64),
+            modified_after: options.modified_since.map(|day| day.and_time(Default::default())),
+            modified_before: options.modified_until.and_then(|day| day.succ_opt()).map(|day| day.and_time(Default::default())),
+            limit: options.limit.unwrap_or(50).min(MAX_SEARCH_RESULTS) as i64,
+            offset: options.offset.unwrap_or(0) as i64,
+        };
+        models::file_index::search_file_index(&self.pool, &filter).await
+    }
+}
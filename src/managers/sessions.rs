@@ -0,0 +1,69 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+use log::{error, info};
+use rocket_session_store::{SessionError, SessionResult, Store};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::consts::SESSION_CLEANUP_INTERVAL_SECONDS;
+use crate::DB;
+use crate::models;
+
+/// A session store that keeps sessions in the database, so they survive restarts and can be shared between instances
+pub struct PostgresStore<T> {
+    pool: DB,
+    _value: PhantomData<T>,
+}
+
+impl<T> PostgresStore<T> {
+    pub fn new(pool: DB) -> Self {
+        Self { pool, _value: PhantomData }
+    }
+
+    /// Periodically removes expired sessions in the background
+    pub fn start_cleanup_task(&self) {
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(SESSION_CLEANUP_INTERVAL_SECONDS));
+            loop {
+                interval.tick().await;
+                match models::session::delete_expired_sessions(&pool).await {
+                    Ok(0) => {},
+                    Ok(count) => info!("Removed {} expired sessions", count),
+                    Err(e) => error!("Failed to remove expired sessions: {}", e),
+                }
+            }
+        });
+    }
+}
+
+#[rocket::async_trait]
+impl<T> Store for PostgresStore<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync,
+{
+    type Value = T;
+
+    async fn get(&self, id: &str) -> SessionResult<Option<Self::Value>> {
+        let data = models::session::get_session(&self.pool, id).await
+            .map_err(|e| { error!("Failed to get session: {}", e); SessionError })?;
+        // A session that can no longer be deserialized, ex. after an update, is treated as logged out
+        Ok(data.and_then(|data| serde_json::from_value(data).ok()))
+    }
+
+    async fn set(&self, id: &str, value: Self::Value, duration: Duration) -> SessionResult<()> {
+        let data = serde_json::to_value(value)
+            .map_err(|e| { error!("Failed to serialize session: {}", e); SessionError })?;
+        models::session::set_session(&self.pool, id, &data, duration.as_secs()).await
+            .map_err(|e| { error!("Failed to set session: {}", e); SessionError })
+    }
+
+    async fn touch(&self, id: &str, duration: Duration) -> SessionResult<()> {
+        models::session::touch_session(&self.pool, id, duration.as_secs()).await
+            .map_err(|e| { error!("Failed to touch session: {}", e); SessionError })
+    }
+
+    async fn remove(&self, id: &str) -> SessionResult<()> {
+        models::session::delete_session(&self.pool, id).await
+            .map_err(|e| { error!("Failed to remove session: {}", e); SessionError })
+    }
+}
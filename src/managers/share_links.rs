@@ -0,0 +1,448 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use chrono::{Duration, Utc};
+use log::{error, info};
+use rocket::FromForm;
+use rocket_session_store::Session;
+use sqlx::types::Uuid;
+use tokio::io::AsyncRead;
+use crate::{models, SessionData, DB};
+use crate::config::GeneralConfig;
+use crate::consts::{ENCRYPTION_ROUNDS, MAX_RENAME_ATTEMPTS, SHARE_UPLOADS_SHOWN};
+use crate::managers::notifications::NotificationManager;
+use crate::models::activity::ActivityAction;
+use crate::models::notification::NotificationKind;
+use crate::models::share_link::{NewShareLink, ShareLinkMode, ShareLinkModel, ShareLinkUploadModel, ShareLinkWithUserModel};
+use crate::objs::library::{check_path, Library};
+use crate::storage::numbered_path;
+use crate::util::{gen_share_token, storage_error, JsonErrorResponse, ResponseError};
+
+/// How a new share link can be used
+pub struct ShareLinkOptions<'a> {
+    pub path: &'a str,
+    pub mode: ShareLinkMode,
+    /// An empty password is the same as none
+    pub password: Option<&'a str>,
+    pub expires_days: Option<u32>,
+    pub max_downloads: Option<u32>,
+    /// Upload links can ask who is uploading
+    pub ask_uploader: bool,
+}
+
+/// Who uploaded a file through an upload link, as they entered it
+#[derive(FromForm, Debug, Default)]
+pub struct Uploader<'r> {
+    #[field(name = "uploader_name")]
+    pub name: Option<&'r str>,
+    #[field(name = "uploader_email")]
+    pub email: Option<&'r str>,
+}
+
+/// Creates and checks public share links, which give anyone with the link access to a single file or folder
+pub struct ShareLinkManager {
+    pool: DB,
+    public_url: String,
+    /// The paths uploads through links are being written to, so two uploads of the same name don't pick the same path
+    uploading: Mutex<HashSet<(Uuid, PathBuf)>>,
+    notifications: Arc<NotificationManager>,
+}
+
+/// A path reserved for an upload until it is dropped
+struct ReservedPath<'a> {
+    uploading: &'a Mutex<HashSet<(Uuid, PathBuf)>>,
+    key: (Uuid, PathBuf),
+}
+
+impl Drop for ReservedPath<'_> {
+    fn drop(&mut self) {
+        self.uploading.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl ShareLinkManager {
+    pub fn new(pool: DB, config: &GeneralConfig, notifications: Arc<NotificationManager>) -> Self {
+        Self {
+            pool,
+            public_url: config.public_url.trim_end_matches('/').to_string(),
+            uploading: Mutex::new(HashSet::new()),
+            notifications,
+        }
+    }
+
+    /// The public URL of the app, which links are shared under as `<public_url>/s/<token>`
+    pub fn public_url(&self) -> &str {
+        &self.public_url
+    }
+
+    /// Creates a link to the file or folder. Upload links can only be created for folders
+    pub async fn create(&self, library: &Library, options: &ShareLinkOptions<'_>, user_id: &str) -> Result<ShareLinkModel, ResponseError> {
+        let path = options.path.trim_matches('/');
+        let rel_path = PathBuf::from(path);
+        check_path(&rel_path).map_err(|_| invalid_path())?;
+        let is_file = library.stat_file(&rel_path).await
+            .map_err(|_| ResponseError::GenericError)?
+            .is_some();
+        if !is_file && !library.exists(&rel_path).await.map_err(|_| ResponseError::GenericError)? {
+            return Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "FILE_NOT_FOUND".to_string(),
+                message: "No file or folder exists at the path".to_string(),
+            }))
+        }
+        if is_file && options.mode == ShareLinkMode::Upload {
+            return Err(ResponseError::BadRequest(JsonErrorResponse {
+                code: "INVALID_SHARE_MODE".to_string(),
+                message: "Upload links can only be created for folders".to_string(),
+            }))
+        }
+        if options.max_downloads == Some(0) {
+            return Err(ResponseError::BadRequest(JsonErrorResponse {
+                code: "INVALID_MAX_DOWNLOADS".to_string(),
+                message: "The download limit must be at least 1".to_string(),
+            }))
+        }
+        let password = match options.password.filter(|password| !password.is_empty()) {
+            Some(password) => Some(bcrypt::hash(password, ENCRYPTION_ROUNDS).map_err(|_| ResponseError::GenericError)?),
+            None => None
+        };
+        let token = gen_share_token();
+        let link = models::share_link::create_share_link(&self.pool, &NewShareLink {
+            library_id: &library.model().id,
+            path,
+            token: &token,
+            mode: options.mode,
+            password: password.as_deref(),
+            expires_at: options.expires_days.map(|days| (Utc::now() + Duration::days(days as i64)).naive_utc()),
+            max_downloads: options.max_downloads.map(|max| max.min(i32::MAX as u32) as i32),
+            ask_uploader: options.ask_uploader && options.mode == ShareLinkMode::Upload,
+            created_by: user_id,
+        }).await
+            .map_err(|_| ResponseError::GenericError)?;
+        library.record_activity(ActivityAction::CreateLink, &rel_path, Some(link.mode.as_str())).await;
+        Ok(link)
+    }
+
+    pub async fn list(&self, library: &Library) -> Result<Vec<ShareLinkWithUserModel>, anyhow::Error> {
+        models::share_link::get_library_share_links(&self.pool, &library.model().id).await
+    }
+
+    pub async fn revoke(&self, library: &Library, link_id: &Uuid) -> Result<(), ResponseError> {
+        let Some(path) = models::share_link::delete_share_link(&self.pool, &library.model().id, link_id).await
+            .map_err(|_| ResponseError::GenericError)? else {
+            return Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "SHARE_LINK_NOT_FOUND".to_string(),
+                message: "The library has no share link with that id".to_string(),
+            }))
+        };
+        library.record_activity(ActivityAction::RevokeLink, Path::new(&path), None).await;
+        Ok(())
+    }
+
+    /// Returns the link with the token, None if there is none or it can no longer be used
+    pub async fn get(&self, token: &str) -> Result<Option<ShareLinkModel>, ResponseError> {
+        models::share_link::get_active_share_link(&self.pool, token).await
+            .map_err(|_| ResponseError::GenericError)
+    }
+
+    /// Checks the password of the link, remembering it in the visitor's session when it is correct
+    pub async fn unlock(&self, link: &ShareLinkModel, password: &str, session: &Session<'_, SessionData>) -> Result<bool, ResponseError> {
+        let Some(hash) = &link.password else {
+            return Ok(true)
+        };
+        if !bcrypt::verify(password, hash).map_err(|_| ResponseError::GenericError)? {
+            return Ok(false)
+        }
+        let mut data = session.get().await
+            .map_err(|_| ResponseError::GenericError)?
+            .unwrap_or_default();
+        if !data.unlocked_share_links.contains(&link.id) {
+            data.unlocked_share_links.push(link.id);
+        }
+        session.set(data).await.map_err(|_| ResponseError::GenericError)?;
+        Ok(true)
+    }
+
+    /// Has the visitor entered the password of the link, always true for links without one
+    pub async fn is_unlocked(&self, link: &ShareLinkModel, session: &Session<'_, SessionData>) -> bool {
+        if !link.has_password() {
+            return true
+        }
+        matches!(session.get().await, Ok(Some(data)) if data.unlocked_share_links.contains(&link.id))
+    }
+
+    /// Writes a file uploaded through the link into its folder. A file that exists is never replaced, the upload is
+    /// renamed instead, ex. `report (1).pdf`, so uploaders can't tell what else is in the folder
+    pub async fn upload(&self, link: &ShareLinkModel, library: &Library, name: &str, uploader: &Uploader<'_>, contents: &mut (dyn AsyncRead + Send + Unpin))
+        -> Result<ShareLinkUploadModel, ResponseError>
+    {
+        let (uploader_name, uploader_email) = check_uploader(link, uploader)?;
+        // Only files directly in the folder, uploads can't create folders
+        let mut components = Path::new(name).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            return Err(ResponseError::BadRequest(JsonErrorResponse {
+                code: "INVALID_NAME".to_string(),
+                message: "The file name is not valid".to_string(),
+            }))
+        }
+        let reserved = self.reserve_path(library, resolve_share_path(link, name)?).await?;
+        let path = &reserved.key.1;
+        let size = match library.write_file(path, contents).await {
+            Ok(size) => size,
+            Err(e) => {
+                // Don't leave a partly uploaded file behind at the reserved name
+                if library.exists(path).await.unwrap_or(false)
+                    && let Err(e) = library.discard_failed_write(path).await {
+                    error!("Failed to remove {} after failed upload: {}", path.display(), e);
+                }
+                return Err(storage_error(e))
+            }
+        };
+        let upload = models::share_link::create_share_link_upload(&self.pool, link, &path.to_string_lossy(), size as i64, uploader_name, uploader_email).await
+            .map_err(|e| {
+                error!("Failed to record upload of {} through share link {}: {}", path.display(), link.id, e);
+                ResponseError::GenericError
+            })?;
+        info!("{} uploaded {} to library {} through a share link", uploader_name.unwrap_or("Someone"), upload.path, library.model().id);
+        // The owner hears of every upload, and so does whoever the library is shared with that created the link
+        let owner_id = &library.model().owner_id;
+        self.notifications.notify(owner_id, NotificationKind::DropUpload, &link.library_id, &upload.path, uploader_name).await;
+        if &link.created_by != owner_id {
+            self.notifications.notify(&link.created_by, NotificationKind::DropUpload, &link.library_id, &upload.path, uploader_name).await;
+        }
+        Ok(upload)
+    }
+
+    /// Returns the path, or the first numbered path next to it, that has no file and no other upload writing to it
+    async fn reserve_path(&self, library: &Library, path: PathBuf) -> Result<ReservedPath<'_>, ResponseError> {
+        for n in 0..=MAX_RENAME_ATTEMPTS {
+            let candidate = if n == 0 { path.clone() } else { numbered_path(&path, n) };
+            let key = (library.model().id, candidate);
+            if !self.uploading.lock().unwrap().insert(key.clone()) {
+                continue
+            }
+            let reserved = ReservedPath { uploading: &self.uploading, key };
+            if !library.exists(&reserved.key.1).await.map_err(storage_error)? {
+                return Ok(reserved)
+            }
+        }
+        Err(ResponseError::Conflict(JsonErrorResponse {
+            code: "FILE_EXISTS".to_string(),
+            message: "Too many files with this name were uploaded".to_string(),
+        }))
+    }
+
+    /// The latest files uploaded to the library through its links
+    pub async fn uploads(&self, library: &Library) -> Result<Vec<ShareLinkUploadModel>, anyhow::Error> {
+        models::share_link::get_library_share_link_uploads(&self.pool, &library.model().id, SHARE_UPLOADS_SHOWN).await
+    }
+
+    /// Counts a download of the link, failing if it ran out of downloads in the meantime
+    pub async fn count_download(&self, link: &ShareLinkModel, session: &Session<'_, SessionData>) -> Result<(), ResponseError> {
+        if !models::share_link::count_share_link_download(&self.pool, &link.id).await
+            .map_err(|_| ResponseError::GenericError)? {
+            return Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "SHARE_LINK_NOT_FOUND".to_string(),
+                message: "The share link has expired".to_string(),
+            }))
+        }
+        self.notify_access(link, session).await;
+        Ok(())
+    }
+
+    /// Lets the creator of the link know it was opened or downloaded from, once per visitor session
+    /// so browsing the shared folder doesn't add a notification for every page
+    pub async fn notify_access(&self, link: &ShareLinkModel, session: &Session<'_, SessionData>) {
+        let mut data = match session.get().await {
+            Ok(data) => data.unwrap_or_default(),
+            Err(e) => {
+                error!("Failed to read session of share link {} visitor: {}", link.id, e);
+                return
+            }
+        };
+        if data.accessed_share_links.contains(&link.id) {
+            return
+        }
+        data.accessed_share_links.push(link.id);
+        if let Err(e) = session.set(data).await {
+            error!("Failed to save session of share link {} visitor: {}", link.id, e);
+            return
+        }
+        self.notifications.notify(&link.created_by, NotificationKind::LinkAccessed, &link.library_id, &link.path, None).await;
+    }
+}
+
+/// Returns the path in the library of a path inside the shared folder, which can't leave it
+pub fn resolve_share_path(link: &ShareLinkModel, sub_path: &str) -> Result<PathBuf, ResponseError> {
+    let mut path = PathBuf::from(&link.path);
+    for component in Path::new(sub_path).components() {
+        match component {
+            Component::Normal(segment) => path.push(segment),
+            Component::RootDir | Component::CurDir => {},
+            Component::ParentDir | Component::Prefix(_) => return Err(invalid_path())
+        }
+    }
+    Ok(path)
+}
+
+/// Returns the uploader's name and email to store with the upload, None if the link doesn't ask for them
+fn check_uploader<'r>(link: &ShareLinkModel, uploader: &Uploader<'r>) -> Result<(Option<&'r str>, Option<&'r str>), ResponseError> {
+    if !link.ask_uploader {
+        return Ok((None, None))
+    }
+    let name = uploader.name.map(str::trim).filter(|name| !name.is_empty());
+    let email = uploader.email.map(str::trim).filter(|email| !email.is_empty());
+    let invalid = |code: &str, message: &str| ResponseError::BadRequest(JsonErrorResponse {
+        code: code.to_string(),
+        message: message.to_string(),
+    });
+    match name {
+        None => return Err(invalid("UPLOADER_NAME_REQUIRED", "Enter your name to upload files")),
+        Some(name) if name.len() > 255 => return Err(invalid("INVALID_UPLOADER_NAME", "The name is too long")),
+        _ => {}
+    }
+    if let Some(email) = email && (email.len() > 255 || !email.contains('@')) {
+        return Err(invalid("INVALID_UPLOADER_EMAIL", "The email address is not valid"))
+    }
+    Ok((name, email))
+}
+
+fn invalid_path() -> ResponseError {
+    ResponseError::BadRequest(JsonErrorResponse {
+        code: "INVALID_PATH".to_string(),
+        message: "The path is not valid".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{error_code, read_file, write_file, TestData};
+    use super::*;
+
+    fn link(path: &str) -> ShareLinkModel {
+        ShareLinkModel {
+            id: Uuid::new_v4(),
+            library_id: Uuid::new_v4(),
+            path: path.to_string(),
+            token: "token".to_string(),
+            mode: ShareLinkMode::Read,
+            password: None,
+            expires_at: None,
+            max_downloads: None,
+            download_count: 0,
+            ask_uploader: false,
+            created_by: Uuid::new_v4().to_string(),
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn resolves_paths_inside_the_shared_folder() {
+        let link = link("shared/folder");
+        assert_eq!(resolve_share_path(&link, "").unwrap(), PathBuf::from("shared/folder"));
+        assert_eq!(resolve_share_path(&link, "a/b.txt").unwrap(), PathBuf::from("shared/folder/a/b.txt"));
+        assert_eq!(resolve_share_path(&link, "./a/./b.txt").unwrap(), PathBuf::from("shared/folder/a/b.txt"));
+        assert_eq!(resolve_share_path(&link, "a//b.txt").unwrap(), PathBuf::from("shared/folder/a/b.txt"));
+        // Absolute paths are relative to the shared folder, not the library
+        assert_eq!(resolve_share_path(&link, "/etc/passwd").unwrap(), PathBuf::from("shared/folder/etc/passwd"));
+    }
+
+    #[test]
+    fn rejects_paths_leaving_the_shared_folder() {
+        let link = link("shared/folder");
+        for sub_path in ["..", "../other", "a/../../other", "a/b/../../..", "./..", "/../x"] {
+            assert!(resolve_share_path(&link, sub_path).is_err(), "{} was resolved", sub_path);
+        }
+    }
+
+    #[test]
+    fn keeps_dots_that_are_part_of_names() {
+        let link = link("shared");
+        assert_eq!(resolve_share_path(&link, "..hidden").unwrap(), PathBuf::from("shared/..hidden"));
+        assert_eq!(resolve_share_path(&link, "a/b..").unwrap(), PathBuf::from("shared/a/b.."));
+    }
+
+    fn uploader<'r>(name: Option<&'r str>, email: Option<&'r str>) -> Uploader<'r> {
+        Uploader { name, email }
+    }
+
+    #[test]
+    fn checks_uploader_details() {
+        let mut link = link("drop");
+        // Links that don't ask store nothing of what was sent
+        assert_eq!(check_uploader(&link, &uploader(Some("Ann"), Some("ann@example.com"))).unwrap(), (None, None));
+        link.ask_uploader = true;
+        assert_eq!(check_uploader(&link, &uploader(Some(" Ann "), None)).unwrap(), (Some("Ann"), None));
+        assert_eq!(check_uploader(&link, &uploader(Some("Ann"), Some(" "))).unwrap(), (Some("Ann"), None));
+        assert_eq!(check_uploader(&link, &uploader(Some("Ann"), Some("ann@example.com"))).unwrap(), (Some("Ann"), Some("ann@example.com")));
+        assert_eq!(error_code(check_uploader(&link, &uploader(None, Some("ann@example.com")))).as_deref(), Some("UPLOADER_NAME_REQUIRED"));
+        assert_eq!(error_code(check_uploader(&link, &uploader(Some("  "), None))).as_deref(), Some("UPLOADER_NAME_REQUIRED"));
+        let long = "a".repeat(256);
+        assert_eq!(error_code(check_uploader(&link, &uploader(Some(&long), None))).as_deref(), Some("INVALID_UPLOADER_NAME"));
+        assert_eq!(error_code(check_uploader(&link, &uploader(Some("Ann"), Some("ann")))).as_deref(), Some("INVALID_UPLOADER_EMAIL"));
+        let long = format!("{}@example.com", "a".repeat(250));
+        assert_eq!(error_code(check_uploader(&link, &uploader(Some("Ann"), Some(&long)))).as_deref(), Some("INVALID_UPLOADER_EMAIL"));
+    }
+
+    fn options(path: &str, mode: ShareLinkMode) -> ShareLinkOptions<'_> {
+        ShareLinkOptions { path, mode, password: None, expires_days: None, max_downloads: None, ask_uploader: true }
+    }
+
+    #[tokio::test]
+    async fn only_creates_upload_links_for_folders() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let libraries = data.libraries(data.trash()).await;
+        let library = data.open(&libraries, &model).await;
+        write_file(&library, "drop/file.txt", "contents").await;
+        let links = data.share_links();
+
+        assert_eq!(error_code(links.create(&library, &options("drop/file.txt", ShareLinkMode::Upload), &owner.id).await).as_deref(), Some("INVALID_SHARE_MODE"));
+        assert_eq!(error_code(links.create(&library, &options("missing", ShareLinkMode::Upload), &owner.id).await).as_deref(), Some("FILE_NOT_FOUND"));
+        let link = links.create(&library, &options("/drop/", ShareLinkMode::Upload), &owner.id).await.unwrap();
+        assert_eq!(link.path, "drop");
+        assert!(link.ask_uploader);
+        // Only upload links ask who is uploading
+        let link = links.create(&library, &options("drop", ShareLinkMode::Read), &owner.id).await.unwrap();
+        assert!(!link.ask_uploader);
+
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn renames_uploads_instead_of_replacing_files() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let libraries = data.libraries(data.trash()).await;
+        let library = data.open(&libraries, &model).await;
+        write_file(&library, "drop/report.pdf", "existing").await;
+        let links = data.share_links();
+        let link = links.create(&library, &options("drop", ShareLinkMode::Upload), &owner.id).await.unwrap();
+        let ann = uploader(Some("Ann"), Some("ann@example.com"));
+
+        let upload = links.upload(&link, &library, "report.pdf", &ann, &mut "first".as_bytes()).await.unwrap();
+        assert_eq!(upload.path, "drop/report (1).pdf");
+        assert_eq!(upload.size, 5);
+        assert_eq!(upload.uploader_name.as_deref(), Some("Ann"));
+        assert_eq!(upload.uploader_email.as_deref(), Some("ann@example.com"));
+        let upload = links.upload(&link, &library, "report.pdf", &ann, &mut "second".as_bytes()).await.unwrap();
+        assert_eq!(upload.path, "drop/report (2).pdf");
+        let upload = links.upload(&link, &library, "notes", &ann, &mut "notes".as_bytes()).await.unwrap();
+        assert_eq!(upload.path, "drop/notes");
+        assert_eq!(read_file(&library, "drop/report.pdf").await.as_deref(), Some("existing"));
+        assert_eq!(read_file(&library, "drop/report (1).pdf").await.as_deref(), Some("first"));
+        assert_eq!(read_file(&library, "drop/report (2).pdf").await.as_deref(), Some("second"));
+        assert_eq!(links.uploads(&library).await.unwrap().len(), 3);
+
+        // Uploads can't create folders or leave the shared folder
+        for name in ["", ".", "..", "../escape.txt", "sub/file.txt", "/file.txt"] {
+            assert_eq!(error_code(links.upload(&link, &library, name, &ann, &mut "x".as_bytes()).await).as_deref(), Some("INVALID_NAME"), "{} was uploaded", name);
+        }
+        assert_eq!(error_code(links.upload(&link, &library, "file.txt", &Uploader::default(), &mut "x".as_bytes()).await).as_deref(), Some("UPLOADER_NAME_REQUIRED"));
+        assert_eq!(read_file(&library, "escape.txt").await, None);
+        assert_eq!(read_file(&library, "drop/file.txt").await, None);
+
+        data.cleanup().await;
+    }
+}
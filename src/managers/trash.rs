@@ -0,0 +1,257 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::anyhow;
+use log::{debug, error, info, warn};
+use sqlx::types::Uuid;
+use tokio::sync::Mutex;
+use crate::config::TrashConfig;
+use crate::consts::TRASH_PURGE_INTERVAL_SECONDS;
+use crate::DB;
+use crate::managers::libraries::LibraryManager;
+use crate::models;
+use crate::models::activity::ActivityAction;
+use crate::models::trash::{TrashItemModel, TrashItemWithUserModel};
+use crate::objs::library::{Library, INTERNAL_FOLDER};
+use crate::util::{database_error, storage_error, JsonErrorResponse, ResponseError};
+
+/// Deleted files are moved into `<library>/.storage/trash/<item id>`, with their original path kept in the database
+pub struct TrashManager {
+    pool: DB,
+    /// How long items are kept, None to keep them until purged manually
+    retention_secs: Option<u64>,
+}
+
+fn trash_path(item_id: &Uuid) -> PathBuf {
+    PathBuf::from(INTERNAL_FOLDER).join("trash").join(item_id.to_string())
+}
+
+impl TrashManager {
+    pub fn new(pool: DB, config: &TrashConfig) -> Self {
+        let retention_days = config.retention_days.unwrap_or(30);
+        Self {
+            pool,
+            retention_secs: (retention_days > 0).then(|| retention_days * 24 * 3600),
+        }
+    }
+
+    /// Moves the file or folder into the library's trash
+    pub async fn trash(&self, library: &Library, path: &str, user_id: &str) -> Result<TrashItemModel, ResponseError> {
+        let path = path.trim_start_matches('/');
+        let rel_path = PathBuf::from(path);
+        if path.is_empty() || !library.exists(&rel_path).await.map_err(storage_error)? {
+            return Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "FILE_NOT_FOUND".to_string(),
+                message: "File or folder does not exist".to_string()
+            }))
+        }
+        self.move_to_trash(library, path, Some(user_id)).await
+    }
+
+    /// Moves the file or folder at the destination of a move or copy that replaces it into the trash,
+    /// as deleted by whoever the library records changes as made by
+    pub(crate) async fn trash_replaced(&self, library: &Library, rel_path: &Path) -> Result<(), anyhow::Error> {
+        self.move_to_trash(library, &rel_path.to_string_lossy(), library.actor().user_id.as_deref()).await
+            .map(|_| ())
+            .map_err(|e| anyhow!("Could not move {:?} to the trash before replacing it: {}", rel_path, e))
+    }
+
+    async fn move_to_trash(&self, library: &Library, path: &str, deleted_by: Option<&str>) -> Result<TrashItemModel, ResponseError> {
+        let rel_path = PathBuf::from(path);
+        let metadata = library.stat_file(&rel_path).await.map_err(storage_error)?;
+        let id = Uuid::new_v4();
+        library.move_internal(&rel_path, &trash_path(&id)).await.map_err(storage_error)?;
+        let item = models::trash::create_trash_item(&self.pool, &id, &library.model().id, path, metadata.is_none(), metadata.map(|meta| meta.size), deleted_by).await;
+        match item {
+            Ok(item) => {
+                debug!("moved {} to trash of library {} as {}", path, library.model().id, id);
+                library.record_activity(ActivityAction::Delete, &rel_path, None).await;
+                Ok(item)
+            },
+            Err(e) => {
+                // Put it back, so the file is not lost in the trash folder without a record of it
+                if let Err(e) = library.move_internal(&trash_path(&id), &rel_path).await {
+                    error!("Failed to move {:?} back out of trash: {}", rel_path, e);
+                }
+                Err(ResponseError::DatabaseError(JsonErrorResponse {
+                    code: "DATABASE_ERROR".to_string(),
+                    message: e.to_string(),
+                }))
+            }
+        }
+    }
+
+    pub async fn list(&self, library: &Library) -> Result<Vec<TrashItemWithUserModel>, anyhow::Error> {
+        models::trash::get_trash_items(&self.pool, &library.model().id).await
+    }
+
+    pub async fn get(&self, library: &Library, item_id: &str) -> Result<TrashItemModel, ResponseError> {
+        let item = match Uuid::parse_str(item_id) {
+            Ok(item_id) => models::trash::get_trash_item(&self.pool, &library.model().id, &item_id).await
+                .map_err(database_error)?,
+            Err(_) => None
+        };
+        item.ok_or_else(|| ResponseError::NotFound(JsonErrorResponse {
+            code: "TRASH_ITEM_NOT_FOUND".to_string(),
+            message: "Item does not exist in the trash".to_string()
+        }))
+    }
+
+    /// Moves the item back to where it was deleted from, failing if something now exists there
+    pub async fn restore(&self, library: &Library, item: &TrashItemModel) -> Result<(), ResponseError> {
+        let rel_path = PathBuf::from(&item.path);
+        if library.exists(&rel_path).await.map_err(storage_error)? {
+            return Err(ResponseError::Conflict(JsonErrorResponse {
+                code: "TRASH_RESTORE_CONFLICT".to_string(),
+                message: format!("A file or folder already exists at {}", item.path)
+            }))
+        }
+        library.move_internal(&trash_path(&item.id), &rel_path).await.map_err(storage_error)?;
+        library.record_activity(ActivityAction::Restore, &rel_path, None).await;
+        models::trash::delete_trash_item(&self.pool, &item.id).await
+            .map_err(database_error)
+    }
+
+    /// Permanently deletes the item
+    pub async fn purge(&self, library: &Library, item: &TrashItemModel) -> Result<(), anyhow::Error> {
+        if let Err(e) = library.delete_internal(&trash_path(&item.id)).await {
+            // Still remove the record if the data is already gone, otherwise it could never be removed
+            if library.exists_internal(&trash_path(&item.id)).await? {
+                return Err(e)
+            }
+            warn!("trash item {} had no data: {}", item.id, e);
+        }
+        models::trash::delete_trash_item(&self.pool, &item.id).await
+    }
+
+    /// Permanently deletes everything in the library's trash
+    pub async fn empty(&self, library: &Library) -> Result<(), anyhow::Error> {
+        for item in self.list(library).await? {
+            self.purge(library, &item.item).await?;
+        }
+        Ok(())
+    }
+
+    /// Periodically purges items older than the retention period in the background
+    pub fn start_purge_task(self: &Arc<Self>, libraries: Arc<Mutex<LibraryManager>>) {
+        if self.retention_secs.is_none() {
+            return
+        }
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(TRASH_PURGE_INTERVAL_SECONDS));
+            loop {
+                interval.tick().await;
+                match manager.purge_expired(&libraries).await {
+                    Ok(0) => {},
+                    Ok(count) => info!("Purged {} items from trash", count),
+                    Err(e) => error!("Failed to purge trash: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Purges all items older than the retention period, returning how many were purged
+    pub async fn purge_expired(&self, libraries: &Mutex<LibraryManager>) -> Result<usize, anyhow::Error> {
+        let Some(retention_secs) = self.retention_secs else {
+            return Ok(0)
+        };
+        let expired = models::trash::get_expired_trash_items(&self.pool, retention_secs).await?;
+        let mut count = 0;
+        for item in &expired {
+            let library = match libraries.lock().await.get(&item.library_id.to_string()).await {
+                Ok(library) => library,
+                Err(e) => {
+                    warn!("Could not purge trash item {}, library unavailable: {:?}", item.id, e);
+                    continue
+                }
+            };
+            match self.purge(&library, item).await {
+                Ok(()) => count += 1,
+                Err(e) => warn!("Failed to purge trash item {}: {}", item.id, e),
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{error_code, read_file, read_internal, write_file, TestData};
+
+    #[tokio::test]
+    async fn restores_deleted_files() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let trash = data.trash();
+        let libraries = data.libraries(trash.clone()).await;
+        let library = data.open(&libraries, &model).await;
+        write_file(&library, "docs/a.txt", "contents").await;
+
+        let item = trash.trash(&library, "/docs/a.txt", &owner.id).await.unwrap();
+        assert_eq!((item.path.as_str(), item.is_folder, item.size), ("docs/a.txt", false, Some(8)));
+        assert_eq!(item.deleted_by.as_deref(), Some(owner.id.as_str()));
+        assert_eq!(read_file(&library, "docs/a.txt").await, None);
+        assert_eq!(read_internal(&library, &trash_path(&item.id)).await.as_deref(), Some("contents"));
+
+        let folder = trash.trash(&library, "docs", &owner.id).await.unwrap();
+        assert_eq!((folder.is_folder, folder.size), (true, None));
+        assert_eq!(trash.list(&library).await.unwrap().len(), 2);
+        // The file goes back into the folder it was in, once that is restored
+        trash.restore(&library, &folder).await.unwrap();
+        trash.restore(&library, &item).await.unwrap();
+        assert_eq!(read_file(&library, "docs/a.txt").await.as_deref(), Some("contents"));
+        assert!(trash.list(&library).await.unwrap().is_empty());
+        assert_eq!(error_code(trash.get(&library, &item.id.to_string()).await).as_deref(), Some("TRASH_ITEM_NOT_FOUND"));
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn does_not_restore_over_existing_files() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let trash = data.trash();
+        let libraries = data.libraries(trash.clone()).await;
+        let library = data.open(&libraries, &model).await;
+        write_file(&library, "a.txt", "old").await;
+        let item = trash.trash(&library, "a.txt", &owner.id).await.unwrap();
+        write_file(&library, "a.txt", "new").await;
+
+        assert_eq!(error_code(trash.restore(&library, &item).await).as_deref(), Some("TRASH_RESTORE_CONFLICT"));
+        assert_eq!(read_file(&library, "a.txt").await.as_deref(), Some("new"));
+        // The item stays in the trash, and can be restored once the path is free
+        let item = trash.get(&library, &item.id.to_string()).await.unwrap();
+        trash.trash(&library, "a.txt", &owner.id).await.unwrap();
+        trash.restore(&library, &item).await.unwrap();
+        assert_eq!(read_file(&library, "a.txt").await.as_deref(), Some("old"));
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn purges_items() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let trash = data.trash();
+        let libraries = data.libraries(trash.clone()).await;
+        let library = data.open(&libraries, &model).await;
+        assert_eq!(error_code(trash.trash(&library, "missing.txt", &owner.id).await).as_deref(), Some("FILE_NOT_FOUND"));
+        assert_eq!(error_code(trash.trash(&library, "", &owner.id).await).as_deref(), Some("FILE_NOT_FOUND"));
+        write_file(&library, "a.txt", "a").await;
+        write_file(&library, "b.txt", "b").await;
+        let a = trash.trash(&library, "a.txt", &owner.id).await.unwrap();
+        let b = trash.trash(&library, "b.txt", &owner.id).await.unwrap();
+
+        trash.purge(&library, &a).await.unwrap();
+        assert!(!library.exists_internal(&trash_path(&a.id)).await.unwrap());
+        assert_eq!(trash.list(&library).await.unwrap().iter().map(|item| item.item.id).collect::<Vec<_>>(), [b.id]);
+        // Records whose data is already gone can still be removed
+        library.delete_internal(&trash_path(&b.id)).await.unwrap();
+        trash.empty(&library).await.unwrap();
+        assert!(trash.list(&library).await.unwrap().is_empty());
+        data.cleanup().await;
+    }
+}
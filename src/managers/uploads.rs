@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use sqlx::types::Uuid;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use crate::archive::{extract_archive, ExtractResult};
+use crate::config::UploadsConfig;
+use crate::consts::UPLOAD_EXPIRY_INTERVAL_SECONDS;
+use crate::DB;
+use crate::managers::versions::VersionManager;
+use crate::models;
+use crate::models::upload::UploadModel;
+use crate::objs::library::{check_path, Library};
+use crate::storage::StorageError;
+use crate::util::{database_error, storage_error, JsonErrorResponse, ResponseError};
+
+/// Logs a failure reading or writing the staging file of an upload
+fn staging_error(e: std::io::Error) -> ResponseError {
+    error!("Upload staging failed: {}", e);
+    ResponseError::InternalServerError(JsonErrorResponse {
+        code: "UPLOAD_STAGING_ERROR".to_string(),
+        message: e.to_string(),
+    })
+}
+
+/// Handles resumable uploads, the data is staged under `<staging dir>/<repo id>/<upload id>`
+/// and only written to the library's storage backend once the whole file has been received
+pub struct UploadManager {
+    pool: DB,
+    versions: Arc<VersionManager>,
+    staging_dir: PathBuf,
+    expire_secs: u64,
+    /// Uploads currently receiving data, as only one request can write to an upload at a time
+    active: Mutex<HashSet<Uuid>>,
+}
+
+/// Marks an upload as active until dropped
+struct ActiveUpload<'a> {
+    manager: &'a UploadManager,
+    id: Uuid,
+}
+
+impl Drop for ActiveUpload<'_> {
+    fn drop(&mut self) {
+        self.manager.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl UploadManager {
+    pub fn new(pool: DB, versions: Arc<VersionManager>, config: &UploadsConfig) -> Self {
+        let staging_dir = config.staging_dir.as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| std::env::temp_dir().join("storage-uploads"));
+        Self {
+            pool,
+            versions,
+            staging_dir,
+            expire_secs: config.expire_hours.unwrap_or(24) * 3600,
+            active: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn staging_path(&self, repo_id: &str, upload_id: &Uuid) -> PathBuf {
+        self.staging_dir.join(repo_id).join(upload_id.to_string())
+    }
+
+    fn try_activate(&self, upload_id: &Uuid) -> Option<ActiveUpload<'_>> {
+        if self.active.lock().unwrap().insert(*upload_id) {
+            Some(ActiveUpload { manager: self, id: *upload_id })
+        } else {
+            None
+        }
+    }
+
+    /// Creates an upload of the file at the path by the user. The path is checked now, instead of once all data was received
+    pub async fn create(&self, library: &Library, path: &str, length: u64, metadata: Option<&str>, user_id: &str) -> Result<UploadModel, anyhow::Error> {
+        let rel_path = Path::new(path.trim_start_matches('/'));
+        if rel_path.file_name().is_none() || rel_path.components().any(|component| matches!(component, Component::ParentDir | Component::Prefix(_))) {
+            return Err(StorageError::InvalidPath.into())
+        }
+        check_path(rel_path)?;
+        let upload = models::upload::create_upload(&self.pool, &library.model().id, path, length, metadata, self.expire_secs, user_id).await?;
+        let staging_path = self.staging_path(&library.model().repo_id, &upload.id);
+        tokio::fs::create_dir_all(staging_path.parent().unwrap()).await?;
+        File::create(&staging_path).await?;
+        debug!("created upload {} for library {} path={}", upload.id, library.model().id, path);
+        Ok(upload)
+    }
+
+    /// Returns the upload, if the user created it. Other users can't tell it exists
+    pub async fn get(&self, library: &Library, upload_id: &str, user_id: &str) -> Result<Option<UploadModel>, anyhow::Error> {
+        let Ok(upload_id) = Uuid::parse_str(upload_id) else {
+            return Ok(None)
+        };
+        models::upload::get_upload(&self.pool, &library.model().id, &upload_id, user_id).await
+    }
+
+    /// Appends the data to the upload, which must currently be at `offset`. Once all data has been received,
+    /// the file is committed into the library as written by the user. Returns the upload with its new offset
+    pub async fn append(&self, library: &Library, upload: &UploadModel, offset: u64, data: &mut (dyn AsyncRead + Send + Unpin), user_id: &str) -> Result<UploadModel, ResponseError> {
+        let Some(_active) = self.try_activate(&upload.id) else {
+            return Err(ResponseError::Locked(JsonErrorResponse {
+                code: "UPLOAD_LOCKED".to_string(),
+                message: "Upload is already receiving data from another request".to_string()
+            }))
+        };
+        let staging_path = self.staging_path(&library.model().repo_id, &upload.id);
+        let mut file = OpenOptions::new().append(true).open(&staging_path).await.map_err(staging_error)?;
+        // The staging file is the source of truth, as a previous request could have been interrupted
+        let current_offset = file.metadata().await.map_err(staging_error)?.len();
+        if current_offset != offset {
+            return Err(ResponseError::Conflict(JsonErrorResponse {
+                code: "UPLOAD_OFFSET_MISMATCH".to_string(),
+                message: format!("Upload-Offset does not match the current offset of {}", current_offset)
+            }))
+        }
+        // Keep whatever was received, even if the connection breaks part way, so the client can resume from there
+        let copy_result = tokio::io::copy(data, &mut file).await;
+        file.flush().await.map_err(staging_error)?;
+        let new_offset = file.metadata().await.map_err(staging_error)?.len();
+        if new_offset > upload.upload_length as u64 {
+            // Discard this request's data, so the client can retry with the correct amount
+            file.set_len(offset).await.map_err(staging_error)?;
+            return Err(ResponseError::PayloadTooLarge(JsonErrorResponse {
+                code: "UPLOAD_TOO_LARGE".to_string(),
+                message: "Received more data than the Upload-Length of the upload".to_string()
+            }))
+        }
+        drop(file);
+        let upload = models::upload::set_upload_offset(&self.pool, &upload.id, new_offset, self.expire_secs).await
+            .map_err(database_error)?;
+        if let Err(e) = copy_result {
+            warn!("upload {} interrupted at offset {}: {}", upload.id, new_offset, e);
+            return Err(ResponseError::GenericError)
+        }
+        if new_offset == upload.upload_length as u64 {
+            self.commit(library, &upload, user_id).await?;
+        }
+        Ok(upload)
+    }
+
+    /// Writes the completed upload into the library and removes it
+    async fn commit(&self, library: &Library, upload: &UploadModel, user_id: &str) -> Result<(), ResponseError> {
+        let staging_path = self.staging_path(&library.model().repo_id, &upload.id);
+        let mut file = File::open(&staging_path).await.map_err(staging_error)?;
+        self.versions.write_file(library, &upload.path, &mut file, Some(user_id)).await.map_err(storage_error)?;
+        debug!("upload {} complete, written to {}", upload.id, upload.path);
+        models::upload::delete_upload(&self.pool, &upload.id).await.map_err(database_error)?;
+        tokio::fs::remove_file(&staging_path).await.map_err(staging_error)?;
+        Ok(())
+    }
+
+    /// Commits an upload that already has all of its data, ex. an empty file or when a previous commit failed
+    pub async fn finish(&self, library: &Library, upload: &UploadModel, user_id: &str) -> Result<(), ResponseError> {
+        let Some(_active) = self.try_activate(&upload.id) else {
+            return Err(ResponseError::Locked(JsonErrorResponse {
+                code: "UPLOAD_LOCKED".to_string(),
+                message: "Upload is already receiving data from another request".to_string()
+            }))
+        };
+        self.commit(library, upload, user_id).await
+    }
+
+    /// Cancels the upload, removing any data received
+    pub async fn terminate(&self, library: &Library, upload: &UploadModel) -> Result<(), ResponseError> {
+        let Some(_active) = self.try_activate(&upload.id) else {
+            return Err(ResponseError::Locked(JsonErrorResponse {
+                code: "UPLOAD_LOCKED".to_string(),
+                message: "Upload is currently receiving data".to_string()
+            }))
+        };
+        models::upload::delete_upload(&self.pool, &upload.id).await.map_err(database_error)?;
+        let staging_path = self.staging_path(&library.model().repo_id, &upload.id);
+        if let Err(e) = tokio::fs::remove_file(&staging_path).await {
+            warn!("failed to remove staging file {:?}: {}", staging_path, e);
+        }
+        Ok(())
+    }
+
+    /// Stages the uploaded archive, then extracts it into the folder of the library
+    pub async fn extract(&self, library: &Library, target: &Path, data: &mut (dyn AsyncRead + Send + Unpin), user_id: &str) -> Result<ExtractResult, ResponseError> {
+        // Archives are read from a seekable file, as zip files list their entries at the end
+        let staging_path = self.staging_path(&library.model().repo_id, &Uuid::new_v4());
+        tokio::fs::create_dir_all(staging_path.parent().unwrap()).await.map_err(staging_error)?;
+        let result = async {
+            let mut file = File::create(&staging_path).await.map_err(staging_error)?;
+            tokio::io::copy(data, &mut file).await.map_err(staging_error)?;
+            file.flush().await.map_err(staging_error)?;
+            drop(file);
+            extract_archive(library, &self.versions, target, &staging_path, user_id).await
+        }.await;
+        if let Err(e) = tokio::fs::remove_file(&staging_path).await {
+            warn!("failed to remove staging file {:?}: {}", staging_path, e);
+        }
+        let result = result?;
+        debug!("extracted archive into {:?} of library {}: {} files, {} folders, {} failures",
+            target, library.model().id, result.files, result.folders, result.failures.len());
+        Ok(result)
+    }
+
+    /// Periodically removes expired uploads in the background
+    pub fn start_expiry_task(self: &Arc<Self>) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(UPLOAD_EXPIRY_INTERVAL_SECONDS));
+            loop {
+                interval.tick().await;
+                match manager.remove_expired().await {
+                    Ok(0) => {},
+                    Ok(count) => info!("Removed {} expired uploads", count),
+                    Err(e) => error!("Failed to remove expired uploads: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Removes all uploads that have not received data before their expiry, returning how many were removed
+    pub async fn remove_expired(&self) -> Result<usize, anyhow::Error> {
+        let expired = models::upload::delete_expired_uploads(&self.pool).await?;
+        for upload in &expired {
+            let staging_path = self.staging_path(&upload.repo_id, &upload.id);
+            if let Err(e) = tokio::fs::remove_file(&staging_path).await {
+                warn!("failed to remove staging file {:?}: {}", staging_path, e);
+            }
+        }
+        Ok(expired.len())
+    }
+}
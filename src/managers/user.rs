@@ -2,6 +2,7 @@ use std::hash::{DefaultHasher, Hash, Hasher};
 use std::net::IpAddr;
 use std::sync::Arc;
 use anyhow::anyhow;
+use chrono::Utc;
 use rocket::futures::TryStreamExt;
 use rocket::serde::Serialize;
 use rocket::State;
@@ -10,7 +11,7 @@ use rocket_session_store::memory::MemoryStore;
 use sqlx::{query, query_as, Pool, QueryBuilder};
 use uuid::Uuid;
 use crate::config::AppConfig;
-use crate::consts::{DISABLE_LOGIN_CHECK, ENCRYPTION_ROUNDS};
+use crate::consts::ENCRYPTION_ROUNDS;
 use crate::{LoginSessionData, SessionData, DB};
 use crate::models::user::{UserAuthError, UserModel, UserModelWithPassword};
 
@@ -111,17 +112,24 @@ impl UserManager {
         })
     }
 
-    pub async fn login_user_session(&self, user: UserModel, ip_address: IpAddr, sessions: &Session<'_, SessionData>) {
+    pub async fn login_user_session(&self, user: UserModel, ip_address: IpAddr, user_agent: Option<String>, sessions: &Session<'_, SessionData>) {
+        let now = Utc::now();
         sessions.set(SessionData {
             csrf_token: None,
             login: Some(LoginSessionData {
+                id: Uuid::new_v4(),
                 user,
-                ip_address
+                ip_address,
+                user_agent,
+                created_at: now,
+                last_active: now,
             }),
+            unlocked_share_links: Vec::new(),
+            accessed_share_links: Vec::new(),
         }).await.unwrap();
     }
 
-    pub async fn login_normal_user(&self, email_or_usrname: &str, password: &str, ip: IpAddr, session: &Session<'_, SessionData>) -> Result<UserModel, UserAuthError> {
+    pub async fn login_normal_user(&self, email_or_usrname: &str, password: &str, ip: IpAddr, user_agent: Option<String>, session: &Session<'_, SessionData>) -> Result<UserModel, UserAuthError> {
         let user = query_as!(UserModelWithPassword,
         "select id, username, password, created_at, email, name  from storage.users where email = $1 OR username = $1", email_or_usrname
     )
@@ -132,7 +140,7 @@ impl UserManager {
             return Err(UserAuthError::UserNotFound);
         };
         if let Some(db_password) = user.password {
-            if !*DISABLE_LOGIN_CHECK || bcrypt::verify(password, &db_password).map_err(|e| UserAuthError::EncryptionError(e))? {
+            if bcrypt::verify(password, &db_password).map_err(|e| UserAuthError::EncryptionError(e))? {
                 let model = UserModel {
                     id: user.id,
                     email: user.email,
@@ -140,7 +148,7 @@ impl UserManager {
                     created_at: user.created_at,
                     name: user.name
                 };
-                self.login_user_session(model.clone(), ip, session).await;
+                self.login_user_session(model.clone(), ip, user_agent, session).await;
                 return Ok(model)
             }
         }
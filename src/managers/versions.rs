@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::anyhow;
+use log::{error, info, warn};
+use sqlx::types::Uuid;
+use tokio::io::AsyncRead;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use crate::consts::VERSION_PRUNE_INTERVAL_SECONDS;
+use crate::DB;
+use crate::managers::libraries::LibraryManager;
+use crate::models;
+use crate::models::file_version::{FileVersionModel, FileVersionWithUserModel};
+use crate::objs::library::{check_path, Library, INTERNAL_FOLDER};
+use crate::storage::StorageError;
+use crate::util::{database_error, JsonErrorResponse, ResponseError};
+
+/// Keeps the history of files written through it. Every write is recorded as a version of the path,
+/// the newest version is the file itself and older versions are kept in `<library>/.storage/versions/<version id>`
+pub struct VersionManager {
+    pool: DB,
+    libraries: Arc<Mutex<LibraryManager>>,
+    /// Paths being written, so writes to the same file happen one after another and each keeps the one before it as a version
+    writing: WritingPaths,
+}
+
+/// The lock of each path being written, by library id and path
+type WritingPaths = std::sync::Mutex<HashMap<(Uuid, PathBuf), Arc<Mutex<()>>>>;
+
+/// Holds the path until it is dropped, the next write to it then goes ahead
+struct WriteLock<'a> {
+    writing: &'a WritingPaths,
+    key: (Uuid, PathBuf),
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for WriteLock<'_> {
+    fn drop(&mut self) {
+        let mut writing = self.writing.lock().unwrap();
+        drop(self.guard.take());
+        // Forget the path once no other write is waiting for it
+        if writing.get(&self.key).is_some_and(|lock| Arc::strong_count(lock) == 1) {
+            writing.remove(&self.key);
+        }
+    }
+}
+
+/// Where the contents of a previous version are kept
+pub fn version_path(version_id: &Uuid) -> PathBuf {
+    PathBuf::from(INTERNAL_FOLDER).join("versions").join(version_id.to_string())
+}
+
+impl VersionManager {
+    pub fn new(pool: DB, libraries: Arc<Mutex<LibraryManager>>) -> Self {
+        Self {
+            pool,
+            libraries,
+            writing: Default::default(),
+        }
+    }
+
+    /// Waits for any other write to the path in the library to finish, then holds it until the returned lock is dropped
+    async fn lock_path(&self, library_id: &Uuid, path: &str) -> WriteLock<'_> {
+        let key = (*library_id, PathBuf::from(path));
+        let lock = self.writing.lock().unwrap().entry(key.clone()).or_default().clone();
+        let guard = lock.lock_owned().await;
+        WriteLock { writing: &self.writing, key, guard: Some(guard) }
+    }
+
+    /// Writes the file, keeping its current contents as a previous version. Returns the amount of bytes written
+    pub async fn write_file(&self, library: &Library, path: &str, contents: &mut (dyn AsyncRead + Send + Unpin), user_id: Option<&str>) -> Result<u64, anyhow::Error> {
+        let path = path.trim_start_matches('/');
+        check_path(Path::new(path))?;
+        let _lock = self.lock_path(&library.model().id, path).await;
+        self.write_locked(library, path, contents, user_id).await
+    }
+
+    /// Writes the file while holding its [WriteLock]
+    async fn write_locked(&self, library: &Library, path: &str, contents: &mut (dyn AsyncRead + Send + Unpin), user_id: Option<&str>) -> Result<u64, anyhow::Error> {
+        let rel_path = PathBuf::from(path);
+        let library_id = &library.model().id;
+        let latest = models::file_version::get_latest_file_version(&self.pool, library_id, path).await?;
+        // The version the current contents are moved to, if there are any
+        let mut previous = None;
+        if let Some(metadata) = library.stat_file(&rel_path).await? {
+            let version = match latest {
+                Some(version) => version,
+                // File was written before it had history, so its author is unknown
+                None => models::file_version::create_file_version(&self.pool, &Uuid::new_v4(), library_id, path, metadata.size,
+                    None, metadata.last_modified.map(|date| date.naive_utc())).await?
+            };
+            library.move_contents_internal(&rel_path, &version_path(&version.id)).await?;
+            previous = Some(version);
+        } else if library.exists(&rel_path).await? {
+            // Files can't replace folders
+            return Err(StorageError::AlreadyExists.into())
+        } else if let Some(version) = latest {
+            // The file was moved or deleted, its contents are no longer ours to keep
+            models::file_version::delete_file_version(&self.pool, &version.id).await?;
+        }
+        let size = match library.write_file(&rel_path, contents).await {
+            Ok(size) => size,
+            Err(e) => {
+                // Don't leave a partly written file behind, and put back the contents it replaced
+                if library.exists(&rel_path).await.unwrap_or(false) {
+                    let _ = library.discard_failed_write(&rel_path).await;
+                }
+                if let Some(version) = previous
+                    && let Err(e) = library.move_contents_internal(&version_path(&version.id), &rel_path).await {
+                    error!("Failed to restore {} after failed write: {}", path, e);
+                }
+                return Err(e)
+            }
+        };
+        models::file_version::create_file_version(&self.pool, &Uuid::new_v4(), library_id, path, size, user_id, None).await?;
+        if let Err(e) = self.prune(library, path).await {
+            warn!("Failed to prune versions of {}: {}", path, e);
+        }
+        Ok(size)
+    }
+
+    /// Lists all versions of the file, newest first
+    pub async fn list(&self, library: &Library, path: &str) -> Result<Vec<FileVersionWithUserModel>, anyhow::Error> {
+        let path = path.trim_start_matches('/');
+        let mut versions = models::file_version::get_file_versions(&self.pool, &library.model().id, path).await?;
+        if let Some(latest) = versions.first_mut() {
+            latest.current = library.exists(&PathBuf::from(path)).await?;
+        }
+        Ok(versions)
+    }
+
+    pub async fn get(&self, library: &Library, version_id: &str) -> Result<FileVersionModel, ResponseError> {
+        let version = match Uuid::parse_str(version_id) {
+            Ok(version_id) => models::file_version::get_file_version(&self.pool, &library.model().id, &version_id).await
+                .map_err(database_error)?,
+            Err(_) => None
+        };
+        version.ok_or_else(|| ResponseError::NotFound(JsonErrorResponse {
+            code: "VERSION_NOT_FOUND".to_string(),
+            message: "File version does not exist".to_string()
+        }))
+    }
+
+    /// Returns where the contents of the version are stored, which is the file itself for the current version
+    pub async fn get_contents_path(&self, library: &Library, version: &FileVersionModel) -> Result<PathBuf, anyhow::Error> {
+        let latest = models::file_version::get_latest_file_version(&self.pool, &library.model().id, &version.path).await?;
+        if latest.is_some_and(|latest| latest.id == version.id) {
+            Ok(PathBuf::from(&version.path))
+        } else {
+            Ok(version_path(&version.id))
+        }
+    }
+
+    /// Makes the version the current contents of the file, the replaced contents are kept as a new version
+    pub async fn restore(&self, library: &Library, version: &FileVersionModel, user_id: &str) -> Result<u64, anyhow::Error> {
+        // Held from before finding the contents, so a write in the meantime can't change which version is current
+        let _lock = self.lock_path(&library.model().id, &version.path).await;
+        let contents_path = self.get_contents_path(library, version).await?;
+        if contents_path == Path::new(&version.path) {
+            return Err(anyhow!("Version is already the current version"))
+        }
+        let Some(mut stream) = library.read_internal(&contents_path).await? else {
+            return Err(anyhow!("Version contents are missing"))
+        };
+        self.write_locked(library, &version.path, &mut stream, Some(user_id)).await
+    }
+
+    /// Permanently deletes the previous version
+    async fn delete(&self, library: &Library, version: &FileVersionModel) -> Result<(), anyhow::Error> {
+        let contents_path = version_path(&version.id);
+        if library.exists_internal(&contents_path).await? {
+            library.delete_internal(&contents_path).await?;
+        }
+        models::file_version::delete_file_version(&self.pool, &version.id).await
+    }
+
+    /// Removes previous versions of the file over the library's limits
+    pub async fn prune(&self, library: &Library, path: &str) -> Result<(), anyhow::Error> {
+        let model = library.model();
+        let Some(max_versions) = model.max_versions else {
+            // Age based limits are handled by the background task
+            return Ok(())
+        };
+        let versions = models::file_version::get_file_versions(&self.pool, &model.id, path).await?;
+        // The first version is the current file, which is never pruned
+        for version in versions.iter().skip(1 + max_versions.max(0) as usize) {
+            self.delete(library, &version.version).await?;
+        }
+        Ok(())
+    }
+
+    /// Periodically removes versions older than their library's retention period in the background
+    pub fn start_prune_task(self: &Arc<Self>) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(VERSION_PRUNE_INTERVAL_SECONDS));
+            loop {
+                interval.tick().await;
+                match manager.prune_expired().await {
+                    Ok(0) => {},
+                    Ok(count) => info!("Removed {} expired file versions", count),
+                    Err(e) => error!("Failed to remove expired file versions: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Removes all versions that were replaced longer ago than their library's retention period, returning how many were removed
+    pub async fn prune_expired(&self) -> Result<usize, anyhow::Error> {
+        let expired = models::file_version::get_expired_file_versions(&self.pool).await?;
+        let mut count = 0;
+        for version in &expired {
+            let library = match self.libraries.lock().await.get(&version.library_id.to_string()).await {
+                Ok(library) => library,
+                Err(e) => {
+                    warn!("Could not remove file version {}, library unavailable: {:?}", version.id, e);
+                    continue
+                }
+            };
+            match self.delete(&library, version).await {
+                Ok(()) => count += 1,
+                Err(e) => warn!("Failed to remove file version {}: {}", version.id, e),
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::query;
+    use super::*;
+    use crate::testing::{read_file, read_internal, TestData};
+
+    async fn versions(data: &TestData) -> (VersionManager, Arc<Mutex<LibraryManager>>) {
+        let libraries = Arc::new(Mutex::new(data.libraries(data.trash()).await));
+        (VersionManager::new(data.pool.clone(), libraries.clone()), libraries)
+    }
+
+    async fn write(versions: &VersionManager, library: &Library, contents: &str, user_id: &str) {
+        versions.write_file(library, "/a.txt", &mut contents.as_bytes(), Some(user_id)).await.unwrap();
+    }
+
+    /// The contents of each version of a.txt, newest first
+    async fn history(versions: &VersionManager, library: &Library) -> Vec<String> {
+        let mut history = Vec::new();
+        for version in versions.list(library, "a.txt").await.unwrap() {
+            let path = versions.get_contents_path(library, &version.version).await.unwrap();
+            history.push(read_internal(library, &path).await.unwrap_or_default());
+        }
+        history
+    }
+
+    #[tokio::test]
+    async fn keeps_replaced_contents_as_versions() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let (versions, libraries) = versions(&data).await;
+        let library = data.open(&*libraries.lock().await, &model).await;
+        for contents in ["one", "two", "three"] {
+            write(&versions, &library, contents, &owner.id).await;
+        }
+
+        assert_eq!(history(&versions, &library).await, ["three", "two", "one"]);
+        let list = versions.list(&library, "a.txt").await.unwrap();
+        assert_eq!(list.iter().map(|version| version.current).collect::<Vec<_>>(), [true, false, false]);
+        assert_eq!(list[0].version.created_by.as_deref(), Some(owner.id.as_str()));
+        assert_eq!(list[0].created_by_username.as_deref(), Some(owner.username.as_str()));
+        // Files can't replace folders
+        library.touch_file(&PathBuf::from("folder"), crate::storage::FileType::Folder).await.unwrap();
+        assert!(versions.write_file(&library, "folder", &mut "x".as_bytes(), None).await.is_err());
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn keeps_every_version_of_concurrent_writes() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let (versions, libraries) = versions(&data).await;
+        let library = data.open(&*libraries.lock().await, &model).await;
+        write(&versions, &library, "base", &owner.id).await;
+
+        tokio::join!(write(&versions, &library, "a", &owner.id), write(&versions, &library, "b", &owner.id));
+        let mut history = history(&versions, &library).await;
+        assert_eq!(history.pop().as_deref(), Some("base"));
+        history.sort();
+        assert_eq!(history, ["a", "b"]);
+        assert!(versions.writing.lock().unwrap().is_empty());
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn restores_versions() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let (versions, libraries) = versions(&data).await;
+        let library = data.open(&*libraries.lock().await, &model).await;
+        write(&versions, &library, "one", &owner.id).await;
+        write(&versions, &library, "two", &owner.id).await;
+        let list = versions.list(&library, "a.txt").await.unwrap();
+        let (current, first) = (&list[0].version, &list[1].version);
+
+        assert!(versions.restore(&library, current, &owner.id).await.is_err());
+        assert_eq!(versions.restore(&library, first, &owner.id).await.unwrap(), 3);
+        assert_eq!(read_file(&library, "a.txt").await.as_deref(), Some("one"));
+        // Restoring keeps the contents it replaced, so it can be undone
+        assert_eq!(history(&versions, &library).await, ["one", "two", "one"]);
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn prunes_versions_over_the_limit() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        models::library::set_library_version_limits(&data.pool, &model.id, Some(2), None).await.unwrap();
+        let (versions, libraries) = versions(&data).await;
+        let library = data.open(&*libraries.lock().await, &model).await;
+        for contents in ["one", "two", "three", "four", "five"] {
+            write(&versions, &library, contents, &owner.id).await;
+        }
+
+        // The current contents don't count towards the limit
+        assert_eq!(history(&versions, &library).await, ["five", "four", "three"]);
+        let remaining = versions.list(&library, "a.txt").await.unwrap().len();
+        // Along with their contents
+        let stored = std::fs::read_dir(data.root.join(model.id.to_string()).join(INTERNAL_FOLDER).join("versions")).unwrap().count();
+        assert_eq!(stored, remaining - 1);
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn prunes_versions_past_the_retention_period() {
+        let mut data = TestData::new().await;
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        models::library::set_library_version_limits(&data.pool, &model.id, None, Some(7)).await.unwrap();
+        let (versions, libraries) = versions(&data).await;
+        let library = data.open(&*libraries.lock().await, &model).await;
+        for contents in ["one", "two", "three"] {
+            write(&versions, &library, contents, &owner.id).await;
+        }
+        let list = versions.list(&library, "a.txt").await.unwrap();
+        // "one" was replaced 8 days ago and "two" 6 days ago
+        for (version, days) in [(&list[2], 10), (&list[1], 8), (&list[0], 6)] {
+            query!("update storage.file_versions set created_at = now() - make_interval(days => $2) where id = $1", version.version.id, days)
+                .execute(&data.pool)
+                .await.unwrap();
+        }
+
+        assert!(versions.prune_expired().await.unwrap() >= 1);
+        assert_eq!(history(&versions, &library).await, ["three", "two"]);
+        assert!(!library.exists_internal(&version_path(&list[2].version.id)).await.unwrap());
+        data.cleanup().await;
+    }
+}
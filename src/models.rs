@@ -1,3 +1,12 @@
 pub mod repo;
 pub mod user;
-pub mod library;
\ No newline at end of file
+pub mod library;
+pub mod upload;
+pub mod api_token;
+pub mod session;
+pub mod trash;pub mod file_version;
+pub mod file_index;
+pub mod share_link;
+pub mod favorite;
+pub mod activity;
+pub mod notification;
@@ -0,0 +1,168 @@
+use anyhow::anyhow;
+use chrono::NaiveDateTime;
+use int_enum::IntEnum;
+use rocket::FromFormField;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+use crate::models::file_index::escape_like;
+
+/// What changed in a library
+#[repr(i16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, IntEnum, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityAction {
+    /// A file was written, replacing it if it existed
+    #[field(value = "upload")]
+    Upload = 0,
+    /// An empty file or folder was created
+    #[field(value = "create")]
+    Create = 1,
+    /// Moved to the trash, or deleted right away
+    #[field(value = "delete")]
+    Delete = 2,
+    /// Moved or renamed, the detail is the new path
+    #[field(value = "move")]
+    Move = 3,
+    /// Copied here, the detail is the library and path it was copied from
+    #[field(value = "copy")]
+    Copy = 4,
+    /// Restored from the trash
+    #[field(value = "restore")]
+    Restore = 5,
+    /// The library was shared with a user, the detail is their username and permission
+    #[field(value = "share")]
+    Share = 6,
+    /// A user's access to the library was removed, the detail is their username
+    #[field(value = "unshare")]
+    Unshare = 7,
+    /// A public share link to the path was created, the detail is its mode
+    #[field(value = "create_link")]
+    CreateLink = 8,
+    /// A public share link to the path was revoked
+    #[field(value = "revoke_link")]
+    RevokeLink = 9,
+}
+
+impl ActivityAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActivityAction::Upload => "upload",
+            ActivityAction::Create => "create",
+            ActivityAction::Delete => "delete",
+            ActivityAction::Move => "move",
+            ActivityAction::Copy => "copy",
+            ActivityAction::Restore => "restore",
+            ActivityAction::Share => "share",
+            ActivityAction::Unshare => "unshare",
+            ActivityAction::CreateLink => "create_link",
+            ActivityAction::RevokeLink => "revoke_link",
+        }
+    }
+}
+
+/// A change made to a library, by whom and from where. Changes made through public share links have no user
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActivityModel {
+    pub id: Uuid,
+    pub library_id: Uuid,
+    pub library_name: String,
+    pub user_id: Option<String>,
+    pub username: Option<String>,
+    pub action: ActivityAction,
+    pub path: String,
+    pub detail: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+struct ActivityRow {
+    id: Uuid,
+    library_id: Uuid,
+    library_name: String,
+    user_id: Option<String>,
+    username: Option<String>,
+    action: i16,
+    path: String,
+    detail: Option<String>,
+    ip: Option<String>,
+    created_at: NaiveDateTime,
+}
+
+impl TryFrom<ActivityRow> for ActivityModel {
+    type Error = anyhow::Error;
+
+    fn try_from(row: ActivityRow) -> Result<Self, Self::Error> {
+        Ok(ActivityModel {
+            action: ActivityAction::try_from(row.action).map_err(|_| anyhow!("Invalid activity action {}", row.action))?,
+            id: row.id,
+            library_id: row.library_id,
+            library_name: row.library_name,
+            user_id: row.user_id,
+            username: row.username,
+            path: row.path,
+            detail: row.detail,
+            ip: row.ip,
+            created_at: row.created_at,
+        })
+    }
+}
+
+pub struct NewActivity<'a> {
+    pub library_id: &'a Uuid,
+    pub user_id: Option<&'a str>,
+    pub action: ActivityAction,
+    pub path: &'a str,
+    pub detail: Option<&'a str>,
+    pub ip: Option<&'a str>,
+}
+
+pub async fn create_activity(pool: &DB, activity: &NewActivity<'_>) -> Result<(), anyhow::Error> {
+    query!(
+        "insert into storage.activity (id, library_id, user_id, action, path, detail, ip) values ($1, $2, $3, $4, $5, $6, $7)",
+        Uuid::new_v4(), activity.library_id, activity.user_id, i16::from(activity.action), activity.path, activity.detail, activity.ip
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Which activity to return, None for a filter that isn't used
+pub struct ActivityFilter<'a> {
+    pub library_ids: &'a [Uuid],
+    pub action: Option<ActivityAction>,
+    pub user_id: Option<&'a str>,
+    /// Part of the path
+    pub path: Option<&'a str>,
+    pub after: Option<NaiveDateTime>,
+    pub before: Option<NaiveDateTime>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// Returns the activity in the libraries matching the filter, newest first
+pub async fn get_activity(pool: &DB, filter: &ActivityFilter<'_>) -> Result<Vec<ActivityModel>, anyhow::Error> {
+    query_as!(ActivityRow,
+        "select a.id, a.library_id, l.name as library_name, a.user_id, u.username as \"username?\", a.action, a.path, a.detail, a.ip, a.created_at \
+        from storage.activity a \
+        join storage.libraries l on l.id = a.library_id \
+        left join storage.users u on u.id = a.user_id \
+        where a.library_id = any($1) \
+        and ($2::smallint is null or a.action = $2) \
+        and ($3::text is null or a.user_id = $3) \
+        and ($4::text is null or a.path ilike $4) \
+        and ($5::timestamp is null or a.created_at >= $5) \
+        and ($6::timestamp is null or a.created_at < $6) \
+        order by a.created_at desc \
+        limit $7 offset $8",
+        filter.library_ids, filter.action.map(i16::from), filter.user_id,
+        filter.path.map(|path| format!("%{}%", escape_like(path))), filter.after, filter.before,
+        filter.limit, filter.offset
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?
+        .into_iter()
+        .map(ActivityModel::try_from)
+        .collect()
+}
@@ -0,0 +1,140 @@
+use anyhow::anyhow;
+use chrono::NaiveDateTime;
+use int_enum::IntEnum;
+use rocket::FromFormField;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+use crate::models::library::PermissionLevel;
+
+/// What an API token is allowed to do in the libraries it can access
+#[repr(i16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, IntEnum, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiTokenScope {
+    /// Can list and download files
+    #[field(value = "read")]
+    Read = 0,
+    /// Can also upload, move and delete files
+    #[field(value = "read_write")]
+    ReadWrite = 1,
+}
+
+impl ApiTokenScope {
+    /// The highest permission level the token can act with, regardless of the user's own permission
+    pub fn max_permission(&self) -> PermissionLevel {
+        match self {
+            ApiTokenScope::Read => PermissionLevel::ReadOnly,
+            ApiTokenScope::ReadWrite => PermissionLevel::ReadWrite,
+        }
+    }
+}
+
+/// A personal API token, the token itself is only stored as a sha256 hash
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiTokenModel {
+    pub id: Uuid,
+    pub user_id: String,
+    pub name: String,
+    /// Restricts the token to a single library
+    pub library_id: Option<Uuid>,
+    pub scope: ApiTokenScope,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTokenWithLibraryModel {
+    pub token: ApiTokenModel,
+    pub library_name: Option<String>,
+}
+
+struct ApiTokenRow {
+    id: Uuid,
+    user_id: String,
+    name: String,
+    library_id: Option<Uuid>,
+    scope: i16,
+    created_at: NaiveDateTime,
+    expires_at: Option<NaiveDateTime>,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+impl TryFrom<ApiTokenRow> for ApiTokenModel {
+    type Error = anyhow::Error;
+
+    fn try_from(row: ApiTokenRow) -> Result<Self, Self::Error> {
+        Ok(ApiTokenModel {
+            scope: ApiTokenScope::try_from(row.scope).map_err(|_| anyhow!("Invalid token scope {}", row.scope))?,
+            id: row.id,
+            user_id: row.user_id,
+            name: row.name,
+            library_id: row.library_id,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            last_used_at: row.last_used_at,
+        })
+    }
+}
+
+pub async fn create_api_token(pool: &DB, user_id: &str, name: &str, token_hash: &str, library_id: Option<&Uuid>, scope: ApiTokenScope, expires_at: Option<NaiveDateTime>) -> Result<ApiTokenModel, anyhow::Error> {
+    query_as!(ApiTokenRow,
+        "insert into storage.api_tokens (id, user_id, name, token_hash, library_id, scope, expires_at) \
+        values ($1, $2, $3, $4, $5, $6, $7) \
+        returning id, user_id, name, library_id, scope, created_at, expires_at, last_used_at",
+        Uuid::new_v4(), user_id, name, token_hash, library_id, i16::from(scope), expires_at
+    )
+        .fetch_one(pool)
+        .await.map_err(anyhow::Error::from)?
+        .try_into()
+}
+
+/// Returns the token with the hash if it has not expired, marking it as used
+pub async fn use_api_token(pool: &DB, token_hash: &str) -> Result<Option<ApiTokenModel>, anyhow::Error> {
+    query_as!(ApiTokenRow,
+        "update storage.api_tokens set last_used_at = now() \
+        where token_hash = $1 and (expires_at is null or expires_at > now()) \
+        returning id, user_id, name, library_id, scope, created_at, expires_at, last_used_at",
+        token_hash
+    )
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)?
+        .map(ApiTokenModel::try_from)
+        .transpose()
+}
+
+pub async fn get_user_api_tokens(pool: &DB, user_id: &str) -> Result<Vec<ApiTokenWithLibraryModel>, anyhow::Error> {
+    let rows = query!(
+        "select t.id, t.user_id, t.name, t.library_id, t.scope, t.created_at, t.expires_at, t.last_used_at, l.name as \"library_name?\" \
+        from storage.api_tokens t left join storage.libraries l on l.id = t.library_id \
+        where t.user_id = $1 order by t.created_at desc",
+        user_id
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?;
+    rows.into_iter()
+        .map(|row| Ok(ApiTokenWithLibraryModel {
+            token: ApiTokenRow {
+                id: row.id,
+                user_id: row.user_id,
+                name: row.name,
+                library_id: row.library_id,
+                scope: row.scope,
+                created_at: row.created_at,
+                expires_at: row.expires_at,
+                last_used_at: row.last_used_at,
+            }.try_into()?,
+            library_name: row.library_name,
+        }))
+        .collect()
+}
+
+/// Deletes the user's token, returns false if they have no token with the id
+pub async fn delete_api_token(pool: &DB, user_id: &str, token_id: &Uuid) -> Result<bool, anyhow::Error> {
+    let result = query!("delete from storage.api_tokens where id = $1 and user_id = $2", token_id, user_id)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected() > 0)
+}
@@ -0,0 +1,89 @@
+use chrono::NaiveDateTime;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+use crate::models::file_index::escape_like;
+
+/// A file, folder or library a user starred. The path is empty for the library itself
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FavoriteModel {
+    pub library_id: Uuid,
+    pub library_name: String,
+    pub path: String,
+    /// From the search index, None if the file is not indexed yet
+    pub is_folder: Option<bool>,
+    pub size: Option<i64>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Stars the file, folder or library for the user, doing nothing if it already is
+pub async fn add_favorite(pool: &DB, user_id: &str, library_id: &Uuid, path: &str) -> Result<(), anyhow::Error> {
+    query!(
+        "insert into storage.favorites (id, user_id, library_id, path) values ($1, $2, $3, $4) \
+        on conflict (user_id, library_id, path) do nothing",
+        Uuid::new_v4(), user_id, library_id, path
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Removes the user's star, returns false if it wasn't starred
+pub async fn delete_favorite(pool: &DB, user_id: &str, library_id: &Uuid, path: &str) -> Result<bool, anyhow::Error> {
+    let result = query!("delete from storage.favorites where user_id = $1 and library_id = $2 and path = $3", user_id, library_id, path)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns the user's favorites in the libraries they can still access, libraries first and then by path
+pub async fn get_user_favorites(pool: &DB, user_id: &str) -> Result<Vec<FavoriteModel>, anyhow::Error> {
+    query_as!(FavoriteModel,
+        "select f.library_id, l.name as library_name, f.path, i.is_folder as \"is_folder?\", i.size, f.created_at \
+        from storage.favorites f \
+        join storage.libraries l on l.id = f.library_id \
+        left join storage.file_index i on i.library_id = f.library_id and i.path = f.path \
+        where f.user_id = $1 \
+        and (l.owner_id = $1 or exists (select 1 from storage.library_permissions p where p.library_id = l.id and p.user_id = $1)) \
+        order by f.path <> '', l.name, f.path",
+        user_id
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Returns the paths the user starred in the folder and its subfolders, for the library root any starred path
+pub async fn get_favorited_paths(pool: &DB, user_id: &str, library_id: &Uuid, folder: &str) -> Result<Vec<String>, anyhow::Error> {
+    let pattern = if folder.is_empty() { "_%".to_string() } else { format!("{}/%", escape_like(folder)) };
+    query!(
+        "select path from storage.favorites where user_id = $1 and library_id = $2 and path like $3",
+        user_id, library_id, pattern
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+        .map(|rows| rows.into_iter().map(|row| row.path).collect())
+}
+
+/// Moves every user's stars on the file or folder, and on anything in the folder, to the new path
+pub async fn move_favorites(pool: &DB, library_id: &Uuid, from: &str, to: &str) -> Result<(), anyhow::Error> {
+    query!(
+        "update storage.favorites set path = $3 || substr(path, length($2) + 1) \
+        where library_id = $1 and (path = $2 or path like $4)",
+        library_id, from, to, format!("{}/%", escape_like(from))
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Removes every user's stars on the file or folder, and on anything in the folder
+pub async fn delete_favorites(pool: &DB, library_id: &Uuid, path: &str) -> Result<(), anyhow::Error> {
+    query!(
+        "delete from storage.favorites where library_id = $1 and (path = $2 or path like $3)",
+        library_id, path, format!("{}/%", escape_like(path))
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
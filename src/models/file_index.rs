@@ -0,0 +1,151 @@
+use chrono::NaiveDateTime;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+
+/// A file or folder in the search index, with what is needed to tell if it changed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileIndexModel {
+    pub library_id: Uuid,
+    pub path: String,
+    pub name: String,
+    pub is_folder: bool,
+    pub size: Option<i64>,
+    pub content_type: Option<String>,
+    pub modified_at: Option<NaiveDateTime>,
+}
+
+/// A file or folder matching a search
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchResultModel {
+    pub library_id: Uuid,
+    pub library_name: String,
+    pub path: String,
+    pub name: String,
+    pub is_folder: bool,
+    /// Size of the file, not known for folders
+    pub size: Option<i64>,
+    pub content_type: Option<String>,
+    pub modified_at: Option<NaiveDateTime>,
+    /// Did the query match the contents of the file, rather than its path
+    pub content_match: bool,
+}
+
+/// Which indexed files match a search, None for a filter that isn't used
+pub struct SearchFilter<'a> {
+    pub library_ids: &'a [Uuid],
+    pub query: &'a str,
+    pub is_folder: Option<bool>,
+    /// The start of the content type, ex. `image/`
+    pub content_type: Option<&'a str>,
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+    pub modified_after: Option<NaiveDateTime>,
+    pub modified_before: Option<NaiveDateTime>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+/// Escapes the LIKE wildcards in the value
+pub(crate) fn escape_like(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Adds the file or folder to the index, or updates it. With contents, they are indexed as text
+pub async fn upsert_file_index(pool: &DB, entry: &FileIndexModel, contents: Option<&str>) -> Result<(), anyhow::Error> {
+    query!(
+        "insert into storage.file_index (library_id, path, name, is_folder, size, content_type, modified_at, contents) \
+        values ($1, $2, $3, $4, $5, $6, $7, to_tsvector('simple', $8)) \
+        on conflict (library_id, path) do update set name = excluded.name, is_folder = excluded.is_folder, size = excluded.size, \
+        content_type = excluded.content_type, modified_at = excluded.modified_at, contents = excluded.contents, indexed_at = now()",
+        entry.library_id, entry.path, entry.name, entry.is_folder, entry.size, entry.content_type, entry.modified_at, contents
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Adds the folders to the index, if they aren't already
+pub async fn add_folders_to_index(pool: &DB, library_id: &Uuid, paths: &[String], names: &[String]) -> Result<(), anyhow::Error> {
+    query!(
+        "insert into storage.file_index (library_id, path, name, is_folder) \
+        select $1, path, name, true from unnest($2::text[], $3::text[]) as folders(path, name) \
+        on conflict (library_id, path) do nothing",
+        library_id, paths, names
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Returns everything indexed in the library
+pub async fn get_library_file_index(pool: &DB, library_id: &Uuid) -> Result<Vec<FileIndexModel>, anyhow::Error> {
+    query_as!(FileIndexModel,
+        "select library_id, path, name, is_folder, size, content_type, modified_at from storage.file_index where library_id = $1",
+        library_id
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Removes the file or folder, and everything in it, from the index
+pub async fn delete_file_index(pool: &DB, library_id: &Uuid, path: &str) -> Result<(), anyhow::Error> {
+    query!(
+        "delete from storage.file_index where library_id = $1 and (path = $2 or path like $3)",
+        library_id, path, format!("{}/%", escape_like(path))
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Removes the paths from the index
+pub async fn delete_file_index_paths(pool: &DB, library_id: &Uuid, paths: &[String]) -> Result<(), anyhow::Error> {
+    query!("delete from storage.file_index where library_id = $1 and path = any($2)", library_id, paths)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Changes the path of the file or folder, and everything in it, after it was moved
+pub async fn move_file_index(pool: &DB, library_id: &Uuid, from: &str, to: &str, name: &str) -> Result<(), anyhow::Error> {
+    query!(
+        "update storage.file_index set path = $3 || substr(path, length($2) + 1), \
+        name = case when path = $2 then $4 else name end \
+        where library_id = $1 and (path = $2 or path like $5)",
+        library_id, from, to, name, format!("{}/%", escape_like(from))
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Finds the files and folders whose path contains the query, or whose contents match its words.
+/// Name matches come first, then the best content matches
+pub async fn search_file_index(pool: &DB, filter: &SearchFilter<'_>) -> Result<Vec<SearchResultModel>, anyhow::Error> {
+    query_as!(SearchResultModel,
+        "select f.library_id, l.name as library_name, f.path, f.name, f.is_folder, f.size, f.content_type, f.modified_at, \
+        coalesce(f.contents @@ websearch_to_tsquery('simple', $2), false) as \"content_match!\" \
+        from storage.file_index f \
+        join storage.libraries l on l.id = f.library_id \
+        where f.library_id = any($1) \
+        and (f.path ilike $3 or f.contents @@ websearch_to_tsquery('simple', $2)) \
+        and ($4::boolean is null or f.is_folder = $4) \
+        and ($5::text is null or f.content_type like $5) \
+        and ($6::bigint is null or f.size >= $6) \
+        and ($7::bigint is null or f.size <= $7) \
+        and ($8::timestamp is null or f.modified_at >= $8) \
+        and ($9::timestamp is null or f.modified_at < $9) \
+        order by f.name ilike $3 desc, ts_rank(f.contents, websearch_to_tsquery('simple', $2)) desc, f.path \
+        limit $10 offset $11",
+        filter.library_ids, filter.query, format!("%{}%", escape_like(filter.query)),
+        filter.is_folder, filter.content_type.map(|prefix| format!("{}%", escape_like(prefix))),
+        filter.min_size, filter.max_size, filter.modified_after, filter.modified_before,
+        filter.limit, filter.offset
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
@@ -0,0 +1,98 @@
+use chrono::NaiveDateTime;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+
+/// A revision of a file, the newest revision of a path is the file's current contents
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileVersionModel {
+    pub id: Uuid,
+    pub library_id: Uuid,
+    pub path: String,
+    pub size: i64,
+    /// The user that wrote this revision, None if unknown
+    pub created_by: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// A file version with the username of who wrote it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileVersionWithUserModel {
+    pub version: FileVersionModel,
+    pub created_by_username: Option<String>,
+    /// Is this the file's current contents
+    pub current: bool,
+}
+
+pub async fn create_file_version(pool: &DB, id: &Uuid, library_id: &Uuid, path: &str, size: u64, created_by: Option<&str>, created_at: Option<NaiveDateTime>) -> Result<FileVersionModel, anyhow::Error> {
+    query_as!(FileVersionModel,
+        "insert into storage.file_versions (id, library_id, path, size, created_by, created_at) \
+        values ($1, $2, $3, $4, $5, coalesce($6::timestamp, now())) returning *",
+        id, library_id, path, size as i64, created_by, created_at
+    )
+        .fetch_one(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+pub async fn get_file_version(pool: &DB, library_id: &Uuid, id: &Uuid) -> Result<Option<FileVersionModel>, anyhow::Error> {
+    query_as!(FileVersionModel, "select * from storage.file_versions where id = $1 and library_id = $2", id, library_id)
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Returns the newest revision of the file, if it has any
+pub async fn get_latest_file_version(pool: &DB, library_id: &Uuid, path: &str) -> Result<Option<FileVersionModel>, anyhow::Error> {
+    query_as!(FileVersionModel,
+        "select * from storage.file_versions where library_id = $1 and path = $2 order by created_at desc limit 1",
+        library_id, path
+    )
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Returns all revisions of the file, newest first. [FileVersionWithUserModel::current] is not set
+pub async fn get_file_versions(pool: &DB, library_id: &Uuid, path: &str) -> Result<Vec<FileVersionWithUserModel>, anyhow::Error> {
+    let rows = query!(
+        "select v.*, u.username as \"created_by_username?\" from storage.file_versions v \
+        left join storage.users u on u.id = v.created_by \
+        where v.library_id = $1 and v.path = $2 order by v.created_at desc",
+        library_id, path
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(rows.into_iter()
+        .map(|row| FileVersionWithUserModel {
+            version: FileVersionModel {
+                id: row.id,
+                library_id: row.library_id,
+                path: row.path,
+                size: row.size,
+                created_by: row.created_by,
+                created_at: row.created_at,
+            },
+            created_by_username: row.created_by_username,
+            current: false,
+        })
+        .collect())
+}
+
+/// Returns previous versions that were replaced longer ago than their library's retention, from every library
+pub async fn get_expired_file_versions(pool: &DB) -> Result<Vec<FileVersionModel>, anyhow::Error> {
+    query_as!(FileVersionModel,
+        "select v.* from storage.file_versions v \
+        join storage.libraries l on l.id = v.library_id \
+        where l.version_retention_days is not null \
+        and exists (select 1 from storage.file_versions n where n.library_id = v.library_id and n.path = v.path \
+            and n.created_at > v.created_at and n.created_at <= now() - make_interval(days => l.version_retention_days))"
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+pub async fn delete_file_version(pool: &DB, id: &Uuid) -> Result<(), anyhow::Error> {
+    query!("delete from storage.file_versions where id = $1", id)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
@@ -1,9 +1,11 @@
 use std::str::FromStr;
 use anyhow::anyhow;
 use chrono::NaiveDateTime;
+use int_enum::IntEnum;
+use rocket::FromFormField;
 use rocket::serde::{Serialize, Deserialize};
 use rocket::time::Date;
-use sqlx::{query_as};
+use sqlx::{query, query_as};
 use sqlx::types::{Uuid};
 use crate::{models, DB};
 use crate::library::Library;
@@ -11,13 +13,17 @@ use crate::models::repo::RepoModel;
 use crate::objs::repo::{Repo};
 use crate::user::User;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LibraryModel {
     pub id: Uuid,
     pub owner_id: String,
     pub repo_id: String,
     pub created_at: NaiveDateTime,
     pub name: String,
+    /// How many previous versions of a file are kept, None to keep all
+    pub max_versions: Option<i32>,
+    /// How many days previous versions of a file are kept after being replaced, None to keep them forever
+    pub version_retention_days: Option<i32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -26,12 +32,51 @@ pub struct LibraryWithRepoModel {
     pub storage_type: String,
 }
 
+/// The access a user has to a library, the owner of a library always has [PermissionLevel::Admin]
+#[repr(i16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, IntEnum, FromFormField)]
+#[serde(rename_all = "snake_case")]
 pub enum PermissionLevel {
+    /// Can list and download files
+    #[field(value = "read_only")]
     ReadOnly = 0,
+    /// Can also upload, move and delete files
+    #[field(value = "read_write")]
     ReadWrite = 1,
+    /// Can also manage who the library is shared with
+    #[field(value = "admin")]
     Admin = 2
 }
 
+impl PermissionLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PermissionLevel::ReadOnly => "read_only",
+            PermissionLevel::ReadWrite => "read_write",
+            PermissionLevel::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LibraryPermissionModel {
+    pub library_id: Uuid,
+    pub user_id: String,
+    pub level: i16,
+    pub created_at: NaiveDateTime,
+}
+
+/// A user a library is shared with
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LibraryShareModel {
+    pub user_id: String,
+    pub username: String,
+    pub name: Option<String>,
+    pub email: String,
+    pub level: PermissionLevel,
+    pub created_at: NaiveDateTime,
+}
+
 pub async fn get_library(pool: &DB, library_id: &str) -> Result<Option<LibraryModel>, anyhow::Error> {
     let library_id = Uuid::from_str(library_id)?;
     let library = query_as!(LibraryModel, "select * from storage.libraries where id = $1", library_id)
@@ -51,4 +96,87 @@ pub async fn get_library_with_repo(pool: &DB, library_id: &str) -> Result<Option
         storage_type: repo.storage_type,
         library: library
     }))
-}
\ No newline at end of file
+}
+
+/// Returns all libraries the user owns or has been shared
+pub async fn get_user_libraries(pool: &DB, user_id: &str) -> Result<Vec<LibraryModel>, anyhow::Error> {
+    query_as!(LibraryModel,
+        "select l.* from storage.libraries l \
+        where l.owner_id = $1 \
+        or exists (select 1 from storage.library_permissions p where p.library_id = l.id and p.user_id = $1) \
+        order by l.name",
+        user_id
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Returns every library, of all users
+pub async fn get_all_libraries(pool: &DB) -> Result<Vec<LibraryModel>, anyhow::Error> {
+    query_as!(LibraryModel, "select * from storage.libraries order by created_at")
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+pub async fn set_library_version_limits(pool: &DB, library_id: &Uuid, max_versions: Option<i32>, retention_days: Option<i32>) -> Result<(), anyhow::Error> {
+    query!("update storage.libraries set max_versions = $2, version_retention_days = $3 where id = $1",
+        library_id, max_versions, retention_days
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Returns the permission the user was granted for the library, this does not account for the owner
+pub async fn get_library_permission(pool: &DB, library_id: &Uuid, user_id: &str) -> Result<Option<PermissionLevel>, anyhow::Error> {
+    let permission = query_as!(LibraryPermissionModel,
+        "select * from storage.library_permissions where library_id = $1 and user_id = $2",
+        library_id, user_id
+    )
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)?;
+    permission.map(|permission| PermissionLevel::try_from(permission.level)
+        .map_err(|_| anyhow!("Invalid permission level {}", permission.level)))
+        .transpose()
+}
+
+pub async fn get_library_shares(pool: &DB, library_id: &Uuid) -> Result<Vec<LibraryShareModel>, anyhow::Error> {
+    let rows = query!(
+        "select p.user_id, u.username, u.name, u.email, p.level, p.created_at from storage.library_permissions p \
+        join storage.users u on u.id = p.user_id \
+        where p.library_id = $1 order by u.username",
+        library_id
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?;
+    rows.into_iter()
+        .map(|row| Ok(LibraryShareModel {
+            level: PermissionLevel::try_from(row.level).map_err(|_| anyhow!("Invalid permission level {}", row.level))?,
+            user_id: row.user_id,
+            username: row.username,
+            name: row.name,
+            email: row.email,
+            created_at: row.created_at,
+        }))
+        .collect()
+}
+
+/// Grants the user access to the library, replacing any existing permission
+pub async fn set_library_permission(pool: &DB, library_id: &Uuid, user_id: &str, level: PermissionLevel) -> Result<(), anyhow::Error> {
+    query!(
+        "insert into storage.library_permissions (library_id, user_id, level) values ($1, $2, $3) \
+        on conflict (library_id, user_id) do update set level = excluded.level",
+        library_id, user_id, i16::from(level)
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Removes the user's access to the library, returns false if the library was not shared with them
+pub async fn remove_library_permission(pool: &DB, library_id: &Uuid, user_id: &str) -> Result<bool, anyhow::Error> {
+    let result = query!("delete from storage.library_permissions where library_id = $1 and user_id = $2", library_id, user_id)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected() > 0)
+}
@@ -0,0 +1,168 @@
+use anyhow::anyhow;
+use chrono::NaiveDateTime;
+use int_enum::IntEnum;
+use rocket::FromFormField;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+
+/// What a user is notified about
+#[repr(i16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, IntEnum, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// A library was shared with the user, the detail is who shared it and the permission
+    #[field(value = "library_shared")]
+    LibraryShared = 0,
+    /// A file was uploaded through an upload link to the user's library, the detail is the uploader's name if the link asked
+    #[field(value = "drop_upload")]
+    DropUpload = 1,
+    /// A share link the user created was opened or downloaded from
+    #[field(value = "link_accessed")]
+    LinkAccessed = 2,
+}
+
+impl NotificationKind {
+    pub const ALL: [NotificationKind; 3] = [NotificationKind::LibraryShared, NotificationKind::DropUpload, NotificationKind::LinkAccessed];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::LibraryShared => "library_shared",
+            NotificationKind::DropUpload => "drop_upload",
+            NotificationKind::LinkAccessed => "link_accessed",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NotificationModel {
+    pub id: Uuid,
+    pub kind: NotificationKind,
+    pub library_id: Uuid,
+    pub library_name: String,
+    pub path: String,
+    pub detail: Option<String>,
+    pub read_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+struct NotificationRow {
+    id: Uuid,
+    kind: i16,
+    library_id: Uuid,
+    library_name: String,
+    path: String,
+    detail: Option<String>,
+    read_at: Option<NaiveDateTime>,
+    created_at: NaiveDateTime,
+}
+
+impl TryFrom<NotificationRow> for NotificationModel {
+    type Error = anyhow::Error;
+
+    fn try_from(row: NotificationRow) -> Result<Self, Self::Error> {
+        Ok(NotificationModel {
+            kind: NotificationKind::try_from(row.kind).map_err(|_| anyhow!("Invalid notification kind {}", row.kind))?,
+            id: row.id,
+            library_id: row.library_id,
+            library_name: row.library_name,
+            path: row.path,
+            detail: row.detail,
+            read_at: row.read_at,
+            created_at: row.created_at,
+        })
+    }
+}
+
+/// Notifies the user, unless they turned off notifications of the kind. With `collapse`, nothing is added
+/// if the user has an unread notification of the kind for the same path, returns whether one was added
+pub async fn create_notification(pool: &DB, user_id: &str, kind: NotificationKind, library_id: &Uuid, path: &str, detail: Option<&str>, collapse: bool)
+    -> Result<bool, anyhow::Error>
+{
+    let result = query!(
+        "insert into storage.notifications (id, user_id, kind, library_id, path, detail) \
+        select $1::uuid, $2::varchar, $3::smallint, $4::uuid, $5::text, $6::text \
+        where not exists (select 1 from storage.notification_preferences where user_id = $2 and kind = $3 and not enabled) \
+        and not ($7::boolean and exists (select 1 from storage.notifications \
+            where user_id = $2 and kind = $3 and library_id = $4 and path = $5 and read_at is null))",
+        Uuid::new_v4(), user_id, i16::from(kind), library_id, path, detail, collapse
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Returns the user's notifications, newest first. Only those of the library when one is given
+pub async fn get_user_notifications(pool: &DB, user_id: &str, library_id: Option<&Uuid>, unread_only: bool, limit: i64, offset: i64) -> Result<Vec<NotificationModel>, anyhow::Error> {
+    query_as!(NotificationRow,
+        "select n.id, n.kind, n.library_id, l.name as library_name, n.path, n.detail, n.read_at, n.created_at \
+        from storage.notifications n join storage.libraries l on l.id = n.library_id \
+        where n.user_id = $1 and ($2::uuid is null or n.library_id = $2) and (not $3 or n.read_at is null) \
+        order by n.created_at desc limit $4 offset $5",
+        user_id, library_id, unread_only, limit, offset
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?
+        .into_iter()
+        .map(NotificationModel::try_from)
+        .collect()
+}
+
+pub async fn count_unread_notifications(pool: &DB, user_id: &str, library_id: Option<&Uuid>) -> Result<i64, anyhow::Error> {
+    let row = query!(
+        "select count(*) as \"count!\" from storage.notifications \
+        where user_id = $1 and ($2::uuid is null or library_id = $2) and read_at is null",
+        user_id, library_id
+    )
+        .fetch_one(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(row.count)
+}
+
+/// Marks the user's notification as read, returns false if they have no notification with the id (in the library when one is given)
+pub async fn mark_notification_read(pool: &DB, user_id: &str, library_id: Option<&Uuid>, id: &Uuid) -> Result<bool, anyhow::Error> {
+    let result = query!(
+        "update storage.notifications set read_at = coalesce(read_at, now()) \
+        where id = $1 and user_id = $2 and ($3::uuid is null or library_id = $3)",
+        id, user_id, library_id
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn mark_all_notifications_read(pool: &DB, user_id: &str, library_id: Option<&Uuid>) -> Result<(), anyhow::Error> {
+    query!(
+        "update storage.notifications set read_at = now() \
+        where user_id = $1 and ($2::uuid is null or library_id = $2) and read_at is null",
+        user_id, library_id
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Returns the kinds of notifications the user turned on or off. Kinds that aren't returned are on
+pub async fn get_notification_preferences(pool: &DB, user_id: &str) -> Result<Vec<(NotificationKind, bool)>, anyhow::Error> {
+    query!("select kind, enabled from storage.notification_preferences where user_id = $1", user_id)
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?
+        .into_iter()
+        .map(|row| Ok((
+            NotificationKind::try_from(row.kind).map_err(|_| anyhow!("Invalid notification kind {}", row.kind))?,
+            row.enabled
+        )))
+        .collect()
+}
+
+pub async fn set_notification_preference(pool: &DB, user_id: &str, kind: NotificationKind, enabled: bool) -> Result<(), anyhow::Error> {
+    query!(
+        "insert into storage.notification_preferences (user_id, kind, enabled) values ($1, $2, $3) \
+        on conflict (user_id, kind) do update set enabled = excluded.enabled",
+        user_id, i16::from(kind), enabled
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::cmp::Reverse;
+use serde_json::Value;
+use sqlx::query;
+use sqlx::types::Uuid;
+use crate::{LoginSessionData, SessionData, DB};
+
+pub async fn get_session(pool: &DB, id: &str) -> Result<Option<Value>, anyhow::Error> {
+    let row = query!("select data from storage.sessions where id = $1 and expires_at > now()", id)
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(row.map(|row| row.data))
+}
+
+/// Creates or replaces the session, expiring it after `expire_secs`
+pub async fn set_session(pool: &DB, id: &str, data: &Value, expire_secs: u64) -> Result<(), anyhow::Error> {
+    query!(
+        "insert into storage.sessions (id, data, expires_at) values ($1, $2, now() + make_interval(secs => $3)) \
+        on conflict (id) do update set data = excluded.data, expires_at = excluded.expires_at",
+        id, data, expire_secs as f64
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Pushes back the expiry of the session
+pub async fn touch_session(pool: &DB, id: &str, expire_secs: u64) -> Result<(), anyhow::Error> {
+    query!("update storage.sessions set expires_at = now() + make_interval(secs => $2) where id = $1", id, expire_secs as f64)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+pub async fn delete_session(pool: &DB, id: &str) -> Result<(), anyhow::Error> {
+    query!("delete from storage.sessions where id = $1", id)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Removes all expired sessions, returning how many were removed
+pub async fn delete_expired_sessions(pool: &DB) -> Result<u64, anyhow::Error> {
+    let result = query!("delete from storage.sessions where expires_at <= now()")
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected())
+}
+
+/// Returns the logins of all of the user's active sessions, most recently active first
+pub async fn get_user_logins(pool: &DB, user_id: &str) -> Result<Vec<LoginSessionData>, anyhow::Error> {
+    let rows = query!("select data from storage.sessions where user_id = $1 and expires_at > now()", user_id)
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?;
+    let mut logins: Vec<LoginSessionData> = rows.into_iter()
+        .filter_map(|row| serde_json::from_value::<SessionData>(row.data).ok())
+        .filter_map(|data| data.login)
+        .collect();
+    logins.sort_by_key(|login| Reverse(login.last_active));
+    Ok(logins)
+}
+
+/// Removes the user's session with the login id, returns false if they have no such session
+pub async fn delete_user_login(pool: &DB, user_id: &str, login_id: &Uuid) -> Result<bool, anyhow::Error> {
+    let result = query!(
+        "delete from storage.sessions where user_id = $1 and data #>> '{login,id}' = $2",
+        user_id, login_id.to_string()
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Removes all of the user's sessions, logging them out everywhere
+pub async fn delete_user_sessions(pool: &DB, user_id: &str) -> Result<u64, anyhow::Error> {
+    let result = query!("delete from storage.sessions where user_id = $1", user_id)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected())
+}
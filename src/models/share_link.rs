@@ -0,0 +1,232 @@
+use anyhow::anyhow;
+use chrono::NaiveDateTime;
+use int_enum::IntEnum;
+use rocket::FromFormField;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+
+/// What someone with a share link can do
+#[repr(i16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, IntEnum, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareLinkMode {
+    /// Can browse and download the shared file or folder
+    #[field(value = "read")]
+    Read = 0,
+    /// Can only upload files into the shared folder, without seeing what is in it
+    #[field(value = "upload")]
+    Upload = 1,
+}
+
+impl ShareLinkMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShareLinkMode::Read => "read",
+            ShareLinkMode::Upload => "upload",
+        }
+    }
+}
+
+/// A public link to a file or folder in a library. The password is stored as a bcrypt hash
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShareLinkModel {
+    pub id: Uuid,
+    pub library_id: Uuid,
+    pub path: String,
+    pub token: String,
+    pub mode: ShareLinkMode,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub max_downloads: Option<i32>,
+    pub download_count: i32,
+    /// Upload links ask who is uploading, their name is required and their email optional
+    pub ask_uploader: bool,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl ShareLinkModel {
+    pub fn has_password(&self) -> bool {
+        self.password.is_some()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareLinkWithUserModel {
+    pub link: ShareLinkModel,
+    pub has_password: bool,
+    pub created_by_username: Option<String>,
+}
+
+struct ShareLinkRow {
+    id: Uuid,
+    library_id: Uuid,
+    path: String,
+    token: String,
+    mode: i16,
+    password: Option<String>,
+    expires_at: Option<NaiveDateTime>,
+    max_downloads: Option<i32>,
+    download_count: i32,
+    ask_uploader: bool,
+    created_by: String,
+    created_at: NaiveDateTime,
+}
+
+impl TryFrom<ShareLinkRow> for ShareLinkModel {
+    type Error = anyhow::Error;
+
+    fn try_from(row: ShareLinkRow) -> Result<Self, Self::Error> {
+        Ok(ShareLinkModel {
+            mode: ShareLinkMode::try_from(row.mode).map_err(|_| anyhow!("Invalid share link mode {}", row.mode))?,
+            id: row.id,
+            library_id: row.library_id,
+            path: row.path,
+            token: row.token,
+            password: row.password,
+            expires_at: row.expires_at,
+            max_downloads: row.max_downloads,
+            download_count: row.download_count,
+            ask_uploader: row.ask_uploader,
+            created_by: row.created_by,
+            created_at: row.created_at,
+        })
+    }
+}
+
+/// What a new share link gives access to
+pub struct NewShareLink<'a> {
+    pub library_id: &'a Uuid,
+    pub path: &'a str,
+    pub token: &'a str,
+    pub mode: ShareLinkMode,
+    /// The bcrypt hash of the password
+    pub password: Option<&'a str>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub max_downloads: Option<i32>,
+    pub ask_uploader: bool,
+    pub created_by: &'a str,
+}
+
+pub async fn create_share_link(pool: &DB, link: &NewShareLink<'_>) -> Result<ShareLinkModel, anyhow::Error> {
+    query_as!(ShareLinkRow,
+        "insert into storage.share_links (id, library_id, path, token, mode, password, expires_at, max_downloads, ask_uploader, created_by) \
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
+        returning id, library_id, path, token, mode, password, expires_at, max_downloads, download_count, ask_uploader, created_by, created_at",
+        Uuid::new_v4(), link.library_id, link.path, link.token, i16::from(link.mode), link.password, link.expires_at,
+        link.max_downloads, link.ask_uploader, link.created_by
+    )
+        .fetch_one(pool)
+        .await.map_err(anyhow::Error::from)?
+        .try_into()
+}
+
+/// Returns the link with the token, if it has not expired and has downloads left
+pub async fn get_active_share_link(pool: &DB, token: &str) -> Result<Option<ShareLinkModel>, anyhow::Error> {
+    query_as!(ShareLinkRow,
+        "select id, library_id, path, token, mode, password, expires_at, max_downloads, download_count, ask_uploader, created_by, created_at \
+        from storage.share_links \
+        where token = $1 and (expires_at is null or expires_at > now()) and (max_downloads is null or download_count < max_downloads)",
+        token
+    )
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)?
+        .map(ShareLinkModel::try_from)
+        .transpose()
+}
+
+pub async fn get_library_share_links(pool: &DB, library_id: &Uuid) -> Result<Vec<ShareLinkWithUserModel>, anyhow::Error> {
+    let rows = query!(
+        "select s.id, s.library_id, s.path, s.token, s.mode, s.password, s.expires_at, s.max_downloads, s.download_count, \
+        s.ask_uploader, s.created_by, s.created_at, u.username as \"created_by_username?\" \
+        from storage.share_links s left join storage.users u on u.id = s.created_by \
+        where s.library_id = $1 order by s.created_at desc",
+        library_id
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?;
+    rows.into_iter()
+        .map(|row| {
+            let link: ShareLinkModel = ShareLinkRow {
+                id: row.id,
+                library_id: row.library_id,
+                path: row.path,
+                token: row.token,
+                mode: row.mode,
+                password: row.password,
+                expires_at: row.expires_at,
+                max_downloads: row.max_downloads,
+                download_count: row.download_count,
+                ask_uploader: row.ask_uploader,
+                created_by: row.created_by,
+                created_at: row.created_at,
+            }.try_into()?;
+            Ok(ShareLinkWithUserModel {
+                has_password: link.has_password(),
+                link,
+                created_by_username: row.created_by_username,
+            })
+        })
+        .collect()
+}
+
+/// Counts a download of the link, returns false without counting it if the link has no downloads left
+pub async fn count_share_link_download(pool: &DB, id: &Uuid) -> Result<bool, anyhow::Error> {
+    let result = query!(
+        "update storage.share_links set download_count = download_count + 1 \
+        where id = $1 and (max_downloads is null or download_count < max_downloads)",
+        id
+    )
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deletes the library's link, returns the path it was for, None if the library has no link with the id
+pub async fn delete_share_link(pool: &DB, library_id: &Uuid, id: &Uuid) -> Result<Option<String>, anyhow::Error> {
+    let row = query!("delete from storage.share_links where id = $1 and library_id = $2 returning path", id, library_id)
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(row.map(|row| row.path))
+}
+
+/// A file uploaded through an upload link, with who uploaded it if the link asked
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareLinkUploadModel {
+    pub id: Uuid,
+    pub library_id: Uuid,
+    /// None once the link is revoked
+    pub link_id: Option<Uuid>,
+    pub path: String,
+    pub size: i64,
+    pub uploader_name: Option<String>,
+    pub uploader_email: Option<String>,
+    pub uploaded_at: NaiveDateTime,
+}
+
+pub async fn create_share_link_upload(pool: &DB, link: &ShareLinkModel, path: &str, size: i64, uploader_name: Option<&str>, uploader_email: Option<&str>)
+    -> Result<ShareLinkUploadModel, anyhow::Error>
+{
+    query_as!(ShareLinkUploadModel,
+        "insert into storage.share_link_uploads (id, library_id, link_id, path, size, uploader_name, uploader_email) \
+        values ($1, $2, $3, $4, $5, $6, $7) \
+        returning id, library_id, link_id, path, size, uploader_name, uploader_email, uploaded_at",
+        Uuid::new_v4(), link.library_id, link.id, path, size, uploader_name, uploader_email
+    )
+        .fetch_one(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Returns the library's most recent uploads through links, newest first
+pub async fn get_library_share_link_uploads(pool: &DB, library_id: &Uuid, limit: i64) -> Result<Vec<ShareLinkUploadModel>, anyhow::Error> {
+    query_as!(ShareLinkUploadModel,
+        "select id, library_id, link_id, path, size, uploader_name, uploader_email, uploaded_at \
+        from storage.share_link_uploads where library_id = $1 order by uploaded_at desc limit $2",
+        library_id, limit
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
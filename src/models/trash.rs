@@ -0,0 +1,84 @@
+use chrono::NaiveDateTime;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+
+/// A deleted file or folder, kept in the library's trash until it is restored or purged
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashItemModel {
+    pub id: Uuid,
+    pub library_id: Uuid,
+    /// The path the item was deleted from
+    pub path: String,
+    pub is_folder: bool,
+    /// Size of the file, not known for folders
+    pub size: Option<i64>,
+    pub deleted_by: Option<String>,
+    pub deleted_at: NaiveDateTime,
+}
+
+/// A trash item with the username of who deleted it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrashItemWithUserModel {
+    pub item: TrashItemModel,
+    pub deleted_by_username: Option<String>,
+}
+
+pub async fn create_trash_item(pool: &DB, id: &Uuid, library_id: &Uuid, path: &str, is_folder: bool, size: Option<u64>, deleted_by: Option<&str>) -> Result<TrashItemModel, anyhow::Error> {
+    query_as!(TrashItemModel,
+        "insert into storage.trash (id, library_id, path, is_folder, size, deleted_by) values ($1, $2, $3, $4, $5, $6) returning *",
+        id, library_id, path, is_folder, size.map(|size| size as i64), deleted_by
+    )
+        .fetch_one(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+pub async fn get_trash_item(pool: &DB, library_id: &Uuid, id: &Uuid) -> Result<Option<TrashItemModel>, anyhow::Error> {
+    query_as!(TrashItemModel, "select * from storage.trash where id = $1 and library_id = $2", id, library_id)
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Returns the library's trash, most recently deleted first
+pub async fn get_trash_items(pool: &DB, library_id: &Uuid) -> Result<Vec<TrashItemWithUserModel>, anyhow::Error> {
+    let rows = query!(
+        "select t.*, u.username as \"deleted_by_username?\" from storage.trash t \
+        left join storage.users u on u.id = t.deleted_by \
+        where t.library_id = $1 order by t.deleted_at desc",
+        library_id
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(rows.into_iter()
+        .map(|row| TrashItemWithUserModel {
+            item: TrashItemModel {
+                id: row.id,
+                library_id: row.library_id,
+                path: row.path,
+                is_folder: row.is_folder,
+                size: row.size,
+                deleted_by: row.deleted_by,
+                deleted_at: row.deleted_at,
+            },
+            deleted_by_username: row.deleted_by_username,
+        })
+        .collect())
+}
+
+/// Returns all items deleted more than `retention_secs` ago, from every library
+pub async fn get_expired_trash_items(pool: &DB, retention_secs: u64) -> Result<Vec<TrashItemModel>, anyhow::Error> {
+    query_as!(TrashItemModel,
+        "select * from storage.trash where deleted_at <= now() - make_interval(secs => $1)",
+        retention_secs as f64
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+pub async fn delete_trash_item(pool: &DB, id: &Uuid) -> Result<(), anyhow::Error> {
+    query!("delete from storage.trash where id = $1", id)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
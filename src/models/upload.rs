@@ -0,0 +1,78 @@
+use chrono::NaiveDateTime;
+use rocket::serde::{Deserialize, Serialize};
+use sqlx::{query, query_as};
+use sqlx::types::Uuid;
+use crate::DB;
+
+/// A resumable upload that has not been committed to its library yet
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UploadModel {
+    pub id: Uuid,
+    pub library_id: Uuid,
+    /// The path inside the library the file will be written to once complete
+    pub path: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    /// The raw Upload-Metadata header the upload was created with
+    pub metadata: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    /// The user that created the upload, the only one that can send it data
+    pub created_by: String,
+}
+
+/// An expired upload, with the repo its staging file is in
+pub struct ExpiredUploadModel {
+    pub id: Uuid,
+    pub repo_id: String,
+}
+
+pub async fn create_upload(pool: &DB, library_id: &Uuid, path: &str, length: u64, metadata: Option<&str>, expire_secs: u64, user_id: &str) -> Result<UploadModel, anyhow::Error> {
+    query_as!(UploadModel,
+        "insert into storage.uploads (id, library_id, path, upload_length, metadata, expires_at, created_by) \
+        values ($1, $2, $3, $4, $5, now() + make_interval(secs => $6), $7) \
+        returning *",
+        Uuid::new_v4(), library_id, path, length as i64, metadata, expire_secs as f64, user_id
+    )
+        .fetch_one(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Returns the upload, if it was created by the user and has not expired
+pub async fn get_upload(pool: &DB, library_id: &Uuid, upload_id: &Uuid, user_id: &str) -> Result<Option<UploadModel>, anyhow::Error> {
+    query_as!(UploadModel,
+        "select * from storage.uploads where id = $1 and library_id = $2 and created_by = $3 and expires_at > now()",
+        upload_id, library_id, user_id
+    )
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+/// Sets the new offset of the upload, pushing back its expiry
+pub async fn set_upload_offset(pool: &DB, upload_id: &Uuid, offset: u64, expire_secs: u64) -> Result<UploadModel, anyhow::Error> {
+    query_as!(UploadModel,
+        "update storage.uploads set upload_offset = $2, expires_at = now() + make_interval(secs => $3) \
+        where id = $1 returning *",
+        upload_id, offset as i64, expire_secs as f64
+    )
+        .fetch_one(pool)
+        .await.map_err(anyhow::Error::from)
+}
+
+pub async fn delete_upload(pool: &DB, upload_id: &Uuid) -> Result<(), anyhow::Error> {
+    query!("delete from storage.uploads where id = $1", upload_id)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
+
+/// Removes all expired uploads, returning them so their staging files can be removed
+pub async fn delete_expired_uploads(pool: &DB) -> Result<Vec<ExpiredUploadModel>, anyhow::Error> {
+    query_as!(ExpiredUploadModel,
+        "delete from storage.uploads u using storage.libraries l \
+        where u.library_id = l.id and u.expires_at <= now() \
+        returning u.id, l.repo_id",
+    )
+        .fetch_all(pool)
+        .await.map_err(anyhow::Error::from)
+}
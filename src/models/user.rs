@@ -8,17 +8,17 @@ use rocket::http::Status;
 use rocket::{form, Request};
 use rocket::form::error::Entity;
 use rocket::response::Responder;
-use rocket::serde::Serialize;
+use rocket::serde::{Deserialize, Serialize};
 use rocket::serde::uuid::Uuid;
 use rocket_session_store::Session;
-use sqlx::{query_as, FromRow};
-use crate::consts::{DISABLE_LOGIN_CHECK, ENCRYPTION_ROUNDS};
+use sqlx::{query, query_as, FromRow};
+use crate::consts::ENCRYPTION_ROUNDS;
 use crate::{LoginSessionData, SessionData, DB};
 use crate::managers::user::UsersState;
 use crate::models::repo::RepoModel;
 use crate::util::JsonErrorResponse;
 
-#[derive(Serialize, Clone, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
 pub struct UserModel {
     pub id: String,
     pub username: String,
@@ -98,11 +98,38 @@ pub async fn get_user(pool: &DB, user_id: &str) -> Result<Option<UserModel>, any
         .fetch_optional(pool)
         .await.map_err(anyhow::Error::from)
 }
+/// Finds a user by their id, username or email
+pub async fn find_user(pool: &DB, id_username_or_email: &str) -> Result<Option<UserModel>, anyhow::Error> {
+    query_as!(UserModel,
+        "select id, username, created_at, email, name from storage.users where id = $1 or username = $1 or email = $1",
+        id_username_or_email
+    )
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)
+}
+/// Returns the version of the user's credentials, found by their id, username or email. It changes whenever credentials
+/// verified earlier should no longer be trusted, like when the user logs out everywhere
+pub async fn get_credentials_version(pool: &DB, id_username_or_email: &str) -> Result<Option<i32>, anyhow::Error> {
+    let row = query!(
+        "select credentials_version from storage.users where id = $1 or username = $1 or email = $1",
+        id_username_or_email
+    )
+        .fetch_optional(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(row.map(|row| row.credentials_version))
+}
+
+pub async fn bump_credentials_version(pool: &DB, user_id: &str) -> Result<(), anyhow::Error> {
+    query!("update storage.users set credentials_version = credentials_version + 1 where id = $1", user_id)
+        .execute(pool)
+        .await.map_err(anyhow::Error::from)?;
+    Ok(())
+}
 /// Validates user login form
-pub async fn try_login_user_form(ctx: &mut Context<'_>, users: &UsersState, ip: IpAddr, session: &Session<'_, SessionData>) -> Result<UserModel, UserAuthError> {
+pub async fn try_login_user_form(ctx: &mut Context<'_>, users: &UsersState, ip: IpAddr, user_agent: Option<String>, session: &Session<'_, SessionData>) -> Result<UserModel, UserAuthError> {
     let username = ctx.field_value("username").unwrap();
     let password = ctx.field_value("password").unwrap(); // TODO: no unwrap
-    users.login_normal_user(username, password, ip, session).await
+    users.login_normal_user(username, password, ip, user_agent, session).await
 }
 pub async fn validate_user(pool: &DB, email_or_usrname: &str, password: &str) -> Result<UserModel, UserAuthError> {
     let user = query_as!(UserModelWithPassword,
@@ -115,7 +142,7 @@ pub async fn validate_user(pool: &DB, email_or_usrname: &str, password: &str) ->
         return Err(UserAuthError::UserNotFound);
     };
     if let Some(db_password) = user.password {
-        if !*DISABLE_LOGIN_CHECK || bcrypt::verify(password, &db_password).map_err(|e| UserAuthError::EncryptionError(e))? {
+        if bcrypt::verify(password, &db_password).map_err(|e| UserAuthError::EncryptionError(e))? {
             return Ok(UserModel {
                 id: user.id,
                 email: user.email,
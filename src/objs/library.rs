@@ -1,44 +1,63 @@
-use std::cmp::Ordering;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
-use anyhow::{anyhow, Error};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use anyhow::Error;
 use log::trace;
 use rocket::response::stream::ReaderStream;
 use rocket::serde::Serialize;
-use tokio::io::BufStream;
+use tokio::io::{AsyncRead, BufStream};
 use crate::managers::repos::RepoContainer;
+use crate::managers::activity::{ActivityManager, Actor};
+use crate::managers::favorites::FavoriteManager;
+use crate::managers::folder_sizes::FolderSizeManager;
+use crate::managers::search::SearchManager;
+use crate::managers::trash::TrashManager;
 use crate::{models, DB};
+use crate::models::activity::ActivityAction;
 use crate::models::library::LibraryModel;
 use crate::models::repo::RepoModel;
-use crate::storage::{FileEntry, FileType};
+use crate::storage::{ConflictPolicy, FileEntry, FileMetadata, FilePage, FileType, ReadStream, StorageError};
+pub use crate::storage::ListOptions;
+use crate::consts::MAX_RECURSIVE_LIST_ENTRIES;
 use crate::util::{JsonErrorResponse, ResponseError};
 
+/// Folder at the root of every library that holds internal data, ex. the trash.
+/// It is hidden from listings and can't be accessed through the normal file methods
+pub const INTERNAL_FOLDER: &str = ".storage";
+
+#[derive(Clone)]
 pub struct Library {
     model: LibraryModel,
     repo: RepoContainer,
+    search: Arc<SearchManager>,
+    sizes: Arc<FolderSizeManager>,
+    favorites: Arc<FavoriteManager>,
+    activity: Arc<ActivityManager>,
+    trash: Arc<TrashManager>,
+    /// Who changes made through this library are recorded as made by
+    actor: Actor,
 }
 
-/// The direction of sort and the field to sort by
-#[derive(PartialEq, Debug)]
-pub struct ListOptions {
-    pub sort_field: Option<String>,
-    pub sort_descending: Option<bool>
-}
-impl Default for ListOptions {
-    fn default() -> Self {
-        ListOptions {
-            sort_field: Some("name".to_string()),
-            sort_descending: Some(false),
-        }
+/// Rejects paths inside the library's [INTERNAL_FOLDER]
+pub(crate) fn check_path(rel_path: &Path) -> Result<(), anyhow::Error> {
+    let first = rel_path.components().find(|component| matches!(component, Component::Normal(_)));
+    if first == Some(Component::Normal(INTERNAL_FOLDER.as_ref())) {
+        return Err(StorageError::InvalidPath.into())
     }
+    Ok(())
 }
 
 impl Library {
-    pub fn new(library_model: LibraryModel, repo: RepoContainer) -> Library {
+    pub fn new(library_model: LibraryModel, repo: RepoContainer, search: Arc<SearchManager>, sizes: Arc<FolderSizeManager>, favorites: Arc<FavoriteManager>, activity: Arc<ActivityManager>,
+        trash: Arc<TrashManager>) -> Library {
         Library {
             model: library_model,
-            repo
+            repo,
+            search,
+            sizes,
+            favorites,
+            activity,
+            trash,
+            actor: Actor::default(),
         }
     }
 
@@ -46,56 +65,283 @@ impl Library {
         &self.model
     }
 
-    pub async fn get_read_stream(&self, rel_path: &PathBuf) -> Result<BufReader<File>, anyhow::Error> {
-        let mut repo = self.repo.read().await;
-        repo.backend.get_read_stream(&self.model.id.to_string(), rel_path)
+    /// Records the changes made through the library as made by the actor
+    pub fn with_actor(mut self, actor: Actor) -> Self {
+        self.actor = actor;
+        self
+    }
+
+    pub fn actor(&self) -> &Actor {
+        &self.actor
+    }
+
+    /// Records a change to the library that isn't made through it, ex. moving a file to the trash or sharing the library
+    pub async fn record_activity(&self, action: ActivityAction, rel_path: &Path, detail: Option<&str>) {
+        self.activity.record(self, action, rel_path, detail).await;
     }
 
     pub async fn touch_file(&self, rel_path: &PathBuf, file_type: FileType) -> Result<(), anyhow::Error> {
-        let mut repo = self.repo.read().await;
-        repo.backend.touch_file(&self.model.id.to_string(), rel_path, file_type)
+        check_path(rel_path)?;
+        self.touch_internal(rel_path, file_type).await?;
+        self.activity.record(self, ActivityAction::Create, rel_path, None).await;
+        Ok(())
     }
 
-    pub async fn write_file(&self, rel_path: &PathBuf, contents: &[u8]) -> Result<(), anyhow::Error> {
-        let mut repo = self.repo.read().await;
-        repo.backend.write_file(&self.model.id.to_string(), rel_path, contents)
+    async fn touch_internal(&self, rel_path: &PathBuf, file_type: FileType) -> Result<(), anyhow::Error> {
+        let repo = self.repo.read().await;
+        repo.backend.touch_file(&self.model.id.to_string(), rel_path, file_type).await?;
+        drop(repo);
+        self.sizes.invalidate(&self.model.id, rel_path, false).await;
+        self.search.on_write(self, rel_path, false).await;
+        Ok(())
+    }
+
+    /// Streams the contents into the file, returning the amount of bytes written
+    pub async fn write_file(&self, rel_path: &PathBuf, contents: &mut (dyn AsyncRead + Send + Unpin)) -> Result<u64, anyhow::Error> {
+        check_path(rel_path)?;
+        let size = self.write_internal(rel_path, contents).await?;
+        self.activity.record(self, ActivityAction::Upload, rel_path, None).await;
+        Ok(size)
     }
 
-    pub async fn read_file(&self, rel_path: &PathBuf) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    async fn write_internal(&self, rel_path: &PathBuf, contents: &mut (dyn AsyncRead + Send + Unpin)) -> Result<u64, anyhow::Error> {
         let repo = self.repo.read().await;
-        repo.backend.read_file(&self.model.id.to_string(), rel_path)
+        let size = repo.backend.write_file(&self.model.id.to_string(), rel_path, contents).await?;
+        drop(repo);
+        self.sizes.invalidate(&self.model.id, rel_path, false).await;
+        self.search.on_write(self, rel_path, false).await;
+        Ok(size)
+    }
+
+    pub async fn read_file(&self, rel_path: &PathBuf) -> Result<Option<ReadStream>, anyhow::Error> {
+        check_path(rel_path)?;
+        self.read_internal(rel_path).await
+    }
+
+    pub async fn stat_file(&self, rel_path: &PathBuf) -> Result<Option<FileMetadata>, anyhow::Error> {
+        check_path(rel_path)?;
+        self.stat_internal(rel_path).await
     }
 
+    /// Lists the whole folder, filtered and sorted by the options
     pub async fn list_files(&self, rel_path: &PathBuf, options: ListOptions) -> Result<Vec<FileEntry>, anyhow::Error> {
+        let options = ListOptions { limit: None, cursor: None, ..options };
+        Ok(self.list_files_page(rel_path, &options).await?.files)
+    }
+
+    /// Lists a page of the folder, filtered and sorted by the options
+    pub async fn list_files_page(&self, rel_path: &PathBuf, options: &ListOptions) -> Result<FilePage, anyhow::Error> {
+        check_path(rel_path)?;
+        let is_root = rel_path.components().all(|component| !matches!(component, Component::Normal(_)));
+        if !options.recursive && !options.folder_sizes {
+            let repo = self.repo.read().await;
+            let mut page = repo.backend.list_files_page(&self.model.id.to_string(), rel_path, options).await?;
+            // Pages of the root can be one entry short because of this, which is fine as the cursor still follows on
+            if is_root {
+                page.files.retain(|entry| entry.path != INTERNAL_FOLDER);
+            }
+            return Ok(page)
+        }
+        // Everything has to be listed first to sort by the computed sizes, or to filter the nested entries
+        let max_depth = if options.recursive { options.max_depth.unwrap_or(u32::MAX) } else { 1 };
+        let mut list = Vec::new();
+        let mut size_budget = MAX_RECURSIVE_LIST_ENTRIES;
+        let mut pending = vec![(PathBuf::new(), 1)];
+        while let Some((folder, depth)) = pending.pop() {
+            let repo = self.repo.read().await;
+            let entries = repo.backend.list_files(&self.model.id.to_string(), &rel_path.join(&folder)).await?;
+            drop(repo);
+            for mut entry in entries {
+                if is_root && folder.as_os_str().is_empty() && entry.path == INTERNAL_FOLDER {
+                    continue;
+                }
+                let path = folder.join(&entry.path);
+                if entry._type == FileType::Folder {
+                    if options.folder_sizes {
+                        let size = Box::pin(self.sizes.get(self, &rel_path.join(&path), &mut size_budget)).await?;
+                        entry.size = size.size;
+                        entry.item_count = Some(size.items);
+                    }
+                    if depth < max_depth {
+                        pending.push((path.clone(), depth + 1));
+                    }
+                }
+                entry.path = path.to_string_lossy().into_owned();
+                list.push(entry);
+            }
+            if list.len() > MAX_RECURSIVE_LIST_ENTRIES {
+                return Err(StorageError::ListingTooLarge.into())
+            }
+        }
+        options.page(list)
+    }
+
+    /// Moves the file or folder, handling an existing destination according to the policy. Returns the path it was moved to
+    pub async fn move_file(&self, rel_path: &PathBuf, new_rel_path: &PathBuf, policy: ConflictPolicy) -> Result<PathBuf, Error> {
+        check_path(rel_path)?;
+        check_path(new_rel_path)?;
+        // Checked before anything is replaced, the backend checks them again
+        if new_rel_path.starts_with(rel_path) {
+            return Err(StorageError::InvalidDestination.into())
+        }
+        if !self.exists_internal(rel_path).await? {
+            return Err(StorageError::NotFound.into())
+        }
+        let policy = self.trash_replaced(new_rel_path, policy).await?;
         let repo = self.repo.read().await;
-        let mut list = repo.backend.list_files(&self.model.id.to_string(), rel_path)?;
-        let field = options.sort_field.unwrap_or("name".to_string());
-        let descending = options.sort_descending.unwrap_or(false);
-        match field.as_str() {
-            "name" => list.sort_by(|a, b| {
-                if a._type == FileType::File && b._type != FileType::File { Ordering::Greater }
-                else if a._type != FileType::File && b._type == FileType::File { Ordering::Less }
-                else { a.path.cmp(&b.path) }
-            }),
-            "size" => list.sort_by(|a, b| {
-                if a._type == FileType::File && b._type != FileType::File { Ordering::Greater }
-                else if a._type != FileType::File && b._type == FileType::File { Ordering::Less }
-                else { a.size.cmp(&b.size) }
-            }),
-            _ => return Err(anyhow!("Unsupported field"))
+        let destination = repo.backend.move_file(&self.model.id.to_string(), rel_path, new_rel_path, policy).await?;
+        drop(repo);
+        self.sizes.invalidate(&self.model.id, rel_path, true).await;
+        self.sizes.invalidate(&self.model.id, &destination, true).await;
+        self.search.on_move(self, rel_path, &destination).await;
+        self.favorites.on_move(self, rel_path, &destination).await;
+        self.activity.record(self, ActivityAction::Move, rel_path, Some(&destination.to_string_lossy())).await;
+        Ok(destination)
+    }
+
+    /// Copies the file, or the folder and everything in it, to the destination library, which can be this library.
+    /// An existing destination is handled according to the policy. Returns the path it was copied to
+    pub async fn copy_to(&self, rel_path: &PathBuf, dest: &Library, dest_rel_path: &PathBuf, policy: ConflictPolicy) -> Result<PathBuf, Error> {
+        check_path(rel_path)?;
+        check_path(dest_rel_path)?;
+        // Copying a folder into itself would never finish
+        if self.model.id == dest.model.id && dest_rel_path.starts_with(rel_path) {
+            return Err(StorageError::InvalidDestination.into())
+        }
+        if !self.exists_internal(rel_path).await? {
+            return Err(StorageError::NotFound.into())
+        }
+        let mut destination = dest_rel_path.clone();
+        if dest.exists_internal(&destination).await? {
+            match policy {
+                ConflictPolicy::Fail => return Err(StorageError::AlreadyExists.into()),
+                ConflictPolicy::Overwrite => dest.trash.trash_replaced(dest, &destination).await?,
+                ConflictPolicy::Rename => {
+                    let repo = dest.repo.read().await;
+                    destination = repo.backend.free_path(&dest.model.id.to_string(), dest_rel_path).await?;
+                }
+            }
         }
-        if descending {
-            list.reverse();
+        if let Some(parent) = destination.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            dest.touch_internal(&parent.to_path_buf(), FileType::Folder).await?;
         }
-        Ok(list)
+        // The backend can only copy natively between libraries it stores
+        let same_backend = Arc::ptr_eq(&self.repo, &dest.repo);
+        let mut pending = vec![(rel_path.clone(), destination.clone())];
+        while let Some((from, to)) = pending.pop() {
+            if self.stat_internal(&from).await?.is_some() {
+                let copied = same_backend && {
+                    let repo = self.repo.read().await;
+                    repo.backend.copy_file(&self.model.id.to_string(), &from, &dest.model.id.to_string(), &to).await?
+                };
+                if !copied {
+                    let mut contents = self.read_internal(&from).await?
+                        .ok_or(StorageError::NotFound)?;
+                    dest.write_internal(&to, &mut contents).await?;
+                }
+            } else {
+                dest.touch_internal(&to, FileType::Folder).await?;
+                // Symlinks are skipped, they could point outside the library
+                for entry in self.list_files(&from, ListOptions::default()).await? {
+                    if matches!(entry._type, FileType::File | FileType::Folder) {
+                        pending.push((from.join(&entry.path), to.join(&entry.path)));
+                    }
+                }
+            }
+        }
+        dest.sizes.invalidate(&dest.model.id, &destination, true).await;
+        dest.search.on_write(dest, &destination, true).await;
+        let source = if self.model.id == dest.model.id {
+            rel_path.to_string_lossy().into_owned()
+        } else {
+            format!("{}/{}", self.model.name, rel_path.to_string_lossy())
+        };
+        dest.activity.record(dest, ActivityAction::Copy, &destination, Some(&source)).await;
+        Ok(destination)
+    }
+
+    /// Moves an existing destination that the policy overwrites into the trash, so it can still be restored.
+    /// Returns the policy to continue with, as nothing has to be overwritten anymore
+    async fn trash_replaced(&self, rel_path: &PathBuf, policy: ConflictPolicy) -> Result<ConflictPolicy, Error> {
+        if policy != ConflictPolicy::Overwrite {
+            return Ok(policy)
+        }
+        if self.exists_internal(rel_path).await? {
+            self.trash.trash_replaced(self, rel_path).await?;
+        }
+        Ok(ConflictPolicy::Fail)
+    }
+
+    pub async fn exists(&self, rel_path: &PathBuf) -> Result<bool, anyhow::Error> {
+        check_path(rel_path)?;
+        self.exists_internal(rel_path).await
+    }
+
+    /// Moves a file without restricting access to the [INTERNAL_FOLDER], failing if the destination exists
+    pub(crate) async fn move_internal(&self, rel_path: &PathBuf, new_rel_path: &PathBuf) -> Result<(), Error> {
+        let repo = self.repo.read().await;
+        repo.backend.move_file(&self.model.id.to_string(), rel_path, new_rel_path, ConflictPolicy::Fail).await?;
+        drop(repo);
+        self.sizes.invalidate(&self.model.id, rel_path, true).await;
+        self.sizes.invalidate(&self.model.id, new_rel_path, true).await;
+        self.search.on_move(self, rel_path, new_rel_path).await;
+        self.favorites.on_move(self, rel_path, new_rel_path).await;
+        Ok(())
+    }
+
+    /// Moves a file into or out of the [INTERNAL_FOLDER] without updating the search index or favorites of the path,
+    /// for keeping its current contents as a version while it is rewritten in place
+    pub(crate) async fn move_contents_internal(&self, rel_path: &PathBuf, new_rel_path: &PathBuf) -> Result<(), Error> {
+        let repo = self.repo.read().await;
+        repo.backend.move_file(&self.model.id.to_string(), rel_path, new_rel_path, ConflictPolicy::Fail).await?;
+        drop(repo);
+        self.sizes.invalidate(&self.model.id, rel_path, true).await;
+        self.sizes.invalidate(&self.model.id, new_rel_path, true).await;
+        Ok(())
     }
 
-    pub async fn delete_file(&self, rel_path: &PathBuf) -> Result<(), anyhow::Error> {
+    /// Permanently deletes what a failed write left at the path, without updating the search index or favorites of the path,
+    /// as the contents it replaced are put back. Everything else is deleted by moving it to the trash
+    pub(crate) async fn discard_failed_write(&self, rel_path: &PathBuf) -> Result<(), Error> {
         let repo = self.repo.read().await;
-        repo.backend.delete_file(&self.model.id.to_string(), rel_path)
+        repo.backend.delete_file(&self.model.id.to_string(), rel_path).await?;
+        drop(repo);
+        self.sizes.invalidate(&self.model.id, rel_path, false).await;
+        Ok(())
     }
-    pub async fn move_file(&self, rel_path: &PathBuf, new_rel_path: &PathBuf) -> Result<(), Error> {
+
+    /// Reads a file without restricting access to the [INTERNAL_FOLDER]
+    pub(crate) async fn read_internal(&self, rel_path: &PathBuf) -> Result<Option<ReadStream>, anyhow::Error> {
+        let repo = self.repo.read().await;
+        repo.backend.read_file(&self.model.id.to_string(), rel_path).await
+    }
+
+    /// Reads part of a file without restricting access to the [INTERNAL_FOLDER]
+    pub(crate) async fn read_range_internal(&self, rel_path: &PathBuf, offset: u64, length: u64) -> Result<Option<ReadStream>, anyhow::Error> {
+        let repo = self.repo.read().await;
+        repo.backend.read_file_range(&self.model.id.to_string(), rel_path, offset, length).await
+    }
+
+    /// Gets a file's metadata without restricting access to the [INTERNAL_FOLDER]
+    pub(crate) async fn stat_internal(&self, rel_path: &PathBuf) -> Result<Option<FileMetadata>, anyhow::Error> {
+        let repo = self.repo.read().await;
+        repo.backend.stat_file(&self.model.id.to_string(), rel_path).await
+    }
+
+    /// Checks if a file exists without restricting access to the [INTERNAL_FOLDER]
+    pub(crate) async fn exists_internal(&self, rel_path: &PathBuf) -> Result<bool, anyhow::Error> {
+        let repo = self.repo.read().await;
+        repo.backend.exists(&self.model.id.to_string(), rel_path).await
+    }
+
+    /// Deletes a file without restricting access to the [INTERNAL_FOLDER]
+    pub(crate) async fn delete_internal(&self, rel_path: &PathBuf) -> Result<(), anyhow::Error> {
         let repo = self.repo.read().await;
-        repo.backend.move_file(&self.model.id.to_string(), rel_path, new_rel_path)
+        repo.backend.delete_file(&self.model.id.to_string(), rel_path).await?;
+        drop(repo);
+        self.sizes.invalidate(&self.model.id, rel_path, true).await;
+        self.search.on_delete(self, rel_path).await;
+        self.favorites.on_delete(self, rel_path).await;
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1 +1,6 @@
-pub mod library;
\ No newline at end of file
+pub mod library;
+pub mod uploads;
+pub mod search;
+pub mod favorites;
+pub mod activity;
+pub mod notifications;
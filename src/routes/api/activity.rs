@@ -0,0 +1,50 @@
+use std::sync::Arc;
+use rocket::{get, State};
+use rocket::serde::json::Json;
+use sqlx::types::Uuid;
+use tokio::sync::Mutex;
+use crate::guards::ApiUser;
+use crate::managers::activity::{ActivityManager, ActivityOptions};
+use crate::managers::libraries::LibraryManager;
+use crate::managers::search::parse_date_filter;
+use crate::models::activity::{ActivityAction, ActivityModel};
+use crate::util::{JsonErrorResponse, ResponseError};
+
+/// Lists the changes made to every library the user can access, or only `library`, newest first.
+/// IP addresses are only included for libraries the user manages
+#[get("/activity?<library>&<action>&<user>&<path>&<since>&<until>&<limit>&<offset>")]
+pub(crate) async fn list(api_user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, activity: &State<Arc<ActivityManager>>,
+    library: Option<&str>, action: Option<ActivityAction>, user: Option<&str>, path: Option<&str>,
+    since: Option<&str>, until: Option<&str>, limit: Option<u32>, offset: Option<u32>
+) -> Result<Json<Vec<ActivityModel>>, ResponseError> {
+    let library = library.filter(|library| !library.is_empty())
+        .map(Uuid::parse_str)
+        .transpose()
+        .map_err(|_| ResponseError::BadRequest(JsonErrorResponse {
+            code: "INVALID_LIBRARY".to_string(),
+            message: "Library must be a library id".to_string()
+        }))?;
+    let token_library = api_user.token.as_ref().and_then(|token| token.library_id);
+    let libraries = libraries.lock().await;
+    let user_libraries: Vec<_> = libraries.list(&api_user.user.id).await
+        .map_err(|_| ResponseError::GenericError)?
+        .into_iter()
+        .filter(|model| library.is_none_or(|library| library == model.id) && token_library.is_none_or(|library| library == model.id))
+        .collect();
+    let admin_ids = libraries.admin_library_ids(&user_libraries, &api_user.user.id).await
+        .map_err(|_| ResponseError::GenericError)?;
+    drop(libraries);
+    let library_ids: Vec<Uuid> = user_libraries.iter().map(|model| model.id).collect();
+    let options = ActivityOptions {
+        action,
+        user,
+        path,
+        since: parse_date_filter(since)?,
+        until: parse_date_filter(until)?,
+        limit,
+        offset,
+    };
+    activity.list(&library_ids, &admin_ids, &options).await
+        .map(Json)
+        .map_err(|_| ResponseError::GenericError)
+}
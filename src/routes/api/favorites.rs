@@ -0,0 +1,35 @@
+use std::path::Path;
+use std::sync::Arc;
+use rocket::{delete, get, post, State};
+use rocket::serde::json::Json;
+use tokio::sync::Mutex;
+use crate::guards::ApiUser;
+use crate::managers::favorites::FavoriteManager;
+use crate::managers::libraries::LibraryManager;
+use crate::models::favorite::FavoriteModel;
+use crate::models::library::PermissionLevel;
+use crate::util::ResponseError;
+
+/// Lists the files, folders and libraries the user starred, in every library they can access
+#[get("/favorites")]
+pub(crate) async fn list(user: ApiUser, favorites: &State<Arc<FavoriteManager>>) -> Result<Json<Vec<FavoriteModel>>, ResponseError> {
+    let token_library = user.token.as_ref().and_then(|token| token.library_id);
+    favorites.list(&user.user.id).await
+        .map(|list| Json(list.into_iter()
+            .filter(|favorite| token_library.is_none_or(|library| library == favorite.library_id))
+            .collect()))
+        .map_err(|_| ResponseError::GenericError)
+}
+
+/// Stars the file or folder at the path, or the library itself when the path is empty
+#[post("/favorites?<library_id>&<path>")]
+pub(crate) async fn add(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, favorites: &State<Arc<FavoriteManager>>, library_id: &str, path: Option<&str>) -> Result<(), ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    favorites.add(&library, &user.user.id, Path::new(path.unwrap_or_default())).await
+}
+
+#[delete("/favorites?<library_id>&<path>")]
+pub(crate) async fn remove(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, favorites: &State<Arc<FavoriteManager>>, library_id: &str, path: Option<&str>) -> Result<(), ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    favorites.remove(&library, &user.user.id, Path::new(path.unwrap_or_default())).await
+}
@@ -1,49 +1,106 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use log::debug;
-use rocket::{delete, get, post, Data, Route, State};
+use rocket::{delete, get, post, response, Data, Either, FromForm, Request, Route, State};
 use rocket::fs::TempFile;
-use rocket::http::Status;
-use rocket::response::status;
+use rocket::http::{ContentType, Status};
+use rocket::response::{status, Responder};
 use rocket::serde::json::Json;
 use rocket::serde::Serialize;
 use sqlx::{query, Postgres};
 use sqlx::types::{Uuid};
-use tokio::io::AsyncReadExt;
 use tokio::sync::Mutex;
 use crate::{library, models, DB};
-use crate::consts::MAX_UPLOAD_SIZE;
+use crate::consts::{FILE_CONSTANTS, MAX_UPLOAD_SIZE};
+use crate::archive::{ArchiveDownload, ArchiveFormat, ExtractResult};
+use crate::download::{DownloadHeaders, FileDownload};
+use crate::managers::favorites::FavoriteManager;
 use crate::managers::libraries::LibraryManager;
 use crate::managers::repos::RepoManager;
-use crate::models::library::{LibraryModel, LibraryWithRepoModel};
+use crate::managers::share_links::{ShareLinkManager, ShareLinkOptions};
+use crate::managers::trash::TrashManager;
+use crate::managers::uploads::UploadManager;
+use crate::managers::versions::VersionManager;
+use crate::guards::ApiUser;
+use crate::models::library::{LibraryModel, LibraryShareModel, LibraryWithRepoModel, PermissionLevel};
+use crate::models::file_version::FileVersionWithUserModel;
+use crate::models::share_link::{ShareLinkMode, ShareLinkModel, ShareLinkUploadModel, ShareLinkWithUserModel};
+use crate::models::trash::{TrashItemModel, TrashItemWithUserModel};
 use crate::models::user;
 use crate::objs::library::ListOptions;
-use crate::storage::{FileEntry, FileType};
-use crate::util::{JsonErrorResponse, ResponseError};
+use crate::storage::{ConflictPolicy, FilePage, FileType};
+use crate::util::{database_error, storage_error, JsonErrorResponse, ResponseError};
 #[get("/<library_id>")]
-pub(crate) async fn get_file(pool: &State<DB>, library_id: &str) -> Result<Option<Json<LibraryWithRepoModel>>, ResponseError> {
+pub(crate) async fn get_file(user: ApiUser, pool: &State<DB>, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str) -> Result<Option<Json<LibraryWithRepoModel>>, ResponseError> {
+    libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
     let library = models::library::get_library_with_repo(pool, library_id).await
         .map_err(|e| ResponseError::GenericError)?;
     Ok(library.map(|lib| Json(lib)))
 }
 
-#[get("/<library_id>/files?<path>")]
-pub(crate) async fn list_files(libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: &str) -> Result<Json<Vec<FileEntry>>, ResponseError> {
-    let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
-    library.list_files(&PathBuf::from(path), ListOptions::default()).await
-        .map(|files| Json(files))
-        .map_err(|e| ResponseError::InternalServerError(JsonErrorResponse {
-            code: "STORAGE_ERROR".to_string(),
-            message: e.to_string(),
+#[derive(FromForm)]
+pub struct ListQuery<'r> {
+    sort_key: Option<&'r str>,
+    sort_dir: Option<&'r str>,
+    name: Option<String>,
+    file_type: Option<FileType>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+    recursive: Option<bool>,
+    max_depth: Option<u32>,
+    folder_sizes: Option<bool>,
+}
+
+/// The listed files, with the cursor of the next page in the `X-Next-Cursor` header when there is one
+pub struct FileListResponse(FilePage);
+
+impl<'r> Responder<'r, 'static> for FileListResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Json(self.0.files).respond_to(request)?;
+        if let Some(cursor) = self.0.next_cursor {
+            response.set_raw_header("X-Next-Cursor", cursor);
+        }
+        Ok(response)
+    }
+}
+
+/// Lists the folder, sorted by `sort_key` (name, size, last_modified or created) in `sort_dir` (asc or desc) order, folders first.
+/// `name` filters by a glob such as `*.jpg`, `file_type` to only files or folders. With a `limit` the listing is paged,
+/// pass the `X-Next-Cursor` header of the response as `cursor` to get the next page.
+/// `recursive` also lists the subfolders, up to `max_depth` levels deep, and `folder_sizes` computes the size and item count of folders.
+/// Files and folders the user starred have `favorited` set
+#[get("/<library_id>/files?<path>&<query..>")]
+pub(crate) async fn list_files(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, favorites: &State<Arc<FavoriteManager>>, library_id: &str, path: &str, query: ListQuery<'_>) -> Result<FileListResponse, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    let sort_key = query.sort_key.unwrap_or("name");
+    if !FILE_CONSTANTS.sort_keys.contains(&sort_key) {
+        return Err(ResponseError::BadRequest(JsonErrorResponse {
+            code: "INVALID_SORT_KEY".to_string(),
+            message: format!("Files can only be sorted by {}", FILE_CONSTANTS.sort_keys.join(", ")),
         }))
+    }
+    let options = ListOptions {
+        sort_field: Some(sort_key.to_string()),
+        sort_descending: Some(query.sort_dir == Some("desc")),
+        name_filter: query.name,
+        file_type: query.file_type,
+        limit: query.limit,
+        cursor: query.cursor,
+        recursive: query.recursive.unwrap_or(false),
+        max_depth: query.max_depth,
+        folder_sizes: query.folder_sizes.unwrap_or(false),
+    };
+    let mut page = library.list_files_page(&PathBuf::from(path), &options).await
+        .map_err(storage_error)?;
+    favorites.mark(&library, &user.user.id, Path::new(path), &mut page.files).await
+        .map_err(|_| ResponseError::GenericError)?;
+    Ok(FileListResponse(page))
 }
 
 
 #[post("/<library_id>/touch?<path>&<file_type>")]
-pub(crate) async fn touch_files(libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: &str, file_type: FileType) -> Result<(), ResponseError> {
-    let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
+pub(crate) async fn touch_files(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: &str, file_type: FileType) -> Result<(), ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
     library.touch_file(&PathBuf::from(path), file_type).await
         .map_err(|e| ResponseError::InternalServerError(JsonErrorResponse {
             code: "STORAGE_ERROR".to_string(),
@@ -52,10 +109,9 @@ pub(crate) async fn touch_files(libraries: &State<Arc<Mutex<LibraryManager>>>, l
 }
 
 #[get("/<library_id>/files/download?<path>")]
-pub(crate) async fn download_file(libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: &str) -> Result<Vec<u8>, ResponseError>   {
-    let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
-    match library.read_file(&PathBuf::from(path)).await
+pub(crate) async fn download_file(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: &str, headers: DownloadHeaders) -> Result<FileDownload, ResponseError>   {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    match FileDownload::open(&library, &PathBuf::from(path), ContentType::Binary, &headers).await
         .map_err(|e| ResponseError::GenericError)?
     {
         None => {
@@ -64,40 +120,227 @@ pub(crate) async fn download_file(libraries: &State<Arc<Mutex<LibraryManager>>>,
                 message: "Requested file does not exist".to_string()
             }))
         }
-        Some(contents) => {
-            // TODO: headers?
-            Ok(contents)
+        Some(download) => Ok(download)
+    }
+}
+
+#[derive(Serialize)]
+pub struct FilePathResponse {
+    /// The path the file was moved or copied to, which differs from the requested path when renamed to avoid a conflict
+    path: String,
+}
+
+/// Moves or renames a file or folder. When the destination exists, the move fails unless `conflict` is `overwrite` or `rename`
+#[post("/<library_id>/files/move?<from>&<to>&<conflict>")]
+pub(crate) async fn move_file(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, from: &str, to: &str, conflict: Option<ConflictPolicy>) -> Result<Json<FilePathResponse>, ResponseError>   {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let path = library.move_file(&PathBuf::from(from.trim_start_matches('/')), &PathBuf::from(to.trim_start_matches('/')), conflict.unwrap_or_default()).await
+        .map_err(storage_error)?;
+    Ok(Json(FilePathResponse { path: path.to_string_lossy().into_owned() }))
+}
+
+/// Copies a file or folder, into another library when `to_library` is set. Conflicts are handled like when moving
+#[post("/<library_id>/files/copy?<from>&<to>&<to_library>&<conflict>")]
+pub(crate) async fn copy_file(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, from: &str, to: &str, to_library: Option<&str>, conflict: Option<ConflictPolicy>) -> Result<Json<FilePathResponse>, ResponseError>   {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    let dest_library = libraries.lock().await.get_for_api_user(to_library.filter(|id| !id.is_empty()).unwrap_or(library_id), &user, PermissionLevel::ReadWrite).await?;
+    let path = library.copy_to(&PathBuf::from(from.trim_start_matches('/')), &dest_library, &PathBuf::from(to.trim_start_matches('/')), conflict.unwrap_or_default()).await
+        .map_err(storage_error)?;
+    Ok(Json(FilePathResponse { path: path.to_string_lossy().into_owned() }))
+}
+
+/// Downloads the files and folders as a single archive, or the whole library when no path is given
+#[get("/<library_id>/archive?<path>&<format>")]
+pub(crate) async fn download_archive(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: Vec<&str>, format: Option<ArchiveFormat>) -> Result<ArchiveDownload, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    let mut paths: Vec<PathBuf> = path.iter().map(|path| PathBuf::from(path.trim_start_matches('/'))).collect();
+    if paths.is_empty() {
+        paths.push(PathBuf::new());
+    }
+    ArchiveDownload::start(&library, &paths, format.unwrap_or_default()).await
+}
+
+/// Uploads the file to the path. With `extract`, the upload is a zip, tar or tar.gz archive that is extracted
+/// into the folder at the path, returning what was extracted and any entries that failed
+#[post("/<library_id>/files?<path>&<extract>", data = "<data>")]
+pub(crate) async fn upload_file(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, versions: &State<Arc<VersionManager>>, uploads: &State<Arc<UploadManager>>, library_id: &str, path: &str, extract: Option<bool>, data: Data<'_>) -> Result<Either<status::NoContent, Json<ExtractResult>>, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let mut stream = data.open(MAX_UPLOAD_SIZE);
+    if extract.unwrap_or(false) {
+        return uploads.extract(&library, &PathBuf::from(path), &mut stream, &user.user.id).await
+            .map(|result| Either::Right(Json(result)))
+    }
+    versions.write_file(&library, path, &mut stream, Some(&user.user.id)).await
+        .map_err(storage_error)?;
+    Ok(Either::Left(status::NoContent))
+}
+
+#[delete("/<library_id>/files/move?<path>")]
+pub(crate) async fn delete_file(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, trash: &State<Arc<TrashManager>>, library_id: &str, path: &str) -> Result<Json<TrashItemModel>, ResponseError>   {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    trash.trash(&library, path, &user.user.id).await
+        .map(Json)
+}
+
+#[get("/<library_id>/trash")]
+pub(crate) async fn list_trash(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, trash: &State<Arc<TrashManager>>, library_id: &str) -> Result<Json<Vec<TrashItemWithUserModel>>, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    trash.list(&library).await
+        .map(Json)
+        .map_err(database_error)
+}
+
+/// Moves the item back to its original path
+#[post("/<library_id>/trash/<item_id>/restore")]
+pub(crate) async fn restore_trash_item(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, trash: &State<Arc<TrashManager>>, library_id: &str, item_id: &str) -> Result<(), ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let item = trash.get(&library, item_id).await?;
+    trash.restore(&library, &item).await
+}
+
+/// Permanently deletes the item
+#[delete("/<library_id>/trash/<item_id>")]
+pub(crate) async fn purge_trash_item(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, trash: &State<Arc<TrashManager>>, library_id: &str, item_id: &str) -> Result<(), ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let item = trash.get(&library, item_id).await?;
+    trash.purge(&library, &item).await
+        .map_err(storage_error)
+}
+
+/// Permanently deletes everything in the trash
+#[delete("/<library_id>/trash")]
+pub(crate) async fn empty_trash(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, trash: &State<Arc<TrashManager>>, library_id: &str) -> Result<(), ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    trash.empty(&library).await
+        .map_err(|e| ResponseError::GenericError)
+}
+
+/// Lists the versions of the file, newest first
+#[get("/<library_id>/versions?<path>")]
+pub(crate) async fn list_versions(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, versions: &State<Arc<VersionManager>>, library_id: &str, path: &str) -> Result<Json<Vec<FileVersionWithUserModel>>, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    versions.list(&library, path).await
+        .map(Json)
+        .map_err(storage_error)
+}
+
+#[get("/<library_id>/versions/<version_id>/download")]
+pub(crate) async fn download_version(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, versions: &State<Arc<VersionManager>>, library_id: &str, version_id: &str, headers: DownloadHeaders) -> Result<FileDownload, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    let version = versions.get(&library, version_id).await?;
+    let contents_path = versions.get_contents_path(&library, &version).await
+        .map_err(storage_error)?;
+    match FileDownload::open_internal(&library, &contents_path, ContentType::Binary, &headers).await
+        .map_err(storage_error)?
+    {
+        None => {
+            Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "VERSION_CONTENTS_NOT_FOUND".to_string(),
+                message: "The contents of this version no longer exist".to_string()
+            }))
         }
+        Some(download) => Ok(download)
     }
 }
 
-#[post("/<library_id>/files/move?<from>&<to>")]
-pub(crate) async fn move_file(libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, from: &str, to: &str) -> Result<(), ResponseError>   {
+/// Makes the version the file's current contents
+#[post("/<library_id>/versions/<version_id>/restore")]
+pub(crate) async fn restore_version(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, versions: &State<Arc<VersionManager>>, library_id: &str, version_id: &str) -> Result<(), ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let version = versions.get(&library, version_id).await?;
+    versions.restore(&library, &version, &user.user.id).await
+        .map_err(|e| ResponseError::BadRequest(JsonErrorResponse {
+            code: "VERSION_RESTORE_FAILED".to_string(),
+            message: e.to_string()
+        }))?;
+    Ok(())
+}
+
+/// Sets how many previous versions of each file are kept and for how many days, omit either for no limit
+#[post("/<library_id>/versions/limits?<max_versions>&<retention_days>")]
+pub(crate) async fn set_version_limits(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, max_versions: Option<u32>, retention_days: Option<u32>) -> Result<(), ResponseError> {
     let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
-    library.move_file(&PathBuf::from(from), &PathBuf::from(to)).await
-        .map_err(|e| ResponseError::GenericError)
+    let library = libs.get_for_api_user(library_id, &user, PermissionLevel::Admin).await?;
+    libs.set_version_limits(&library, max_versions, retention_days).await
 }
 
-#[post("/<library_id>/files?<path>", data = "<data>")]
-pub(crate) async fn upload_file(libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: &str, data: Data<'_>) -> Result<status::NoContent, ResponseError> {
+#[get("/<library_id>/permissions")]
+pub(crate) async fn list_permissions(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str) -> Result<Json<Vec<LibraryShareModel>>, ResponseError> {
     let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
-    let mut stream = data.open(MAX_UPLOAD_SIZE);
-    // TODO: don't just copy all to memory
-    let mut buf = Vec::new();
-    stream.read_to_end(&mut buf).await.unwrap();
+    let library = libs.get_for_api_user(library_id, &user, PermissionLevel::Admin).await?;
+    libs.list_shares(&library).await
+        .map(Json)
+        .map_err(database_error)
+}
 
-    library.write_file(&PathBuf::from(path), &buf).await
-        .map_err(|e| ResponseError::GenericError)?;
-    Ok(status::NoContent)
+/// Shares the library with the user (by id, username or email) at the permission level
+#[post("/<library_id>/permissions?<user>&<level>")]
+pub(crate) async fn set_permission(auth_user: ApiUser, pool: &State<DB>, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, user: &str, level: PermissionLevel) -> Result<(), ResponseError> {
+    let libs = libraries.lock().await;
+    let library = libs.get_for_api_user(library_id, &auth_user, PermissionLevel::Admin).await?;
+    let Some(target) = models::user::find_user(pool, user).await.map_err(database_error)? else {
+        return Err(ResponseError::NotFound(JsonErrorResponse {
+            code: "USER_NOT_FOUND".to_string(),
+            message: "No user found with provided id, username or email".to_string()
+        }))
+    };
+    libs.share(&library, &target.id, level).await
 }
 
-#[delete("/<library_id>/files/move?<path>")]
-pub(crate) async fn delete_file(libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: &str) -> Result<(), ResponseError>   {
+#[delete("/<library_id>/permissions?<user_id>")]
+pub(crate) async fn remove_permission(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, user_id: &str) -> Result<(), ResponseError> {
     let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
-    library.delete_file(&PathBuf::from(path)).await
-        .map_err(|e| ResponseError::GenericError)
+    let library = libs.get_for_api_user(library_id, &user, PermissionLevel::Admin).await?;
+    libs.unshare(&library, user_id).await
+}
+
+#[get("/<library_id>/links")]
+pub(crate) async fn list_links(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, links: &State<Arc<ShareLinkManager>>, library_id: &str) -> Result<Json<Vec<ShareLinkWithUserModel>>, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::Admin).await?;
+    links.list(&library).await
+        .map(Json)
+        .map_err(database_error)
+}
+
+#[derive(FromForm)]
+pub struct LinkQuery<'r> {
+    path: &'r str,
+    mode: Option<ShareLinkMode>,
+    password: Option<&'r str>,
+    expires_days: Option<u32>,
+    max_downloads: Option<u32>,
+    ask_uploader: bool,
+}
+
+/// Creates a public link to the file or folder at the path, served at `/s/<token>`. `mode` is read (the default)
+/// or upload, for folders only, and the link can have a password, expire after a number of days, or after a number of downloads.
+/// Upload links with `ask_uploader` require uploaders to enter their name, and optionally their email
+#[post("/<library_id>/links?<query..>")]
+pub(crate) async fn create_link(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, links: &State<Arc<ShareLinkManager>>, library_id: &str, query: LinkQuery<'_>) -> Result<Json<ShareLinkModel>, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::Admin).await?;
+    let options = ShareLinkOptions {
+        path: query.path,
+        mode: query.mode.unwrap_or(ShareLinkMode::Read),
+        password: query.password,
+        expires_days: query.expires_days,
+        max_downloads: query.max_downloads,
+        ask_uploader: query.ask_uploader,
+    };
+    links.create(&library, &options, &user.user.id).await
+        .map(Json)
+}
+
+#[delete("/<library_id>/links/<link_id>")]
+pub(crate) async fn revoke_link(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, links: &State<Arc<ShareLinkManager>>, library_id: &str, link_id: Uuid) -> Result<(), ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::Admin).await?;
+    links.revoke(&library, &link_id).await
 }
 
+/// The latest files uploaded to the library through upload links, with who uploaded them
+#[get("/<library_id>/links/uploads")]
+pub(crate) async fn list_link_uploads(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, links: &State<Arc<ShareLinkManager>>, library_id: &str) -> Result<Json<Vec<ShareLinkUploadModel>>, ResponseError> {
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::Admin).await?;
+    links.uploads(&library).await
+        .map(Json)
+        .map_err(|_| ResponseError::GenericError)
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use log::error;
+use rocket::{get, post, State};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use sqlx::types::Uuid;
+use crate::guards::ApiUser;
+use crate::managers::notifications::NotificationManager;
+use crate::models::notification::NotificationModel;
+use crate::util::{JsonErrorResponse, ResponseError};
+
+#[derive(Serialize)]
+pub(crate) struct UnreadCount {
+    unread: i64,
+}
+
+/// The library the API token is scoped to, tokens scoped to a library only see and change its notifications
+fn token_library(user: &ApiUser) -> Option<Uuid> {
+    user.token.as_ref().and_then(|token| token.library_id)
+}
+
+/// Lists the user's notifications newest first, only the unread ones with `unread`
+#[get("/notifications?<unread>&<limit>&<offset>")]
+pub(crate) async fn list(user: ApiUser, notifications: &State<Arc<NotificationManager>>, unread: Option<bool>, limit: Option<u32>, offset: Option<u32>)
+    -> Result<Json<Vec<NotificationModel>>, ResponseError>
+{
+    notifications.list(&user.user.id, token_library(&user).as_ref(), unread.unwrap_or(false), limit, offset).await
+        .map(Json)
+        .map_err(|e| {
+            error!("Failed to list notifications of {}: {}", user.user.id, e);
+            ResponseError::GenericError
+        })
+}
+
+/// How many of the user's notifications are unread, shown on the bell in the navbar
+#[get("/notifications/unread")]
+pub(crate) async fn unread_count(user: ApiUser, notifications: &State<Arc<NotificationManager>>) -> Result<Json<UnreadCount>, ResponseError> {
+    notifications.unread_count(&user.user.id, token_library(&user).as_ref()).await
+        .map(|unread| Json(UnreadCount { unread }))
+        .map_err(|e| {
+            error!("Failed to count unread notifications of {}: {}", user.user.id, e);
+            ResponseError::GenericError
+        })
+}
+
+#[post("/notifications/<id>/read")]
+pub(crate) async fn mark_read(user: ApiUser, notifications: &State<Arc<NotificationManager>>, id: &str) -> Result<(), ResponseError> {
+    let id = Uuid::parse_str(id).map_err(|_| ResponseError::NotFound(JsonErrorResponse {
+        code: "NOTIFICATION_NOT_FOUND".to_string(),
+        message: "You have no notification with that id".to_string(),
+    }))?;
+    notifications.mark_read(&user.user.id, token_library(&user).as_ref(), &id).await
+}
+
+#[post("/notifications/read")]
+pub(crate) async fn mark_all_read(user: ApiUser, notifications: &State<Arc<NotificationManager>>) -> Result<(), ResponseError> {
+    notifications.mark_all_read(&user.user.id, token_library(&user).as_ref()).await
+        .map_err(|e| {
+            error!("Failed to mark notifications of {} read: {}", user.user.id, e);
+            ResponseError::GenericError
+        })
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+use rocket::{get, State};
+use rocket::serde::json::Json;
+use sqlx::types::Uuid;
+use tokio::sync::Mutex;
+use crate::guards::ApiUser;
+use crate::managers::libraries::LibraryManager;
+use crate::managers::search::{parse_date_filter, SearchFileType, SearchManager, SearchOptions};
+use crate::models::file_index::SearchResultModel;
+use crate::util::{JsonErrorResponse, ResponseError};
+
+/// Searches every library the user can access, or only `library`, by file name and path, and by contents if they are indexed
+#[get("/search?<q>&<library>&<type>&<min_size>&<max_size>&<modified_since>&<modified_until>&<limit>&<offset>")]
+pub(crate) async fn search(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, search: &State<Arc<SearchManager>>,
+    q: &str, library: Option<&str>, r#type: Option<SearchFileType>, min_size: Option<u64>, max_size: Option<u64>,
+    modified_since: Option<&str>, modified_until: Option<&str>, limit: Option<u32>, offset: Option<u32>
+) -> Result<Json<Vec<SearchResultModel>>, ResponseError> {
+    let library = library.filter(|library| !library.is_empty())
+        .map(Uuid::parse_str)
+        .transpose()
+        .map_err(|_| ResponseError::BadRequest(JsonErrorResponse {
+            code: "INVALID_LIBRARY".to_string(),
+            message: "Library must be a library id".to_string()
+        }))?;
+    let token_library = user.token.as_ref().and_then(|token| token.library_id);
+    let library_ids: Vec<Uuid> = libraries.lock().await.list(&user.user.id).await
+        .map_err(|_| ResponseError::GenericError)?
+        .into_iter()
+        .map(|model| model.id)
+        .filter(|id| library.is_none_or(|library| &library == id) && token_library.is_none_or(|library| &library == id))
+        .collect();
+    let options = SearchOptions {
+        query: q,
+        file_type: r#type,
+        min_size,
+        max_size,
+        modified_since: parse_date_filter(modified_since)?,
+        modified_until: parse_date_filter(modified_until)?,
+        limit,
+        offset,
+    };
+    search.search(&library_ids, &options).await
+        .map(Json)
+        .map_err(|_| ResponseError::GenericError)
+}
@@ -0,0 +1,305 @@
+use std::sync::Arc;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use rocket::{delete, head, options, patch, post, Data, Request, Response, State};
+use rocket::data::ToByteUnit;
+use rocket::http::{Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::Responder;
+use tokio::sync::Mutex;
+use crate::consts::MAX_UPLOAD_SIZE;
+use crate::guards::ApiUser;
+use crate::managers::libraries::LibraryManager;
+use crate::models::library::PermissionLevel;
+use crate::managers::uploads::UploadManager;
+use crate::models::upload::UploadModel;
+use crate::storage::StorageError;
+use crate::util::{database_error, format_http_date, JsonErrorResponse, ResponseError};
+
+/// The version of the tus protocol that is implemented, see https://tus.io/protocols/resumable-upload
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,creation-with-upload,termination,expiration";
+
+/// The tus headers sent with each request
+pub struct TusHeaders {
+    tus_resumable: Option<String>,
+    upload_length: Option<String>,
+    upload_offset: Option<String>,
+    upload_metadata: Option<String>,
+    content_type: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        Outcome::Success(TusHeaders {
+            tus_resumable: headers.get_one("Tus-Resumable").map(str::to_string),
+            upload_length: headers.get_one("Upload-Length").map(str::to_string),
+            upload_offset: headers.get_one("Upload-Offset").map(str::to_string),
+            upload_metadata: headers.get_one("Upload-Metadata").map(str::to_string),
+            content_type: headers.get_one("Content-Type").map(str::to_string),
+        })
+    }
+}
+
+impl TusHeaders {
+    fn check_version(&self) -> Result<(), ResponseError> {
+        if self.tus_resumable.as_deref() != Some(TUS_VERSION) {
+            return Err(ResponseError::PreconditionFailed(JsonErrorResponse {
+                code: "TUS_VERSION_UNSUPPORTED".to_string(),
+                message: format!("Only tus version {} is supported", TUS_VERSION)
+            }))
+        }
+        Ok(())
+    }
+
+    fn is_upload_data(&self) -> bool {
+        self.content_type.as_deref() == Some("application/offset+octet-stream")
+    }
+
+    /// Returns the length of the upload, deferred lengths aren't supported so it must be present
+    fn upload_length(&self) -> Result<u64, ResponseError> {
+        let Some(length) = self.upload_length.as_deref().and_then(parse_header_number) else {
+            return Err(ResponseError::BadRequest(JsonErrorResponse {
+                code: "UPLOAD_LENGTH_INVALID".to_string(),
+                message: "Upload-Length header is missing or invalid, deferred lengths are not supported".to_string()
+            }))
+        };
+        if length > MAX_UPLOAD_SIZE.as_u64() {
+            return Err(ResponseError::PayloadTooLarge(JsonErrorResponse {
+                code: "UPLOAD_TOO_LARGE".to_string(),
+                message: format!("Upload-Length exceeds the maximum size of {}", MAX_UPLOAD_SIZE)
+            }))
+        }
+        Ok(length)
+    }
+
+    fn upload_offset(&self) -> Result<u64, ResponseError> {
+        self.upload_offset.as_deref()
+            .and_then(parse_header_number)
+            .ok_or_else(|| ResponseError::BadRequest(JsonErrorResponse {
+                code: "UPLOAD_OFFSET_INVALID".to_string(),
+                message: "Upload-Offset header is missing or invalid".to_string()
+            }))
+    }
+
+    /// Returns the decoded value of the key in Upload-Metadata, which is formatted as `key base64value,key2 base64value`
+    fn metadata_value(&self, key: &str) -> Option<String> {
+        self.upload_metadata.as_deref()?
+            .split(',')
+            .filter_map(|pair| {
+                let mut parts = pair.trim().splitn(2, ' ');
+                Some((parts.next()?, parts.next().unwrap_or("")))
+            })
+            .find(|(pair_key, _)| *pair_key == key)
+            .and_then(|(_, value)| BASE64_STANDARD.decode(value).ok())
+            .and_then(|value| String::from_utf8(value).ok())
+    }
+}
+
+/// Parses the non-negative integers of Upload-Length and Upload-Offset, which unlike `u64::from_str` doesn't allow a sign
+fn parse_header_number(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None
+    }
+    value.parse().ok()
+}
+
+/// A response to a tus request, which always includes the Tus-Resumable header
+pub struct TusResponse {
+    status: Status,
+    headers: Vec<Header<'static>>,
+}
+
+impl TusResponse {
+    fn new(status: Status) -> Self {
+        TusResponse { status, headers: Vec::new() }
+    }
+
+    fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.headers.push(Header::new(name, value.to_string()));
+        self
+    }
+
+    fn upload_headers(self, upload: &UploadModel) -> Self {
+        self.header("Upload-Offset", upload.upload_offset)
+            .header("Upload-Expires", format_http_date(&upload.expires_at.and_utc()))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let mut builder = Response::build();
+        builder.status(self.status);
+        builder.raw_header("Tus-Resumable", TUS_VERSION);
+        for header in self.headers {
+            builder.header_adjoin(header);
+        }
+        builder.ok()
+    }
+}
+
+fn upload_not_found() -> ResponseError {
+    ResponseError::NotFound(JsonErrorResponse {
+        code: "UPLOAD_NOT_FOUND".to_string(),
+        message: "Upload does not exist or has expired".to_string()
+    })
+}
+
+#[options("/<_>/uploads")]
+pub(crate) async fn options() -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", TUS_EXTENSIONS)
+        .header("Tus-Max-Size", MAX_UPLOAD_SIZE.as_u64())
+}
+
+/// Creates a new upload for the file at `path`, which can alternatively be provided as the `path` key of Upload-Metadata
+#[post("/<library_id>/uploads?<path>", data = "<data>")]
+pub(crate) async fn create(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, uploads: &State<Arc<UploadManager>>, library_id: &str, path: Option<&str>, headers: TusHeaders, data: Data<'_>) -> Result<TusResponse, ResponseError> {
+    headers.check_version()?;
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let length = headers.upload_length()?;
+    let Some(path) = path.map(str::to_string).or_else(|| headers.metadata_value("path")).filter(|path| !path.is_empty()) else {
+        return Err(ResponseError::BadRequest(JsonErrorResponse {
+            code: "UPLOAD_PATH_MISSING".to_string(),
+            message: "No path was provided for the upload".to_string()
+        }))
+    };
+    let mut upload = uploads.create(&library, &path, length, headers.upload_metadata.as_deref(), &user.user.id).await
+        .map_err(|e| match e.downcast::<StorageError>() {
+            Ok(e) => e.into(),
+            Err(e) => ResponseError::InternalServerError(JsonErrorResponse {
+                code: "UPLOAD_CREATE_FAILED".to_string(),
+                message: e.to_string(),
+            })
+        })?;
+    // creation-with-upload, the request can already contain the first chunk of data
+    if headers.is_upload_data() {
+        let mut stream = data.open((length + 1).bytes());
+        upload = uploads.append(&library, &upload, 0, &mut stream, &user.user.id).await?;
+    } else if length == 0 {
+        uploads.finish(&library, &upload, &user.user.id).await?;
+    }
+    Ok(TusResponse::new(Status::Created)
+        .header("Location", format!("/api/library/{}/uploads/{}", library_id, upload.id))
+        .upload_headers(&upload))
+}
+
+#[head("/<library_id>/uploads/<upload_id>")]
+pub(crate) async fn status(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, uploads: &State<Arc<UploadManager>>, library_id: &str, upload_id: &str, headers: TusHeaders) -> Result<TusResponse, ResponseError> {
+    headers.check_version()?;
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let upload = uploads.get(&library, upload_id, &user.user.id).await
+        .map_err(database_error)?
+        .ok_or_else(upload_not_found)?;
+    let mut response = TusResponse::new(Status::Ok)
+        .header("Upload-Length", upload.upload_length)
+        .header("Cache-Control", "no-store")
+        .upload_headers(&upload);
+    if let Some(metadata) = &upload.metadata {
+        response = response.header("Upload-Metadata", metadata);
+    }
+    Ok(response)
+}
+
+#[patch("/<library_id>/uploads/<upload_id>", data = "<data>")]
+pub(crate) async fn append(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, uploads: &State<Arc<UploadManager>>, library_id: &str, upload_id: &str, headers: TusHeaders, data: Data<'_>) -> Result<TusResponse, ResponseError> {
+    headers.check_version()?;
+    if !headers.is_upload_data() {
+        return Err(ResponseError::UnsupportedMediaType(JsonErrorResponse {
+            code: "UPLOAD_CONTENT_TYPE_INVALID".to_string(),
+            message: "Content-Type must be application/offset+octet-stream".to_string()
+        }))
+    }
+    let offset = headers.upload_offset()?;
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let upload = uploads.get(&library, upload_id, &user.user.id).await
+        .map_err(database_error)?
+        .ok_or_else(upload_not_found)?;
+    let upload = if offset == upload.upload_length as u64 && upload.upload_offset == upload.upload_length {
+        // All data was already received, but the upload could not be written to the library
+        uploads.finish(&library, &upload, &user.user.id).await?;
+        upload
+    } else {
+        // Read one byte past the length, so uploads sending too much data can be detected
+        let remaining = (upload.upload_length as u64).saturating_sub(offset);
+        let mut stream = data.open((remaining + 1).bytes());
+        uploads.append(&library, &upload, offset, &mut stream, &user.user.id).await?
+    };
+    Ok(TusResponse::new(Status::NoContent).upload_headers(&upload))
+}
+
+#[delete("/<library_id>/uploads/<upload_id>")]
+pub(crate) async fn terminate(user: ApiUser, libraries: &State<Arc<Mutex<LibraryManager>>>, uploads: &State<Arc<UploadManager>>, library_id: &str, upload_id: &str, headers: TusHeaders) -> Result<TusResponse, ResponseError> {
+    headers.check_version()?;
+    let library = libraries.lock().await.get_for_api_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let upload = uploads.get(&library, upload_id, &user.user.id).await
+        .map_err(database_error)?
+        .ok_or_else(upload_not_found)?;
+    uploads.terminate(&library, &upload).await?;
+    Ok(TusResponse::new(Status::NoContent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(upload_length: Option<&str>, upload_offset: Option<&str>, upload_metadata: Option<&str>) -> TusHeaders {
+        TusHeaders {
+            tus_resumable: Some(TUS_VERSION.to_string()),
+            upload_length: upload_length.map(str::to_string),
+            upload_offset: upload_offset.map(str::to_string),
+            upload_metadata: upload_metadata.map(str::to_string),
+            content_type: None,
+        }
+    }
+
+    #[test]
+    fn parses_upload_offset() {
+        assert_eq!(headers(None, Some("0"), None).upload_offset().unwrap(), 0);
+        assert_eq!(headers(None, Some("1048576"), None).upload_offset().unwrap(), 1048576);
+        for offset in ["", "-1", "+1", " 1", "1 ", "1.5", "0x10", "abc", "18446744073709551616"] {
+            assert!(headers(None, Some(offset), None).upload_offset().is_err(), "{:?} was accepted", offset);
+        }
+        assert!(headers(None, None, None).upload_offset().is_err());
+    }
+
+    #[test]
+    fn parses_upload_length() {
+        assert_eq!(headers(Some("0"), None, None).upload_length().unwrap(), 0);
+        assert_eq!(headers(Some("100"), None, None).upload_length().unwrap(), 100);
+        assert_eq!(headers(Some(&MAX_UPLOAD_SIZE.as_u64().to_string()), None, None).upload_length().unwrap(), MAX_UPLOAD_SIZE.as_u64());
+        for length in ["", "-1", "+1", "1e3", "abc", "18446744073709551616"] {
+            assert!(matches!(headers(Some(length), None, None).upload_length(), Err(ResponseError::BadRequest(_))), "{:?} was accepted", length);
+        }
+        assert!(matches!(headers(None, None, None).upload_length(), Err(ResponseError::BadRequest(_))));
+        let too_large = (MAX_UPLOAD_SIZE.as_u64() + 1).to_string();
+        assert!(matches!(headers(Some(&too_large), None, None).upload_length(), Err(ResponseError::PayloadTooLarge(_))));
+    }
+
+    #[test]
+    fn decodes_metadata_values() {
+        // "path" is "a/b.txt" and "name" is "b.txt"
+        let headers = headers(None, None, Some("path YS9iLnR4dA==, name Yi50eHQ=,empty,invalid !!!,binary /w=="));
+        assert_eq!(headers.metadata_value("path").as_deref(), Some("a/b.txt"));
+        assert_eq!(headers.metadata_value("name").as_deref(), Some("b.txt"));
+        assert_eq!(headers.metadata_value("empty").as_deref(), Some(""));
+        assert_eq!(headers.metadata_value("invalid"), None);
+        assert_eq!(headers.metadata_value("binary"), None);
+        assert_eq!(headers.metadata_value("missing"), None);
+    }
+
+    #[test]
+    fn checks_tus_version() {
+        assert!(headers(None, None, None).check_version().is_ok());
+        let mut headers = headers(None, None, None);
+        headers.tus_resumable = Some("0.2.2".to_string());
+        assert!(headers.check_version().is_err());
+        headers.tus_resumable = None;
+        assert!(headers.check_version().is_err());
+    }
+}
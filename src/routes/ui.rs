@@ -1,4 +1,11 @@
 pub mod user;
 pub mod help;
 pub(crate) mod auth;
-pub mod admin;
\ No newline at end of file
+pub mod admin;
+pub mod library;
+pub mod settings;
+pub mod search;
+pub mod share;
+pub mod favorites;
+pub mod activity;
+pub mod notifications;
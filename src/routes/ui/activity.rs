@@ -0,0 +1,103 @@
+use std::sync::Arc;
+use openidconnect::url::form_urlencoded;
+use rocket::{get, FromForm, Route, State};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use crate::guards::AuthUser;
+use crate::managers::activity::{ActivityManager, ActivityOptions};
+use crate::managers::libraries::LibraryManager;
+use crate::managers::search::parse_date_filter;
+use crate::models::activity::{ActivityAction, ActivityModel};
+use crate::util::ResponseError;
+
+const ACTIVITY_PER_PAGE: u32 = 50;
+
+/// The filters of the activity feed, sent back to fill in the form
+#[derive(Debug, Default, Serialize, FromForm)]
+pub struct ActivityQuery {
+    library: Option<String>,
+    action: Option<ActivityAction>,
+    user: Option<String>,
+    path: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    page: Option<u32>,
+}
+
+impl ActivityQuery {
+    /// The query string of the filters without the page, to link to other pages
+    fn to_query_string(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        let values = [
+            ("library", self.library.clone()),
+            ("action", self.action.map(|action| action.as_str().to_string())),
+            ("user", self.user.clone()),
+            ("path", self.path.clone()),
+            ("since", self.since.clone()),
+            ("until", self.until.clone()),
+        ];
+        for (key, value) in values {
+            if let Some(value) = value.filter(|value| !value.is_empty()) {
+                query.append_pair(key, &value);
+            }
+        }
+        query.finish()
+    }
+}
+
+#[derive(Serialize)]
+struct ActivityView {
+    activity: ActivityModel,
+    /// The folder the path is in, empty for the root of the library
+    parent: String,
+}
+
+/// The changes made to the libraries the user can access, or to one library when filtered by it
+#[get("/activity?<query..>")]
+pub async fn activity_page(
+    user: AuthUser,
+    route: &Route,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    activity: &State<Arc<ActivityManager>>,
+    query: ActivityQuery,
+) -> Result<Template, ResponseError> {
+    let page = query.page.unwrap_or(1).max(1);
+    let libraries = libraries.lock().await;
+    let user_libraries = libraries.list(&user.session.user.id).await
+        .map_err(|_| ResponseError::GenericError)?;
+    let library_ids: Vec<_> = user_libraries.iter()
+        .map(|library| library.id)
+        .filter(|id| query.library.as_deref().is_none_or(|library| library.is_empty() || library == id.to_string()))
+        .collect();
+    let admin_ids = libraries.admin_library_ids(&user_libraries, &user.session.user.id).await
+        .map_err(|_| ResponseError::GenericError)?;
+    drop(libraries);
+    let options = ActivityOptions {
+        action: query.action,
+        user: query.user.as_deref(),
+        path: query.path.as_deref(),
+        since: parse_date_filter(query.since.as_deref())?,
+        until: parse_date_filter(query.until.as_deref())?,
+        limit: Some(ACTIVITY_PER_PAGE),
+        offset: Some((page - 1) * ACTIVITY_PER_PAGE),
+    };
+    let entries: Vec<_> = activity.list(&library_ids, &admin_ids, &options).await
+        .map_err(|_| ResponseError::GenericError)?
+        .into_iter()
+        .map(|activity| ActivityView {
+            parent: activity.path.rsplit_once('/').map(|(parent, _)| parent.to_string()).unwrap_or_default(),
+            activity,
+        })
+        .collect();
+    Ok(Template::render("activity", context! {
+        session: user.session,
+        route: route.uri.path(),
+        libraries: user_libraries,
+        page_query: query.to_query_string(),
+        previous_page: (page > 1).then(|| page - 1),
+        next_page: (entries.len() as u32 == ACTIVITY_PER_PAGE).then(|| page + 1),
+        form: query,
+        entries,
+    }))
+}
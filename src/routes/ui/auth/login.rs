@@ -7,7 +7,8 @@ use rocket_dyn_templates::{context, Template};
 use rocket_session_store::Session;
 use crate::{GlobalMetadata, LoginSessionData, SessionData, DB};
 use crate::config::AppConfig;
-use crate::consts::{APP_METADATA, DISABLE_LOGIN_CHECK};
+use crate::consts::APP_METADATA;
+use crate::guards::UserAgent;
 use crate::managers::user::UsersState;
 use crate::models::user::try_login_user_form;
 use crate::routes::ui::auth::HackyRedirectBecauseRocketBug;
@@ -52,6 +53,7 @@ struct LoginForm<'r> {
 pub async fn handler(
     route: &Route,
     ip_addr: IpAddr,
+    user_agent: UserAgent,
     session: Session<'_, SessionData>,
     mut form: Form<Contextual<'_, LoginForm<'_>>>,
     users: &State<UsersState>,
@@ -60,7 +62,7 @@ pub async fn handler(
 ) -> Result<HackyRedirectBecauseRocketBug, Template> {
     trace!("handler");
     validate_csrf_form(&mut form.context, &session).await;
-    let user = try_login_user_form(&mut form.context, users.inner(), ip_addr, &session).await.ok();
+    let user = try_login_user_form(&mut form.context, users.inner(), ip_addr, user_agent.0, &session).await.ok();
     // TODO: use new users fetch user
     trace!("check form");
     if form.context.status() == Status::Ok {
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+use rocket::{get, Route, State};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+use crate::guards::AuthUser;
+use crate::managers::favorites::FavoriteManager;
+use crate::models::favorite::FavoriteModel;
+use crate::util::ResponseError;
+
+#[derive(Serialize)]
+struct FavoriteView {
+    favorite: FavoriteModel,
+    name: String,
+    /// The folder the favorite is in, empty for the root of the library
+    parent: String,
+}
+
+#[get("/favorites")]
+pub async fn favorites_page(user: AuthUser, route: &Route, favorites: &State<Arc<FavoriteManager>>) -> Result<Template, ResponseError> {
+    let (libraries, files): (Vec<_>, Vec<_>) = favorites.list(&user.session.user.id).await
+        .map_err(|_| ResponseError::GenericError)?
+        .into_iter()
+        .map(|favorite| FavoriteView {
+            name: favorite.path.rsplit('/').next().unwrap_or_default().to_string(),
+            parent: favorite.path.rsplit_once('/').map(|(parent, _)| parent.to_string()).unwrap_or_default(),
+            favorite,
+        })
+        .partition(|view| view.favorite.path.is_empty());
+    Ok(Template::render("favorites", context! {
+        session: user.session,
+        route: route.uri.path(),
+        libraries,
+        files,
+    }))
+}
@@ -0,0 +1,461 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use rocket::{get, post, uri, FromForm, Route, State};
+use rocket::form::{Context, Contextual, Error, Form};
+use rocket::http::ContentType;
+use rocket::response::Redirect;
+use rocket_dyn_templates::{context, Template};
+use rocket_session_store::Session;
+use sqlx::types::Uuid;
+use tokio::sync::Mutex;
+use crate::{models, SessionData, DB};
+use crate::archive::{ArchiveDownload, ArchiveFormat};
+use crate::download::{DownloadHeaders, FileDownload};
+use crate::guards::AuthUser;
+use crate::managers::libraries::LibraryManager;
+use crate::managers::share_links::{ShareLinkManager, ShareLinkOptions};
+use crate::managers::trash::TrashManager;
+use crate::managers::versions::VersionManager;
+use crate::models::library::PermissionLevel;
+use crate::models::share_link::ShareLinkMode;
+use crate::objs::library::Library;
+use crate::util::{database_error, set_csrf, storage_error, validate_csrf_form, JsonErrorResponse, ResponseError};
+
+/// `path` fills in the file or folder to create a share link for
+#[get("/libraries/<library_id>/share?<path>")]
+pub async fn share_page(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    links: &State<Arc<ShareLinkManager>>,
+    library_id: &str,
+    path: Option<&str>,
+) -> Result<Template, ResponseError> {
+    let libs = libraries.lock().await;
+    let library = libs.get_for_auth_user(library_id, &user, PermissionLevel::Admin).await?;
+    render_share_page(&libs, links, &library, user, route, &session, Context::default(), path).await
+}
+
+/// `link_path` fills in the path of a new share link, when the form has no value for it
+async fn render_share_page(libs: &LibraryManager, links: &ShareLinkManager, library: &Library, user: AuthUser, route: &Route, session: &Session<'_, SessionData>, form: Context<'_>, link_path: Option<&str>)
+    -> Result<Template, ResponseError>
+{
+    let shares = libs.list_shares(library).await
+        .map_err(database_error)?;
+    let share_links = links.list(library).await
+        .map_err(database_error)?;
+    let link_uploads = links.uploads(library).await
+        .map_err(database_error)?;
+    let csrf_token = set_csrf(session).await;
+    Ok(Template::render("library_share", context! {
+        session: user.session,
+        route: route.uri.path(),
+        csrf_token,
+        library: library.model(),
+        shares,
+        share_links,
+        link_uploads,
+        public_url: links.public_url(),
+        link_path,
+        form: &form,
+    }))
+}
+
+#[derive(FromForm, Debug)]
+struct ShareForm<'r> {
+    _csrf: &'r str,
+    /// The user's username or email
+    #[field(validate = len(1..))]
+    user: &'r str,
+    level: PermissionLevel,
+}
+
+#[post("/libraries/<library_id>/share", data = "<form>")]
+pub async fn share_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    pool: &State<DB>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    links: &State<Arc<ShareLinkManager>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, ShareForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let libs = libraries.lock().await;
+    let library = libs.get_for_auth_user(library_id, &user, PermissionLevel::Admin).await?;
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        match models::user::find_user(pool, value.user).await.map_err(database_error)? {
+            None => form.context.push_error(Error::validation("No user found with that username or email").with_name("user")),
+            Some(target) => match libs.share(&library, &target.id, value.level).await {
+                Ok(()) => return Ok(Ok(Redirect::to(uri!(share_page(library_id, _))))),
+                Err(ResponseError::BadRequest(e)) => form.context.push_error(Error::validation(e.message).with_name("user")),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+    render_share_page(&libs, links, &library, user, route, &session, form.into_inner().context, None).await.map(Err)
+}
+
+#[derive(FromForm, Debug)]
+struct UnshareForm<'r> {
+    _csrf: &'r str,
+    user_id: &'r str,
+}
+
+#[post("/libraries/<library_id>/share/remove", data = "<form>")]
+pub async fn unshare_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    links: &State<Arc<ShareLinkManager>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, UnshareForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let libs = libraries.lock().await;
+    let library = libs.get_for_auth_user(library_id, &user, PermissionLevel::Admin).await?;
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        libs.unshare(&library, value.user_id).await?;
+        return Ok(Ok(Redirect::to(uri!(share_page(library_id, _)))))
+    }
+    render_share_page(&libs, links, &library, user, route, &session, form.into_inner().context, None).await.map(Err)
+}
+
+#[derive(FromForm, Debug)]
+struct LinkForm<'r> {
+    _csrf: &'r str,
+    path: &'r str,
+    mode: ShareLinkMode,
+    password: Option<&'r str>,
+    expires_days: Option<u32>,
+    max_downloads: Option<u32>,
+    ask_uploader: bool,
+}
+
+#[post("/libraries/<library_id>/share/links", data = "<form>")]
+pub async fn create_link_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    links: &State<Arc<ShareLinkManager>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, LinkForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let libs = libraries.lock().await;
+    let library = libs.get_for_auth_user(library_id, &user, PermissionLevel::Admin).await?;
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        let options = ShareLinkOptions {
+            path: value.path,
+            mode: value.mode,
+            password: value.password,
+            expires_days: value.expires_days,
+            max_downloads: value.max_downloads,
+            ask_uploader: value.ask_uploader,
+        };
+        match links.create(&library, &options, &user.session.user.id).await {
+            Ok(_) => return Ok(Ok(Redirect::to(uri!(share_page(library_id, _))))),
+            Err(ResponseError::BadRequest(e) | ResponseError::NotFound(e)) => form.context.push_error(Error::validation(e.message).with_name("path")),
+            Err(e) => return Err(e)
+        }
+    }
+    render_share_page(&libs, links, &library, user, route, &session, form.into_inner().context, None).await.map(Err)
+}
+
+#[derive(FromForm, Debug)]
+struct RevokeLinkForm<'r> {
+    _csrf: &'r str,
+    link_id: Uuid,
+}
+
+#[post("/libraries/<library_id>/share/links/revoke", data = "<form>")]
+pub async fn revoke_link_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    links: &State<Arc<ShareLinkManager>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, RevokeLinkForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let libs = libraries.lock().await;
+    let library = libs.get_for_auth_user(library_id, &user, PermissionLevel::Admin).await?;
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        links.revoke(&library, &value.link_id).await?;
+        return Ok(Ok(Redirect::to(uri!(share_page(library_id, _)))))
+    }
+    render_share_page(&libs, links, &library, user, route, &session, form.into_inner().context, None).await.map(Err)
+}
+
+#[get("/libraries/<library_id>/trash")]
+pub async fn trash_page(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    trash: &State<Arc<TrashManager>>,
+    library_id: &str
+) -> Result<Template, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    render_trash_page(trash, &library, user, route, &session, Context::default()).await
+}
+
+async fn render_trash_page(trash: &TrashManager, library: &Library, user: AuthUser, route: &Route, session: &Session<'_, SessionData>, form: Context<'_>)
+    -> Result<Template, ResponseError>
+{
+    let items = trash.list(library).await
+        .map_err(database_error)?;
+    let csrf_token = set_csrf(session).await;
+    Ok(Template::render("library_trash", context! {
+        session: user.session,
+        route: route.uri.path(),
+        csrf_token,
+        library: library.model(),
+        items,
+        form: &form,
+    }))
+}
+
+#[derive(FromForm, Debug)]
+struct TrashItemForm<'r> {
+    _csrf: &'r str,
+    item_id: &'r str,
+}
+
+#[post("/libraries/<library_id>/trash/restore", data = "<form>")]
+pub async fn restore_trash_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    trash: &State<Arc<TrashManager>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, TrashItemForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        let item = trash.get(&library, value.item_id).await?;
+        match trash.restore(&library, &item).await {
+            Ok(()) => return Ok(Ok(Redirect::to(uri!(trash_page(library_id))))),
+            Err(ResponseError::Conflict(e)) => form.context.push_error(Error::validation(e.message)),
+            Err(e) => return Err(e)
+        }
+    }
+    render_trash_page(trash, &library, user, route, &session, form.into_inner().context).await.map(Err)
+}
+
+#[post("/libraries/<library_id>/trash/purge", data = "<form>")]
+pub async fn purge_trash_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    trash: &State<Arc<TrashManager>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, TrashItemForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        let item = trash.get(&library, value.item_id).await?;
+        trash.purge(&library, &item).await
+            .map_err(storage_error)?;
+        return Ok(Ok(Redirect::to(uri!(trash_page(library_id)))))
+    }
+    render_trash_page(trash, &library, user, route, &session, form.into_inner().context).await.map(Err)
+}
+
+#[derive(FromForm, Debug)]
+struct EmptyTrashForm<'r> {
+    _csrf: &'r str,
+}
+
+#[post("/libraries/<library_id>/trash/empty", data = "<form>")]
+pub async fn empty_trash_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    trash: &State<Arc<TrashManager>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, EmptyTrashForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    if validate_csrf_form(&mut form.context, &session).await && form.value.is_some() {
+        trash.empty(&library).await
+            .map_err(storage_error)?;
+        return Ok(Ok(Redirect::to(uri!(trash_page(library_id)))))
+    }
+    render_trash_page(trash, &library, user, route, &session, form.into_inner().context).await.map(Err)
+}
+
+#[get("/libraries/<library_id>/versions?<path>")]
+pub async fn versions_page(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    versions: &State<Arc<VersionManager>>,
+    library_id: &str,
+    path: &str,
+) -> Result<Template, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    render_versions_page(versions, &library, path, user, route, &session, Context::default()).await
+}
+
+async fn render_versions_page(versions: &VersionManager, library: &Library, path: &str, user: AuthUser, route: &Route, session: &Session<'_, SessionData>, form: Context<'_>)
+    -> Result<Template, ResponseError>
+{
+    let path = path.trim_start_matches('/');
+    let list = versions.list(library, path).await
+        .map_err(storage_error)?;
+    let csrf_token = set_csrf(session).await;
+    Ok(Template::render("library_versions", context! {
+        session: user.session,
+        route: route.uri.path(),
+        csrf_token,
+        library: library.model(),
+        path,
+        versions: list,
+        form: &form,
+    }))
+}
+
+#[get("/libraries/<library_id>/versions/<version_id>/download")]
+pub async fn download_version(
+    user: AuthUser,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    versions: &State<Arc<VersionManager>>,
+    library_id: &str,
+    version_id: &str,
+    headers: DownloadHeaders
+) -> Result<FileDownload, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    let version = versions.get(&library, version_id).await?;
+    let contents_path = versions.get_contents_path(&library, &version).await
+        .map_err(storage_error)?;
+    let path = PathBuf::from(&version.path);
+    let file_type = path.extension()
+        .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+        .unwrap_or(ContentType::Binary);
+    match FileDownload::open_internal(&library, &contents_path, file_type, &headers).await
+        .map_err(storage_error)?
+    {
+        None => {
+            Err(ResponseError::NotFound(JsonErrorResponse {
+                code: "VERSION_CONTENTS_NOT_FOUND".to_string(),
+                message: "The contents of this version no longer exist".to_string()
+            }))
+        }
+        Some(download) => {
+            let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            Ok(download.with_disposition(format!("attachment; filename=\"{}\"", file_name)))
+        }
+    }
+}
+
+/// Downloads the selected files and folders of the file list as an archive, or the folder that is open when nothing is selected
+#[get("/libraries/<library_id>/archive?<path>&<folder>&<format>")]
+pub async fn download_archive(
+    user: AuthUser,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    library_id: &str,
+    path: Vec<&str>,
+    folder: Option<&str>,
+    format: Option<ArchiveFormat>,
+) -> Result<ArchiveDownload, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::ReadOnly).await?;
+    let mut paths: Vec<PathBuf> = path.iter().map(|path| PathBuf::from(path.trim_start_matches('/'))).collect();
+    if paths.is_empty() {
+        paths.push(PathBuf::from(folder.unwrap_or_default().trim_matches('/')));
+    }
+    ArchiveDownload::start(&library, &paths, format.unwrap_or_default()).await
+}
+
+#[derive(FromForm, Debug)]
+struct RestoreVersionForm<'r> {
+    _csrf: &'r str,
+    version_id: &'r str,
+}
+
+#[post("/libraries/<library_id>/versions/restore", data = "<form>")]
+pub async fn restore_version_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    versions: &State<Arc<VersionManager>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, RestoreVersionForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::ReadWrite).await?;
+    let Some(value) = &form.value else {
+        return Err(ResponseError::BadRequest(JsonErrorResponse {
+            code: "INVALID_FORM".to_string(),
+            message: "Missing version to restore".to_string()
+        }))
+    };
+    let version = versions.get(&library, value.version_id).await?;
+    if validate_csrf_form(&mut form.context, &session).await {
+        match versions.restore(&library, &version, &user.session.user.id).await {
+            Ok(_) => return Ok(Ok(Redirect::to(uri!(versions_page(library_id, &version.path))))),
+            Err(e) => form.context.push_error(Error::validation(e.to_string()))
+        }
+    }
+    render_versions_page(versions, &library, &version.path, user, route, &session, form.into_inner().context).await.map(Err)
+}
+
+#[get("/libraries/<library_id>/settings")]
+pub async fn settings_page(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    library_id: &str
+) -> Result<Template, ResponseError> {
+    let library = libraries.lock().await.get_for_auth_user(library_id, &user, PermissionLevel::Admin).await?;
+    render_settings_page(&library, user, route, &session, Context::default()).await
+}
+
+async fn render_settings_page(library: &Library, user: AuthUser, route: &Route, session: &Session<'_, SessionData>, form: Context<'_>)
+    -> Result<Template, ResponseError>
+{
+    let csrf_token = set_csrf(session).await;
+    Ok(Template::render("library_settings", context! {
+        session: user.session,
+        route: route.uri.path(),
+        csrf_token,
+        library: library.model(),
+        form: &form,
+    }))
+}
+
+#[derive(FromForm, Debug)]
+struct VersionLimitsForm<'r> {
+    _csrf: &'r str,
+    /// Left empty to keep every version
+    max_versions: Option<u32>,
+    /// Left empty to keep versions forever
+    retention_days: Option<u32>,
+}
+
+#[post("/libraries/<library_id>/settings/versions", data = "<form>")]
+pub async fn version_limits_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    library_id: &str,
+    mut form: Form<Contextual<'_, VersionLimitsForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    let libs = libraries.lock().await;
+    let library = libs.get_for_auth_user(library_id, &user, PermissionLevel::Admin).await?;
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        match libs.set_version_limits(&library, value.max_versions, value.retention_days).await {
+            Ok(()) => return Ok(Ok(Redirect::to(uri!(settings_page(library_id))))),
+            Err(ResponseError::BadRequest(e)) => form.context.push_error(Error::validation(e.message)),
+            Err(e) => return Err(e)
+        }
+    }
+    render_settings_page(&library, user, route, &session, form.into_inner().context).await.map(Err)
+}
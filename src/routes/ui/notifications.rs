@@ -0,0 +1,108 @@
+use std::sync::Arc;
+use rocket::{get, post, FromForm, Route, State};
+use rocket::form::{Context, Contextual, Form};
+use rocket::response::Redirect;
+use rocket_dyn_templates::{context, Template};
+use rocket_session_store::Session;
+use serde::Serialize;
+use sqlx::types::Uuid;
+use crate::SessionData;
+use crate::guards::AuthUser;
+use crate::managers::notifications::NotificationManager;
+use crate::models::notification::{NotificationKind, NotificationModel};
+use crate::util::{set_csrf, validate_csrf_form, ResponseError};
+
+const NOTIFICATIONS_PER_PAGE: u32 = 50;
+
+#[derive(Serialize)]
+struct NotificationView {
+    notification: NotificationModel,
+    /// Where the notification links to
+    url: String,
+}
+
+/// The page the notification is about: the library that was shared, the folder a file was uploaded to,
+/// or the share page of the library with the link
+fn notification_url(notification: &NotificationModel) -> String {
+    let library = format!("{}/{}", notification.library_id, notification.library_name);
+    match notification.kind {
+        NotificationKind::LibraryShared => format!("/library/{}/", library),
+        NotificationKind::DropUpload => {
+            let parent = notification.path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or_default();
+            format!("/library/{}/{}", library, parent)
+        },
+        NotificationKind::LinkAccessed => format!("/libraries/{}/share", notification.library_id),
+    }
+}
+
+#[get("/notifications?<unread>&<page>")]
+pub async fn notifications_page(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    notifications: &State<Arc<NotificationManager>>,
+    unread: Option<bool>,
+    page: Option<u32>,
+) -> Result<Template, ResponseError> {
+    render_notifications(user, route, &session, notifications, unread.unwrap_or(false), page.unwrap_or(1), Context::default()).await
+}
+
+async fn render_notifications(
+    user: AuthUser,
+    route: &Route,
+    session: &Session<'_, SessionData>,
+    notifications: &NotificationManager,
+    unread: bool,
+    page: u32,
+    form: Context<'_>,
+) -> Result<Template, ResponseError> {
+    let page = page.max(1);
+    let entries: Vec<_> = notifications.list(&user.session.user.id, None, unread, Some(NOTIFICATIONS_PER_PAGE), Some((page - 1) * NOTIFICATIONS_PER_PAGE)).await
+        .map_err(|_| ResponseError::GenericError)?
+        .into_iter()
+        .map(|notification| NotificationView {
+            url: notification_url(&notification),
+            notification,
+        })
+        .collect();
+    let unread_count = notifications.unread_count(&user.session.user.id, None).await
+        .map_err(|_| ResponseError::GenericError)?;
+    let csrf_token = set_csrf(session).await;
+    Ok(Template::render("notifications", context! {
+        session: user.session,
+        route: route.uri.path(),
+        csrf_token,
+        unread,
+        unread_count,
+        previous_page: (page > 1).then(|| page - 1),
+        next_page: (entries.len() as u32 == NOTIFICATIONS_PER_PAGE).then(|| page + 1),
+        entries,
+        form: &form,
+    }))
+}
+
+#[derive(FromForm, Debug)]
+struct MarkReadForm<'r> {
+    _csrf: &'r str,
+    /// The notification to mark as read, every notification when empty
+    id: Option<Uuid>,
+}
+
+#[post("/notifications/read", data = "<form>")]
+pub async fn mark_read_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    notifications: &State<Arc<NotificationManager>>,
+    mut form: Form<Contextual<'_, MarkReadForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        match &value.id {
+            Some(id) => notifications.mark_read(&user.session.user.id, None, id).await?,
+            None => notifications.mark_all_read(&user.session.user.id, None).await
+                .map_err(|_| ResponseError::GenericError)?
+        }
+        return Ok(Ok(Redirect::to("/notifications")))
+    }
+    render_notifications(user, route, &session, notifications, false, 1, form.into_inner().context).await.map(Err)
+}
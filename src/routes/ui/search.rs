@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use openidconnect::url::form_urlencoded;
+use rocket::{get, FromForm, Route, State};
+use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use crate::guards::AuthUser;
+use crate::managers::libraries::LibraryManager;
+use crate::managers::search::{parse_date_filter, SearchFileType, SearchManager, SearchOptions};
+use crate::models::file_index::SearchResultModel;
+use crate::util::ResponseError;
+
+const RESULTS_PER_PAGE: u32 = 50;
+
+/// The search and its filters, sent back to fill in the form
+#[derive(Debug, Default, Serialize, FromForm)]
+pub struct SearchQuery {
+    q: Option<String>,
+    library: Option<String>,
+    #[field(name = "type")]
+    #[serde(rename = "type")]
+    file_type: Option<SearchFileType>,
+    min_mb: Option<u64>,
+    max_mb: Option<u64>,
+    modified_since: Option<String>,
+    modified_until: Option<String>,
+    page: Option<u32>,
+}
+
+impl SearchQuery {
+    fn has_filters(&self) -> bool {
+        self.library.as_deref().is_some_and(|library| !library.is_empty())
+            || self.file_type.is_some()
+            || self.min_mb.is_some()
+            || self.max_mb.is_some()
+            || self.modified_since.as_deref().is_some_and(|date| !date.is_empty())
+            || self.modified_until.as_deref().is_some_and(|date| !date.is_empty())
+    }
+
+    /// The query string of the search without the page, to link to other pages
+    fn to_query_string(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        let values = [
+            ("q", self.q.clone()),
+            ("library", self.library.clone()),
+            ("type", self.file_type.map(|file_type| file_type.as_str().to_string())),
+            ("min_mb", self.min_mb.map(|size| size.to_string())),
+            ("max_mb", self.max_mb.map(|size| size.to_string())),
+            ("modified_since", self.modified_since.clone()),
+            ("modified_until", self.modified_until.clone()),
+        ];
+        for (key, value) in values {
+            if let Some(value) = value.filter(|value| !value.is_empty()) {
+                query.append_pair(key, &value);
+            }
+        }
+        query.finish()
+    }
+}
+
+#[derive(Serialize)]
+struct SearchResultView {
+    result: SearchResultModel,
+    /// The folder the result is in, empty for the root of the library
+    parent: String,
+}
+
+#[get("/search?<query..>")]
+pub async fn search_page(
+    user: AuthUser,
+    route: &Route,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    search: &State<Arc<SearchManager>>,
+    query: SearchQuery,
+) -> Result<Template, ResponseError> {
+    let user_libraries = libraries.lock().await.list(&user.session.user.id).await
+        .map_err(|_| ResponseError::GenericError)?;
+    let text = query.q.as_deref().unwrap_or("").trim();
+    let page = query.page.unwrap_or(1).max(1);
+    let searched = !text.is_empty() || query.has_filters();
+    let mut results = Vec::new();
+    if searched {
+        let library_ids: Vec<_> = user_libraries.iter()
+            .map(|library| library.id)
+            .filter(|id| query.library.as_deref().is_none_or(|library| library.is_empty() || library == id.to_string()))
+            .collect();
+        let options = SearchOptions {
+            query: text,
+            file_type: query.file_type,
+            min_size: query.min_mb.map(|size| size * 1_000_000),
+            max_size: query.max_mb.map(|size| size * 1_000_000),
+            modified_since: parse_date_filter(query.modified_since.as_deref())?,
+            modified_until: parse_date_filter(query.modified_until.as_deref())?,
+            limit: Some(RESULTS_PER_PAGE),
+            offset: Some((page - 1) * RESULTS_PER_PAGE),
+        };
+        results = search.search(&library_ids, &options).await
+            .map_err(|_| ResponseError::GenericError)?
+            .into_iter()
+            .map(|result| SearchResultView {
+                parent: result.path.rsplit_once('/').map(|(parent, _)| parent.to_string()).unwrap_or_default(),
+                result,
+            })
+            .collect();
+    }
+    Ok(Template::render("search", context! {
+        session: user.session,
+        route: route.uri.path(),
+        libraries: user_libraries,
+        page_query: query.to_query_string(),
+        previous_page: (page > 1).then(|| page - 1),
+        next_page: (results.len() as u32 == RESULTS_PER_PAGE).then(|| page + 1),
+        searched,
+        form: query,
+        results,
+    }))
+}
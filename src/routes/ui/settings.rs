@@ -0,0 +1,211 @@
+use std::sync::Arc;
+use chrono::{Duration, Utc};
+use rocket::{get, post, uri, FromForm, Route, State};
+use rocket::form::{Context, Contextual, Error, Form};
+use rocket::response::Redirect;
+use rocket_dyn_templates::{context, Template};
+use rocket_session_store::Session;
+use sqlx::types::Uuid;
+use tokio::sync::Mutex;
+use crate::{models, SessionData, DB};
+use crate::guards::AuthUser;
+use crate::managers::libraries::LibraryManager;
+use crate::managers::notifications::NotificationManager;
+use crate::models::api_token::ApiTokenScope;
+use crate::models::library::PermissionLevel;
+use crate::models::notification::NotificationKind;
+use crate::routes::ui::auth;
+use crate::util::{database_error, gen_api_token, hash_api_token, set_csrf, validate_csrf_form, ResponseError};
+
+#[get("/settings")]
+pub async fn user_settings(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    pool: &State<DB>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    notifications: &State<Arc<NotificationManager>>,
+) -> Result<Template, ResponseError> {
+    render_settings(user, route, &session, pool, libraries, notifications, Context::default(), None).await
+}
+
+/// Renders the settings page, `new_token` is the API token that was just created, as it can only be shown once
+async fn render_settings(
+    user: AuthUser,
+    route: &Route,
+    session: &Session<'_, SessionData>,
+    pool: &DB,
+    libraries: &Mutex<LibraryManager>,
+    notifications: &NotificationManager,
+    form: Context<'_>,
+    new_token: Option<String>,
+) -> Result<Template, ResponseError> {
+    let api_tokens = models::api_token::get_user_api_tokens(pool, &user.session.user.id).await
+        .map_err(database_error)?;
+    let libraries = libraries.lock().await.list(&user.session.user.id).await
+        .map_err(database_error)?;
+    let sessions = models::session::get_user_logins(pool, &user.session.user.id).await
+        .map_err(database_error)?;
+    let notification_preferences = notifications.preferences(&user.session.user.id).await
+        .map_err(database_error)?;
+    let csrf_token = set_csrf(session).await;
+    Ok(Template::render("settings", context! {
+        current_session_id: user.session.id,
+        session: user.session,
+        route: route.uri.path(),
+        csrf_token,
+        sessions,
+        api_tokens,
+        libraries,
+        notification_preferences,
+        new_token,
+        form: &form,
+    }))
+}
+
+#[derive(FromForm, Debug)]
+struct CreateTokenForm<'r> {
+    _csrf: &'r str,
+    #[field(validate = len(1..=64))]
+    name: &'r str,
+    /// Restricts the token to a single library, empty for all libraries
+    library: Option<&'r str>,
+    scope: ApiTokenScope,
+    /// Days until the token expires, empty to never expire
+    expires_days: Option<u32>,
+}
+
+#[post("/settings/tokens", data = "<form>")]
+pub async fn create_token_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    pool: &State<DB>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    notifications: &State<Arc<NotificationManager>>,
+    mut form: Form<Contextual<'_, CreateTokenForm<'_>>>,
+) -> Result<Template, ResponseError> {
+    let mut new_token = None;
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        let library_id = match value.library.filter(|library| !library.is_empty()) {
+            None => Ok(None),
+            Some(library_id) => libraries.lock().await.get_for_user(library_id, &user.session.user.id, PermissionLevel::ReadOnly).await
+                .map(|library| Some(library.model().id))
+        };
+        match library_id {
+            Ok(library_id) => {
+                let token = gen_api_token();
+                let expires_at = value.expires_days.map(|days| (Utc::now() + Duration::days(days as i64)).naive_utc());
+                models::api_token::create_api_token(pool, &user.session.user.id, value.name, &hash_api_token(&token), library_id.as_ref(), value.scope, expires_at).await
+                    .map_err(database_error)?;
+                new_token = Some(token);
+            },
+            Err(_) => form.context.push_error(Error::validation("Library does not exist").with_name("library"))
+        }
+    }
+    let form = if new_token.is_some() { Context::default() } else { form.into_inner().context };
+    render_settings(user, route, &session, pool, libraries, notifications, form, new_token).await
+}
+
+#[derive(FromForm, Debug)]
+struct RevokeTokenForm<'r> {
+    _csrf: &'r str,
+    id: Uuid,
+}
+
+#[post("/settings/tokens/revoke", data = "<form>")]
+pub async fn revoke_token_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    pool: &State<DB>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    notifications: &State<Arc<NotificationManager>>,
+    mut form: Form<Contextual<'_, RevokeTokenForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        models::api_token::delete_api_token(pool, &user.session.user.id, &value.id).await
+            .map_err(database_error)?;
+        return Ok(Ok(Redirect::to("/settings#tokens")))
+    }
+    render_settings(user, route, &session, pool, libraries, notifications, form.into_inner().context, None).await.map(Err)
+}
+
+#[derive(FromForm, Debug)]
+struct RevokeSessionForm<'r> {
+    _csrf: &'r str,
+    id: Uuid,
+}
+
+#[post("/settings/sessions/revoke", data = "<form>")]
+pub async fn revoke_session_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    pool: &State<DB>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    notifications: &State<Arc<NotificationManager>>,
+    mut form: Form<Contextual<'_, RevokeSessionForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        models::session::delete_user_login(pool, &user.session.user.id, &value.id).await
+            .map_err(database_error)?;
+        if value.id == user.session.id {
+            return Ok(Ok(Redirect::to(uri!(auth::login::page(_, Some(true))))))
+        }
+        return Ok(Ok(Redirect::to("/settings#sessions")))
+    }
+    render_settings(user, route, &session, pool, libraries, notifications, form.into_inner().context, None).await.map(Err)
+}
+
+#[derive(FromForm, Debug)]
+struct LogoutEverywhereForm<'r> {
+    _csrf: &'r str,
+}
+
+/// Revokes all of the user's sessions, including the current one
+#[post("/settings/sessions/revoke-all", data = "<form>")]
+pub async fn logout_everywhere_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    pool: &State<DB>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    notifications: &State<Arc<NotificationManager>>,
+    mut form: Form<Contextual<'_, LogoutEverywhereForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    if validate_csrf_form(&mut form.context, &session).await && form.value.is_some() {
+        models::session::delete_user_sessions(pool, &user.session.user.id).await
+            .map_err(database_error)?;
+        // Also sign out WebDAV clients that remembered the password
+        models::user::bump_credentials_version(pool, &user.session.user.id).await
+            .map_err(database_error)?;
+        return Ok(Ok(Redirect::to(uri!(auth::login::page(_, Some(true))))))
+    }
+    render_settings(user, route, &session, pool, libraries, notifications, form.into_inner().context, None).await.map(Err)
+}
+
+#[derive(FromForm, Debug)]
+struct NotificationPreferencesForm<'r> {
+    _csrf: &'r str,
+    /// The kinds of notifications to get, every other kind is turned off
+    kinds: Vec<NotificationKind>,
+}
+
+#[post("/settings/notifications", data = "<form>")]
+pub async fn notification_preferences_handler(
+    user: AuthUser,
+    route: &Route,
+    session: Session<'_, SessionData>,
+    pool: &State<DB>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    notifications: &State<Arc<NotificationManager>>,
+    mut form: Form<Contextual<'_, NotificationPreferencesForm<'_>>>,
+) -> Result<Result<Redirect, Template>, ResponseError> {
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        notifications.set_preferences(&user.session.user.id, &value.kinds).await
+            .map_err(database_error)?;
+        return Ok(Ok(Redirect::to("/settings#notifications")))
+    }
+    render_settings(user, route, &session, pool, libraries, notifications, form.into_inner().context, None).await.map(Err)
+}
@@ -0,0 +1,381 @@
+use std::net::IpAddr;
+use chrono::NaiveDateTime;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use rocket::{get, post, uri, Data, FromForm, State};
+use rocket::form::{Context, Contextual, Error, Form};
+use rocket::http::ContentType;
+use rocket::response::{status, Redirect};
+use rocket_dyn_templates::{context, Template};
+use rocket_session_store::Session;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use crate::SessionData;
+use crate::archive::{ArchiveDownload, ArchiveFormat};
+use crate::consts::{APP_METADATA, MAX_UPLOAD_SIZE};
+use crate::download::{DownloadHeaders, FileDownload};
+use crate::managers::activity::Actor;
+use crate::managers::libraries::LibraryManager;
+use crate::managers::share_links::{resolve_share_path, ShareLinkManager, Uploader};
+use crate::models::share_link::{ShareLinkMode, ShareLinkModel};
+use crate::objs::library::{Library, ListOptions};
+use crate::util::{set_csrf, storage_error, validate_csrf_form, JsonErrorResponse, ResponseError};
+
+/// Returns the link and its library, None if the link does not exist or can no longer be used
+async fn open_link(links: &ShareLinkManager, libraries: &Mutex<LibraryManager>, token: &str) -> Result<Option<(ShareLinkModel, Library)>, ResponseError> {
+    let Some(link) = links.get(token).await? else {
+        return Ok(None)
+    };
+    let library = libraries.lock().await.get(&link.library_id.to_string()).await?;
+    Ok(Some((link, library)))
+}
+
+/// Fails unless the visitor entered the password of the link, and the link is of the mode
+async fn check_access(links: &ShareLinkManager, link: &ShareLinkModel, mode: ShareLinkMode, session: &Session<'_, SessionData>) -> Result<(), ResponseError> {
+    if !links.is_unlocked(link, session).await {
+        return Err(ResponseError::Forbidden(JsonErrorResponse {
+            code: "SHARE_LINK_LOCKED".to_string(),
+            message: "The password of the share link is required".to_string(),
+        }))
+    }
+    if link.mode != mode {
+        return Err(ResponseError::Forbidden(JsonErrorResponse {
+            code: "SHARE_LINK_MODE".to_string(),
+            message: match mode {
+                ShareLinkMode::Read => "Files can only be uploaded through this share link",
+                ShareLinkMode::Upload => "Files can't be uploaded through this share link",
+            }.to_string(),
+        }))
+    }
+    Ok(())
+}
+
+/// What the share page shows of a link, which doesn't reveal who created it or where it is in the library
+#[derive(Serialize)]
+struct SharedLinkView<'a> {
+    token: &'a str,
+    mode: ShareLinkMode,
+    ask_uploader: bool,
+    expires_at: Option<NaiveDateTime>,
+}
+
+impl<'a> From<&'a ShareLinkModel> for SharedLinkView<'a> {
+    fn from(link: &'a ShareLinkModel) -> Self {
+        SharedLinkView {
+            token: &link.token,
+            mode: link.mode,
+            ask_uploader: link.ask_uploader,
+            expires_at: link.expires_at,
+        }
+    }
+}
+
+/// The name the shared file or folder is shown with
+fn shared_name(link: &ShareLinkModel, library: &Library) -> String {
+    Path::new(&link.path).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| library.model().name.clone())
+}
+
+#[get("/s/<token>?<path>")]
+pub async fn share_page(
+    session: Session<'_, SessionData>,
+    links: &State<Arc<ShareLinkManager>>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    token: &str,
+    path: Option<&str>,
+) -> Result<Option<Template>, ResponseError> {
+    let Some((link, library)) = open_link(links, libraries, token).await? else {
+        return Ok(None)
+    };
+    render_share_page(links, &link, &library, path.unwrap_or_default(), &session, Context::default()).await.map(Some)
+}
+
+async fn render_share_page(links: &ShareLinkManager, link: &ShareLinkModel, library: &Library, sub_path: &str, session: &Session<'_, SessionData>, form: Context<'_>)
+    -> Result<Template, ResponseError>
+{
+    let name = shared_name(link, library);
+    let view = SharedLinkView::from(link);
+    if !links.is_unlocked(link, session).await {
+        let csrf_token = set_csrf(session).await;
+        return Ok(Template::render("share", context! {
+            meta: APP_METADATA.clone(),
+            link: &view,
+            name,
+            locked: true,
+            csrf_token,
+            form: &form,
+        }))
+    }
+    links.notify_access(link, session).await;
+    if link.mode == ShareLinkMode::Upload {
+        return Ok(Template::render("share", context! {
+            meta: APP_METADATA.clone(),
+            link: &view,
+            name,
+        }))
+    }
+    let path = resolve_share_path(link, sub_path)?;
+    let file = library.stat_file(&path).await.map_err(storage_error)?;
+    let files = match file {
+        Some(_) => Vec::new(),
+        None => library.list_files(&path, ListOptions::default()).await.map_err(storage_error)?
+    };
+    let sub_path = path.strip_prefix(&link.path).unwrap_or(&path).to_string_lossy().into_owned();
+    // Links to each folder between the shared folder and the open one
+    let mut segment_path = PathBuf::new();
+    let segments: Vec<_> = Path::new(&sub_path).components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => {
+                segment_path.push(segment);
+                Some(context! { path: segment_path.to_string_lossy().into_owned(), segment: segment.to_string_lossy().into_owned() })
+            },
+            _ => None
+        })
+        .collect();
+    Ok(Template::render("share", context! {
+        meta: APP_METADATA.clone(),
+        link: &view,
+        name,
+        file,
+        files,
+        path: sub_path,
+        path_segments: segments,
+    }))
+}
+
+#[derive(FromForm, Debug)]
+struct UnlockForm<'r> {
+    _csrf: &'r str,
+    password: &'r str,
+}
+
+#[post("/s/<token>", data = "<form>")]
+pub async fn unlock_handler(
+    session: Session<'_, SessionData>,
+    links: &State<Arc<ShareLinkManager>>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    token: &str,
+    mut form: Form<Contextual<'_, UnlockForm<'_>>>,
+) -> Result<Option<Result<Redirect, Template>>, ResponseError> {
+    let Some((link, library)) = open_link(links, libraries, token).await? else {
+        return Ok(None)
+    };
+    if validate_csrf_form(&mut form.context, &session).await && let Some(value) = &form.value {
+        if links.unlock(&link, value.password, &session).await? {
+            return Ok(Some(Ok(Redirect::to(uri!(share_page(token, _))))))
+        }
+        form.context.push_error(Error::validation("The password is incorrect").with_name("password"));
+    }
+    render_share_page(links, &link, &library, "", &session, form.into_inner().context).await.map(|page| Some(Err(page)))
+}
+
+/// Downloads the shared file, or a file in the shared folder. Every download counts towards the link's limit.
+/// Links without a limit don't count requests continuing a download
+#[get("/s/<token>/download?<path>")]
+pub async fn download_file(
+    session: Session<'_, SessionData>,
+    links: &State<Arc<ShareLinkManager>>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    token: &str,
+    path: Option<&str>,
+    headers: DownloadHeaders,
+) -> Result<Option<FileDownload>, ResponseError> {
+    let Some((link, library)) = open_link(links, libraries, token).await? else {
+        return Ok(None)
+    };
+    check_access(links, &link, ShareLinkMode::Read, &session).await?;
+    let path = resolve_share_path(&link, path.unwrap_or_default())?;
+    let content_type = path.extension()
+        .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+        .unwrap_or(ContentType::Binary);
+    let Some(download) = FileDownload::open(&library, &path, content_type, &headers).await.map_err(storage_error)? else {
+        return Ok(None)
+    };
+    // Links with a download limit count every request, so the limit can't be worked around by downloading in parts
+    if link.max_downloads.is_some() || !headers.is_continuation() {
+        links.count_download(&link, &session).await?;
+    }
+    let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    Ok(Some(download.with_disposition(format!("attachment; filename=\"{}\"", file_name))))
+}
+
+/// Downloads the shared folder, or a folder in it, as an archive. Counts as a single download
+#[get("/s/<token>/archive?<path>&<format>")]
+pub async fn download_archive(
+    session: Session<'_, SessionData>,
+    links: &State<Arc<ShareLinkManager>>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    token: &str,
+    path: Option<&str>,
+    format: Option<ArchiveFormat>,
+) -> Result<Option<ArchiveDownload>, ResponseError> {
+    let Some((link, library)) = open_link(links, libraries, token).await? else {
+        return Ok(None)
+    };
+    check_access(links, &link, ShareLinkMode::Read, &session).await?;
+    let path = resolve_share_path(&link, path.unwrap_or_default())?;
+    let archive = ArchiveDownload::start(&library, &[path], format.unwrap_or_default()).await?;
+    links.count_download(&link, &session).await?;
+    Ok(Some(archive))
+}
+
+/// Uploads a file into the folder of an upload link. Existing files are never replaced, and nothing about them is revealed
+#[post("/s/<token>/upload?<name>&<uploader..>", data = "<data>")]
+pub async fn upload_file(
+    session: Session<'_, SessionData>,
+    links: &State<Arc<ShareLinkManager>>,
+    libraries: &State<Arc<Mutex<LibraryManager>>>,
+    token: &str,
+    name: &str,
+    uploader: Uploader<'_>,
+    ip: Option<IpAddr>,
+    data: Data<'_>,
+) -> Result<Option<status::NoContent>, ResponseError> {
+    let Some((link, library)) = open_link(links, libraries, token).await? else {
+        return Ok(None)
+    };
+    // Uploads through links are recorded in the activity log without a user
+    let library = library.with_actor(Actor { user_id: None, ip });
+    check_access(links, &link, ShareLinkMode::Upload, &session).await?;
+    links.upload(&link, &library, name, &uploader, &mut data.open(MAX_UPLOAD_SIZE)).await?;
+    Ok(Some(status::NoContent))
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use crate::managers::share_links::ShareLinkOptions;
+    use crate::models::library::LibraryModel;
+    use crate::testing::{write_file, TestData};
+    use crate::util::setup_session_store;
+    use super::*;
+
+    /// A client that keeps its cookies, like a visitor's browser
+    async fn client(data: &TestData, libraries: LibraryManager, links: Arc<ShareLinkManager>) -> Client {
+        let rocket = rocket::build()
+            .manage(data.pool.clone())
+            .manage(Arc::new(Mutex::new(libraries)))
+            .manage(links)
+            .attach(setup_session_store(data.pool.clone()).fairing())
+            .attach(crate::templates())
+            .mount("/", rocket::routes![share_page, unlock_handler, download_file, download_archive, upload_file]);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    fn options(path: &str, mode: ShareLinkMode) -> ShareLinkOptions<'_> {
+        ShareLinkOptions { path, mode, password: None, expires_days: None, max_downloads: None, ask_uploader: false }
+    }
+
+    /// Creates a link with the options to a library with `folder/file.txt` in it
+    async fn share(data: &mut TestData, options: ShareLinkOptions<'_>) -> (Client, LibraryModel, ShareLinkModel) {
+        let owner = data.user("owner").await;
+        let model = data.library(&owner).await;
+        let libraries = data.libraries(data.trash()).await;
+        let library = data.open(&libraries, &model).await;
+        write_file(&library, "folder/file.txt", "contents").await;
+        let links = data.share_links();
+        let link = links.create(&library, &options, &owner.id).await.unwrap();
+        (client(data, libraries, links).await, model, link)
+    }
+
+    async fn download(client: &Client, link: &ShareLinkModel) -> Status {
+        client.get(format!("/s/{}/download", link.token)).dispatch().await.status()
+    }
+
+    /// Opens the share page and submits the password with the CSRF token from its form
+    async fn unlock(client: &Client, link: &ShareLinkModel, password: &str) -> Status {
+        let page = client.get(format!("/s/{}", link.token)).dispatch().await.into_string().await.unwrap();
+        let csrf = page.split("name=\"_csrf\" value=\"").nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .expect("The share page has no password form");
+        client.post(format!("/s/{}", link.token))
+            .header(ContentType::Form)
+            .body(format!("_csrf={}&password={}", csrf, password))
+            .dispatch().await.status()
+    }
+
+    #[tokio::test]
+    async fn requires_the_password() {
+        let mut data = TestData::new().await;
+        let (visitor, _, link) = share(&mut data, ShareLinkOptions { password: Some("secret"), ..options("folder/file.txt", ShareLinkMode::Read) }).await;
+
+        assert_eq!(download(&visitor, &link).await, Status::Forbidden);
+        // A wrong password shows the form again
+        assert_eq!(unlock(&visitor, &link, "wrong").await, Status::Ok);
+        assert_eq!(download(&visitor, &link).await, Status::Forbidden);
+        assert_eq!(unlock(&visitor, &link, "secret").await, Status::SeeOther);
+        let response = visitor.get(format!("/s/{}/download", link.token)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.into_string().await.unwrap(), "contents");
+        // The link is only unlocked for the visitor who entered the password
+        let other = client(&data, data.libraries(data.trash()).await, data.share_links()).await;
+        assert_eq!(download(&other, &link).await, Status::Forbidden);
+
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn stops_working_once_expired() {
+        let mut data = TestData::new().await;
+        let (visitor, _, link) = share(&mut data, ShareLinkOptions { expires_days: Some(1), ..options("folder", ShareLinkMode::Read) }).await;
+
+        assert_eq!(visitor.get(format!("/s/{}/download?path=file.txt", link.token)).dispatch().await.status(), Status::Ok);
+        sqlx::query!("update storage.share_links set expires_at = now() - interval '1 minute' where id = $1", link.id)
+            .execute(&data.pool)
+            .await.unwrap();
+        assert_eq!(visitor.get(format!("/s/{}", link.token)).dispatch().await.status(), Status::NotFound);
+        assert_eq!(visitor.get(format!("/s/{}/download?path=file.txt", link.token)).dispatch().await.status(), Status::NotFound);
+
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn stops_working_after_the_download_limit() {
+        let mut data = TestData::new().await;
+        let (visitor, _, link) = share(&mut data, ShareLinkOptions { max_downloads: Some(2), ..options("folder/file.txt", ShareLinkMode::Read) }).await;
+
+        assert_eq!(download(&visitor, &link).await, Status::Ok);
+        // Downloading part of the file counts too on links with a limit
+        let response = visitor.get(format!("/s/{}/download", link.token))
+            .header(rocket::http::Header::new("Range", "bytes=4-"))
+            .dispatch().await;
+        assert_eq!(response.status(), Status::PartialContent);
+        assert_eq!(response.into_string().await.unwrap(), "ents");
+        assert_eq!(download(&visitor, &link).await, Status::NotFound);
+        assert_eq!(visitor.get(format!("/s/{}", link.token)).dispatch().await.status(), Status::NotFound);
+
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn upload_links_refuse_listing_and_downloads() {
+        let mut data = TestData::new().await;
+        let (visitor, model, link) = share(&mut data, options("folder", ShareLinkMode::Upload)).await;
+
+        let page = visitor.get(format!("/s/{}", link.token)).dispatch().await;
+        assert_eq!(page.status(), Status::Ok);
+        assert!(!page.into_string().await.unwrap().contains("file.txt"), "The upload page lists the folder");
+        assert_eq!(visitor.get(format!("/s/{}?path=file.txt", link.token)).dispatch().await.status(), Status::Ok);
+        assert_eq!(visitor.get(format!("/s/{}/download?path=file.txt", link.token)).dispatch().await.status(), Status::Forbidden);
+        assert_eq!(visitor.get(format!("/s/{}/archive", link.token)).dispatch().await.status(), Status::Forbidden);
+        let response = visitor.post(format!("/s/{}/upload?name=new.txt", link.token)).body("new").dispatch().await;
+        assert_eq!(response.status(), Status::NoContent);
+        assert!(data.root.join(model.id.to_string()).join("folder/new.txt").exists());
+
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn read_links_refuse_uploads() {
+        let mut data = TestData::new().await;
+        let (visitor, model, link) = share(&mut data, options("folder", ShareLinkMode::Read)).await;
+
+        let response = visitor.post(format!("/s/{}/upload?name=new.txt", link.token)).body("new").dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(!data.root.join(model.id.to_string()).join("folder/new.txt").exists());
+
+        data.cleanup().await;
+    }
+}
@@ -3,6 +3,7 @@ use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use log::debug;
+use openidconnect::url::form_urlencoded;
 use rocket::{catch, get, uri, Response, Route, State};
 use rocket::fs::NamedFile;
 use rocket::http::{ContentType, Header};
@@ -13,23 +14,39 @@ use rocket::serde::json::{json, Json};
 use rocket_dyn_templates::{context, Template};
 use serde::Serialize;
 use serde_json::Value;
+use sqlx::types::Uuid;
 use tokio::sync::Mutex;
-use crate::consts::FILE_CONSTANTS;
+use crate::consts::{FILE_CONSTANTS, LIST_PAGE_SIZE};
+use crate::download::{DownloadHeaders, FileDownload};
 use crate::guards::{AuthUser};
+use crate::managers::favorites::FavoriteManager;
 use crate::managers::libraries::LibraryManager;
 use crate::managers::user::UsersState;
+use crate::models::library::{LibraryModel, PermissionLevel};
 use crate::objs::library::ListOptions;
 use crate::routes::ui::auth;
-use crate::util::{JsonErrorResponse, ResponseError};
+use crate::storage::FileType;
+use crate::util::{database_error, storage_error, JsonErrorResponse, ResponseError};
 
-#[get("/settings")]
-pub async fn user_settings(user: AuthUser, route: &Route) -> Template {
-    Template::render("settings", context! { session: user.session, route: route.uri.path() })
+#[derive(Serialize)]
+struct LibraryView {
+    #[serde(flatten)]
+    library: LibraryModel,
+    favorited: bool,
 }
+
 #[get("/")]
-pub async fn index(user: AuthUser, libraries: &State<Arc<Mutex<LibraryManager>>>, route: &Route) -> Template {
+pub async fn index(user: AuthUser, libraries: &State<Arc<Mutex<LibraryManager>>>, favorites: &State<Arc<FavoriteManager>>, route: &Route) -> Template {
     let libraries = libraries.lock().await;
     let list = libraries.list(&user.session.user.id).await.unwrap();
+    let starred: Vec<Uuid> = favorites.list(&user.session.user.id).await.unwrap_or_default()
+        .into_iter()
+        .filter(|favorite| favorite.path.is_empty())
+        .map(|favorite| favorite.library_id)
+        .collect();
+    let list: Vec<LibraryView> = list.into_iter()
+        .map(|library| LibraryView { favorited: starred.contains(&library.id), library })
+        .collect();
     Template::render("index", context! { session: user.session, libraries: list, route: route.uri.path(), test: "value" })
 }
 
@@ -38,39 +55,62 @@ pub async fn redirect_list_library_files(user: AuthUser, libraries: &State<Arc<M
   -> Result<Redirect, ResponseError>
 {
     let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
-    Ok(Redirect::to(uri!(list_library_files(library_id, library.model().name, "", Some("name"), Some("asc"), Some("list")))))
+    let library = libs.get_for_user(library_id, &user.session.user.id, PermissionLevel::ReadOnly).await?;
+    Ok(Redirect::to(uri!(list_library_files(library_id, library.model().name, "", Some("name"), Some("asc"), Some("list"), _, _, _, _))))
 }
 
-#[get("/library/<library_id>/<_>/<path..>?<sort_key>&<sort_dir>&<display>")]
+#[get("/library/<library_id>/<_>/<path..>?<sort_key>&<sort_dir>&<display>&<name>&<file_type>&<sizes>&<cursor>")]
 pub async fn list_library_files(
     user: AuthUser,
     route: &Route,
     libraries: &State<Arc<Mutex<LibraryManager>>>,
+    favorites: &State<Arc<FavoriteManager>>,
     library_id: &str,
     path: PathBuf,
     sort_key: Option<String>,
     sort_dir: Option<String>,
     display: Option<String>,
+    name: Option<String>,
+    file_type: Option<FileType>,
+    sizes: Option<bool>,
+    cursor: Option<String>,
 ) -> Result<Template, ResponseError> {
+    let name = name.filter(|name| !name.is_empty());
+    // The UI only filters to files or to folders
+    let file_type = file_type.filter(|file_type| matches!(file_type, FileType::File | FileType::Folder));
+    let sort_key = validate_option(sort_key, FILE_CONSTANTS.sort_keys, "name");
+    // Folder sizes require listing everything in them, so they are only computed when asked for or sorted by
+    let sizes = sizes.unwrap_or(false) || sort_key == "size";
     let options = FileDisplayOptions {
         // TODO: prevent bad values
         // TODO: fix login errror msg -------_____------
-        sort_key: validate_option(sort_key, FILE_CONSTANTS.sort_keys, "name"),
+        sort_key,
         sort_dir: validate_option(sort_dir, &["asc", "desc"], "asc"),
         display: validate_option(display, FILE_CONSTANTS.display_options, "list"),
+        filter_query: filter_query(name.as_deref(), file_type, sizes),
+        name: name.clone(),
+        file_type,
+        sizes,
+        cursor: cursor.clone(),
     };
     let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
+    let library = libs.get_for_user(library_id, &user.session.user.id, PermissionLevel::ReadOnly).await?;
+    let permission = libs.get_permission(library.model(), &user.session.user.id).await
+        .map_err(database_error)?;
     let list_options = ListOptions {
         sort_field: Some(options.sort_key.clone()),
         sort_descending: Some(options.sort_dir == "desc"),
+        name_filter: name,
+        file_type,
+        limit: Some(LIST_PAGE_SIZE),
+        cursor,
+        folder_sizes: sizes,
+        ..Default::default()
     };
-    let files = library.list_files(&PathBuf::from(&path), list_options).await
-        .map_err(|e| ResponseError::InternalServerError(JsonErrorResponse {
-            code: "STORAGE_ERROR".to_string(),
-            message: e.to_string(),
-        }))?;
+    let mut page = library.list_files_page(&PathBuf::from(&path), &list_options).await
+        .map_err(storage_error)?;
+    favorites.mark(&library, &user.session.user.id, &path, &mut page.files).await
+        .map_err(|_| ResponseError::GenericError)?;
 
     // TODO:
     // parent
@@ -96,7 +136,9 @@ pub async fn list_library_files(
         session: user.session,
         route: route.uri.path(),
         library: library.model(),
-        files: files,
+        permission,
+        files: page.files,
+        next_cursor: page.next_cursor,
         parent,
         path_segments: segments,
         // TODO: have struct?
@@ -115,11 +157,34 @@ fn validate_option(option: Option<String>, valid_values: &[&str], default_value:
     default_value.to_string()
 }
 
+/// Returns the query parameters of the name and type filters and of showing folder sizes, to keep them when sorting or paging
+fn filter_query(name: Option<&str>, file_type: Option<FileType>, sizes: bool) -> String {
+    let mut query = form_urlencoded::Serializer::new(String::new());
+    if let Some(name) = name {
+        query.append_pair("name", name);
+    }
+    if let Some(file_type) = file_type {
+        query.append_pair("file_type", if file_type == FileType::Folder { "folder" } else { "file" });
+    }
+    if sizes {
+        query.append_pair("sizes", "true");
+    }
+    let query = query.finish();
+    if query.is_empty() { query } else { format!("&{}", query) }
+}
+
 #[derive(Serialize)]
 struct FileDisplayOptions {
     sort_key: String,
     sort_dir: String,
-    display: String
+    display: String,
+    name: Option<String>,
+    file_type: Option<FileType>,
+    /// Are the sizes of folders shown
+    sizes: bool,
+    cursor: Option<String>,
+    /// The filters as query parameters, starting with `&` when there are any
+    filter_query: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -128,22 +193,15 @@ struct PathSegmentPiece {
     pub segment: String
 }
 
-#[derive(Responder)]
-#[response(status = 200)]
-struct FileAttachment {
-    content: Vec<u8>,
-    // Override the Content-Type declared above.
-    content_type: ContentType,
-    disposition: Header<'static>,
-}
-
 #[get("/file/<library_id>/<path..>")]
-pub async fn get_library_file<'a>(user: AuthUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: PathBuf)
-    -> Result<FileAttachment, ResponseError>
+pub async fn get_library_file<'a>(user: AuthUser, libraries: &State<Arc<Mutex<LibraryManager>>>, library_id: &str, path: PathBuf, headers: DownloadHeaders)
+    -> Result<FileDownload, ResponseError>
 {
-    let libs = libraries.lock().await;
-    let library = libs.get(library_id).await?;
-    match library.read_file(&PathBuf::from(&path)).await
+    let library = libraries.lock().await.get_for_user(library_id, &user.session.user.id, PermissionLevel::ReadOnly).await?;
+    let file_type = path.extension()
+        .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
+        .unwrap_or(ContentType::Binary);
+    match FileDownload::open(&library, &path, file_type, &headers).await
         .map_err(|e| ResponseError::GenericError)?
     {
         None => {
@@ -152,16 +210,9 @@ pub async fn get_library_file<'a>(user: AuthUser, libraries: &State<Arc<Mutex<Li
                 message: "Requested file does not exist".to_string()
             }))
         }
-        Some(contents) => {
+        Some(download) => {
             let file_name = path.file_name().unwrap().to_string_lossy();
-            let ext = path.extension().unwrap().to_string_lossy();
-            let file_type = ContentType::from_extension(&ext);
-            Ok(FileAttachment {
-                content: contents,
-                content_type: file_type.unwrap_or(ContentType::Binary),
-                disposition: Header::new("Content-Disposition", format!("filename=\"{}\"", file_name))
-            })
-            // Ok(res)
+            Ok(download.with_disposition(format!("filename=\"{}\"", file_name)))
         }
     }
 
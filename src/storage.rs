@@ -1,17 +1,23 @@
 mod local;
 mod s3;
 
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
 use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use glob::Pattern;
 use int_enum::IntEnum;
 use rocket::FromFormField;
+use rocket::http::ContentType;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::types::JsonValue;
+use tokio::io::AsyncRead;
 use crate::storage::local::LocalStorage;
+use crate::consts::MAX_RENAME_ATTEMPTS;
 use crate::storage::s3::S3Storage;
 
 pub enum StorageBackendMap {
@@ -19,7 +25,7 @@ pub enum StorageBackendMap {
     S3(S3Storage)
 }
 
-#[derive(Debug, Serialize, Deserialize, FromFormField, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, FromFormField, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum FileType {
     File,
@@ -45,31 +51,300 @@ impl From<std::fs::FileType> for FileType {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileEntry {
     pub path: String,
-    // last_modified:
     pub size: u64,
     #[serde(rename="type")]
     pub _type: FileType,
+    pub last_modified: Option<DateTime<Utc>>,
+    /// When the file was created, not all backends know this
+    pub created: Option<DateTime<Utc>>,
+    /// The MIME type of files, guessed from their extension
+    pub mime: Option<String>,
+    /// How many files and folders a folder holds, including those in its subfolders. Only known when folder sizes are
+    /// computed, which also sets the size of the folder to the total size of its files
+    pub item_count: Option<u64>,
+    /// Whether the user listing the folder starred the file or folder
+    pub favorited: bool,
+    /// The same identifier of the file's contents as [FileMetadata::etag], None for folders
+    pub etag: Option<String>,
 }
 
+/// Extensions of text files that aren't known content types, which are treated as plain text
+const TEXT_EXTENSIONS: &[&str] = &[
+    "md", "rs", "py", "go", "toml", "yaml", "yml", "ini", "conf", "cfg", "log", "sh", "sql",
+    "c", "h", "cpp", "hpp", "java", "kt", "ts", "tsx", "jsx", "rb", "php", "lua",
+];
 
+/// Guesses the MIME type of the file from the extension of its name, without parameters such as the charset
+pub fn guess_mime_type(name: &str) -> Option<String> {
+    let (_, extension) = name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    match ContentType::from_extension(&extension) {
+        Some(content_type) => Some(format!("{}/{}", content_type.top(), content_type.sub())),
+        None => TEXT_EXTENSIONS.contains(&extension.as_str()).then(|| "text/plain".to_string())
+    }
+}
+
+/// How to sort, filter and page a folder listing
+#[derive(PartialEq, Debug)]
+pub struct ListOptions {
+    pub sort_field: Option<String>,
+    pub sort_descending: Option<bool>,
+    /// Only list entries whose name matches the glob, ex. `*.jpg`
+    pub name_filter: Option<String>,
+    /// Only list entries of the type, ex. only folders
+    pub file_type: Option<FileType>,
+    /// The most entries to return, None for all of them
+    pub limit: Option<usize>,
+    /// Where to continue the listing, as returned in [FilePage::next_cursor]
+    pub cursor: Option<String>,
+    /// Also list everything in the subfolders, with paths relative to the listed folder
+    pub recursive: bool,
+    /// How many levels of subfolders a recursive listing goes into, None for all of them
+    pub max_depth: Option<u32>,
+    /// Compute the total size and item count of folders, see [FileEntry::item_count]
+    pub folder_sizes: bool,
+}
+impl Default for ListOptions {
+    fn default() -> Self {
+        ListOptions {
+            sort_field: Some("name".to_string()),
+            sort_descending: Some(false),
+            name_filter: None,
+            file_type: None,
+            limit: None,
+            cursor: None,
+            recursive: false,
+            max_depth: None,
+            folder_sizes: false,
+        }
+    }
+}
+
+impl ListOptions {
+    /// Parses the name filter, None if there is no filter
+    pub fn pattern(&self) -> Result<Option<Pattern>, Error> {
+        self.name_filter.as_deref()
+            .map(|filter| Pattern::new(filter).map_err(|_| StorageError::InvalidFilter.into()))
+            .transpose()
+    }
+
+    /// Is the listing sorted by name, from A to Z. Backends that list in that order can page natively
+    pub fn is_name_ascending(&self) -> bool {
+        self.sort_field.as_deref().unwrap_or("name") == "name" && !self.sort_descending.unwrap_or(false)
+    }
+
+    /// Filters and sorts the whole listing, then returns the page at the cursor, which is an offset into it
+    pub fn page(&self, mut list: Vec<FileEntry>) -> Result<FilePage, Error> {
+        let pattern = self.pattern()?;
+        list.retain(|entry| {
+            self.file_type.as_ref().is_none_or(|file_type| &entry._type == file_type)
+                && pattern.as_ref().is_none_or(|pattern| pattern.matches(&entry.path))
+        });
+        self.sort(&mut list)?;
+        let offset = match &self.cursor {
+            Some(cursor) => cursor.parse::<usize>().map_err(|_| StorageError::InvalidCursor)?,
+            None => 0
+        };
+        let mut files: Vec<FileEntry> = list.into_iter().skip(offset).collect();
+        let mut next_cursor = None;
+        if let Some(limit) = self.limit && files.len() > limit {
+            files.truncate(limit);
+            next_cursor = Some((offset + limit).to_string());
+        }
+        Ok(FilePage { files, next_cursor })
+    }
+
+    fn sort(&self, list: &mut [FileEntry]) -> Result<(), Error> {
+        let descending = self.sort_descending.unwrap_or(false);
+        let compare: fn(&FileEntry, &FileEntry) -> Ordering = match self.sort_field.as_deref().unwrap_or("name") {
+            "name" => |a, b| a.path.cmp(&b.path),
+            "size" => |a, b| a.size.cmp(&b.size),
+            "last_modified" => |a, b| a.last_modified.cmp(&b.last_modified),
+            "created" => |a, b| a.created.cmp(&b.created),
+            _ => return Err(anyhow!("Unsupported field"))
+        };
+        // Folders always come before files, in either direction
+        list.sort_by(|a, b| {
+            if a._type == FileType::File && b._type != FileType::File { Ordering::Greater }
+            else if a._type != FileType::File && b._type == FileType::File { Ordering::Less }
+            else if descending { compare(b, a).then_with(|| b.path.cmp(&a.path)) }
+            else { compare(a, b).then_with(|| a.path.cmp(&b.path)) }
+        });
+        Ok(())
+    }
+}
+
+/// A page of a folder listing
+#[derive(Debug, Serialize)]
+pub struct FilePage {
+    pub files: Vec<FileEntry>,
+    /// Pass as the cursor to get the next page, None on the last page
+    pub next_cursor: Option<String>,
+}
+
+/// What to do when the destination of a move already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Fail without changing anything
+    #[default]
+    Fail,
+    /// Replace the destination. Folders are replaced as a whole, not merged
+    Overwrite,
+    /// Use the next free name next to the destination, ex. `report (1).pdf`
+    Rename,
+}
+
+/// Errors from storage operations that are caused by the request, rather than the backend
+#[derive(Debug)]
+pub enum StorageError {
+    /// The path leaves the library, or is inside its internal folder
+    InvalidPath,
+    NotFound,
+    AlreadyExists,
+    /// The destination is the path itself or inside it
+    InvalidDestination,
+    /// The name filter of a listing is not a valid glob
+    InvalidFilter,
+    /// The cursor of a listing was not returned by a previous page
+    InvalidCursor,
+    /// A recursive listing would hold more than [MAX_RECURSIVE_LIST_ENTRIES](crate::consts::MAX_RECURSIVE_LIST_ENTRIES)
+    ListingTooLarge,
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.get_err_code(), self.get_err_msg())
+    }
+}
+
+impl StdError for StorageError {
+
+}
+impl StorageError {
+    pub fn get_err_code(&self) -> String {
+        match self {
+            StorageError::InvalidPath => "INVALID_PATH",
+            StorageError::NotFound => "FILE_NOT_FOUND",
+            StorageError::AlreadyExists => "FILE_EXISTS",
+            StorageError::InvalidDestination => "INVALID_DESTINATION",
+            StorageError::InvalidFilter => "INVALID_FILTER",
+            StorageError::InvalidCursor => "INVALID_CURSOR",
+            StorageError::ListingTooLarge => "LISTING_TOO_LARGE",
+        }.to_string()
+    }
+    pub fn get_err_msg(&self) -> String {
+        match self {
+            StorageError::InvalidPath => "The path is not valid",
+            StorageError::NotFound => "No file or folder exists at the path",
+            StorageError::AlreadyExists => "A file or folder already exists at the destination",
+            StorageError::InvalidDestination => "A file or folder can't be moved or copied to itself or inside itself",
+            StorageError::InvalidFilter => "The name filter is not a valid pattern",
+            StorageError::InvalidCursor => "The cursor is not valid for this listing",
+            StorageError::ListingTooLarge => "The folder holds too many files to list recursively, use a lower max depth",
+        }.to_string()
+    }
+}
+
+/// Returns `name (n).ext` for the path, ex. `folder/report (2).pdf`
+pub(crate) fn numbered_path(rel_path: &Path, n: u32) -> PathBuf {
+    let stem = rel_path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let name = match rel_path.extension() {
+        Some(ext) => format!("{} ({}).{}", stem, n, ext.to_string_lossy()),
+        None => format!("{} ({})", stem, n),
+    };
+    rel_path.with_file_name(name)
+}
+
+/// Metadata of a single file, used for caching and range requests
+#[derive(Debug, Clone, Serialize)]
+pub struct FileMetadata {
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+    /// An opaque identifier that changes whenever the file's contents change, without quotes
+    pub etag: Option<String>,
+}
 
 pub fn get_backend(storage_type: &str, settings: &JsonValue) -> Result<Option<Box<dyn StorageBackend + Send + Sync>>, anyhow::Error> {
     Ok(match storage_type {
         "local" => Some(Box::new(LocalStorage::new(settings)?)),
+        "s3" => Some(Box::new(S3Storage::new(settings)?)),
         _ => None
     })
 }
 
+/// A stream of a file's contents, returned by [StorageBackend::read_file]
+pub type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+
+#[rocket::async_trait]
 pub trait StorageBackend {
     // fn new(settings: &JsonValue) -> Result<Self, StorageBackendError>;
-    fn touch_file(&self, library_id: &str, rel_path: &PathBuf, file_type: FileType) -> Result<(), anyhow::Error>;
-    fn write_file(&self, library_id: &str, rel_path: &PathBuf, contents: &[u8]) -> Result<(), anyhow::Error>;
+    async fn touch_file(&self, library_id: &str, rel_path: &PathBuf, file_type: FileType) -> Result<(), anyhow::Error>;
+    /// Writes the contents of the stream to the file, replacing it if it exists. Returns the amount of bytes written
+    async fn write_file(&self, library_id: &str, rel_path: &PathBuf, contents: &mut (dyn AsyncRead + Send + Unpin)) -> Result<u64, anyhow::Error>;
+
+    /// Opens a stream to the file's contents, or None if the file does not exist
+    async fn read_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<Option<ReadStream>, anyhow::Error>;
 
-    fn read_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<Option<Vec<u8>>, anyhow::Error>;
+    /// Opens a stream to `length` bytes of the file, starting at `offset`, or None if the file does not exist
+    async fn read_file_range(&self, library_id: &str, rel_path: &PathBuf, offset: u64, length: u64) -> Result<Option<ReadStream>, anyhow::Error>;
 
-    fn list_files(&self, library_id: &str, rel_path: &PathBuf) -> Result<Vec<FileEntry>, anyhow::Error>;
+    /// Returns the metadata of the file, or None if it does not exist or is not a file
+    async fn stat_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<Option<FileMetadata>, anyhow::Error>;
 
-    fn delete_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<(), anyhow::Error>;
-    fn move_file(&self, library_id: &str, rel_path: &PathBuf, new_rel_path: &PathBuf) -> Result<(), Error>;
-    fn get_read_stream(&self, library_id: &str, rel_path: &PathBuf,) -> Result<BufReader<File>, Error>;
+    async fn list_files(&self, library_id: &str, rel_path: &PathBuf) -> Result<Vec<FileEntry>, anyhow::Error>;
+
+    /// Lists a page of the folder's entries. The default lists the whole folder and pages it in memory,
+    /// backends that can filter or page while listing override it
+    async fn list_files_page(&self, library_id: &str, rel_path: &PathBuf, options: &ListOptions) -> Result<FilePage, anyhow::Error> {
+        let list = self.list_files(library_id, rel_path).await?;
+        options.page(list)
+    }
+
+    /// Returns whether a file or folder exists at the path
+    async fn exists(&self, library_id: &str, rel_path: &PathBuf) -> Result<bool, anyhow::Error>;
+
+    /// Deletes the file, or the folder and everything in it. Fails with [StorageError::NotFound] if nothing exists at the path
+    async fn delete_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<(), anyhow::Error>;
+    /// Moves the file or folder to a path that does not exist, creating any missing parent folders of the new path.
+    /// Use [StorageBackend::move_file], which checks the source and destination first
+    async fn rename_file(&self, library_id: &str, rel_path: &PathBuf, new_rel_path: &PathBuf) -> Result<(), Error>;
+
+    /// Copies a single file to a path that does not exist, which can be in another library stored by this backend,
+    /// creating any missing parent folders. Returns false without copying if the backend has no faster way than
+    /// streaming the contents, which is then done by [Library::copy_to](crate::objs::library::Library::copy_to)
+    async fn copy_file(&self, library_id: &str, rel_path: &PathBuf, dest_library_id: &str, dest_rel_path: &PathBuf) -> Result<bool, Error>;
+
+    /// Moves the file or folder, handling an existing destination according to the policy. Returns the path it was moved to.
+    /// Backends never delete an existing destination, [Library](crate::objs::library::Library) moves it to the trash before
+    /// moving with [ConflictPolicy::Overwrite], which otherwise fails like [ConflictPolicy::Fail]
+    async fn move_file(&self, library_id: &str, rel_path: &PathBuf, new_rel_path: &PathBuf, policy: ConflictPolicy) -> Result<PathBuf, Error> {
+        // Also rejects moving a path to itself, and moving the library root
+        if new_rel_path.starts_with(rel_path) {
+            return Err(StorageError::InvalidDestination.into())
+        }
+        if !self.exists(library_id, rel_path).await? {
+            return Err(StorageError::NotFound.into())
+        }
+        let mut destination = new_rel_path.clone();
+        if self.exists(library_id, &destination).await? {
+            match policy {
+                ConflictPolicy::Fail | ConflictPolicy::Overwrite => return Err(StorageError::AlreadyExists.into()),
+                ConflictPolicy::Rename => destination = self.free_path(library_id, new_rel_path).await?,
+            }
+        }
+        self.rename_file(library_id, rel_path, &destination).await?;
+        Ok(destination)
+    }
+
+    /// Returns the first path of `name (1).ext`, `name (2).ext`, ... that does not exist
+    async fn free_path(&self, library_id: &str, rel_path: &PathBuf) -> Result<PathBuf, Error> {
+        for n in 1..=MAX_RENAME_ATTEMPTS {
+            let path = numbered_path(rel_path, n);
+            if !self.exists(library_id, &path).await? {
+                return Ok(path)
+            }
+        }
+        Err(StorageError::AlreadyExists.into())
+    }
 }
@@ -1,12 +1,14 @@
-use std::env::join_paths;
-use std::fs::File;
-use std::io::BufReader;
+use std::fs::Metadata;
 use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use anyhow::{anyhow, Error};
 use log::debug;
 use sqlx::types::JsonValue;
-use crate::storage::{FileEntry, FileType, StorageBackend};
+use tokio::fs::File;
+use std::io::SeekFrom;
+use chrono::{DateTime, Utc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use crate::storage::{guess_mime_type, FileEntry, FileMetadata, FileType, ReadStream, StorageBackend, StorageError};
 
 pub struct LocalStorage {
     folder_root: PathBuf
@@ -21,79 +23,151 @@ impl LocalStorage {
     }
 }
 
-fn get_path(folder_root: &PathBuf, library_id: &str, mut path: &Path) -> Result<PathBuf, anyhow::Error> {
-    if path.starts_with("/") {
-        path = path.strip_prefix("/")?
-    }
-    let path = folder_root.join(library_id).join(path);
-    // Prevent path traversal
-    debug!("root={:?}", folder_root);
-    debug!("path={:?}", path);
-    if !path.starts_with(&folder_root) {
-        return Err(anyhow!("Invalid path provided"))
+fn get_path(folder_root: &PathBuf, library_id: &str, path: &Path) -> Result<PathBuf, anyhow::Error> {
+    let mut full_path = folder_root.join(library_id);
+    for component in path.components() {
+        match component {
+            Component::Normal(segment) => full_path.push(segment),
+            Component::RootDir | Component::CurDir => {},
+            // Prevent path traversal, which also keeps paths inside the library's folder
+            Component::ParentDir | Component::Prefix(_) => return Err(StorageError::InvalidPath.into())
+        }
     }
-    debug!("{:?}", path);
-    Ok(path)
+    debug!("path={:?}", full_path);
+    Ok(full_path)
+}
+
+/// Same format as nginx, the modification time and size in hex
+fn etag(meta: &Metadata) -> String {
+    format!("{:x}-{:x}", meta.mtime(), meta.size())
 }
+
+#[rocket::async_trait]
 impl StorageBackend for LocalStorage {
-    fn touch_file(&self, library_id: &str, rel_path: &PathBuf, file_type: FileType) -> Result<(), anyhow::Error> {
+    async fn touch_file(&self, library_id: &str, rel_path: &PathBuf, file_type: FileType) -> Result<(), anyhow::Error> {
         let path = get_path(&self.folder_root, library_id, rel_path)?;
         match file_type {
             FileType::File => {
                 // open and close file
-                File::open(path).map_err(|e| anyhow!(e))?;
+                File::open(path).await.map_err(|e| anyhow!(e))?;
             }
             FileType::Folder => {
-                std::fs::create_dir_all(path).map_err(|e| anyhow!(e))?;
+                tokio::fs::create_dir_all(path).await.map_err(|e| anyhow!(e))?;
             }
             _ => return Err(anyhow!("Unsupported"))
         }
         Ok(())
     }
-    fn write_file(&self, library_id: &str, rel_path: &PathBuf, contents: &[u8]) -> Result<(), Error> {
+
+    async fn write_file(&self, library_id: &str, rel_path: &PathBuf, contents: &mut (dyn AsyncRead + Send + Unpin)) -> Result<u64, Error> {
         let path = get_path(&self.folder_root, library_id, rel_path)?;
-        std::fs::write(path, contents).map_err(|e| anyhow!(e))
+        let mut file = File::create(path).await?;
+        let written = tokio::io::copy(contents, &mut file).await?;
+        file.flush().await?;
+        Ok(written)
     }
 
-    fn read_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<Option<Vec<u8>>, Error> {
+    async fn read_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<Option<ReadStream>, Error> {
         let path = get_path(&self.folder_root, library_id, rel_path)?;
-        match std::fs::read(path) {
-            Ok(contents) => Ok(Some(contents)),
+        match File::open(path).await {
+            Ok(file) => Ok(Some(Box::new(BufReader::new(file)))),
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
             Err(e) => Err(anyhow!(e)),
         }
     }
-    fn list_files(&self, library_id: &str, rel_path: &PathBuf) -> Result<Vec<FileEntry>, Error> {
+
+    async fn read_file_range(&self, library_id: &str, rel_path: &PathBuf, offset: u64, length: u64) -> Result<Option<ReadStream>, Error> {
+        let path = get_path(&self.folder_root, library_id, rel_path)?;
+        let mut file = match File::open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow!(e)),
+        };
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Some(Box::new(BufReader::new(file).take(length))))
+    }
+
+    async fn stat_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<Option<FileMetadata>, Error> {
+        let path = get_path(&self.folder_root, library_id, rel_path)?;
+        let meta = match tokio::fs::metadata(path).await {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow!(e)),
+        };
+        if !meta.is_file() {
+            return Ok(None)
+        }
+        let last_modified = meta.modified().ok().map(DateTime::<Utc>::from);
+        Ok(Some(FileMetadata {
+            size: meta.size(),
+            etag: Some(etag(&meta)),
+            last_modified,
+        }))
+    }
+
+    async fn list_files(&self, library_id: &str, rel_path: &PathBuf) -> Result<Vec<FileEntry>, Error> {
+        let path = get_path(&self.folder_root, library_id, rel_path)?;
+        let mut entries = tokio::fs::read_dir(path).await?;
+        let mut list = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let meta = entry.metadata().await?;
+            let file_type: FileType = meta.file_type().into();
+            let name = entry.file_name().into_string().unwrap();
+            // TODO: filter out 'other'
+            list.push(FileEntry {
+                mime: (file_type == FileType::File).then(|| guess_mime_type(&name)).flatten(),
+                _type: file_type,
+                path: name,
+                // The size of a folder's own inode says nothing about its contents
+                size: if file_type == FileType::Folder { 0 } else { meta.size() },
+                last_modified: meta.modified().ok().map(DateTime::<Utc>::from),
+                // Not every filesystem records it
+                created: meta.created().ok().map(DateTime::<Utc>::from),
+                item_count: None,
+                favorited: false,
+                etag: (file_type == FileType::File).then(|| etag(&meta)),
+            });
+        }
+        Ok(list)
+    }
+
+    async fn exists(&self, library_id: &str, rel_path: &PathBuf) -> Result<bool, Error> {
         let path = get_path(&self.folder_root, library_id, rel_path)?;
-        Ok(std::fs::read_dir(path)?
-            .map(|entry| entry.unwrap())
-            .map(|entry| {
-                let meta = entry.metadata().unwrap();
-                let file_type = meta.file_type().into();
-                // TODO: filter out 'other'
-                FileEntry {
-                    _type: file_type,
-                    path: entry.file_name().into_string().unwrap(),
-                    size: meta.size()
-                }
-            })
-            .collect())
+        tokio::fs::try_exists(path).await.map_err(|e| anyhow!(e))
     }
 
-    fn delete_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<(), Error> {
-         let path = get_path(&self.folder_root, library_id, rel_path)?;
-         // TODO: check if folder?
-         std::fs::remove_file(path).map_err(|e| anyhow!(e))
-     }
+    async fn delete_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<(), Error> {
+        let path = get_path(&self.folder_root, library_id, rel_path)?;
+        let meta = match tokio::fs::symlink_metadata(&path).await {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(StorageError::NotFound.into()),
+            Err(e) => return Err(anyhow!(e)),
+        };
+        if meta.is_dir() {
+            tokio::fs::remove_dir_all(path).await.map_err(|e| anyhow!(e))
+        } else {
+            tokio::fs::remove_file(path).await.map_err(|e| anyhow!(e))
+        }
+    }
 
-    fn move_file(&self, library_id: &str, rel_path: &PathBuf, new_rel_path: &PathBuf) -> Result<(), Error> {
+    async fn copy_file(&self, library_id: &str, rel_path: &PathBuf, dest_library_id: &str, dest_rel_path: &PathBuf) -> Result<bool, Error> {
         let path = get_path(&self.folder_root, library_id, rel_path)?;
-        std::fs::rename(path, new_rel_path).map_err(|e| anyhow!(e))
+        let new_path = get_path(&self.folder_root, dest_library_id, dest_rel_path)?;
+        if let Some(parent) = new_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Uses copy_file_range on Linux, which copies inside the kernel and reflinks on filesystems that support it.
+        // Hardlinks aren't used, as files are overwritten in place
+        tokio::fs::copy(path, new_path).await?;
+        Ok(true)
     }
 
-    fn get_read_stream(&self, library_id: &str, rel_path: &PathBuf,) -> Result<BufReader<File>, Error> {
+    async fn rename_file(&self, library_id: &str, rel_path: &PathBuf, new_rel_path: &PathBuf) -> Result<(), Error> {
         let path = get_path(&self.folder_root, library_id, rel_path)?;
-        let file = File::open(path)?;
-        Ok(BufReader::new(file))
+        let new_path = get_path(&self.folder_root, library_id, new_rel_path)?;
+        if let Some(parent) = new_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(path, new_path).await.map_err(|e| anyhow!(e))
     }
-}
\ No newline at end of file
+}
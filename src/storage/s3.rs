@@ -1,26 +1,580 @@
-use serde_json::Value;
+use std::path::{Component, Path, PathBuf};
+use anyhow::{anyhow, Error};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use rocket::futures::TryStreamExt;
+use chrono::{DateTime, Utc};
+use ::s3::command::Command;
+use ::s3::creds::Credentials;
+use ::s3::error::S3Error;
+use ::s3::request::{Request, ResponseDataStream};
+use ::s3::request::tokio_backend::ReqwestRequest;
+use ::s3::serde_types::Object;
+use ::s3::{Bucket, Region};
 use sqlx::types::JsonValue;
-use crate::storage::{StorageBackend};
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+use crate::storage::{guess_mime_type, FileEntry, FileMetadata, FilePage, FileType, ListOptions, ReadStream, StorageBackend, StorageError};
 
+/// The largest object S3 can copy in a single CopyObject request, 5 GiB
+const MAX_COPY_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Stores libraries as objects in an S3 (or S3-compatible, ex. MinIO) bucket.
+/// Each library lives under `<prefix><library_id>/`, folders are emulated through key prefixes,
+/// with an empty `<folder>/` marker object so that empty folders can exist.
+///
+/// Settings:
+/// ```json
+/// {
+///     "bucket": "storage",
+///     "region": "us-east-1",
+///     "endpoint": "http://localhost:9000",
+///     "prefix": "libraries/",
+///     "access_key": "minioadmin",
+///     "secret_key": "minioadmin",
+///     "path_style": true
+/// }
+/// ```
+/// `endpoint` is optional for AWS, `path_style` defaults to true when an endpoint is set.
 pub struct S3Storage {
-    // pub fn uri:
-    /* uri, bucket id, auth */
+    bucket: Box<Bucket>,
+    prefix: String,
+    /// Objects above this size are streamed through when copied, [MAX_COPY_OBJECT_SIZE] outside of tests
+    max_copy_size: u64,
 }
+
 impl S3Storage {
-    fn new(settings: &JsonValue) -> Result<Self, anyhow::Error> {
-        todo!()
-    }
-}
-// impl StorageBackend for S3Storage {
-//
-//
-//     fn write_file(&mut self, path: &str, contents: &[u8]) -> Result<(), StorageBackendError> {
-//         unimplemented!()
-//     }
-//     fn read_file(&self, path: &str) -> Result<Vec<u8>, StorageBackendError> {
-//         unimplemented!()
-//     }
-//     fn delete_file(&mut self, path: &str) -> Result<(), StorageBackendError> {
-//         unimplemented!()
-//     }
-// }
\ No newline at end of file
+    pub(crate) fn new(settings: &JsonValue) -> Result<Self, anyhow::Error> {
+        let bucket_name = settings["bucket"].as_str().ok_or_else(|| anyhow!("No 'bucket' configured"))?;
+        let region_name = settings["region"].as_str().unwrap_or("us-east-1").to_string();
+        let endpoint = settings["endpoint"].as_str();
+        let region = match endpoint {
+            Some(endpoint) => Region::Custom { region: region_name, endpoint: endpoint.trim_end_matches('/').to_string() },
+            None => region_name.parse().map_err(|e| anyhow!("Invalid 'region': {}", e))?
+        };
+        let credentials = Credentials::new(
+            settings["access_key"].as_str(),
+            settings["secret_key"].as_str(),
+            None,
+            settings["session_token"].as_str(),
+            None
+        ).map_err(|e| anyhow!("Invalid S3 credentials: {}", e))?;
+        let mut bucket = Bucket::new(bucket_name, region, credentials)?;
+        if settings["path_style"].as_bool().unwrap_or(endpoint.is_some()) {
+            bucket.set_path_style();
+        }
+        // Normalize prefix to either be empty or end in a single slash
+        let prefix = settings["prefix"].as_str().unwrap_or("").trim_matches('/');
+        let prefix = if prefix.is_empty() { String::new() } else { format!("{}/", prefix) };
+        Ok(S3Storage {
+            bucket,
+            prefix,
+            max_copy_size: MAX_COPY_OBJECT_SIZE
+        })
+    }
+
+    /// Returns the object key for the path inside the library, without any trailing slash
+    fn get_key(&self, library_id: &str, path: &Path) -> Result<String, anyhow::Error> {
+        let mut key = format!("{}{}", self.prefix, library_id);
+        for component in path.components() {
+            match component {
+                Component::Normal(segment) => {
+                    let segment = segment.to_str().ok_or(StorageError::InvalidPath)?;
+                    key.push('/');
+                    key.push_str(segment);
+                },
+                Component::RootDir | Component::CurDir => {},
+                // Prevent path traversal
+                Component::ParentDir | Component::Prefix(_) => return Err(StorageError::InvalidPath.into())
+            }
+        }
+        Ok(key)
+    }
+
+    /// Returns the key prefix that all children of the folder share
+    fn get_folder_prefix(&self, library_id: &str, path: &Path) -> Result<String, anyhow::Error> {
+        Ok(format!("{}/", self.get_key(library_id, path)?))
+    }
+
+    async fn object_exists(&self, key: &str) -> Result<bool, anyhow::Error> {
+        match self.bucket.head_object(key).await {
+            Ok(_) => Ok(true),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(false),
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+
+    /// Returns the size of the object, None if there is none with the key
+    async fn object_size(&self, key: &str) -> Result<Option<u64>, anyhow::Error> {
+        match self.bucket.head_object(key).await {
+            Ok((head, _)) => Ok(Some(head.content_length.unwrap_or(0) as u64)),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+
+    /// Returns all objects under the prefix, recursively
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<Object>, anyhow::Error> {
+        let pages = self.bucket.list(prefix.to_string(), None).await?;
+        Ok(pages.into_iter()
+            .flat_map(|page| page.contents)
+            .collect())
+    }
+
+    /// Copies the object inside the bucket. Objects too large for a single CopyObject request
+    /// are streamed through instead, and uploaded again in parts
+    async fn copy_object(&self, key: &str, new_key: &str, size: u64) -> Result<(), anyhow::Error> {
+        if size <= self.max_copy_size {
+            self.bucket.copy_object_internal(key, new_key).await?;
+            return Ok(())
+        }
+        let mut contents = to_read_stream(self.bucket.get_object_stream(key).await?);
+        self.bucket.put_object_stream(&mut contents, new_key).await?;
+        Ok(())
+    }
+}
+
+/// Returns the part of the name filter before its first wildcard, which all matching names start with
+fn literal_prefix(name_filter: Option<&str>) -> &str {
+    name_filter
+        .map(|filter| &filter[..filter.find(['*', '?', '[']).unwrap_or(filter.len())])
+        .unwrap_or("")
+}
+
+/// Returns the cursor for a page ending with the name
+fn encode_cursor(name: &str) -> String {
+    BASE64_URL.encode(name)
+}
+
+/// Returns the key a page continues after, from the cursor of the previous page
+fn decode_cursor(prefix: &str, cursor: &str, folders: bool) -> Result<String, StorageError> {
+    let name = BASE64_URL.decode(cursor).ok()
+        .and_then(|name| String::from_utf8(name).ok())
+        .ok_or(StorageError::InvalidCursor)?;
+    // Skip everything inside the last folder, not just its name
+    Ok(if folders { format!("{}{}/{}", prefix, name, char::MAX) } else { format!("{}{}", prefix, name) })
+}
+
+/// Returns the entry for a common prefix, by its name inside the listed folder
+fn folder_entry(name: &str) -> FileEntry {
+    FileEntry {
+        path: name.trim_end_matches('/').to_string(),
+        size: 0,
+        _type: FileType::Folder,
+        last_modified: None,
+        created: None,
+        mime: None,
+        item_count: None,
+        favorited: false,
+        etag: None,
+    }
+}
+
+/// Returns the entry for an object, by its name inside the listed folder
+fn object_entry(name: &str, object: &Object) -> FileEntry {
+    FileEntry {
+        path: name.to_string(),
+        size: object.size,
+        _type: FileType::File,
+        last_modified: DateTime::parse_from_rfc3339(&object.last_modified).ok().map(|date| date.with_timezone(&Utc)),
+        // S3 only keeps when the object was last written
+        created: None,
+        mime: guess_mime_type(name),
+        item_count: None,
+        favorited: false,
+        etag: object.e_tag.as_ref().map(|etag| etag.trim_matches('"').to_string()),
+    }
+}
+
+fn to_read_stream(response: ResponseDataStream) -> ReadStream {
+    let stream = response.bytes.map_err(std::io::Error::other);
+    Box::new(StreamReader::new(stream))
+}
+
+#[rocket::async_trait]
+impl StorageBackend for S3Storage {
+    async fn touch_file(&self, library_id: &str, rel_path: &PathBuf, file_type: FileType) -> Result<(), anyhow::Error> {
+        let key = match file_type {
+            FileType::File => self.get_key(library_id, rel_path)?,
+            FileType::Folder => self.get_folder_prefix(library_id, rel_path)?,
+            _ => return Err(anyhow!("Unsupported"))
+        };
+        if !self.object_exists(&key).await? {
+            self.bucket.put_object(&key, &[]).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_file(&self, library_id: &str, rel_path: &PathBuf, contents: &mut (dyn AsyncRead + Send + Unpin)) -> Result<u64, Error> {
+        let key = self.get_key(library_id, rel_path)?;
+        // Uploads in chunks (multipart for larger files), so the whole file is never in memory
+        let response = self.bucket.put_object_stream(contents, &key).await?;
+        Ok(response.uploaded_bytes() as u64)
+    }
+
+    async fn read_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<Option<ReadStream>, Error> {
+        let key = self.get_key(library_id, rel_path)?;
+        match self.bucket.get_object_stream(&key).await {
+            Ok(response) => Ok(Some(to_read_stream(response))),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+
+    async fn read_file_range(&self, library_id: &str, rel_path: &PathBuf, offset: u64, length: u64) -> Result<Option<ReadStream>, Error> {
+        if length == 0 {
+            return Ok(Some(Box::new(tokio::io::empty())))
+        }
+        let key = self.get_key(library_id, rel_path)?;
+        // Bucket has no streaming variant of get_object_range, so make the request ourselves
+        let command = Command::GetObjectRange { start: offset, end: Some(offset + length - 1) };
+        let request = ReqwestRequest::new(&self.bucket, &key, command).await?;
+        match request.response_data_to_stream().await {
+            Ok(response) => Ok(Some(to_read_stream(response))),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(anyhow!(e)),
+        }
+    }
+
+    async fn stat_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<Option<FileMetadata>, Error> {
+        let key = self.get_key(library_id, rel_path)?;
+        let (head, _) = match self.bucket.head_object(&key).await {
+            Ok(result) => result,
+            Err(S3Error::HttpFailWithBody(404, _)) => return Ok(None),
+            Err(e) => return Err(anyhow!(e)),
+        };
+        Ok(Some(FileMetadata {
+            size: head.content_length.unwrap_or(0) as u64,
+            last_modified: head.last_modified
+                .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+                .map(|date| date.with_timezone(&Utc)),
+            etag: head.e_tag.map(|etag| etag.trim_matches('"').to_string()),
+        }))
+    }
+
+    async fn list_files(&self, library_id: &str, rel_path: &PathBuf) -> Result<Vec<FileEntry>, Error> {
+        let prefix = self.get_folder_prefix(library_id, rel_path)?;
+        let pages = self.bucket.list(prefix.clone(), Some("/".to_string())).await?;
+        let mut entries = Vec::new();
+        for page in pages {
+            for common_prefix in page.common_prefixes.unwrap_or_default() {
+                entries.push(folder_entry(&common_prefix.prefix[prefix.len()..]));
+            }
+            for object in page.contents {
+                // Skip the folder's own marker object
+                if object.key == prefix { continue; }
+                entries.push(object_entry(&object.key[prefix.len()..], &object));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Pages natively when listing only files or only folders by name, as S3 lists keys in that order.
+    /// The cursor is then the last name of the page, which the next page starts after
+    async fn list_files_page(&self, library_id: &str, rel_path: &PathBuf, options: &ListOptions) -> Result<FilePage, Error> {
+        let (Some(limit), Some(file_type @ (FileType::File | FileType::Folder))) = (options.limit, &options.file_type) else {
+            return options.page(self.list_files(library_id, rel_path).await?)
+        };
+        if !options.is_name_ascending() || limit == 0 {
+            return options.page(self.list_files(library_id, rel_path).await?)
+        }
+        let folders = *file_type == FileType::Folder;
+        let prefix = self.get_folder_prefix(library_id, rel_path)?;
+        let pattern = options.pattern()?;
+        // Only keys starting with the literal part of the filter can match
+        let literal = literal_prefix(options.name_filter.as_deref());
+        let mut start_after = options.cursor.as_deref()
+            .map(|cursor| decode_cursor(&prefix, cursor, folders))
+            .transpose()?;
+        let mut continuation_token = None;
+        let mut files = Vec::new();
+        loop {
+            let (page, _) = self.bucket.list_page(format!("{}{}", prefix, literal), Some("/".to_string()),
+                continuation_token.take(), start_after.take(), None).await?;
+            let entries: Vec<FileEntry> = if folders {
+                page.common_prefixes.unwrap_or_default().iter()
+                    .map(|common_prefix| folder_entry(&common_prefix.prefix[prefix.len()..]))
+                    .collect()
+            } else {
+                page.contents.iter()
+                    .filter(|object| object.key != prefix)
+                    .map(|object| object_entry(&object.key[prefix.len()..], object))
+                    .collect()
+            };
+            let count = entries.len();
+            for (i, entry) in entries.into_iter().enumerate() {
+                if pattern.as_ref().is_some_and(|pattern| !pattern.matches(&entry.path)) { continue; }
+                files.push(entry);
+                if files.len() == limit {
+                    let is_last = i + 1 == count && !page.is_truncated;
+                    let next_cursor = (!is_last).then(|| encode_cursor(&files[limit - 1].path));
+                    return Ok(FilePage { files, next_cursor })
+                }
+            }
+            if !page.is_truncated {
+                return Ok(FilePage { files, next_cursor: None })
+            }
+            continuation_token = page.next_continuation_token;
+        }
+    }
+
+    async fn exists(&self, library_id: &str, rel_path: &PathBuf) -> Result<bool, Error> {
+        if self.object_exists(&self.get_key(library_id, rel_path)?).await? {
+            return Ok(true)
+        }
+        let prefix = self.get_folder_prefix(library_id, rel_path)?;
+        let pages = self.bucket.list_page(prefix, None, None, None, Some(1)).await?;
+        Ok(!pages.0.contents.is_empty())
+    }
+
+    async fn delete_file(&self, library_id: &str, rel_path: &PathBuf) -> Result<(), Error> {
+        let key = self.get_key(library_id, rel_path)?;
+        if self.object_exists(&key).await? {
+            self.bucket.delete_object(&key).await?;
+            return Ok(())
+        }
+        // Not a file, delete the folder and everything under it
+        let objects = self.list_objects(&self.get_folder_prefix(library_id, rel_path)?).await?;
+        if objects.is_empty() {
+            return Err(StorageError::NotFound.into())
+        }
+        for object in objects {
+            self.bucket.delete_object(&object.key).await?;
+        }
+        Ok(())
+    }
+
+    async fn copy_file(&self, library_id: &str, rel_path: &PathBuf, dest_library_id: &str, dest_rel_path: &PathBuf) -> Result<bool, Error> {
+        let key = self.get_key(library_id, rel_path)?;
+        let size = self.object_size(&key).await?.ok_or(StorageError::NotFound)?;
+        // Let the caller stream objects a single CopyObject request can't copy, as it can write them anywhere
+        if size > self.max_copy_size {
+            return Ok(false)
+        }
+        self.copy_object(&key, &self.get_key(dest_library_id, dest_rel_path)?, size).await?;
+        Ok(true)
+    }
+
+    async fn rename_file(&self, library_id: &str, rel_path: &PathBuf, new_rel_path: &PathBuf) -> Result<(), Error> {
+        let key = self.get_key(library_id, rel_path)?;
+        let new_key = self.get_key(library_id, new_rel_path)?;
+        if let Some(size) = self.object_size(&key).await? {
+            self.copy_object(&key, &new_key, size).await?;
+            self.bucket.delete_object(&key).await?;
+            return Ok(())
+        }
+        // S3 has no folders, so every object under the folder has to be moved on its own
+        let prefix = self.get_folder_prefix(library_id, rel_path)?;
+        let new_prefix = self.get_folder_prefix(library_id, new_rel_path)?;
+        let objects = self.list_objects(&prefix).await?;
+        if objects.is_empty() {
+            return Err(StorageError::NotFound.into())
+        }
+        for object in objects {
+            let new_key = format!("{}{}", new_prefix, &object.key[prefix.len()..]);
+            self.copy_object(&object.key, &new_key, object.size).await?;
+            self.bucket.delete_object(&object.key).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::io::AsyncReadExt;
+    use uuid::Uuid;
+
+    fn storage(prefix: &str) -> S3Storage {
+        S3Storage::new(&json!({
+            "bucket": "storage",
+            "endpoint": "http://localhost:9000/",
+            "prefix": prefix,
+            "access_key": "key",
+            "secret_key": "secret",
+        })).unwrap()
+    }
+
+    #[test]
+    fn normalizes_the_prefix() {
+        assert_eq!(storage("").prefix, "");
+        assert_eq!(storage("/").prefix, "");
+        assert_eq!(storage("libraries").prefix, "libraries/");
+        assert_eq!(storage("/libraries//").prefix, "libraries/");
+        assert_eq!(storage("a/b/").prefix, "a/b/");
+    }
+
+    #[test]
+    fn builds_keys_inside_the_library() {
+        let storage = storage("libraries/");
+        assert_eq!(storage.get_key("lib", Path::new("")).unwrap(), "libraries/lib");
+        assert_eq!(storage.get_key("lib", Path::new("a/b.txt")).unwrap(), "libraries/lib/a/b.txt");
+        assert_eq!(storage.get_key("lib", Path::new("/a/./b.txt")).unwrap(), "libraries/lib/a/b.txt");
+        assert_eq!(storage.get_key("lib", Path::new("a//b/")).unwrap(), "libraries/lib/a/b");
+        assert_eq!(storage.get_folder_prefix("lib", Path::new("")).unwrap(), "libraries/lib/");
+        assert_eq!(storage.get_folder_prefix("lib", Path::new("a/b/")).unwrap(), "libraries/lib/a/b/");
+    }
+
+    #[test]
+    fn rejects_keys_leaving_the_library() {
+        let storage = storage("");
+        for path in ["..", "../other", "a/../../other", "a/.."] {
+            assert!(storage.get_key("lib", Path::new(path)).is_err(), "{} was accepted", path);
+            assert!(storage.get_folder_prefix("lib", Path::new(path)).is_err(), "{} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn takes_the_literal_prefix_of_filters() {
+        assert_eq!(literal_prefix(None), "");
+        assert_eq!(literal_prefix(Some("report.pdf")), "report.pdf");
+        assert_eq!(literal_prefix(Some("IMG_*.jpg")), "IMG_");
+        assert_eq!(literal_prefix(Some("a?c")), "a");
+        assert_eq!(literal_prefix(Some("[ab]*")), "");
+        assert_eq!(literal_prefix(Some("*")), "");
+    }
+
+    #[test]
+    fn round_trips_cursors() {
+        for name in ["a.txt", "folder", "with space & ünïcödé/?", ""] {
+            let cursor = encode_cursor(name);
+            assert!(!cursor.contains(['+', '/', '=']), "{} is not URL safe", cursor);
+            assert_eq!(decode_cursor("lib/", &cursor, false).unwrap(), format!("lib/{}", name));
+        }
+    }
+
+    #[test]
+    fn skips_the_contents_of_the_last_folder() {
+        let start_after = decode_cursor("lib/", &encode_cursor("photos"), true).unwrap();
+        assert_eq!(start_after, format!("lib/photos/{}", char::MAX));
+        assert!(start_after.as_str() > "lib/photos/zzz/deep.jpg");
+        assert!(start_after.as_str() < "lib/photos2/");
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        // Not base64, and base64 of invalid UTF-8
+        for cursor in ["!!!", "/w"] {
+            assert!(matches!(decode_cursor("lib/", cursor, false), Err(StorageError::InvalidCursor)), "{} was accepted", cursor);
+        }
+    }
+
+    /// Runs against a real S3 server with the bucket from `S3_TEST_SETTINGS`, which holds the same JSON as a library's
+    /// storage settings, ex. for MinIO:
+    /// `S3_TEST_SETTINGS='{"bucket":"test","endpoint":"http://localhost:9000","access_key":"minioadmin","secret_key":"minioadmin"}'`
+    /// `cargo test s3 -- --ignored`
+    fn test_storage() -> Option<S3Storage> {
+        let settings = std::env::var("S3_TEST_SETTINGS").ok()?;
+        let mut storage = S3Storage::new(&serde_json::from_str(&settings).expect("Invalid S3_TEST_SETTINGS")).unwrap();
+        // Stream objects of more than a few bytes, so the fallback can be tested without 5 GiB objects
+        storage.max_copy_size = 4;
+        Some(storage)
+    }
+
+    async fn write(storage: &S3Storage, library_id: &str, path: &str, contents: &str) {
+        storage.write_file(library_id, &PathBuf::from(path), &mut contents.as_bytes()).await.unwrap();
+    }
+
+    async fn read(stream: Option<ReadStream>) -> String {
+        let mut contents = String::new();
+        stream.expect("No file").read_to_string(&mut contents).await.unwrap();
+        contents
+    }
+
+    async fn names(storage: &S3Storage, library_id: &str, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = storage.list_files(library_id, &PathBuf::from(path)).await.unwrap()
+            .into_iter().map(|entry| entry.path).collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 server, see S3_TEST_SETTINGS"]
+    async fn reads_ranges() {
+        let Some(storage) = test_storage() else { return };
+        let library_id = Uuid::new_v4().to_string();
+        write(&storage, &library_id, "file.txt", "0123456789").await;
+        let path = PathBuf::from("file.txt");
+        assert_eq!(read(storage.read_file(&library_id, &path).await.unwrap()).await, "0123456789");
+        assert_eq!(read(storage.read_file_range(&library_id, &path, 2, 3).await.unwrap()).await, "234");
+        assert_eq!(read(storage.read_file_range(&library_id, &path, 8, 2).await.unwrap()).await, "89");
+        assert_eq!(storage.stat_file(&library_id, &path).await.unwrap().map(|metadata| metadata.size), Some(10));
+        assert!(storage.read_file(&library_id, &PathBuf::from("missing.txt")).await.unwrap().is_none());
+        storage.delete_file(&library_id, &PathBuf::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 server, see S3_TEST_SETTINGS"]
+    async fn pages_with_cursors() {
+        let Some(storage) = test_storage() else { return };
+        let library_id = Uuid::new_v4().to_string();
+        for name in ["a.txt", "b.jpg", "c.txt", "d.txt", "e.jpg"] {
+            write(&storage, &library_id, name, "x").await;
+        }
+        for folder in ["f1", "f2", "f3"] {
+            write(&storage, &library_id, &format!("{}/inner.txt", folder), "x").await;
+            write(&storage, &library_id, &format!("{}/sub/deep.txt", folder), "x").await;
+        }
+        let list_all = async |file_type: FileType, name_filter: Option<&str>| {
+            let mut names = Vec::new();
+            let mut cursor = None;
+            loop {
+                let options = ListOptions { file_type: Some(file_type), limit: Some(2), cursor, name_filter: name_filter.map(str::to_string), ..Default::default() };
+                let page = storage.list_files_page(&library_id, &PathBuf::new(), &options).await.unwrap();
+                assert!(page.files.len() <= 2);
+                names.extend(page.files.into_iter().map(|entry| entry.path));
+                match page.next_cursor {
+                    Some(next_cursor) => cursor = Some(next_cursor),
+                    None => return names
+                }
+            }
+        };
+        assert_eq!(list_all(FileType::File, None).await, ["a.txt", "b.jpg", "c.txt", "d.txt", "e.jpg"]);
+        assert_eq!(list_all(FileType::File, Some("*.txt")).await, ["a.txt", "c.txt", "d.txt"]);
+        assert_eq!(list_all(FileType::Folder, None).await, ["f1", "f2", "f3"]);
+        assert_eq!(list_all(FileType::Folder, Some("f2")).await, ["f2"]);
+        storage.delete_file(&library_id, &PathBuf::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 server, see S3_TEST_SETTINGS"]
+    async fn copies_large_objects() {
+        let Some(storage) = test_storage() else { return };
+        let library_id = Uuid::new_v4().to_string();
+        write(&storage, &library_id, "small.txt", "abc").await;
+        write(&storage, &library_id, "large.txt", "0123456789").await;
+        let (small, large) = (PathBuf::from("small.txt"), PathBuf::from("large.txt"));
+        assert!(storage.copy_file(&library_id, &small, &library_id, &PathBuf::from("small copy.txt")).await.unwrap());
+        assert_eq!(read(storage.read_file(&library_id, &PathBuf::from("small copy.txt")).await.unwrap()).await, "abc");
+        // Left for the caller to stream
+        assert!(!storage.copy_file(&library_id, &large, &library_id, &PathBuf::from("large copy.txt")).await.unwrap());
+        // Renames stream it through instead
+        storage.rename_file(&library_id, &large, &PathBuf::from("renamed.txt")).await.unwrap();
+        assert_eq!(read(storage.read_file(&library_id, &PathBuf::from("renamed.txt")).await.unwrap()).await, "0123456789");
+        assert!(!storage.exists(&library_id, &large).await.unwrap());
+        storage.delete_file(&library_id, &PathBuf::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3 server, see S3_TEST_SETTINGS"]
+    async fn moves_and_deletes_folders_by_prefix() {
+        let Some(storage) = test_storage() else { return };
+        let library_id = Uuid::new_v4().to_string();
+        write(&storage, &library_id, "docs/a.txt", "a").await;
+        write(&storage, &library_id, "docs/sub/b.txt", "bbbbbbbb").await;
+        storage.touch_file(&library_id, &PathBuf::from("docs/empty"), FileType::Folder).await.unwrap();
+        // Shares the name as a prefix, but isn't inside the folder
+        write(&storage, &library_id, "docs2/c.txt", "c").await;
+
+        storage.rename_file(&library_id, &PathBuf::from("docs"), &PathBuf::from("moved")).await.unwrap();
+        assert_eq!(names(&storage, &library_id, "").await, ["docs2", "moved"]);
+        assert_eq!(names(&storage, &library_id, "moved").await, ["a.txt", "empty", "sub"]);
+        assert_eq!(read(storage.read_file(&library_id, &PathBuf::from("moved/sub/b.txt")).await.unwrap()).await, "bbbbbbbb");
+
+        storage.delete_file(&library_id, &PathBuf::from("moved")).await.unwrap();
+        assert_eq!(names(&storage, &library_id, "").await, ["docs2"]);
+        assert!(storage.delete_file(&library_id, &PathBuf::from("moved")).await.is_err());
+        storage.delete_file(&library_id, &PathBuf::new()).await.unwrap();
+    }
+}
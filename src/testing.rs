@@ -0,0 +1,224 @@
+//! Fixtures for tests that need the database. They use the database from `DATABASE_URL`, the same one the query macros
+//! are checked against, creating their own users, repo and libraries with fresh ids, which [TestData::cleanup] removes again
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use chrono::{NaiveDateTime, Utc};
+use tokio::io::AsyncReadExt;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::query;
+use sqlx::types::{Json, Uuid};
+use crate::config::{GeneralConfig, SearchConfig, TrashConfig};
+use crate::managers::activity::ActivityManager;
+use crate::managers::favorites::FavoriteManager;
+use crate::managers::libraries::LibraryManager;
+use crate::managers::notifications::NotificationManager;
+use crate::managers::repos::RepoManager;
+use crate::managers::search::SearchManager;
+use crate::managers::share_links::ShareLinkManager;
+use crate::managers::trash::TrashManager;
+use crate::models::api_token::{ApiTokenModel, ApiTokenScope};
+use crate::models::library::{LibraryModel, PermissionLevel};
+use crate::models::user::UserModel;
+use crate::objs::library::Library;
+use crate::storage::FileType;
+use crate::util::{gen_api_token, hash_api_token, ResponseError};
+use crate::consts::SESSION_LIFETIME_SECONDS;
+use crate::models;
+use crate::{LoginSessionData, SessionData, DB};
+
+/// Marks the repos and users created by tests
+const TEST_REPO_PREFIX: &str = "test-";
+const TEST_EMAIL_DOMAIN: &str = "storage-test.invalid";
+
+pub struct TestData {
+    pub pool: DB,
+    pub repo_id: String,
+    /// Where the test repo stores its libraries
+    pub root: PathBuf,
+    user_ids: Vec<String>,
+}
+
+impl TestData {
+    /// Creates a repo that stores libraries in a new temporary folder
+    pub async fn new() -> Self {
+        dotenvy::dotenv().ok();
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(&std::env::var("DATABASE_URL").expect("DATABASE_URL must be set"))
+            .await
+            .expect("Failed to connect to the test database");
+        remove_stale(&pool).await;
+        let repo_id = format!("{}{}", TEST_REPO_PREFIX, Uuid::new_v4());
+        let root = std::env::temp_dir().join(&repo_id);
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        query!("insert into storage.repos (id, storage_type, storage_settings) values ($1, 'local', $2)",
+            repo_id, Json(serde_json::json!({ "path": root })) as _
+        )
+            .execute(&pool)
+            .await.unwrap();
+        Self { pool, repo_id, root, user_ids: Vec::new() }
+    }
+
+    /// Creates a user without a password, named the username followed by a random suffix
+    pub async fn user(&mut self, username: &str) -> UserModel {
+        let id = Uuid::new_v4().to_string();
+        let username = format!("{}-{}", username, &id[..8]);
+        let user = sqlx::query_as!(UserModel,
+            "insert into storage.users (id, username, email) values ($1, $2, $3) returning id, username, email, created_at, name",
+            id, username, format!("{}@{}", username, TEST_EMAIL_DOMAIN)
+        )
+            .fetch_one(&self.pool)
+            .await.unwrap();
+        self.user_ids.push(user.id.clone());
+        user
+    }
+
+    /// Sets the user's password, hashed with the lowest cost so tests stay fast
+    pub async fn set_password(&self, user: &UserModel, password: &str) {
+        query!("update storage.users set password = $2 where id = $1", user.id, bcrypt::hash(password, 4).unwrap())
+            .execute(&self.pool)
+            .await.unwrap();
+    }
+
+    /// Creates an empty library owned by the user in the test repo
+    pub async fn library(&self, owner: &UserModel) -> LibraryModel {
+        let library = sqlx::query_as!(LibraryModel,
+            "insert into storage.libraries (id, owner_id, repo_id, name) values ($1, $2, $3, 'test') returning *",
+            Uuid::new_v4(), owner.id, self.repo_id
+        )
+            .fetch_one(&self.pool)
+            .await.unwrap();
+        tokio::fs::create_dir(self.root.join(library.id.to_string())).await.unwrap();
+        library
+    }
+
+    /// Logs the user in, returning the id of the new session
+    pub async fn login(&self, user: &UserModel) -> String {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let session = SessionData {
+            login: Some(LoginSessionData {
+                id: Uuid::new_v4(),
+                user: user.clone(),
+                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                user_agent: None,
+                created_at: now,
+                last_active: now,
+            }),
+            ..Default::default()
+        };
+        models::session::set_session(&self.pool, &id, &serde_json::to_value(session).unwrap(), SESSION_LIFETIME_SECONDS).await.unwrap();
+        id
+    }
+
+    /// Creates an API token for the user, returning the token along with it
+    pub async fn api_token(&self, user: &UserModel, library_id: Option<&Uuid>, scope: ApiTokenScope, expires_at: Option<NaiveDateTime>) -> (String, ApiTokenModel) {
+        let token = gen_api_token();
+        let model = models::api_token::create_api_token(&self.pool, &user.id, "test", &hash_api_token(&token), library_id, scope, expires_at).await.unwrap();
+        (token, model)
+    }
+
+    pub async fn repos(&self) -> RepoManager {
+        let mut repos = RepoManager::new(self.pool.clone());
+        repos.fetch_repos().await.unwrap();
+        repos
+    }
+
+    /// A library manager with the default configuration, trashing deleted files through the trash manager
+    pub async fn libraries(&self, trash: Arc<TrashManager>) -> LibraryManager {
+        LibraryManager::new(self.pool.clone(), self.repos().await,
+            Arc::new(SearchManager::new(self.pool.clone(), &SearchConfig::default())),
+            Arc::new(FavoriteManager::new(self.pool.clone())),
+            Arc::new(ActivityManager::new(self.pool.clone())),
+            Arc::new(NotificationManager::new(self.pool.clone())),
+            trash)
+    }
+
+    /// Opens the library as its owner
+    pub async fn open(&self, libraries: &LibraryManager, library: &LibraryModel) -> Library {
+        libraries.get_for_user(&library.id.to_string(), &library.owner_id, PermissionLevel::Admin).await
+            .expect("Owner can't open the library")
+    }
+
+    pub fn share_links(&self) -> Arc<ShareLinkManager> {
+        let config = GeneralConfig {
+            listen_ip: None,
+            listen_port: None,
+            public_url: "http://localhost".to_string(),
+            database_url: None,
+        };
+        Arc::new(ShareLinkManager::new(self.pool.clone(), &config, Arc::new(NotificationManager::new(self.pool.clone()))))
+    }
+
+    pub fn trash(&self) -> Arc<TrashManager> {
+        Arc::new(TrashManager::new(self.pool.clone(), &TrashConfig::default()))
+    }
+
+    /// Removes the repo, its libraries and the users, along with everything of theirs
+    pub async fn cleanup(self) {
+        query!("delete from storage.sessions where user_id = any($1)", &self.user_ids)
+            .execute(&self.pool)
+            .await.unwrap();
+        query!("delete from storage.repos where id = $1", self.repo_id)
+            .execute(&self.pool)
+            .await.unwrap();
+        query!("delete from storage.users where id = any($1)", &self.user_ids)
+            .execute(&self.pool)
+            .await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&self.root).await;
+    }
+}
+
+/// Removes what tests that failed before their cleanup left behind, once they can no longer be running
+async fn remove_stale(pool: &DB) {
+    let repos = query!("delete from storage.repos where id like $1 and created_at < now() - interval '1 hour' returning id",
+        format!("{}%", TEST_REPO_PREFIX)
+    )
+        .fetch_all(pool)
+        .await.unwrap();
+    for repo in repos {
+        let _ = tokio::fs::remove_dir_all(std::env::temp_dir().join(repo.id)).await;
+    }
+    let users = query!("delete from storage.users where email like $1 and created_at < now() - interval '1 hour' returning id",
+        format!("%@{}", TEST_EMAIL_DOMAIN)
+    )
+        .fetch_all(pool)
+        .await.unwrap();
+    let user_ids: Vec<String> = users.into_iter().map(|user| user.id).collect();
+    query!("delete from storage.sessions where user_id = any($1)", &user_ids)
+        .execute(pool)
+        .await.unwrap();
+}
+
+/// The code of the error the result failed with, the status for errors without one. None if it succeeded
+pub fn error_code<T>(result: Result<T, ResponseError>) -> Option<String> {
+    match result {
+        Ok(_) => None,
+        Err(ResponseError::NotFound(e) | ResponseError::Unauthorized(e) | ResponseError::Forbidden(e) | ResponseError::BadRequest(e)
+            | ResponseError::Conflict(e) | ResponseError::PayloadTooLarge(e) | ResponseError::UnsupportedMediaType(e)
+            | ResponseError::PreconditionFailed(e) | ResponseError::Locked(e) | ResponseError::InternalServerError(e)
+            | ResponseError::DatabaseError(e)) => Some(e.code),
+        Err(e) => Some(e.get_http_status().to_string())
+    }
+}
+
+/// Writes the file, creating the folder it is in if needed
+pub async fn write_file(library: &Library, path: &str, contents: &str) {
+    if let Some(parent) = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        library.touch_file(&parent.to_path_buf(), FileType::Folder).await.unwrap();
+    }
+    library.write_file(&PathBuf::from(path), &mut contents.as_bytes()).await.unwrap();
+}
+
+/// The contents of the file, None if there is no file at the path
+pub async fn read_file(library: &Library, path: &str) -> Option<String> {
+    read_internal(library, &PathBuf::from(path)).await
+}
+
+/// The contents of the file, which can be in the library's internal folder
+pub async fn read_internal(library: &Library, path: &PathBuf) -> Option<String> {
+    let mut contents = String::new();
+    library.read_internal(path).await.unwrap()?.read_to_string(&mut contents).await.unwrap();
+    Some(contents)
+}
@@ -1,7 +1,10 @@
 use std::fs;
 use std::io::Cursor;
+use std::pin::Pin;
+use std::task::{self, Poll};
 use std::time::Duration;
-use log::trace;
+use chrono::{DateTime, Utc};
+use log::{error, trace};
 use rand::rngs::OsRng;
 use rand::{rng, Rng, TryRngCore};
 use rand::distr::Alphanumeric;
@@ -15,15 +18,18 @@ use rocket::response::Responder;
 use rocket::serde::Serialize;
 use rocket_dyn_templates::handlebars::Handlebars;
 use rocket_session_store::{Session, SessionError, SessionResult, SessionStore};
-use rocket_session_store::memory::MemoryStore;
 use sqlx::{migrate, Error, Pool, Postgres};
 use sqlx::postgres::PgPoolOptions;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, ReadBuf};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use uuid::Uuid;
-use crate::consts::{SESSION_COOKIE_NAME, SESSION_LIFETIME_SECONDS};
+use crate::consts::{API_TOKEN_PREFIX, SESSION_COOKIE_NAME, SESSION_LIFETIME_SECONDS};
 use crate::models::user::{UserAuthError,};
-use crate::SessionData;
+use crate::storage::StorageError;
+use crate::{SessionData, DB};
+use crate::managers::sessions::PostgresStore;
 use crate::util::ResponseError::DatabaseError;
 
 pub(crate) fn setup_logger() {
@@ -49,10 +55,11 @@ pub async fn setup_db() -> Pool<Postgres> {
     pool
 }
 
-pub fn setup_session_store() -> SessionStore<SessionData> {
-    let memory_store: MemoryStore::<SessionData> = MemoryStore::default();
+pub fn setup_session_store(pool: DB) -> SessionStore<SessionData> {
+    let store: PostgresStore<SessionData> = PostgresStore::new(pool);
+    store.start_cleanup_task();
     SessionStore {
-        store: Box::new(memory_store),
+        store: Box::new(store),
         name: SESSION_COOKIE_NAME.into(),
         duration: Duration::from_secs(SESSION_LIFETIME_SECONDS),
         // The cookie builder is used to set the cookie's path and other options.
@@ -72,6 +79,8 @@ pub async fn set_csrf(session: &Session<'_, SessionData>) -> String {
         .unwrap_or_else(|| SessionData {
             csrf_token: None,
             login: None,
+            unlocked_share_links: Vec::new(),
+            accessed_share_links: Vec::new(),
         });
     sess.csrf_token = Some(token.clone());
     session.set(sess).await.unwrap();
@@ -106,6 +115,39 @@ pub fn gen_csrf_token() -> String {
         .collect()
 }
 
+/// Generates a new personal API token, which is only shown to the user once
+pub fn gen_api_token() -> String {
+    let token: String = rng()
+        .sample_iter(&Alphanumeric)
+        .map(char::from)
+        .take(40)
+        .collect();
+    format!("{}{}", API_TOKEN_PREFIX, token)
+}
+
+/// Generates the token of a public share link, which is part of its URL
+pub fn gen_share_token() -> String {
+    rng()
+        .sample_iter(&Alphanumeric)
+        .map(char::from)
+        .take(32)
+        .collect()
+}
+
+/// Returns the hex encoded sha256 hash of an API token, as stored in the database
+pub fn hash_api_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Parses an HTTP date, ex. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn parse_http_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date).ok().map(|date| date.with_timezone(&Utc))
+}
+
+pub fn format_http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct JsonErrorResponse {
     pub(crate) code: String,
@@ -115,6 +157,14 @@ pub struct JsonErrorResponse {
 #[derive(Debug)]
 pub enum ResponseError {
     NotFound(JsonErrorResponse),
+    Unauthorized(JsonErrorResponse),
+    Forbidden(JsonErrorResponse),
+    BadRequest(JsonErrorResponse),
+    Conflict(JsonErrorResponse),
+    PayloadTooLarge(JsonErrorResponse),
+    UnsupportedMediaType(JsonErrorResponse),
+    PreconditionFailed(JsonErrorResponse),
+    Locked(JsonErrorResponse),
     GenericError,
     InternalServerError(JsonErrorResponse),
     DatabaseError(JsonErrorResponse),
@@ -123,21 +173,36 @@ pub enum ResponseError {
 }
 
 impl ResponseError {
-    fn get_http_status(&self) -> Status {
+    pub(crate) fn get_http_status(&self) -> Status {
         match self {
             ResponseError::InternalServerError(_) => Status::InternalServerError,
             ResponseError::GenericError => Status::InternalServerError,
             ResponseError::NotFound(_) => Status::NotFound,
+            ResponseError::Unauthorized(_) => Status::Unauthorized,
+            ResponseError::Forbidden(_) => Status::Forbidden,
+            ResponseError::BadRequest(_) => Status::BadRequest,
+            ResponseError::Conflict(_) => Status::Conflict,
+            ResponseError::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            ResponseError::UnsupportedMediaType(_) => Status::UnsupportedMediaType,
+            ResponseError::PreconditionFailed(_) => Status::PreconditionFailed,
+            ResponseError::Locked(_) => Status::new(423),
             ResponseError::DatabaseError(_) => Status::InternalServerError,
             ResponseError::AuthError(e) => e.get_response_code(),
             ResponseError::CSRFError => Status::Unauthorized,
-            _ => Status::BadRequest,
         }
     }
 
     fn into_res_err(self) -> JsonErrorResponse {
         match self {
             ResponseError::NotFound(e) => e,
+            ResponseError::Unauthorized(e) => e,
+            ResponseError::Forbidden(e) => e,
+            ResponseError::BadRequest(e) => e,
+            ResponseError::Conflict(e) => e,
+            ResponseError::PayloadTooLarge(e) => e,
+            ResponseError::UnsupportedMediaType(e) => e,
+            ResponseError::PreconditionFailed(e) => e,
+            ResponseError::Locked(e) => e,
             ResponseError::GenericError => {
                 JsonErrorResponse {
                     code: "INTERNAL_SERVER_ERROR".to_string(),
@@ -172,6 +237,44 @@ impl From<UserAuthError> for ResponseError {
     }
 }
 
+impl From<StorageError> for ResponseError {
+    fn from(value: StorageError) -> Self {
+        let response = JsonErrorResponse {
+            code: value.get_err_code(),
+            message: value.get_err_msg(),
+        };
+        match value {
+            StorageError::InvalidPath => ResponseError::BadRequest(response),
+            StorageError::NotFound => ResponseError::NotFound(response),
+            StorageError::AlreadyExists => ResponseError::Conflict(response),
+            StorageError::InvalidDestination => ResponseError::BadRequest(response),
+            StorageError::InvalidFilter => ResponseError::BadRequest(response),
+            StorageError::InvalidCursor => ResponseError::BadRequest(response),
+            StorageError::ListingTooLarge => ResponseError::BadRequest(response),
+        }
+    }
+}
+
+/// Converts an error from a storage backend, keeping the status of a [StorageError]
+pub fn storage_error(e: anyhow::Error) -> ResponseError {
+    match e.downcast::<StorageError>() {
+        Ok(e) => e.into(),
+        Err(e) => ResponseError::InternalServerError(JsonErrorResponse {
+            code: "STORAGE_ERROR".to_string(),
+            message: e.to_string(),
+        })
+    }
+}
+
+/// Logs the error of a database query, which is only described to the client as a database error
+pub fn database_error(e: anyhow::Error) -> ResponseError {
+    error!("Database query failed: {:#}", e);
+    ResponseError::DatabaseError(JsonErrorResponse {
+        code: "DATABASE_ERROR".to_string(),
+        message: "A database error occurred".to_string(),
+    })
+}
+
 impl std::fmt::Display for ResponseError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(fmt, "Error {}.", self.get_http_status())
@@ -190,4 +293,39 @@ impl<'r> Responder<'r, 'static> for ResponseError {
             .sized_body(err_response.len(), Cursor::new(err_response))
             .ok()
     }
-}
\ No newline at end of file
+}
+/// Fails reads once more than the limit has been read, unlike [AsyncReadExt::take](tokio::io::AsyncReadExt::take) which silently stops there
+pub struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    /// Whether reading failed because there was more than the limit
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        if read > self.remaining {
+            self.exceeded = true;
+            return Poll::Ready(Err(std::io::Error::other("Read more than the limit")))
+        }
+        self.remaining -= read;
+        result
+    }
+}
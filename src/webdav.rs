@@ -0,0 +1,920 @@
+mod locks;
+mod proxy;
+mod xml;
+
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::{debug, error, info, warn};
+use moka::future::Cache;
+use rocket::Shutdown;
+use rocket::futures::TryStreamExt;
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::http::hyper::{Body, Request, Response, Uri};
+use rocket::http::hyper::request::Parts;
+use rocket::http::hyper::server::Server;
+use rocket::http::hyper::server::conn::AddrStream;
+use rocket::http::hyper::service::{make_service_fn, service_fn};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use tokio_util::io::{ReaderStream, StreamReader};
+use uuid::Uuid;
+use crate::consts::{API_TOKEN_PREFIX, MAX_UPLOAD_SIZE, WEBDAV_AUTH_CACHE_SECONDS, WEBDAV_LOGIN_LOCKOUT_SECONDS, WEBDAV_MAX_FAILED_LOGINS, WEBDAV_MAX_LOCK_SECONDS};
+use crate::DB;
+use crate::download::{DownloadHeaders, FileDownload};
+use crate::guards::ApiUser;
+use crate::managers::libraries::LibraryManager;
+use crate::managers::trash::TrashManager;
+use crate::managers::versions::VersionManager;
+use crate::models;
+use crate::models::library::{LibraryModel, PermissionLevel};
+use crate::objs::library::{Library, ListOptions, INTERNAL_FOLDER};
+use crate::storage::{ConflictPolicy, FileMetadata, FileType};
+use crate::util::{hash_api_token, LimitedReader, ResponseError};
+use crate::webdav::locks::{is_inside, LockManager};
+use crate::webdav::proxy::AppProxy;
+use crate::webdav::xml::DavEntry;
+
+pub use crate::webdav::proxy::app_address;
+
+/// The path the WebDAV server is served under
+const DAV_PREFIX: &str = "/dav";
+const ALLOWED_METHODS: &str = "OPTIONS, PROPFIND, PROPPATCH, GET, HEAD, PUT, MKCOL, DELETE, COPY, MOVE, LOCK, UNLOCK";
+/// The most a LOCK request body is read, it's only checked for being empty
+const MAX_LOCK_BODY_SIZE: u64 = 64 * 1024;
+
+/// Serves every library the user can access as a WebDAV collection under `/dav/<library name>/`.
+/// Rocket can't route WebDAV methods (PROPFIND, MKCOL, ...), so when enabled this server takes the app's address,
+/// and passes every request outside of [DAV_PREFIX] on to Rocket, which then only listens on loopback.
+/// Users sign in with basic auth, using their username or email and password, or any username with an API token as the password
+pub struct WebDav {
+    pool: DB,
+    libraries: Arc<Mutex<LibraryManager>>,
+    trash: Arc<TrashManager>,
+    versions: Arc<VersionManager>,
+    locks: LockManager,
+    /// Users of recently verified passwords, keyed by the Authorization header's hash
+    credentials: Cache<String, CachedLogin>,
+    /// Failed logins by IP address, which are locked out after [WEBDAV_MAX_FAILED_LOGINS]
+    failed_logins: Cache<IpAddr, u32>,
+}
+
+#[derive(Clone)]
+struct CachedLogin {
+    user: Arc<ApiUser>,
+    /// The user's credentials version when the password was verified, the login is forgotten once it changes
+    credentials_version: i32,
+}
+
+/// What the request path points to
+enum Target {
+    /// The list of libraries
+    Root,
+    Library {
+        library: Library,
+        /// The name the library is listed as
+        name: String,
+        /// Path inside the library, without leading or trailing slashes
+        path: String,
+    },
+}
+
+enum Resource {
+    Missing,
+    Folder,
+    File(FileMetadata),
+}
+
+fn response(status: Status) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status.code.try_into().unwrap_or_default();
+    response
+}
+
+fn xml_response(status: Status, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status.code)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap_or_else(|_| response(Status::InternalServerError))
+}
+
+fn internal_error(e: anyhow::Error) -> Status {
+    error!("WebDAV request failed: {}", e);
+    Status::InternalServerError
+}
+
+fn response_error(e: ResponseError) -> Status {
+    e.get_http_status()
+}
+
+fn header<'a>(parts: &'a Parts, name: &str) -> Option<&'a str> {
+    parts.headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Splits the path into its decoded segments, after the [DAV_PREFIX]
+fn parse_path(path: &str) -> Option<Vec<String>> {
+    let rest = path.strip_prefix(DAV_PREFIX)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None
+    }
+    let mut segments = Vec::new();
+    for segment in rest.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = RawStr::new(segment).percent_decode().ok()?;
+        if segment == "." || segment == ".." || segment.contains(['/', '\\']) {
+            return None
+        }
+        segments.push(segment.into_owned());
+    }
+    Some(segments)
+}
+
+fn encode_href(library_name: &str, path: &str, is_collection: bool) -> String {
+    let mut href = format!("{}/{}", DAV_PREFIX, RawStr::new(library_name).percent_encode());
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        href.push('/');
+        href.push_str(RawStr::new(segment).percent_encode().as_str());
+    }
+    if is_collection {
+        href.push('/');
+    }
+    href
+}
+
+fn join_path(folder: &str, name: &str) -> String {
+    if folder.is_empty() { name.to_string() } else { format!("{}/{}", folder, name) }
+}
+
+fn parent_path(path: &str) -> &str {
+    path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn content_type(path: &str) -> ContentType {
+    path.rsplit_once('.')
+        .and_then(|(_, ext)| ContentType::from_extension(ext))
+        .unwrap_or(ContentType::Binary)
+}
+
+/// Returns the lock tokens submitted in the If and Lock-Token headers
+fn submitted_tokens(parts: &Parts) -> Vec<Uuid> {
+    let values = parts.headers.get_all("If").iter()
+        .chain(parts.headers.get_all("Lock-Token").iter())
+        .filter_map(|value| value.to_str().ok());
+    let mut tokens = Vec::new();
+    for value in values {
+        for tag in value.split('<').skip(1) {
+            let token = tag.split('>').next()
+                .and_then(|tag| tag.strip_prefix("opaquelocktoken:"))
+                .and_then(|token| Uuid::parse_str(token).ok());
+            if let Some(token) = token {
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
+
+/// Parses the Timeout header of a LOCK request, ex. `Second-3600` or `Infinite`
+fn parse_timeout(parts: &Parts) -> u64 {
+    header(parts, "Timeout")
+        .and_then(|value| value.split(',').next())
+        .and_then(|value| value.trim().strip_prefix("Second-"))
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(WEBDAV_MAX_LOCK_SECONDS)
+}
+
+async fn stat(library: &Library, path: &str) -> Result<Resource, Status> {
+    if path.is_empty() {
+        return Ok(Resource::Folder)
+    }
+    let rel_path = PathBuf::from(path);
+    if let Some(metadata) = library.stat_file(&rel_path).await.map_err(internal_error)? {
+        return Ok(Resource::File(metadata))
+    }
+    if library.exists(&rel_path).await.map_err(internal_error)? {
+        Ok(Resource::Folder)
+    } else {
+        Ok(Resource::Missing)
+    }
+}
+
+/// Returns an error unless the path's parent folder exists
+async fn check_parent(library: &Library, path: &str) -> Result<(), Status> {
+    match stat(library, parent_path(path)).await? {
+        Resource::Folder => Ok(()),
+        _ => Err(Status::Conflict)
+    }
+}
+
+impl WebDav {
+    pub fn new(pool: DB, libraries: Arc<Mutex<LibraryManager>>, trash: Arc<TrashManager>, versions: Arc<VersionManager>) -> Self {
+        Self {
+            pool,
+            libraries,
+            trash,
+            versions,
+            locks: LockManager::default(),
+            credentials: Cache::builder()
+                .time_to_live(Duration::from_secs(WEBDAV_AUTH_CACHE_SECONDS))
+                .build(),
+            failed_logins: Cache::builder()
+                .time_to_live(Duration::from_secs(WEBDAV_LOGIN_LOCKOUT_SECONDS))
+                .build(),
+        }
+    }
+
+    /// Starts serving on `addr` in the background, passing requests that aren't for WebDAV on to Rocket at `app_addr`.
+    /// Stops along with Rocket, and shuts Rocket down if the address can't be bound
+    pub fn serve(self: &Arc<Self>, addr: SocketAddr, app_addr: SocketAddr, shutdown: Shutdown) {
+        let webdav = self.clone();
+        let app = Arc::new(AppProxy::new(app_addr));
+        tokio::spawn(async move {
+            let make_service = make_service_fn(move |connection: &AddrStream| {
+                let webdav = webdav.clone();
+                let app = app.clone();
+                let remote = connection.remote_addr();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        let webdav = webdav.clone();
+                        let app = app.clone();
+                        async move {
+                            if parse_path(request.uri().path()).is_some() {
+                                Ok::<_, Infallible>(webdav.handle(request, remote).await)
+                            } else {
+                                Ok(app.forward(request, remote).await)
+                            }
+                        }
+                    }))
+                }
+            });
+            match Server::try_bind(&addr) {
+                Ok(server) => {
+                    info!("WebDAV listening on {}{}/", addr, DAV_PREFIX);
+                    if let Err(e) = server.serve(make_service).with_graceful_shutdown(shutdown.clone()).await {
+                        error!("WebDAV server stopped: {}", e);
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to listen on {}: {}", addr, e);
+                    shutdown.notify();
+                }
+            }
+        });
+    }
+
+    async fn handle(&self, request: Request<Body>, remote: SocketAddr) -> Response<Body> {
+        let (parts, body) = request.into_parts();
+        debug!("webdav {} {}", parts.method, parts.uri.path());
+        let Some(segments) = parse_path(parts.uri.path()) else {
+            return response(Status::NotFound)
+        };
+        if parts.method.as_str() == "OPTIONS" {
+            // Clients check for WebDAV support before signing in
+            return Response::builder()
+                .header("DAV", "1, 2")
+                .header("MS-Author-Via", "DAV")
+                .header("Allow", ALLOWED_METHODS)
+                .body(Body::empty())
+                .unwrap_or_else(|_| response(Status::InternalServerError))
+        }
+        let user = match self.authenticate(&parts, remote.ip()).await {
+            // Credentials are cached across connections, so the IP address is added to each request's copy
+            Some(user) => ApiUser { ip: Some(remote.ip()), ..(*user).clone() },
+            None => return Response::builder()
+                .status(Status::Unauthorized.code)
+                .header("WWW-Authenticate", "Basic realm=\"Storage\", charset=\"UTF-8\"")
+                .body(Body::empty())
+                .unwrap_or_else(|_| response(Status::InternalServerError))
+        };
+        let result = match parts.method.as_str() {
+            "PROPFIND" => self.propfind(&parts, &user, &segments).await,
+            "PROPPATCH" => self.proppatch(&parts, &user, &segments).await,
+            "GET" => self.get(&parts, &user, &segments, false).await,
+            "HEAD" => self.get(&parts, &user, &segments, true).await,
+            "PUT" => self.put(&parts, body, &user, &segments).await,
+            "MKCOL" => self.mkcol(&parts, &user, &segments).await,
+            "DELETE" => self.delete(&parts, &user, &segments).await,
+            "COPY" => self.copy_or_move(&parts, &user, &segments, false).await,
+            "MOVE" => self.copy_or_move(&parts, &user, &segments, true).await,
+            "LOCK" => self.lock(&parts, body, &user, &segments).await,
+            "UNLOCK" => self.unlock(&parts, &user, &segments).await,
+            _ => Err(Status::MethodNotAllowed)
+        };
+        result.unwrap_or_else(response)
+    }
+
+    /// Returns the user of the basic auth credentials, if they are valid. API tokens are checked on every request,
+    /// so revoking them takes effect right away, while verified passwords are remembered until the user's credentials version changes
+    async fn authenticate(&self, parts: &Parts, ip: IpAddr) -> Option<Arc<ApiUser>> {
+        let authorization = header(parts, "Authorization")?;
+        let key = format!("{:x}", Sha256::digest(authorization.as_bytes()));
+        if let Some(login) = self.credentials.get(&key).await {
+            match models::user::get_credentials_version(&self.pool, &login.user.user.id).await {
+                Ok(Some(version)) if version == login.credentials_version => return Some(login.user),
+                Ok(_) => self.credentials.invalidate(&key).await,
+                Err(e) => {
+                    error!("Failed to look up credentials version: {}", e);
+                    return None
+                }
+            }
+        }
+        if self.failed_logins.get(&ip).await.is_some_and(|failures| failures >= WEBDAV_MAX_FAILED_LOGINS) {
+            warn!("WebDAV login from {} refused after too many failed attempts", ip);
+            return None
+        }
+        let (scheme, credentials) = authorization.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None
+        }
+        let credentials = String::from_utf8(BASE64.decode(credentials.trim()).ok()?).ok()?;
+        let (username, password) = credentials.split_once(':')?;
+        if password.starts_with(API_TOKEN_PREFIX) {
+            let token = models::api_token::use_api_token(&self.pool, &hash_api_token(password)).await
+                .map_err(|e| error!("Failed to look up api token: {}", e)).ok()?;
+            let Some(token) = token else {
+                self.record_failed_login(ip).await;
+                return None
+            };
+            let user = models::user::find_user(&self.pool, &token.user_id).await
+                .map_err(|e| error!("Failed to look up user of api token: {}", e)).ok()??;
+            return Some(Arc::new(ApiUser { user, token: Some(token), ip: None }))
+        }
+        // Read before the password is verified, so anything that changes it meanwhile still makes the login forgotten
+        let credentials_version = models::user::get_credentials_version(&self.pool, username).await
+            .map_err(|e| error!("Failed to look up credentials version: {}", e)).ok()?
+            .unwrap_or_default();
+        let user = match models::user::validate_user(&self.pool, username, password).await {
+            Ok(user) => Arc::new(ApiUser { user, token: None, ip: None }),
+            Err(e) => {
+                warn!("WebDAV login failed for {}: {:?}", username, e);
+                self.record_failed_login(ip).await;
+                return None
+            }
+        };
+        self.credentials.insert(key, CachedLogin { user: user.clone(), credentials_version }).await;
+        Some(user)
+    }
+
+    async fn record_failed_login(&self, ip: IpAddr) {
+        let failures = self.failed_logins.get(&ip).await.unwrap_or(0);
+        self.failed_logins.insert(ip, failures + 1).await;
+    }
+
+    /// Lists the libraries the user can access with the names they are served as.
+    /// Libraries are served by name, falling back to their id if the name is ambiguous or not a valid path segment
+    async fn list_libraries(&self, libraries: &LibraryManager, user: &ApiUser) -> Result<Vec<(String, LibraryModel)>, Status> {
+        let mut list = libraries.list(&user.user.id).await.map_err(internal_error)?;
+        if let Some(library_id) = user.token.as_ref().and_then(|token| token.library_id) {
+            list.retain(|library| library.id == library_id);
+        }
+        let names: Vec<String> = list.iter().map(|library| {
+            let ambiguous = list.iter().filter(|other| other.name == library.name).count() > 1;
+            if ambiguous || library.name.is_empty() || library.name.contains(['/', '\\']) || library.name == "." || library.name == ".." {
+                library.id.to_string()
+            } else {
+                library.name.clone()
+            }
+        }).collect();
+        Ok(names.into_iter().zip(list).collect())
+    }
+
+    async fn resolve(&self, user: &ApiUser, segments: &[String], required: PermissionLevel) -> Result<Target, Status> {
+        let Some((name, rest)) = segments.split_first() else {
+            return Ok(Target::Root)
+        };
+        if rest.first().is_some_and(|segment| segment == INTERNAL_FOLDER) {
+            return Err(Status::NotFound)
+        }
+        let libraries = self.libraries.lock().await;
+        let list = self.list_libraries(&libraries, user).await?;
+        let Some((name, model)) = list.iter().find(|(library_name, _)| library_name == name) else {
+            return Err(Status::NotFound)
+        };
+        let library = libraries.get_for_api_user(&model.id.to_string(), user, required).await
+            .map_err(response_error)?;
+        Ok(Target::Library { library, name: name.clone(), path: rest.join("/") })
+    }
+
+    /// Returns the library and path the request points to, failing for the list of libraries or a library itself
+    async fn resolve_path(&self, user: &ApiUser, segments: &[String], required: PermissionLevel) -> Result<(Library, String, String), Status> {
+        match self.resolve(user, segments, required).await? {
+            Target::Library { library, name, path } if !path.is_empty() => Ok((library, name, path)),
+            _ => Err(Status::Forbidden)
+        }
+    }
+
+    /// Returns an error if the path is locked and the request did not submit the lock's token
+    fn check_lock(&self, parts: &Parts, user: &ApiUser, library: &Library, path: &str, recursive: bool) -> Result<(), Status> {
+        if self.locks.can_modify(&library.model().id, path, recursive, &user.user.id, &submitted_tokens(parts)) {
+            Ok(())
+        } else {
+            Err(Status::new(423))
+        }
+    }
+
+    fn entry(&self, library: &Library, name: &str, path: &str, resource: &Resource) -> DavEntry {
+        let is_collection = !matches!(resource, Resource::File(_));
+        let metadata = match resource {
+            Resource::File(metadata) => Some(metadata),
+            _ => None
+        };
+        DavEntry {
+            href: encode_href(name, path, is_collection),
+            name: if path.is_empty() { library.model().name.clone() } else { file_name(path).to_string() },
+            is_collection,
+            size: metadata.map(|metadata| metadata.size),
+            content_type: (!is_collection).then(|| content_type(path).to_string()),
+            last_modified: metadata.and_then(|metadata| metadata.last_modified),
+            etag: metadata.and_then(|metadata| metadata.etag.clone()),
+            lock: self.locks.get(&library.model().id, path),
+        }
+    }
+
+    async fn propfind(&self, parts: &Parts, user: &ApiUser, segments: &[String]) -> Result<Response<Body>, Status> {
+        // Infinite depth is treated as 1, as listing a whole library could be very expensive
+        let include_children = header(parts, "Depth") != Some("0");
+        let mut entries = Vec::new();
+        match self.resolve(user, segments, PermissionLevel::ReadOnly).await? {
+            Target::Root => {
+                entries.push(DavEntry {
+                    href: format!("{}/", DAV_PREFIX),
+                    name: String::new(),
+                    is_collection: true,
+                    size: None,
+                    content_type: None,
+                    last_modified: None,
+                    etag: None,
+                    lock: None,
+                });
+                if include_children {
+                    let libraries = self.libraries.lock().await;
+                    for (name, library) in self.list_libraries(&libraries, user).await? {
+                        entries.push(DavEntry {
+                            href: encode_href(&name, "", true),
+                            name: library.name,
+                            is_collection: true,
+                            size: None,
+                            content_type: None,
+                            last_modified: Some(library.created_at.and_utc()),
+                            etag: None,
+                            lock: self.locks.get(&library.id, ""),
+                        });
+                    }
+                }
+            },
+            Target::Library { library, name, path } => {
+                let resource = stat(&library, &path).await?;
+                match resource {
+                    Resource::Missing => return Err(Status::NotFound),
+                    Resource::File(_) => entries.push(self.entry(&library, &name, &path, &resource)),
+                    Resource::Folder => {
+                        entries.push(self.entry(&library, &name, &path, &resource));
+                        if include_children {
+                            let files = library.list_files(&PathBuf::from(&path), ListOptions::default()).await
+                                .map_err(internal_error)?;
+                            for file in files {
+                                let child_path = join_path(&path, &file.path);
+                                // The listing already has everything a stat would return
+                                let resource = match file._type {
+                                    FileType::File => Resource::File(FileMetadata {
+                                        size: file.size,
+                                        last_modified: file.last_modified,
+                                        etag: file.etag,
+                                    }),
+                                    FileType::Folder => Resource::Folder,
+                                    _ => continue
+                                };
+                                entries.push(self.entry(&library, &name, &child_path, &resource));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(xml_response(Status::new(207), xml::multistatus(&entries)))
+    }
+
+    /// Properties can't be stored, but clients like Windows Explorer fail to copy files if setting them fails
+    async fn proppatch(&self, parts: &Parts, user: &ApiUser, segments: &[String]) -> Result<Response<Body>, Status> {
+        let (library, name, path) = self.resolve_path(user, segments, PermissionLevel::ReadWrite).await?;
+        let resource = stat(&library, &path).await?;
+        if matches!(resource, Resource::Missing) {
+            return Err(Status::NotFound)
+        }
+        self.check_lock(parts, user, &library, &path, false)?;
+        let href = encode_href(&name, &path, matches!(resource, Resource::Folder));
+        Ok(xml_response(Status::new(207), xml::proppatch(&href)))
+    }
+
+    async fn get(&self, parts: &Parts, user: &ApiUser, segments: &[String], head: bool) -> Result<Response<Body>, Status> {
+        let Target::Library { library, path, .. } = self.resolve(user, segments, PermissionLevel::ReadOnly).await? else {
+            return Err(Status::MethodNotAllowed)
+        };
+        if path.is_empty() {
+            return Err(Status::MethodNotAllowed)
+        }
+        let headers = DownloadHeaders::from_headers(|name| header(parts, name));
+        let download = FileDownload::open(&library, &PathBuf::from(&path), content_type(&path), &headers).await
+            .map_err(internal_error)?;
+        let Some(download) = download else {
+            // Folders can't be downloaded
+            return match stat(&library, &path).await? {
+                Resource::Folder => Err(Status::MethodNotAllowed),
+                _ => Err(Status::NotFound)
+            }
+        };
+        let (status, headers, body) = download.into_parts();
+        let mut builder = Response::builder().status(status.code);
+        for header in headers.iter() {
+            builder = builder.header(header.name().as_str(), header.value());
+        }
+        let body = match body {
+            Some(body) if !head => Body::wrap_stream(ReaderStream::new(body)),
+            _ => Body::empty()
+        };
+        builder.body(body).map_err(|_| Status::InternalServerError)
+    }
+
+    async fn put(&self, parts: &Parts, body: Body, user: &ApiUser, segments: &[String]) -> Result<Response<Body>, Status> {
+        if segments.len() < 2 {
+            return Err(Status::MethodNotAllowed)
+        }
+        let (library, _, path) = self.resolve_path(user, segments, PermissionLevel::ReadWrite).await?;
+        let existed = match stat(&library, &path).await? {
+            Resource::Folder => return Err(Status::MethodNotAllowed),
+            Resource::File(_) => true,
+            Resource::Missing => false
+        };
+        if header(parts, "Content-Length").and_then(|length| length.parse::<u64>().ok()).is_some_and(|length| length > MAX_UPLOAD_SIZE.as_u64()) {
+            return Err(Status::PayloadTooLarge)
+        }
+        check_parent(&library, &path).await?;
+        self.check_lock(parts, user, &library, &path, false)?;
+        let stream = body.map_err(std::io::Error::other);
+        // Chunked bodies have no length to check up front, the write fails and is rolled back once they pass the limit
+        let mut contents = LimitedReader::new(StreamReader::new(stream), MAX_UPLOAD_SIZE.as_u64());
+        match self.versions.write_file(&library, &path, &mut contents, Some(&user.user.id)).await {
+            Ok(_) => {},
+            Err(_) if contents.exceeded() => return Err(Status::PayloadTooLarge),
+            Err(e) => return Err(internal_error(e))
+        }
+        Ok(response(if existed { Status::NoContent } else { Status::Created }))
+    }
+
+    async fn mkcol(&self, parts: &Parts, user: &ApiUser, segments: &[String]) -> Result<Response<Body>, Status> {
+        if segments.len() < 2 {
+            return Err(Status::MethodNotAllowed)
+        }
+        let (library, _, path) = self.resolve_path(user, segments, PermissionLevel::ReadWrite).await?;
+        if header(parts, "Content-Length").is_some_and(|length| length != "0") || parts.headers.contains_key("Transfer-Encoding") {
+            return Err(Status::UnsupportedMediaType)
+        }
+        if !matches!(stat(&library, &path).await?, Resource::Missing) {
+            return Err(Status::MethodNotAllowed)
+        }
+        check_parent(&library, &path).await?;
+        self.check_lock(parts, user, &library, &path, false)?;
+        library.touch_file(&PathBuf::from(&path), FileType::Folder).await
+            .map_err(internal_error)?;
+        Ok(response(Status::Created))
+    }
+
+    /// Deleted files are moved to the library's trash
+    async fn delete(&self, parts: &Parts, user: &ApiUser, segments: &[String]) -> Result<Response<Body>, Status> {
+        let (library, _, path) = self.resolve_path(user, segments, PermissionLevel::ReadWrite).await?;
+        if matches!(stat(&library, &path).await?, Resource::Missing) {
+            return Err(Status::NotFound)
+        }
+        self.check_lock(parts, user, &library, &path, true)?;
+        self.trash.trash(&library, &path, &user.user.id).await
+            .map_err(response_error)?;
+        self.locks.remove_all(&library.model().id, &path);
+        Ok(response(Status::NoContent))
+    }
+
+    async fn copy_or_move(&self, parts: &Parts, user: &ApiUser, segments: &[String], is_move: bool) -> Result<Response<Body>, Status> {
+        let required = if is_move { PermissionLevel::ReadWrite } else { PermissionLevel::ReadOnly };
+        let (library, _, path) = self.resolve_path(user, segments, required).await?;
+        let resource = stat(&library, &path).await?;
+        if matches!(resource, Resource::Missing) {
+            return Err(Status::NotFound)
+        }
+        let destination = header(parts, "Destination")
+            .and_then(|destination| destination.parse::<Uri>().ok())
+            .ok_or(Status::BadRequest)?;
+        let destination = parse_path(destination.path()).ok_or(Status::BadGateway)?;
+        let (dest_library, _, dest_path) = self.resolve_path(user, &destination, PermissionLevel::ReadWrite).await?;
+        let same_library = library.model().id == dest_library.model().id;
+        if same_library && (dest_path == path || is_inside(&dest_path, &path)) {
+            return Err(Status::Forbidden)
+        }
+        let existed = !matches!(stat(&dest_library, &dest_path).await?, Resource::Missing);
+        if existed && header(parts, "Overwrite").is_some_and(|overwrite| overwrite.eq_ignore_ascii_case("F")) {
+            return Err(Status::PreconditionFailed)
+        }
+        check_parent(&dest_library, &dest_path).await?;
+        if is_move {
+            self.check_lock(parts, user, &library, &path, true)?;
+        }
+        self.check_lock(parts, user, &dest_library, &dest_path, true)?;
+        if existed {
+            self.trash.trash(&dest_library, &dest_path, &user.user.id).await
+                .map_err(response_error)?;
+            self.locks.remove_all(&dest_library.model().id, &dest_path);
+        }
+        if is_move && same_library {
+            library.move_file(&PathBuf::from(&path), &PathBuf::from(&dest_path), ConflictPolicy::Fail).await
+                .map_err(internal_error)?;
+        } else {
+            // A folder copied with a depth of 0 is copied without its contents
+            if !is_move && header(parts, "Depth") == Some("0") && matches!(resource, Resource::Folder) {
+                dest_library.touch_file(&PathBuf::from(&dest_path), FileType::Folder).await
+                    .map_err(internal_error)?;
+            } else {
+                library.copy_to(&PathBuf::from(&path), &dest_library, &PathBuf::from(&dest_path), ConflictPolicy::Fail).await
+                    .map_err(internal_error)?;
+            }
+            if is_move {
+                self.trash.trash(&library, &path, &user.user.id).await
+                    .map_err(response_error)?;
+            }
+        }
+        if is_move {
+            self.locks.remove_all(&library.model().id, &path);
+        }
+        Ok(response(if existed { Status::NoContent } else { Status::Created }))
+    }
+
+    async fn lock(&self, parts: &Parts, body: Body, user: &ApiUser, segments: &[String]) -> Result<Response<Body>, Status> {
+        let Target::Library { library, name, path } = self.resolve(user, segments, PermissionLevel::ReadWrite).await? else {
+            return Err(Status::Forbidden)
+        };
+        let library_id = &library.model().id;
+        let timeout = parse_timeout(parts);
+        let mut request_body = Vec::new();
+        StreamReader::new(body.map_err(std::io::Error::other))
+            .take(MAX_LOCK_BODY_SIZE)
+            .read_to_end(&mut request_body).await
+            .map_err(|_| Status::BadRequest)?;
+        if request_body.iter().all(u8::is_ascii_whitespace) {
+            // A request without a body refreshes an existing lock
+            let lock = submitted_tokens(parts).iter()
+                .find_map(|token| self.locks.refresh(library_id, &path, token, &user.user.id, timeout))
+                .ok_or(Status::PreconditionFailed)?;
+            let is_collection = !matches!(stat(&library, &path).await?, Resource::File(_));
+            return Ok(xml_response(Status::Ok, xml::lock_discovery(&lock, &encode_href(&name, &path, is_collection))))
+        }
+        let resource = stat(&library, &path).await?;
+        let created = matches!(resource, Resource::Missing);
+        let recursive = header(parts, "Depth") != Some("0");
+        let lock = self.locks.lock(library_id, &path, recursive, &user.user.id, &user.user.username, timeout)
+            .ok_or(Status::new(423))?;
+        if created {
+            // Locking a path that doesn't exist creates an empty file, which clients then write to
+            let result = match check_parent(&library, &path).await {
+                Ok(()) => self.versions.write_file(&library, &path, &mut tokio::io::empty(), Some(&user.user.id)).await
+                    .map(|_| ()).map_err(internal_error),
+                Err(status) => Err(status)
+            };
+            if let Err(status) = result {
+                self.locks.unlock(library_id, &path, &lock.token, &user.user.id);
+                return Err(status)
+            }
+        }
+        let href = encode_href(&name, &path, matches!(resource, Resource::Folder));
+        Response::builder()
+            .status(if created { Status::Created.code } else { Status::Ok.code })
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .header("Lock-Token", format!("<{}>", lock.token_uri()))
+            .body(Body::from(xml::lock_discovery(&lock, &href)))
+            .map_err(|_| Status::InternalServerError)
+    }
+
+    async fn unlock(&self, parts: &Parts, user: &ApiUser, segments: &[String]) -> Result<Response<Body>, Status> {
+        let Target::Library { library, path, .. } = self.resolve(user, segments, PermissionLevel::ReadWrite).await? else {
+            return Err(Status::Conflict)
+        };
+        let token = header(parts, "Lock-Token")
+            .map(|token| token.trim().trim_start_matches('<').trim_end_matches('>'))
+            .and_then(|token| token.strip_prefix("opaquelocktoken:"))
+            .and_then(|token| Uuid::parse_str(token).ok())
+            .ok_or(Status::BadRequest)?;
+        if self.locks.unlock(&library.model().id, &path, &token, &user.user.id) {
+            Ok(response(Status::NoContent))
+        } else {
+            Err(Status::Conflict)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+    use crate::models::api_token::ApiTokenScope;
+    use crate::models::user::UserModel;
+    use crate::testing::{read_file, write_file, TestData};
+
+    fn parts(headers: &[(&str, &str)]) -> Parts {
+        let mut request = Request::builder();
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn parses_paths_under_the_prefix() {
+        assert_eq!(parse_path("/dav"), Some(vec![]));
+        assert_eq!(parse_path("/dav/"), Some(vec![]));
+        assert_eq!(parse_path("/dav/lib/a%20b/c.txt"), Some(vec!["lib".to_string(), "a b".to_string(), "c.txt".to_string()]));
+        assert_eq!(parse_path("/dav//lib//"), Some(vec!["lib".to_string()]));
+        for path in ["/", "/api/library", "/davx", "/dav/lib/..", "/dav/./lib", "/dav/lib/a%2Fb", "/dav/lib/%2e%2e", "/dav/lib/a%5Cb"] {
+            assert_eq!(parse_path(path), None, "{} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn encodes_hrefs() {
+        assert_eq!(encode_href("my lib", "", true), "/dav/my%20lib/");
+        assert_eq!(encode_href("lib", "a b/c#.txt", false), "/dav/lib/a%20b/c%23.txt");
+        assert_eq!(parse_path(&encode_href("l?b", "a%b/ü", false)), Some(vec!["l?b".to_string(), "a%b".to_string(), "ü".to_string()]));
+    }
+
+    #[test]
+    fn reads_lock_headers() {
+        let token = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let parts = parts(&[
+            ("If", &format!("(<opaquelocktoken:{}>) (Not <DAV:no-lock>)", token)),
+            ("Lock-Token", &format!("<opaquelocktoken:{}>", other)),
+        ]);
+        assert_eq!(submitted_tokens(&parts), [token, other]);
+        assert!(submitted_tokens(&self::parts(&[("If", "(<urn:uuid:1234>)")])).is_empty());
+
+        assert_eq!(parse_timeout(&self::parts(&[("Timeout", "Second-600")])), 600);
+        assert_eq!(parse_timeout(&self::parts(&[("Timeout", "Second-60, Infinite")])), 60);
+        assert_eq!(parse_timeout(&self::parts(&[("Timeout", "Infinite")])), WEBDAV_MAX_LOCK_SECONDS);
+        assert_eq!(parse_timeout(&self::parts(&[])), WEBDAV_MAX_LOCK_SECONDS);
+    }
+
+    async fn webdav(data: &TestData) -> WebDav {
+        let trash = data.trash();
+        let libraries = Arc::new(Mutex::new(data.libraries(trash.clone()).await));
+        let versions = Arc::new(VersionManager::new(data.pool.clone(), libraries.clone()));
+        WebDav::new(data.pool.clone(), libraries, trash, versions)
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", username, password)))
+    }
+
+    /// Sends the request from the IP address, returning the status and body of the response
+    async fn send(webdav: &WebDav, ip: [u8; 4], method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> (u16, String) {
+        let mut request = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let remote = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), 1234);
+        let response = webdav.handle(request.body(Body::from(body.to_string())).unwrap(), remote).await;
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    async fn propfind(webdav: &WebDav, user: &UserModel, path: &str) -> u16 {
+        send(webdav, [10, 0, 0, 1], "PROPFIND", path, &[("Authorization", &basic(&user.username, "password")), ("Depth", "0")], "").await.0
+    }
+
+    #[tokio::test]
+    async fn locks_out_after_failed_logins() {
+        let mut data = TestData::new().await;
+        let user = data.user("user").await;
+        data.set_password(&user, "password").await;
+        let webdav = webdav(&data).await;
+        let locked_out = [10, 0, 0, 2];
+
+        assert_eq!(send(&webdav, locked_out, "PROPFIND", "/dav/", &[], "").await.0, 401);
+        for _ in 0..WEBDAV_MAX_FAILED_LOGINS {
+            let (status, _) = send(&webdav, locked_out, "PROPFIND", "/dav/", &[("Authorization", &basic(&user.username, "wrong"))], "").await;
+            assert_eq!(status, 401);
+        }
+        // Now even the right password is refused, but only from that address
+        let authorization = basic(&user.email, "password");
+        assert_eq!(send(&webdav, locked_out, "PROPFIND", "/dav/", &[("Authorization", &authorization)], "").await.0, 401);
+        assert_eq!(send(&webdav, [10, 0, 0, 3], "PROPFIND", "/dav/", &[("Authorization", &authorization)], "").await.0, 207);
+        // Invalid API tokens count as failures too
+        let token_ip = [10, 0, 0, 4];
+        for _ in 0..WEBDAV_MAX_FAILED_LOGINS {
+            let (status, _) = send(&webdav, token_ip, "PROPFIND", "/dav/", &[("Authorization", &basic("x", &format!("{}unknown", API_TOKEN_PREFIX)))], "").await;
+            assert_eq!(status, 401);
+        }
+        let (token, _) = data.api_token(&user, None, ApiTokenScope::Read, None).await;
+        assert_eq!(send(&webdav, token_ip, "PROPFIND", "/dav/", &[("Authorization", &basic("x", &token))], "").await.0, 401);
+        assert_eq!(send(&webdav, [10, 0, 0, 5], "PROPFIND", "/dav/", &[("Authorization", &basic("x", &token))], "").await.0, 207);
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn forgets_logins_when_credentials_change() {
+        let mut data = TestData::new().await;
+        let user = data.user("user").await;
+        data.set_password(&user, "password").await;
+        let webdav = webdav(&data).await;
+        assert_eq!(propfind(&webdav, &user, "/dav/").await, 207);
+
+        data.set_password(&user, "changed").await;
+        // The verified password is remembered, until the change is recorded
+        assert_eq!(propfind(&webdav, &user, "/dav/").await, 207);
+        models::user::bump_credentials_version(&data.pool, &user.id).await.unwrap();
+        assert_eq!(propfind(&webdav, &user, "/dav/").await, 401);
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn lists_children_depending_on_depth() {
+        let mut data = TestData::new().await;
+        let user = data.user("user").await;
+        data.set_password(&user, "password").await;
+        let model = data.library(&user).await;
+        let webdav = webdav(&data).await;
+        let library = data.open(&*webdav.libraries.lock().await, &model).await;
+        write_file(&library, "docs/a.txt", "a").await;
+        write_file(&library, "docs/sub/b.txt", "b").await;
+        let authorization = basic(&user.username, "password");
+        let list = async |path: &str, depth: Option<&str>| {
+            let mut headers = vec![("Authorization", authorization.as_str())];
+            headers.extend(depth.map(|depth| ("Depth", depth)));
+            let (status, body) = send(&webdav, [10, 0, 0, 1], "PROPFIND", path, &headers, "").await;
+            assert_eq!(status, 207, "{}", body);
+            body.split("<D:href>").skip(1)
+                .filter_map(|href| href.split("</D:href>").next())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(list("/dav/test/docs", Some("0")).await, ["/dav/test/docs/"]);
+        let mut children = list("/dav/test/docs", Some("1")).await;
+        children.sort();
+        assert_eq!(children, ["/dav/test/docs/", "/dav/test/docs/a.txt", "/dav/test/docs/sub/"]);
+        // Infinite depth only lists the children too, as does leaving it out
+        let mut infinite = list("/dav/test/docs", Some("infinity")).await;
+        infinite.sort();
+        assert_eq!(infinite, children);
+        let mut default = list("/dav/test/docs", None).await;
+        default.sort();
+        assert_eq!(default, children);
+        assert_eq!(list("/dav/", Some("1")).await, ["/dav/", "/dav/test/"]);
+        assert_eq!(list("/dav/test/docs/a.txt", Some("1")).await, ["/dav/test/docs/a.txt"]);
+        // The internal folder isn't served
+        let (status, _) = send(&webdav, [10, 0, 0, 1], "PROPFIND", &format!("/dav/test/{}", INTERNAL_FOLDER), &[("Authorization", &authorization)], "").await;
+        assert_eq!(status, 404);
+        data.cleanup().await;
+    }
+
+    #[tokio::test]
+    async fn moves_overwritten_files_to_the_trash() {
+        let mut data = TestData::new().await;
+        let user = data.user("user").await;
+        data.set_password(&user, "password").await;
+        let model = data.library(&user).await;
+        let webdav = webdav(&data).await;
+        let library = data.open(&*webdav.libraries.lock().await, &model).await;
+        let authorization = basic(&user.username, "password");
+        let request = async |method: &str, path: &str, headers: &[(&str, &str)], body: &str| {
+            let mut headers = headers.to_vec();
+            headers.push(("Authorization", &authorization));
+            send(&webdav, [10, 0, 0, 1], method, path, &headers, body).await.0
+        };
+
+        assert_eq!(request("PUT", "/dav/test/a.txt", &[], "old").await, 201);
+        assert_eq!(request("PUT", "/dav/test/b.txt", &[], "new").await, 201);
+        let destination = [("Destination", "http://localhost/dav/test/a.txt")];
+        assert_eq!(request("MOVE", "/dav/test/b.txt", &[destination[0], ("Overwrite", "F")], "").await, 412);
+        assert_eq!(request("MOVE", "/dav/test/b.txt", &destination, "").await, 204);
+        assert_eq!(read_file(&library, "a.txt").await.as_deref(), Some("new"));
+        assert_eq!(read_file(&library, "b.txt").await, None);
+
+        let trash = webdav.trash.list(&library).await.unwrap();
+        assert_eq!(trash.iter().map(|item| item.item.path.as_str()).collect::<Vec<_>>(), ["a.txt"]);
+        webdav.trash.purge(&library, &trash[0].item).await.unwrap();
+        // Replacing a file by writing to it keeps the old contents as a version instead
+        assert_eq!(request("PUT", "/dav/test/a.txt", &[], "newer").await, 204);
+        assert!(webdav.trash.list(&library).await.unwrap().is_empty());
+        assert_eq!(webdav.versions.list(&library, "a.txt").await.unwrap().len(), 2);
+        // Deleting moves to the trash as well
+        assert_eq!(request("DELETE", "/dav/test/a.txt", &[], "").await, 204);
+        assert_eq!(read_file(&library, "a.txt").await, None);
+        assert_eq!(webdav.trash.list(&library).await.unwrap().len(), 1);
+        data.cleanup().await;
+    }
+}
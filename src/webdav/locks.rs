@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::{DateTime, TimeDelta, Utc};
+use uuid::Uuid;
+use crate::consts::WEBDAV_MAX_LOCK_SECONDS;
+
+/// An exclusive write lock on a file or folder, only kept in memory
+#[derive(Debug, Clone)]
+pub struct DavLock {
+    pub token: Uuid,
+    pub library_id: Uuid,
+    /// Path inside the library, without leading or trailing slashes
+    pub path: String,
+    /// Does the lock also cover everything inside the folder
+    pub recursive: bool,
+    pub user_id: String,
+    pub owner: String,
+    pub timeout_secs: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl DavLock {
+    /// The token as sent in the Lock-Token and If headers
+    pub fn token_uri(&self) -> String {
+        format!("opaquelocktoken:{}", self.token)
+    }
+
+    fn covers(&self, library_id: &Uuid, path: &str) -> bool {
+        &self.library_id == library_id
+            && (self.path == path || (self.recursive && is_inside(path, &self.path)))
+    }
+}
+
+/// Is the path inside the folder, at any depth
+pub fn is_inside(path: &str, folder: &str) -> bool {
+    (folder.is_empty() && !path.is_empty())
+        || path.strip_prefix(folder).is_some_and(|rest| rest.starts_with('/'))
+}
+
+#[derive(Default)]
+pub struct LockManager {
+    locks: Mutex<HashMap<Uuid, DavLock>>,
+}
+
+impl LockManager {
+    /// Returns the active locks, after removing any that expired
+    fn active(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, DavLock>> {
+        let mut locks = self.locks.lock().unwrap();
+        let now = Utc::now();
+        locks.retain(|_, lock| lock.expires_at > now);
+        locks
+    }
+
+    /// Returns the lock that applies to the path, if any
+    pub fn get(&self, library_id: &Uuid, path: &str) -> Option<DavLock> {
+        self.active().values()
+            .find(|lock| lock.covers(library_id, path))
+            .cloned()
+    }
+
+    /// Locks the path, returns None if it, or anything inside it when recursive, is already locked
+    pub fn lock(&self, library_id: &Uuid, path: &str, recursive: bool, user_id: &str, owner: &str, timeout_secs: u64) -> Option<DavLock> {
+        let mut locks = self.active();
+        let conflict = locks.values().any(|lock| lock.covers(library_id, path)
+            || (recursive && &lock.library_id == library_id && is_inside(&lock.path, path)));
+        if conflict {
+            return None
+        }
+        let timeout_secs = timeout_secs.min(WEBDAV_MAX_LOCK_SECONDS);
+        let lock = DavLock {
+            token: Uuid::new_v4(),
+            library_id: *library_id,
+            path: path.to_string(),
+            recursive,
+            user_id: user_id.to_string(),
+            owner: owner.to_string(),
+            timeout_secs,
+            expires_at: Utc::now() + TimeDelta::seconds(timeout_secs as i64),
+        };
+        locks.insert(lock.token, lock.clone());
+        Some(lock)
+    }
+
+    /// Extends the lock, if it is held by the user and applies to the path
+    pub fn refresh(&self, library_id: &Uuid, path: &str, token: &Uuid, user_id: &str, timeout_secs: u64) -> Option<DavLock> {
+        let mut locks = self.active();
+        let lock = locks.get_mut(token)
+            .filter(|lock| lock.user_id == user_id && lock.covers(library_id, path))?;
+        lock.timeout_secs = timeout_secs.min(WEBDAV_MAX_LOCK_SECONDS);
+        lock.expires_at = Utc::now() + TimeDelta::seconds(lock.timeout_secs as i64);
+        Some(lock.clone())
+    }
+
+    /// Removes the lock, returns false if it is not held by the user or does not apply to the path
+    pub fn unlock(&self, library_id: &Uuid, path: &str, token: &Uuid, user_id: &str) -> bool {
+        let mut locks = self.active();
+        if locks.get(token).is_some_and(|lock| lock.user_id == user_id && lock.covers(library_id, path)) {
+            locks.remove(token);
+            return true
+        }
+        false
+    }
+
+    /// Checks the user can modify the path, which requires submitting the token of any lock on it.
+    /// When recursive, locks on anything inside the path are also checked
+    pub fn can_modify(&self, library_id: &Uuid, path: &str, recursive: bool, user_id: &str, submitted: &[Uuid]) -> bool {
+        self.active().values()
+            .filter(|lock| lock.covers(library_id, path)
+                || (recursive && &lock.library_id == library_id && is_inside(&lock.path, path)))
+            .all(|lock| lock.user_id == user_id && submitted.contains(&lock.token))
+    }
+
+    /// Removes the locks on the path and anything inside it, after it was deleted or moved away
+    pub fn remove_all(&self, library_id: &Uuid, path: &str) {
+        self.active().retain(|_, lock| !(&lock.library_id == library_id
+            && (lock.path == path || is_inside(&lock.path, path))));
+    }
+}
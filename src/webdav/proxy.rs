@@ -0,0 +1,74 @@
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::time::Duration;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use log::error;
+
+/// Headers that only apply to a single connection, so aren't passed on
+const HOP_BY_HOP_HEADERS: [&str; 6] = ["connection", "keep-alive", "proxy-connection", "te", "trailer", "upgrade"];
+/// The header Rocket reads the client's IP address from, see [rocket::Config::ip_header]
+const REAL_IP_HEADER: &str = "x-real-ip";
+/// Kept under Rocket's default keep-alive of 5 seconds, so pooled connections aren't reused just as Rocket closes them
+const POOL_IDLE_SECONDS: u64 = 2;
+
+/// Returns a free port on the loopback interface for Rocket to listen on, so only the WebDAV server is exposed
+pub fn app_address() -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.local_addr()
+}
+
+/// Passes requests that aren't for WebDAV on to Rocket
+pub struct AppProxy {
+    client: Client<HttpConnector>,
+    addr: SocketAddr,
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+impl AppProxy {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            client: Client::builder()
+                .pool_idle_timeout(Duration::from_secs(POOL_IDLE_SECONDS))
+                .build_http(),
+            addr,
+        }
+    }
+
+    /// Sends the request to Rocket and streams back its response. The client's IP address is passed in [REAL_IP_HEADER],
+    /// unless a reverse proxy in front of the app already set it
+    pub async fn forward(&self, request: Request<Body>, remote: SocketAddr) -> Response<Body> {
+        let (mut parts, body) = request.into_parts();
+        let path = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+        parts.uri = match format!("http://{}{}", self.addr, path).parse::<Uri>() {
+            Ok(uri) => uri,
+            Err(_) => return status_response(StatusCode::BAD_REQUEST)
+        };
+        remove_hop_by_hop(&mut parts.headers);
+        if !parts.headers.contains_key(REAL_IP_HEADER)
+            && let Ok(ip) = HeaderValue::from_str(&remote.ip().to_string()) {
+            parts.headers.insert(HeaderName::from_static(REAL_IP_HEADER), ip);
+        }
+        match self.client.request(Request::from_parts(parts, body)).await {
+            Ok(mut response) => {
+                remove_hop_by_hop(response.headers_mut());
+                response
+            },
+            Err(e) => {
+                error!("Failed to pass request on to the app server: {}", e);
+                status_response(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,89 @@
+use std::fmt::Write;
+use chrono::{DateTime, Utc};
+use crate::util::format_http_date;
+use crate::webdav::locks::DavLock;
+
+/// The properties of a file or folder, as returned by PROPFIND
+pub struct DavEntry {
+    /// The encoded url path of the entry, folders end in a slash
+    pub href: String,
+    pub name: String,
+    pub is_collection: bool,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub etag: Option<String>,
+    pub lock: Option<DavLock>,
+}
+
+pub fn escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn write_active_lock(out: &mut String, lock: &DavLock, href: &str) {
+    let _ = write!(out, "<D:activelock>\
+        <D:locktype><D:write/></D:locktype>\
+        <D:lockscope><D:exclusive/></D:lockscope>\
+        <D:depth>{}</D:depth>\
+        <D:owner>{}</D:owner>\
+        <D:timeout>Second-{}</D:timeout>\
+        <D:locktoken><D:href>{}</D:href></D:locktoken>\
+        <D:lockroot><D:href>{}</D:href></D:lockroot>\
+        </D:activelock>",
+        if lock.recursive { "infinity" } else { "0" }, escape(&lock.owner), lock.timeout_secs, lock.token_uri(), href);
+}
+
+/// The 207 Multi-Status body for a PROPFIND, all properties are always returned
+pub fn multistatus(entries: &[DavEntry]) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">");
+    for entry in entries {
+        let _ = write!(out, "<D:response><D:href>{}</D:href><D:propstat><D:prop>", escape(&entry.href));
+        let _ = write!(out, "<D:displayname>{}</D:displayname>", escape(&entry.name));
+        if entry.is_collection {
+            out.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+        } else {
+            out.push_str("<D:resourcetype/>");
+        }
+        if let Some(size) = entry.size {
+            let _ = write!(out, "<D:getcontentlength>{}</D:getcontentlength>", size);
+        }
+        if let Some(content_type) = &entry.content_type {
+            let _ = write!(out, "<D:getcontenttype>{}</D:getcontenttype>", escape(content_type));
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            let _ = write!(out, "<D:getlastmodified>{}</D:getlastmodified>", format_http_date(last_modified));
+        }
+        if let Some(etag) = &entry.etag {
+            let _ = write!(out, "<D:getetag>\"{}\"</D:getetag>", escape(etag));
+        }
+        out.push_str("<D:supportedlock><D:lockentry>\
+            <D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype>\
+            </D:lockentry></D:supportedlock>");
+        out.push_str("<D:lockdiscovery>");
+        if let Some(lock) = &entry.lock {
+            write_active_lock(&mut out, lock, &entry.href);
+        }
+        out.push_str("</D:lockdiscovery>");
+        out.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>");
+    }
+    out.push_str("</D:multistatus>");
+    out
+}
+
+/// The body of a PROPPATCH response, which reports success without storing anything
+pub fn proppatch(href: &str) -> String {
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\
+        <D:response><D:href>{}</D:href><D:propstat><D:prop/><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\
+        </D:multistatus>", escape(href))
+}
+
+/// The body of a LOCK response
+pub fn lock_discovery(lock: &DavLock, href: &str) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>");
+    write_active_lock(&mut out, lock, href);
+    out.push_str("</D:lockdiscovery></D:prop>");
+    out
+}
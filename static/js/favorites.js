@@ -0,0 +1,18 @@
+// Stars or unstars the file, folder or library of the button, from its data-library and data-path attributes
+async function toggleFavorite(button) {
+    const favorited = button.dataset.favorited === "true"
+    const params = new URLSearchParams({ library_id: button.dataset.library, path: button.dataset.path || "" })
+    const response = await fetch(`/api/favorites?${params}`, { method: favorited ? "DELETE" : "POST" })
+    if(response.ok) {
+        button.dataset.favorited = !favorited
+        const icon = button.querySelector("i")
+        icon.classList.toggle("fas", !favorited)
+        icon.classList.toggle("far", favorited)
+        if(favorited && button.dataset.removeRow) {
+            button.closest("tr").remove()
+        }
+    } else {
+        const error = await response.json().catch(() => ({ message: response.statusText }))
+        alert(`Could not update favorite: ${error.message}`)
+    }
+}